@@ -0,0 +1,162 @@
+use std::{env, hint::black_box, path::Path};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+#[cfg(feature = "dynarec")]
+use rust_8080_emulator::emulator::dynarec::Dynarec;
+use rust_8080_emulator::emulator::{self, assembler, cpu::Cpu, VideoInterrupts, CYCLES_PER_FRAME};
+
+// Instructions executed per measured iteration
+const INSTRUCTIONS: u64 = 100_000;
+
+// Frames of attract mode per measured iteration, a second of the game's time
+const FRAMES: u64 = 60;
+
+const ALU_LOOP: &str = "
+Start:  mvi b,0
+Loop:   mov a,b
+        add c
+        adc d
+        sub e
+        sbb b
+        ana h
+        xra l
+        ora c
+        cmp d
+        rlc
+        daa
+        mov c,a
+        inr d
+        dcr e
+        dcr b
+        jnz Loop
+        hlt
+";
+
+const MEMORY_COPY: &str = "
+Start:  lxi h,1000h
+        lxi d,2000h
+        lxi b,0400h
+Copy:   mov a,m
+        stax d
+        inx h
+        inx d
+        dcx b
+        mov a,b
+        ora c
+        jnz Copy
+        hlt
+";
+
+fn assemble(source: &str) -> Cpu {
+    Cpu::new(assembler::assemble(source).unwrap().image)
+}
+
+// Runs count instructions, starting the program again whenever it halts
+fn run(cpu: &mut Cpu, count: u64) {
+    for _ in 0..count {
+        if cpu.memory[cpu.pc as usize] == 0x76 {
+            cpu.pc = 0;
+        }
+        cpu.cycle().unwrap();
+    }
+}
+
+// Runs at least count instructions a block at a time
+#[cfg(feature = "dynarec")]
+fn run_translated(dynarec: &mut Dynarec, cpu: &mut Cpu, count: u64) {
+    let mut executed = 0;
+    while executed < count {
+        if cpu.memory[cpu.pc as usize] == 0x76 {
+            cpu.pc = 0;
+        }
+        executed += dynarec.step(cpu).unwrap() as u64;
+    }
+}
+
+// Runs the given number of frames with the interrupts of the Space Invaders video hardware
+fn run_frames(cpu: &mut Cpu, frames: u64) {
+    let end = cpu.cycles() + frames * CYCLES_PER_FRAME;
+    let mut video = VideoInterrupts::new(cpu);
+    while cpu.cycles() < end {
+        cpu.cycle().unwrap();
+        video.update(cpu);
+    }
+}
+
+// The file named by an environment variable, for workloads whose ROMs can't be distributed
+fn external(variable: &str) -> Option<String> {
+    let path = env::var(variable).ok();
+    if path.is_none() {
+        eprintln!("Skipping: set {} to run this benchmark", variable);
+    }
+    path
+}
+
+fn programs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("instructions");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+
+    let mut cpu = assemble(ALU_LOOP);
+    group.bench_function("alu loop", |b| b.iter(|| run(black_box(&mut cpu), INSTRUCTIONS)));
+
+    let mut cpu = assemble(MEMORY_COPY);
+    group.bench_function("memory copy", |b| b.iter(|| run(black_box(&mut cpu), INSTRUCTIONS)));
+
+    #[cfg(feature = "dynarec")]
+    for (name, source) in [("alu loop (dynarec)", ALU_LOOP), ("memory copy (dynarec)", MEMORY_COPY)] {
+        let mut dynarec = Dynarec::new().unwrap();
+        let mut cpu = assemble(source);
+        group.bench_function(name, |b| {
+            b.iter(|| run_translated(&mut dynarec, black_box(&mut cpu), INSTRUCTIONS))
+        });
+    }
+
+    // A CP/M exerciser such as 8080EXER.COM, loaded at 0100h. BDOS calls at 0005h return
+    // straight away, and the stack goes at the top of the TPA it reads from 0006h.
+    if let Some(path) = external("CPU_EXERCISER") {
+        let program = emulator::read_program("-b", Path::new(&path)).unwrap();
+        let mut image = vec![0; 0x100];
+        image[0x00] = 0x76;
+        image[0x05..0x08].copy_from_slice(&[0xc3, 0x00, 0xf0]);
+        image.extend(&program.image);
+        image.resize(0xf001, 0);
+        image[0xf000] = 0xc9;
+        group.bench_function("cpu exerciser", |b| {
+            b.iter_batched(
+                || {
+                    let mut cpu = Box::new(Cpu::new(image.clone()));
+                    cpu.pc = 0x100;
+                    cpu
+                },
+                |mut cpu| run(&mut cpu, INSTRUCTIONS),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+// Measured in frames, so the throughput reads as frames per second
+fn attract_mode(c: &mut Criterion) {
+    // A ROM set directory or ZIP, e.g. INVADERS_ROMS=roms/invaders.zip. Each iteration boots
+    // the machine and runs its attract mode from reset.
+    let path = match external("INVADERS_ROMS") {
+        Some(path) => path,
+        None => return,
+    };
+    let program = emulator::read_program("-m", Path::new(&path)).unwrap();
+    let mut group = c.benchmark_group("frames");
+    group.throughput(Throughput::Elements(FRAMES));
+    group.bench_function("invaders attract mode", |b| {
+        b.iter_batched(
+            || Box::new(program.clone().machine_cpu(None)),
+            |mut cpu| run_frames(&mut cpu, FRAMES),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, programs, attract_mode);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust_8080_emulator::emulator;
+
+const VRAM_START: usize = 0x2400;
+const VRAM_END: usize = 0x4000;
+
+// Video memory filled with a repeating pattern in which about one byte in fill is non-zero
+fn memory(fill: usize) -> Vec<u8> {
+    let mut memory = vec![0; 0x10000];
+    for (index, byte) in memory[VRAM_START..VRAM_END].iter_mut().enumerate() {
+        if index % fill == 0 {
+            *byte = (index * 37) as u8 | 0x01;
+        }
+    }
+    memory
+}
+
+fn render(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vram");
+    group.throughput(Throughput::Bytes((VRAM_END - VRAM_START) as u64));
+    // The attract mode screen has a few hundred lit bytes, a busy game screen a few thousand
+    for (name, fill) in [("sparse", 16), ("busy", 2), ("full", 1)] {
+        let memory = memory(fill);
+        group.bench_function(name, |b| b.iter(|| emulator::vram_points(black_box(&memory))));
+    }
+    group.throughput(Throughput::Bytes(1));
+    group.bench_function("byte_to_points", |b| {
+        b.iter(|| emulator::byte_to_points(black_box(0xa5), black_box(0x3000)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -1,8 +1,31 @@
-use std::{path::Path, fs::{File, read_to_string}, io::Read, time::Duration};
+use std::{path::Path, fs::{File, read_to_string}, io::Read, time::{Duration, Instant}};
 
 use sdl2::{pixels::Color, event::Event, keyboard::Keycode, video::Window, render::Canvas, Sdl, rect::Point};
 
-mod cpu;
+pub mod assembler;
+pub mod breakpoint;
+pub mod cpu;
+pub mod dap;
+pub mod disassembler;
+#[cfg(feature = "dynarec")]
+pub mod dynarec;
+pub mod error;
+mod expression;
+pub mod gdb;
+pub mod intel_hex;
+pub mod io;
+pub mod rom;
+pub mod single_step;
+pub mod symbols;
+pub mod trace;
+pub mod trace_diff;
+pub mod watchpoint;
+
+use breakpoint::Breakpoint;
+use error::EmulatorError;
+use symbols::SymbolTable;
+use trace::Tracer;
+use watchpoint::{WatchHit, Watchpoint};
 
 // Actual window dimensions
 const SCREEN_WIDTH: usize = 448;
@@ -12,48 +35,95 @@ const SCREEN_HEIGHT: usize = 512;
 const LOGICAL_SCREEN_WIDTH: usize = 224;
 const LOGICAL_SCREEN_HEIGHT: usize = 256;
 
+// Space Invaders runs at 2MHz and 60 frames a second
+pub const CYCLES_PER_FRAME: u64 = 2_000_000 / 60;
+
 pub struct Emulator {
-    breakpoints: Vec<u16>,
+    breakpoints: Vec<Breakpoint>,
     cpu: cpu::Cpu,
+    runner: Runner,
+    machine: Option<&'static rom::Machine>,
+    symbols: SymbolTable,
+    tracer: Option<Tracer>,
     sdl_context: Sdl,
     canvas: Canvas<Window>,
 }
 
 impl Emulator {
-    pub fn new(flag: &String, path: &Path) -> Emulator {
-        let program;
-        if flag == "-b" {
-            program = read_program_bin(path);
-        } else if flag == "-t" {
-            program = read_program_text(path);
-        } else {
-            panic!("Invalid flag");
-        }
+    pub fn new(program: Program) -> Result<Emulator, EmulatorError> {
+        let identification = rom::identify(&program.image);
+        println!("{}", identification);
 
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
+        let sdl_context = sdl2::init().map_err(EmulatorError::Sdl)?;
+        let video_subsystem = sdl_context.video().map_err(EmulatorError::Sdl)?;
         let window = video_subsystem.window("Space Invaders", SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
             .position_centered()
             .build()
-            .unwrap();
-        let mut canvas = window.into_canvas().build().unwrap();
-        canvas.set_logical_size(LOGICAL_SCREEN_WIDTH as u32, LOGICAL_SCREEN_HEIGHT as u32).unwrap();
+            .map_err(|error| EmulatorError::Sdl(error.to_string()))?;
+        let mut canvas = window.into_canvas().build().map_err(|error| EmulatorError::Sdl(error.to_string()))?;
+        canvas.set_logical_size(LOGICAL_SCREEN_WIDTH as u32, LOGICAL_SCREEN_HEIGHT as u32)
+            .map_err(|error| EmulatorError::Sdl(error.to_string()))?;
 
-        let breakpoints: Vec<u16> = vec![
+        let breakpoints: Vec<Breakpoint> = vec![
             // Add any breakpoints here
-            0x18DF,
+            Breakpoint::new(0x18DF),
         ];
 
-        Emulator {
+        let mut cpu = program.machine_cpu(None);
+        let mut runner = Runner::new(&cpu, identification.machine().is_some())?;
+        runner.set_breakpoints(&mut cpu, &breakpoints);
+
+        Ok(Emulator {
             breakpoints,
-            cpu: cpu::Cpu::new(program),
+            cpu,
+            runner,
+            machine: identification.machine(),
+            symbols: SymbolTable::new(),
+            tracer: None,
             sdl_context,
             canvas,
+        })
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+        self.runner.set_breakpoints(&mut self.cpu, &self.breakpoints);
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.cpu.add_watchpoint(watchpoint);
+    }
+
+    // Stops on undocumented opcodes instead of running them
+    pub fn set_strict(&mut self, strict: bool) {
+        self.cpu.set_strict(strict);
+    }
+
+    pub fn set_model(&mut self, model: cpu::Model) {
+        self.cpu.set_model(model);
+    }
+
+    // Labels shown when execution stops
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    // Logs every executed instruction to the tracer
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Runs until the window is closed or the CPU stops on an error. The trace is written either way.
+    pub fn start(&mut self) -> Result<(), EmulatorError> {
+        let result = self.run();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.flush()?;
         }
+        result
     }
 
-    pub fn start(&mut self) {
-        let mut event_pump = self.sdl_context.event_pump().unwrap();
+    fn run(&mut self) -> Result<(), EmulatorError> {
+        let mut event_pump = self.sdl_context.event_pump().map_err(EmulatorError::Sdl)?;
 
         'running: loop {
             for event in event_pump.poll_iter() {
@@ -75,11 +145,14 @@ impl Emulator {
                         self.clear_screen();
 
                         self.cpu.enable = 1;
-                        self.cpu.cycle();
+                        let result = self.cycle(true);
                         self.cpu.enable = 0;
+                        result?;
 
-                        self.update_screen();
+                        self.update_screen()?;
+                        self.check_watchpoint();
                         self.cpu.print_registers();
+                        println!("NEXT {}", self.describe_pc());
                     },
                     _ => {}
                 }
@@ -88,65 +161,325 @@ impl Emulator {
             // The rest of the game loop goes here...
             if self.cpu.enable != 0 {
                 self.clear_screen();
-                self.cpu.cycle();
-                self.update_screen();
+                self.cycle(false)?;
+                self.update_screen()?;
+                self.check_watchpoint();
                 self.check_breakpoint();
             }
 
             std::thread::sleep(2 * Duration::from_micros(1)); // Should be 2Mhz
         }
+
+        Ok(())
+    }
+
+    // Runs one instruction if single is set, otherwise as far as the runner goes in one step.
+    // The trace and breakpoints without an address see every instruction.
+    fn cycle(&mut self, single: bool) -> Result<(), EmulatorError> {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.cpu)?;
+        }
+        let single = single
+            || self.tracer.is_some()
+            || self.breakpoints.iter().any(|breakpoint| breakpoint.address.is_none());
+        let halt = self.cpu.peek(self.cpu.pc) == 0x76;
+        self.runner.run(&mut self.cpu, single)?;
+        if halt {
+            println!("Halting");
+        }
+        Ok(())
     }
 
     fn check_breakpoint(&mut self) {
-        for breakpoint in &self.breakpoints {
-            if self.cpu.pc == *breakpoint {
+        for breakpoint in &mut self.breakpoints {
+            if breakpoint.check(&self.cpu) {
                 self.cpu.enable = 0;
-                println!("BREAK {:04X}", self.cpu.pc);
+                println!("BREAK {}", self.describe_pc());
                 self.cpu.print_registers();
                 return;
             }
         }
     }
 
+    // The address and instruction at pc, e.g. "1439 (DrawSprite)  CALL ClearScreen"
+    fn describe_pc(&self) -> String {
+        let instruction = disassembler::disassemble_model(&self.cpu.memory, self.cpu.pc, self.cpu.model());
+        match self.symbols.name(self.cpu.pc) {
+            Some(label) => format!("{:04X} ({})  {}", self.cpu.pc, label, instruction.symbolic(&self.symbols)),
+            None => format!("{:04X}  {}", self.cpu.pc, instruction.symbolic(&self.symbols)),
+        }
+    }
+
+    fn check_watchpoint(&mut self) {
+        if let Some(hit) = stop_on_watch_hit(&mut self.cpu) {
+            println!("{}", hit);
+            self.cpu.print_registers();
+        }
+    }
+
     fn clear_screen(&mut self) {
         self.canvas.set_draw_color(Color::BLACK);
         self.canvas.clear();
     }
 
-    fn update_screen(&mut self) {
+    fn update_screen(&mut self) -> Result<(), EmulatorError> {
+        let points = vram_points(&self.cpu.memory);
+
+        // Points under the machine's coloured overlay are drawn in its colour, the rest white
+        let overlay = self.machine.map_or(&[][..], |machine| machine.config.overlay);
+        let (tinted, white): (Vec<Point>, Vec<Point>) = points
+            .into_iter()
+            .partition(|point| overlay.iter().any(|band| band.contains(point.x(), point.y())));
         self.canvas.set_draw_color(Color::WHITE);
-        for byte_index in 0x2400..0x3FFF {
-            let byte = self.cpu.memory[byte_index];
-            if byte != 0 {
-                let points = byte_to_points(byte, byte_index);
-                self.canvas.draw_points(points.as_slice()).unwrap();
-            }
+        self.canvas.draw_points(white.as_slice()).map_err(EmulatorError::Sdl)?;
+        for band in overlay {
+            let (red, green, blue) = band.color;
+            let points: Vec<Point> = tinted
+                .iter()
+                .copied()
+                .filter(|point| band.contains(point.x(), point.y()))
+                .collect();
+            self.canvas.set_draw_color(Color::RGB(red, green, blue));
+            self.canvas.draw_points(points.as_slice()).map_err(EmulatorError::Sdl)?;
         }
         self.canvas.present();
+        Ok(())
+    }
+}
+
+// A program image loaded at address 0 and where execution starts, 0 unless the file says
+#[derive(Clone)]
+pub struct Program {
+    pub image: Vec<u8>,
+    pub start: Option<u16>,
+}
+
+impl Program {
+    fn new(image: Vec<u8>) -> Program {
+        Program { image, start: None }
+    }
+
+    pub fn cpu(self) -> cpu::Cpu {
+        let start = self.start.unwrap_or(0);
+        let mut cpu = cpu::Cpu::new(self.image);
+        cpu.pc = start;
+        cpu
+    }
+
+    // A cpu set up like the board of the machine the image is identified as, if it's a known
+    // one: its processor, unless model says otherwise, and the devices on its I/O ports
+    pub fn machine_cpu(self, model: Option<cpu::Model>) -> cpu::Cpu {
+        let machine = rom::identify(&self.image).machine();
+        let mut cpu = self.cpu();
+        if let Some(machine) = machine {
+            cpu.set_model(machine.config.cpu);
+            cpu.set_io(io::Io::board(machine.config));
+        }
+        if let Some(model) = model {
+            cpu.set_model(model);
+        }
+        cpu
+    }
+
+    // machine_cpu and a runner for it that fires its board's video interrupts, if it's a known
+    // machine, as the frontend does
+    pub fn machine_runner(self, model: Option<cpu::Model>) -> Result<(cpu::Cpu, Runner), EmulatorError> {
+        let video = rom::identify(&self.image).machine().is_some();
+        let cpu = self.machine_cpu(model);
+        let runner = Runner::new(&cpu, video)?;
+        Ok((cpu, runner))
     }
 }
 
-fn read_program_text(path: &Path) -> Vec<u8> {
-    let file_string = read_to_string(&path).expect("Failed to read file.");
+// The interrupts of the Space Invaders video hardware, RST 1 at mid-screen and RST 2 at
+// vertical blank
+#[derive(Debug, Clone)]
+pub struct VideoInterrupts {
+    // The cycle count the next one is due at
+    next: u64,
+    rst: u8,
+}
+
+impl VideoInterrupts {
+    pub fn new(cpu: &cpu::Cpu) -> VideoInterrupts {
+        VideoInterrupts {
+            next: cpu.cycles() + CYCLES_PER_FRAME / 2,
+            rst: 1,
+        }
+    }
+
+    // Interrupts the cpu if the next one is due. It's lost if interrupts are disabled, as the
+    // board doesn't hold it.
+    pub fn update(&mut self, cpu: &mut cpu::Cpu) {
+        if cpu.cycles() >= self.next {
+            cpu.interrupt(self.rst);
+            self.rst = 3 - self.rst;
+            self.next += CYCLES_PER_FRAME / 2;
+        }
+    }
+}
+
+// Stops the cpu after an instruction that set off a watchpoint, returning the hit to report
+fn stop_on_watch_hit(cpu: &mut cpu::Cpu) -> Option<WatchHit> {
+    let hit = cpu.take_watch_hit()?;
+    cpu.enable = 0;
+    Some(hit)
+}
+
+// Runs the cpu for the frontend. Built with the dynarec feature it runs a translated block at a
+// time, otherwise an instruction. The video interrupts of a known machine's board are taken
+// after each one, so between blocks, but not after a watchpoint fires, so the cpu stops where
+// it did. An interrupt that's due then is taken after the next run.
+pub struct Runner {
+    video: Option<VideoInterrupts>,
+    #[cfg(feature = "dynarec")]
+    dynarec: dynarec::Dynarec,
+}
+
+impl Runner {
+    pub fn new(cpu: &cpu::Cpu, video: bool) -> Result<Runner, EmulatorError> {
+        Ok(Runner {
+            video: video.then(|| VideoInterrupts::new(cpu)),
+            #[cfg(feature = "dynarec")]
+            dynarec: dynarec::Dynarec::new()?,
+        })
+    }
+
+    // Keeps translated blocks from running past the addresses of breakpoints
+    #[cfg(feature = "dynarec")]
+    pub fn set_breakpoints(&mut self, cpu: &mut cpu::Cpu, breakpoints: &[Breakpoint]) {
+        let addresses = breakpoints.iter().filter_map(|breakpoint| breakpoint.address).collect();
+        self.dynarec.set_breakpoints(cpu, addresses);
+    }
+
+    // The interpreter stops after every instruction anyway
+    #[cfg(not(feature = "dynarec"))]
+    pub fn set_breakpoints(&mut self, _cpu: &mut cpu::Cpu, _breakpoints: &[Breakpoint]) {}
+
+    // Runs a block, or one instruction in the interpreter if single is set, and takes the
+    // interrupt that's due, if any. Returns the number of instructions executed.
+    pub fn run(&mut self, cpu: &mut cpu::Cpu, single: bool) -> Result<u32, EmulatorError> {
+        let executed = if single {
+            cpu.cycle()?;
+            1
+        } else {
+            self.step(cpu)?
+        };
+        if let Some(video) = self.video.as_mut().filter(|_| cpu.watch_hit().is_none()) {
+            video.update(cpu);
+        }
+        Ok(executed)
+    }
+
+    #[cfg(feature = "dynarec")]
+    fn step(&mut self, cpu: &mut cpu::Cpu) -> Result<u32, EmulatorError> {
+        self.dynarec.step(cpu)
+    }
+
+    #[cfg(not(feature = "dynarec"))]
+    fn step(&mut self, cpu: &mut cpu::Cpu) -> Result<u32, EmulatorError> {
+        cpu.cycle()?;
+        Ok(1)
+    }
+}
+
+// Runs the program flat out for about the given time, restarting it whenever it reaches a
+// HLT. Returns the number of instructions and cycles executed and the time taken. Built with
+// the dynarec feature, the program runs translated.
+pub fn benchmark(program: Program, duration: Duration) -> Result<(u64, u64, Duration), EmulatorError> {
+    const BATCH: u64 = 100_000;
+    let start = program.start.unwrap_or(0);
+    let mut cpu = program.cpu();
+    let mut runner = Runner::new(&cpu, false)?;
+    let mut instructions = 0;
+    let timer = Instant::now();
+    while timer.elapsed() < duration {
+        let target = instructions + BATCH;
+        while instructions < target {
+            if cpu.memory[cpu.pc as usize] == 0x76 {
+                cpu.pc = start;
+            }
+            instructions += runner.run(&mut cpu, false)? as u64;
+        }
+    }
+    Ok((instructions, cpu.cycles(), timer.elapsed()))
+}
+
+pub fn read_program(flag: &str, path: &Path) -> Result<Program, EmulatorError> {
+    if flag == "-b" {
+        Ok(Program::new(read_program_bin(path)?))
+    } else if flag == "-t" {
+        Ok(Program::new(read_program_text(path)?))
+    } else if flag == "-a" {
+        Ok(Program::new(read_program_asm(path)?))
+    } else if flag == "-i" {
+        read_program_hex(path)
+    } else if flag == "-r" {
+        read_program_roms(path)
+    } else if flag == "-m" {
+        Ok(rom::load_machine(path)?)
+    } else {
+        Err(EmulatorError::Usage(format!("Invalid flag '{}', expected -b, -t, -a, -i, -r or -m", flag)))
+    }
+}
+
+fn read_text(path: &Path) -> Result<String, EmulatorError> {
+    read_to_string(path).map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })
+}
+
+fn read_program_text(path: &Path) -> Result<Vec<u8>, EmulatorError> {
+    let file_string = read_text(path)?;
     file_string
         .split(char::is_whitespace)
         .filter(|item| !item.is_empty())
         .enumerate()
         .map(|(index, item)| {
-            u8::from_str_radix(item, 16)
-                .expect(format!("Failed to parse opcode at: {} '{}'", index + 1, item).as_str())
+            u8::from_str_radix(item, 16).map_err(|_| EmulatorError::Format {
+                path: path.to_path_buf(),
+                message: format!("Failed to parse opcode at: {} '{}'", index + 1, item),
+            })
         })
         .collect()
 }
 
-fn read_program_bin(path: &Path) -> Vec<u8> {
-    // TODO: Better error handling
+fn read_program_asm(path: &Path) -> Result<Vec<u8>, EmulatorError> {
+    Ok(assembler::assemble_file(path)?.image)
+}
+
+fn read_program_hex(path: &Path) -> Result<Program, EmulatorError> {
+    let text = read_text(path)?;
+    intel_hex::parse(&text).map_err(|error| EmulatorError::Format {
+        path: path.to_path_buf(),
+        message: error.to_string(),
+    })
+}
+
+// A comma separated list of file@address specs
+fn read_program_roms(path: &Path) -> Result<Program, EmulatorError> {
+    let specs = rom::RomSpec::parse_list(&path.to_string_lossy())?;
+    Ok(rom::load(&specs, 0)?)
+}
+
+fn read_program_bin(path: &Path) -> Result<Vec<u8>, EmulatorError> {
     let mut buffer: Vec<u8> = Vec::new();
-    File::open(path).unwrap().read_to_end(&mut buffer).unwrap();
-    buffer
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut buffer))
+        .map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })?;
+    Ok(buffer)
 }
 
-fn byte_to_points(byte: u8, byte_index: usize) -> Vec<Point> {
+// The lit pixels of video memory, rotated to the upright screen
+pub fn vram_points(memory: &[u8]) -> Vec<Point> {
+    let mut points: Vec<Point> = Vec::new();
+    for (offset, &byte) in memory[0x2400..0x3FFF].iter().enumerate() {
+        if byte != 0 {
+            points.extend(byte_to_points(byte, 0x2400 + offset));
+        }
+    }
+    points
+}
+
+pub fn byte_to_points(byte: u8, byte_index: usize) -> Vec<Point> {
     let mut points: Vec<Point> = Vec::new();
     let mask: u8 = 0b10000000;
     for i in 0..8 {
@@ -175,4 +508,120 @@ mod byte_to_xy_tests {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod runner_tests {
+    use super::*;
+
+    // Counts RST 1 and RST 2 at 2000h and 2001h while the main loop uses the shift register
+    const PROGRAM: &str = "
+        jmp Start
+        org 8
+        push h
+        lxi h,2000h
+        inr m
+        pop h
+        ei
+        ret
+        org 10h
+        push h
+        lxi h,2001h
+        inr m
+        pop h
+        ei
+        ret
+Start:  lxi sp,2400h
+        ei
+Loop:   mov a,b
+        out 4
+        in 3
+        add c
+        mov c,a
+        inr b
+        jmp Loop
+";
+
+    fn board_cpu() -> cpu::Cpu {
+        let mut cpu = cpu::Cpu::new(assembler::assemble(PROGRAM).unwrap().image);
+        cpu.set_io(io::Io::board(rom::Machine::find("invaders").unwrap().config));
+        cpu
+    }
+
+    #[test]
+    fn video_interrupts() {
+        let mut cpu = board_cpu();
+        let mut runner = Runner::new(&cpu, true).unwrap();
+        while cpu.cycles() < CYCLES_PER_FRAME / 2 {
+            assert_eq!(cpu.peek(0x2000), 0);
+            runner.run(&mut cpu, true).unwrap();
+        }
+        // Taken straight after the instruction that reaches it
+        assert_eq!(cpu.pc, 0x0008);
+        while cpu.cycles() < 3 * CYCLES_PER_FRAME + CYCLES_PER_FRAME / 4 {
+            runner.run(&mut cpu, true).unwrap();
+        }
+        assert_eq!((cpu.peek(0x2000), cpu.peek(0x2001)), (3, 3));
+    }
+
+    #[test]
+    fn watchpoint_stops_before_interrupt() {
+        // The store finishes just as the RST 1 of mid-screen is due
+        let program = "
+        lxi b,693
+Loop:   dcx b
+        mov a,b
+        ora c
+        jnz Loop
+        ei
+        nop
+        nop
+        sta 2000h
+        nop
+        hlt
+";
+        let mut cpu = cpu::Cpu::new(assembler::assemble(program).unwrap().image);
+        cpu.add_watchpoint(watchpoint::Watchpoint::write(0x2000));
+        let mut runner = Runner::new(&cpu, true).unwrap();
+        let hit = loop {
+            runner.run(&mut cpu, false).unwrap();
+            if let Some(hit) = stop_on_watch_hit(&mut cpu) {
+                break hit;
+            }
+        };
+        assert_eq!(cpu.enable, 0);
+        assert!(cpu.cycles() >= CYCLES_PER_FRAME / 2);
+        assert_eq!((hit.pc, cpu.pc), (0x000c, 0x000f));
+        assert_eq!(cpu.register(cpu::Register::Sp), 0xfffe);
+
+        // Resuming takes it after the next instruction
+        runner.run(&mut cpu, false).unwrap();
+        assert_eq!(cpu.pc, 0x0008);
+    }
+
+    // The runner's blocks against the interpreter, with the interrupts taken at the same points
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn dynarec_matches_the_interpreter() {
+        let mut translated = board_cpu();
+        let mut interpreted = board_cpu();
+        let mut runner = Runner::new(&translated, true).unwrap();
+        let mut video = VideoInterrupts::new(&interpreted);
+        let mut step = 0;
+        let mut instructions = 0;
+        while translated.cycles() < 2 * CYCLES_PER_FRAME {
+            let executed = runner.run(&mut translated, false).unwrap();
+            instructions += executed;
+            for _ in 0..executed {
+                interpreted.cycle().unwrap();
+            }
+            video.update(&mut interpreted);
+            assert_eq!(translated.state(), interpreted.state(), "step {}", step);
+            assert!(translated.memory == interpreted.memory, "memory differs at step {}", step);
+            step += 1;
+        }
+        assert_eq!((translated.peek(0x2000), translated.peek(0x2001)), (2, 1));
+        // Some blocks ran several instructions at a time
+        assert!(step < instructions);
+    }
+}
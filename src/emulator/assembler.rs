@@ -0,0 +1,1537 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+use super::disassembler::format_word;
+
+/*
+A two pass assembler for Intel 8080 mnemonics. Each line is
+
+    [label[:]] [operation [operands]] [; comment]
+
+A label in the first column doesn't need the ':', anywhere else it does. Labels starting with
+'.' are local to the label before them, so ".loop" after "Draw:" is "Draw.loop". The directives
+are
+
+    ORG expr            continue assembling at expr
+    name EQU expr       define a constant, SET defines one that can be redefined
+    DB expr|'text',...  bytes and strings
+    DW expr,...         little endian words
+    DS expr             reserve expr bytes
+    END                 ignore the rest of the file
+    INCLUDE file        assemble another file, relative to the current one
+    IF expr             assemble the following lines if expr isn't 0, up to ELSE or ENDIF
+    IFDEF name          the same if name is defined, IFNDEF if it isn't
+    name MACRO a,b      define a macro with parameters a and b, up to ENDM
+
+In a macro body LOCAL x,y gives x and y a new name in every expansion, '&' joins a parameter to
+the text around it and EXITM ends the expansion. IF conditions must be known in the first pass.
+
+Numbers are decimal or hex, octal and binary with an Intel suffix or prefix: 18D4h, 0x18D4,
+$18D4, 17o, 17q, 1010b. '$' on its own is the address of the current line and 'A' is a
+character code. Operators are + - * / % & | ^ ~ << >> and the comparisons
+= == != < > <= >=, which give -1 for true. Their Intel names MOD AND OR XOR NOT SHL SHR EQ NE LT
+GT LE GE work too, and HIGH and LOW give the bytes of a word.
+
+The image always starts at address 0 so it can be loaded like a ROM, with any gaps left by ORG
+and DS filled with zeros.
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyError {
+    // None when assembling a string
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}: {}", file.display(), self.line, self.message),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+impl Error for AssemblyError {}
+
+pub struct Assembly {
+    pub image: Vec<u8>,
+    // Every source line with its address and bytes, followed by the symbols
+    pub listing: String,
+    pub symbols: BTreeMap<String, u16>,
+}
+
+impl Assembly {
+    // The symbols in a format SymbolTable can load
+    pub fn symbol_file(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|(name, value)| format!("{} EQU {}\n", name, format_word(*value)))
+            .collect()
+    }
+}
+
+// INCLUDE paths are relative to the working directory
+pub fn assemble(source: &str) -> Result<Assembly, AssemblyError> {
+    assemble_source(source, None)
+}
+
+// INCLUDE paths are relative to the file's directory
+pub fn assemble_file(path: &Path) -> Result<Assembly, AssemblyError> {
+    let source = read_to_string(path).map_err(|error| AssemblyError {
+        file: Some(path.to_path_buf()),
+        line: 0,
+        message: error.to_string(),
+    })?;
+    assemble_source(&source, Some(path))
+}
+
+fn assemble_source(source: &str, path: Option<&Path>) -> Result<Assembly, AssemblyError> {
+    let lines: Vec<String> = source.lines().map(str::to_string).collect();
+    let mut assembler = Assembler::new();
+    for pass in 1..=2 {
+        assembler.start_pass(pass);
+        assembler.file = path.map(Path::to_path_buf);
+        assembler.block(&lines, true)?;
+    }
+    Ok(assembler.finish())
+}
+
+// How deeply macro expansions and includes can nest
+const MAX_DEPTH: usize = 32;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+
+const IMPLIED: [(&str, u8); 17] = [
+    ("NOP", 0x00),
+    ("RLC", 0x07),
+    ("RRC", 0x0f),
+    ("RAL", 0x17),
+    ("RAR", 0x1f),
+    ("DAA", 0x27),
+    ("CMA", 0x2f),
+    ("STC", 0x37),
+    ("CMC", 0x3f),
+    ("HLT", 0x76),
+    ("RET", 0xc9),
+    ("XTHL", 0xe3),
+    ("PCHL", 0xe9),
+    ("XCHG", 0xeb),
+    ("DI", 0xf3),
+    ("SPHL", 0xf9),
+    ("EI", 0xfb),
+];
+
+// Arithmetic and logic with a register and with an immediate byte. The immediate form of each
+// opcode is the register form with bits 0x46 set.
+const ALU: [(&str, &str, u8); 8] = [
+    ("ADD", "ADI", 0x80),
+    ("ADC", "ACI", 0x88),
+    ("SUB", "SUI", 0x90),
+    ("SBB", "SBI", 0x98),
+    ("ANA", "ANI", 0xa0),
+    ("XRA", "XRI", 0xa8),
+    ("ORA", "ORI", 0xb0),
+    ("CMP", "CPI", 0xb8),
+];
+
+// Instructions with a 16 bit address operand
+const ADDRESS: [(&str, u8); 6] = [
+    ("JMP", 0xc3),
+    ("CALL", 0xcd),
+    ("LDA", 0x3a),
+    ("STA", 0x32),
+    ("LHLD", 0x2a),
+    ("SHLD", 0x22),
+];
+
+const OTHER: [&str; 15] = [
+    "MOV", "MVI", "INR", "DCR", "LXI", "DAD", "INX", "DCX", "PUSH", "POP", "STAX", "LDAX", "IN",
+    "OUT", "RST",
+];
+
+const DIRECTIVES: [&str; 17] = [
+    "ORG", "EQU", "SET", "DB", "DW", "DS", "END", "INCLUDE", "IF", "IFDEF", "IFNDEF", "ELSE",
+    "ENDIF", "MACRO", "ENDM", "EXITM", "LOCAL",
+];
+
+fn is_operation(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    IMPLIED.iter().any(|(name, _)| *name == word)
+        || ALU.iter().any(|(register, immediate, _)| *register == word || *immediate == word)
+        || ADDRESS.iter().any(|(name, _)| *name == word)
+        || OTHER.contains(&word.as_str())
+        || DIRECTIVES.contains(&word.as_str())
+        || condition(&word).is_some()
+}
+
+// Jcc, Ccc and Rcc as the base opcode and condition number
+fn condition(mnemonic: &str) -> Option<(u8, u8)> {
+    let (prefix, rest) = mnemonic.split_at(mnemonic.len().min(1));
+    let base = match prefix {
+        "J" => 0xc2,
+        "C" => 0xc4,
+        "R" => 0xc0,
+        _ => return None,
+    };
+    let number = CONDITIONS.iter().position(|condition| *condition == rest)?;
+    Some((base, number as u8))
+}
+
+// How a block of lines ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    // EXITM
+    Exit,
+    // END
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<String>,
+}
+
+// A macro whose body is being read
+struct Recording {
+    name: String,
+    parameters: Vec<String>,
+    body: Vec<String>,
+    // MACRO lines inside the body waiting for their ENDM
+    nesting: usize,
+}
+
+// An IF being assembled
+struct Condition {
+    // The lines in the current branch are assembled
+    active: bool,
+    // A branch has been chosen, so ELSE is inactive
+    taken: bool,
+    // The lines around the IF are assembled
+    enclosing: bool,
+}
+
+struct Assembler {
+    pass: u8,
+    file: Option<PathBuf>,
+    line: usize,
+    address: u32,
+    symbols: BTreeMap<String, i64>,
+    // Names defined with SET, which may be redefined
+    variables: Vec<String>,
+    // Keyed by the upper case name
+    macros: HashMap<String, Macro>,
+    // The innermost macro being expanded, and how deep expansions and includes are nested
+    expanding: Option<String>,
+    depth: usize,
+    // Numbers the LOCAL names of each expansion
+    unique: usize,
+    // The last label not starting with '.'
+    scope: String,
+    image: Vec<u8>,
+    listing: String,
+    // Address and bytes of the line being assembled, for the listing
+    line_address: Option<u32>,
+    line_bytes: Vec<u8>,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            pass: 1,
+            file: None,
+            line: 0,
+            address: 0,
+            symbols: BTreeMap::new(),
+            variables: Vec::new(),
+            macros: HashMap::new(),
+            expanding: None,
+            depth: 0,
+            unique: 0,
+            scope: String::new(),
+            image: Vec::new(),
+            listing: String::new(),
+            line_address: None,
+            line_bytes: Vec::new(),
+        }
+    }
+
+    fn start_pass(&mut self, pass: u8) {
+        self.pass = pass;
+        self.address = 0;
+        self.macros.clear();
+        self.unique = 0;
+        self.scope.clear();
+    }
+
+    fn finish(self) -> Assembly {
+        let mut listing = self.listing;
+        let symbols: BTreeMap<String, u16> = self
+            .symbols
+            .into_iter()
+            .map(|(name, value)| (name, value as u16))
+            .collect();
+        if !symbols.is_empty() {
+            listing.push_str("\nSymbols:\n");
+            for (name, value) in &symbols {
+                listing.push_str(&format!("{:<16} {}\n", name, format_word(*value)));
+            }
+        }
+        Assembly {
+            image: self.image,
+            listing,
+            symbols,
+        }
+    }
+
+    fn error(&self, message: String) -> AssemblyError {
+        let message = match &self.expanding {
+            Some(name) => format!("{} (in macro {})", message, name),
+            None => message,
+        };
+        AssemblyError {
+            file: self.file.clone(),
+            line: self.line,
+            message,
+        }
+    }
+
+    fn is_operation(&self, word: &str) -> bool {
+        is_operation(word) || self.macros.contains_key(&word.to_ascii_uppercase())
+    }
+
+    // Assembles the lines of a file, or of a macro expansion when `numbered` is false so errors
+    // point at the line that used the macro
+    fn block(&mut self, lines: &[String], numbered: bool) -> Result<Flow, AssemblyError> {
+        let mut conditions = Vec::new();
+        let mut recording = None;
+        for (index, text) in lines.iter().enumerate() {
+            if numbered {
+                self.line = index + 1;
+            }
+            self.line_address = None;
+            self.line_bytes.clear();
+            match self.assemble_line(text, &mut conditions, &mut recording)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        if let Some(recording) = recording {
+            return Err(self.error(format!("MACRO {} without ENDM", recording.name)));
+        }
+        if !conditions.is_empty() {
+            return Err(self.error("IF without ENDIF".to_string()));
+        }
+        Ok(Flow::Next)
+    }
+
+    fn assemble_line(
+        &mut self,
+        text: &str,
+        conditions: &mut Vec<Condition>,
+        recording: &mut Option<Recording>,
+    ) -> Result<Flow, AssemblyError> {
+        let parsed = parse_line(text, &|word| self.is_operation(word));
+        let operation = parsed
+            .as_ref()
+            .ok()
+            .and_then(|line| line.operation)
+            .map(str::to_ascii_uppercase);
+        let operation = operation.as_deref();
+
+        // Macro bodies are stored as they are and only assembled when expanded
+        if let Some(macro_recording) = recording {
+            match operation {
+                Some("MACRO") => macro_recording.nesting += 1,
+                Some("ENDM") if macro_recording.nesting > 0 => macro_recording.nesting -= 1,
+                Some("ENDM") => {
+                    if let Some(done) = recording.take() {
+                        self.macros.insert(
+                            done.name.to_ascii_uppercase(),
+                            Macro {
+                                parameters: done.parameters,
+                                body: done.body,
+                            },
+                        );
+                    }
+                    self.list(text);
+                    return Ok(Flow::Next);
+                }
+                _ => {}
+            }
+            macro_recording.body.push(text.to_string());
+            self.list(text);
+            return Ok(Flow::Next);
+        }
+
+        let active = conditions.last().is_none_or(|condition| condition.active);
+        match operation {
+            Some("IF") | Some("IFDEF") | Some("IFNDEF") | Some("ELSE") | Some("ENDIF") => {
+                let line = parsed.map_err(|message| self.error(message))?;
+                self.conditional(operation.unwrap_or(""), &line.operands, conditions, active)
+                    .map_err(|message| self.error(message))?;
+                self.list(text);
+                return Ok(Flow::Next);
+            }
+            _ if !active => {
+                self.list(text);
+                return Ok(Flow::Next);
+            }
+            _ => {}
+        }
+
+        let line = parsed.map_err(|message| self.error(message))?;
+        match operation {
+            Some("MACRO") => {
+                *recording = Some(self.start_macro(&line).map_err(|message| self.error(message))?);
+                self.list(text);
+                Ok(Flow::Next)
+            }
+            Some("ENDM") => Err(self.error("ENDM without MACRO".to_string())),
+            Some("LOCAL") => Err(self.error("LOCAL outside a macro".to_string())),
+            Some("EXITM") if self.expanding.is_none() => {
+                Err(self.error("EXITM outside a macro".to_string()))
+            }
+            Some("EXITM") => {
+                self.list(text);
+                Ok(Flow::Exit)
+            }
+            Some("INCLUDE") => {
+                self.label(&line).map_err(|message| self.error(message))?;
+                self.list(text);
+                self.include(&line.operands)
+            }
+            Some(name) if self.macros.contains_key(name) => {
+                self.label(&line).map_err(|message| self.error(message))?;
+                self.list(text);
+                self.expand(name, &line.operands)
+            }
+            _ => {
+                let result = self.statement(&line);
+                self.list(text);
+                result.map_err(|message| self.error(message))
+            }
+        }
+    }
+
+    fn conditional(
+        &mut self,
+        operation: &str,
+        operands: &[String],
+        conditions: &mut Vec<Condition>,
+        active: bool,
+    ) -> Result<(), String> {
+        match operation {
+            "ELSE" => {
+                let condition = conditions.last_mut().ok_or("ELSE without IF")?;
+                condition.active = condition.enclosing && !condition.taken;
+                condition.taken = true;
+            }
+            "ENDIF" => {
+                conditions.pop().ok_or("ENDIF without IF")?;
+            }
+            _ => {
+                // Conditions inside a skipped block aren't evaluated
+                let value = active
+                    && match operation {
+                        "IF" => self.required(operands)? != 0,
+                        _ => {
+                            let name = self.single(operands)?.ok_or("Missing name")?;
+                            let defined = self.symbols.contains_key(&self.qualify(name))
+                                || self.macros.contains_key(&name.to_ascii_uppercase());
+                            defined == (operation == "IFDEF")
+                        }
+                    };
+                conditions.push(Condition {
+                    active: value,
+                    taken: value,
+                    enclosing: active,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn start_macro(&self, line: &Line) -> Result<Recording, String> {
+        let name = line.label.ok_or("MACRO needs a name")?;
+        if !is_name(name) || is_operation(name) {
+            return Err(format!("Invalid macro name '{}'", name));
+        }
+        if self.macros.contains_key(&name.to_ascii_uppercase()) {
+            return Err(format!("Macro '{}' is already defined", name));
+        }
+        if let Some(parameter) = line.operands.iter().find(|parameter| !is_name(parameter)) {
+            return Err(format!("Invalid parameter '{}'", parameter));
+        }
+        Ok(Recording {
+            name: name.to_string(),
+            parameters: line.operands.clone(),
+            body: Vec::new(),
+            nesting: 0,
+        })
+    }
+
+    fn expand(&mut self, name: &str, arguments: &[String]) -> Result<Flow, AssemblyError> {
+        let definition = self.macros[name].clone();
+        if arguments.len() > definition.parameters.len() {
+            return Err(self.error(format!(
+                "Macro {} takes {} arguments, found {}",
+                name,
+                definition.parameters.len(),
+                arguments.len()
+            )));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("Macros nested too deeply".to_string()));
+        }
+
+        // Missing arguments are empty
+        let mut replacements: Vec<(String, String)> = definition
+            .parameters
+            .iter()
+            .enumerate()
+            .map(|(index, parameter)| {
+                (parameter.clone(), arguments.get(index).cloned().unwrap_or_default())
+            })
+            .collect();
+        let mut body = Vec::new();
+        for text in &definition.body {
+            match parse_line(text, &|word| self.is_operation(word)) {
+                Ok(line) if line.operation.is_some_and(|operation| operation.eq_ignore_ascii_case("LOCAL")) => {
+                    for local in line.operands {
+                        self.unique += 1;
+                        replacements.push((local, format!("??{:04}", self.unique)));
+                    }
+                }
+                _ => body.push(text),
+            }
+        }
+        let lines: Vec<String> = body
+            .into_iter()
+            .map(|text| substitute(text, &replacements))
+            .collect();
+
+        let outer = self.expanding.replace(name.to_string());
+        self.depth += 1;
+        let flow = self.block(&lines, false);
+        self.depth -= 1;
+        self.expanding = outer;
+        match flow? {
+            Flow::End => Ok(Flow::End),
+            _ => Ok(Flow::Next),
+        }
+    }
+
+    fn include(&mut self, operands: &[String]) -> Result<Flow, AssemblyError> {
+        let name = match self.single(operands) {
+            Ok(Some(name)) => name.trim_matches(|c| c == '\'' || c == '"'),
+            Ok(None) => return Err(self.error("Missing file name".to_string())),
+            Err(message) => return Err(self.error(message)),
+        };
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("Includes nested too deeply".to_string()));
+        }
+        let path = match self.file.as_ref().and_then(|file| file.parent()) {
+            Some(directory) => directory.join(name),
+            None => PathBuf::from(name),
+        };
+        let source = read_to_string(&path)
+            .map_err(|error| self.error(format!("Can't read '{}': {}", path.display(), error)))?;
+        let lines: Vec<String> = source.lines().map(str::to_string).collect();
+
+        let outer = (self.file.replace(path), self.line);
+        self.depth += 1;
+        let flow = self.block(&lines, true);
+        self.depth -= 1;
+        (self.file, self.line) = outer;
+        flow
+    }
+
+    // Defines the line's label as the current address
+    fn label(&mut self, line: &Line) -> Result<(), String> {
+        if let Some(label) = line.label {
+            let name = self.qualify(label);
+            if !label.starts_with('.') {
+                self.scope = label.to_string();
+            }
+            self.define(&name, self.address as i64)?;
+            self.line_address = Some(self.address);
+        }
+        Ok(())
+    }
+
+    // Local labels are prefixed with the label they belong to
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('.') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    // Returns Flow::End on END
+    fn statement(&mut self, line: &Line) -> Result<Flow, String> {
+        let operation = line.operation.map(|operation| operation.to_ascii_uppercase());
+        match operation.as_deref() {
+            Some(directive @ ("EQU" | "SET")) => match line.label {
+                Some(name) => {
+                    let name = self.qualify(name);
+                    self.define_constant(&name, &line.operands, directive == "SET")?;
+                    Ok(Flow::Next)
+                }
+                None => Err(format!("{} needs a name", directive)),
+            },
+            operation => {
+                self.label(line)?;
+                match operation {
+                    Some(operation) if self.operation(operation, &line.operands)? => Ok(Flow::End),
+                    _ => Ok(Flow::Next),
+                }
+            }
+        }
+    }
+
+    fn list(&mut self, text: &str) {
+        if self.pass != 2 {
+            return;
+        }
+        // Lines from macro expansions are marked with a '+'
+        let marker = if self.expanding.is_some() { '+' } else { ' ' };
+        let mut chunks = self.line_bytes.chunks(4);
+        let first = chunks.next().unwrap_or(&[]);
+        let address = match self.line_address {
+            Some(address) => format!("{:04X}", address),
+            None => "    ".to_string(),
+        };
+        self.listing.push_str(&format!(
+            "{:5}{} {}  {:<12} {}\n",
+            self.line,
+            marker,
+            address,
+            hex(first),
+            text.trim_end()
+        ));
+        let mut address = self.line_address.unwrap_or(0) + first.len() as u32;
+        for chunk in chunks {
+            self.listing
+                .push_str(&format!("{:5}  {:04X}  {}\n", "", address, hex(chunk)));
+            address += chunk.len() as u32;
+        }
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if !is_name(name) {
+            return Err(format!("Invalid label '{}'", name));
+        }
+        if is_operation(name) || register(name).is_some() {
+            return Err(format!("'{}' is reserved", name));
+        }
+        if self.pass == 1 && self.symbols.contains_key(name) {
+            return Err(format!("'{}' is already defined", name));
+        }
+        self.symbols.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: &str, operands: &[String], variable: bool) -> Result<(), String> {
+        let value = match self.single(operands)?.map(|operand| self.evaluate(operand, true)) {
+            Some(value) => value,
+            None => return Err("Missing value".to_string()),
+        };
+        let value = match value {
+            Ok(value) => value,
+            // Forward references are resolved in the second pass
+            Err(_) if self.pass == 1 && !variable => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        if variable {
+            if self.symbols.contains_key(name) && !self.variables.iter().any(|v| v == name) {
+                return Err(format!("'{}' is already defined", name));
+            }
+            if !self.variables.iter().any(|v| v == name) {
+                self.variables.push(name.to_string());
+            }
+            self.symbols.insert(name.to_string(), value);
+            return Ok(());
+        }
+        if self.pass == 2 {
+            self.symbols.remove(name);
+        }
+        self.define(name, value)
+    }
+
+    fn operation(&mut self, operation: &str, operands: &[String]) -> Result<bool, String> {
+        self.line_address = Some(self.address);
+        match operation {
+            "ORG" => {
+                let address = self.required(operands)?;
+                if !(0..=0xffff).contains(&address) {
+                    return Err(format!("Address {} is out of range", address));
+                }
+                self.address = address as u32;
+                self.line_address = Some(self.address);
+            }
+            "DS" => {
+                let size = self.required(operands)?;
+                if size < 0 {
+                    return Err("Negative size".to_string());
+                }
+                self.reserve(size as u32)?;
+            }
+            "DB" => {
+                for operand in operands {
+                    match string_literal(operand) {
+                        Some(text) => self.emit(text.as_bytes())?,
+                        None => {
+                            let value = self.byte(operand)?;
+                            self.emit(&[value])?;
+                        }
+                    }
+                }
+            }
+            "DW" => {
+                for operand in operands {
+                    let value = self.word(operand)?;
+                    self.emit(&value.to_le_bytes())?;
+                }
+            }
+            "END" => return Ok(true),
+            mnemonic => self.instruction(mnemonic, operands)?,
+        }
+        Ok(false)
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[String]) -> Result<(), String> {
+        if let Some((_, opcode)) = IMPLIED.iter().find(|(name, _)| *name == mnemonic) {
+            self.operands(operands, 0)?;
+            return self.emit(&[*opcode]);
+        }
+        if let Some((register_name, _, base)) = ALU
+            .iter()
+            .find(|(register, immediate, _)| *register == mnemonic || *immediate == mnemonic)
+        {
+            let operands = self.operands(operands, 1)?;
+            return if *register_name == mnemonic {
+                self.emit(&[base | register_operand(&operands[0])?])
+            } else {
+                let value = self.byte(&operands[0])?;
+                self.emit(&[base | 0x46, value])
+            };
+        }
+        if let Some((_, opcode)) = ADDRESS.iter().find(|(name, _)| *name == mnemonic) {
+            let operands = self.operands(operands, 1)?;
+            let address = self.word(&operands[0])?;
+            return self.emit_word(*opcode, address);
+        }
+        if let Some((base, number)) = condition(mnemonic) {
+            let operands = self.operands(operands, if base == 0xc0 { 0 } else { 1 })?;
+            let opcode = base | number << 3;
+            return match operands.first() {
+                Some(operand) => {
+                    let address = self.word(operand)?;
+                    self.emit_word(opcode, address)
+                }
+                None => self.emit(&[opcode]),
+            };
+        }
+        match mnemonic {
+            "MOV" => {
+                let operands = self.operands(operands, 2)?;
+                let destination = register_operand(&operands[0])?;
+                let source = register_operand(&operands[1])?;
+                if destination == 6 && source == 6 {
+                    return Err("MOV M,M is not an instruction".to_string());
+                }
+                self.emit(&[0x40 | destination << 3 | source])
+            }
+            "MVI" => {
+                let operands = self.operands(operands, 2)?;
+                let register = register_operand(&operands[0])?;
+                let value = self.byte(&operands[1])?;
+                self.emit(&[0x06 | register << 3, value])
+            }
+            "INR" | "DCR" => {
+                let operands = self.operands(operands, 1)?;
+                let base = if mnemonic == "INR" { 0x04 } else { 0x05 };
+                self.emit(&[base | register_operand(&operands[0])? << 3])
+            }
+            "LXI" => {
+                let operands = self.operands(operands, 2)?;
+                let pair = pair_operand(&operands[0], "SP")?;
+                let value = self.word(&operands[1])?;
+                self.emit_word(0x01 | pair << 4, value)
+            }
+            "DAD" | "INX" | "DCX" => {
+                let operands = self.operands(operands, 1)?;
+                let base = match mnemonic {
+                    "DAD" => 0x09,
+                    "INX" => 0x03,
+                    _ => 0x0b,
+                };
+                self.emit(&[base | pair_operand(&operands[0], "SP")? << 4])
+            }
+            "PUSH" | "POP" => {
+                let operands = self.operands(operands, 1)?;
+                let base = if mnemonic == "PUSH" { 0xc5 } else { 0xc1 };
+                self.emit(&[base | pair_operand(&operands[0], "PSW")? << 4])
+            }
+            "STAX" | "LDAX" => {
+                let operands = self.operands(operands, 1)?;
+                let pair = pair_operand(&operands[0], "")?;
+                if pair > 1 {
+                    return Err(format!("{} only takes B or D", mnemonic));
+                }
+                let base = if mnemonic == "STAX" { 0x02 } else { 0x0a };
+                self.emit(&[base | pair << 4])
+            }
+            "IN" | "OUT" => {
+                let operands = self.operands(operands, 1)?;
+                let port = self.byte(&operands[0])?;
+                self.emit(&[if mnemonic == "IN" { 0xdb } else { 0xd3 }, port])
+            }
+            "RST" => {
+                let operands = self.operands(operands, 1)?;
+                let number = self.evaluate(&operands[0], false)?;
+                if !(0..8).contains(&number) {
+                    return Err(format!("RST {} is out of range", number));
+                }
+                self.emit(&[0xc7 | (number as u8) << 3])
+            }
+            _ => Err(format!("Unknown instruction '{}'", mnemonic)),
+        }
+    }
+
+    fn operands<'a>(&self, operands: &'a [String], count: usize) -> Result<&'a [String], String> {
+        if operands.len() != count {
+            return Err(format!("Expected {} operands, found {}", count, operands.len()));
+        }
+        Ok(operands)
+    }
+
+    fn single<'a>(&self, operands: &'a [String]) -> Result<Option<&'a str>, String> {
+        match operands {
+            [] => Ok(None),
+            [operand] => Ok(Some(operand)),
+            _ => Err(format!("Expected 1 operand, found {}", operands.len())),
+        }
+    }
+
+    // A value that must be known in the first pass, such as an ORG address
+    fn required(&self, operands: &[String]) -> Result<i64, String> {
+        match self.single(operands)? {
+            Some(operand) => self.evaluate(operand, true),
+            None => Err("Missing value".to_string()),
+        }
+    }
+
+    fn byte(&self, operand: &str) -> Result<u8, String> {
+        let value = self.evaluate(operand, false)?;
+        if !(-0x80..=0xff).contains(&value) {
+            return Err(format!("Value {} doesn't fit in a byte", value));
+        }
+        Ok(value as u8)
+    }
+
+    fn word(&self, operand: &str) -> Result<u16, String> {
+        let value = self.evaluate(operand, false)?;
+        if !(-0x8000..=0xffff).contains(&value) {
+            return Err(format!("Value {} doesn't fit in a word", value));
+        }
+        Ok(value as u16)
+    }
+
+    // Undefined symbols are taken as 0 in the first pass unless the value is `required`
+    fn evaluate(&self, text: &str, required: bool) -> Result<i64, String> {
+        let lenient = self.pass == 1 && !required;
+        let lookup = |name: &str| match self.symbols.get(&self.qualify(name)) {
+            Some(value) => Some(*value),
+            None if lenient => Some(0),
+            None => None,
+        };
+        evaluate(text, self.line_address.unwrap_or(self.address) as i64, &lookup)
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.pass == 2 {
+            let end = self.address as usize + bytes.len();
+            if end > 0x10000 {
+                return Err("Code runs past FFFFh".to_string());
+            }
+            if self.image.len() < end {
+                self.image.resize(end, 0);
+            }
+            self.image[self.address as usize..end].copy_from_slice(bytes);
+            self.line_bytes.extend_from_slice(bytes);
+        }
+        self.address += bytes.len() as u32;
+        Ok(())
+    }
+
+    fn emit_word(&mut self, opcode: u8, value: u16) -> Result<(), String> {
+        let [low, high] = value.to_le_bytes();
+        self.emit(&[opcode, low, high])
+    }
+
+    fn reserve(&mut self, size: u32) -> Result<(), String> {
+        if self.address + size > 0x10000 {
+            return Err("DS runs past FFFFh".to_string());
+        }
+        self.address += size;
+        if self.pass == 2 && self.image.len() < self.address as usize {
+            self.image.resize(self.address as usize, 0);
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn register(text: &str) -> Option<u8> {
+    let upper = text.trim().to_ascii_uppercase();
+    REGISTERS
+        .iter()
+        .position(|register| *register == upper)
+        .map(|number| number as u8)
+}
+
+fn register_operand(text: &str) -> Result<u8, String> {
+    register(text).ok_or_else(|| format!("Expected a register, found '{}'", text))
+}
+
+// B, D and H, or BC, DE and HL, then `last` for SP or PSW
+fn pair_operand(text: &str, last: &str) -> Result<u8, String> {
+    match text.trim().to_ascii_uppercase().as_str() {
+        "B" | "BC" => Ok(0),
+        "D" | "DE" => Ok(1),
+        "H" | "HL" => Ok(2),
+        pair if !last.is_empty() && pair == last => Ok(3),
+        _ => Err(format!("Expected a register pair, found '{}'", text)),
+    }
+}
+
+struct Line<'a> {
+    label: Option<&'a str>,
+    operation: Option<&'a str>,
+    operands: Vec<String>,
+}
+
+// `is_operation` tells labels in the first column from instructions, directives and macros
+fn parse_line<'a>(text: &'a str, is_operation: &dyn Fn(&str) -> bool) -> Result<Line<'a>, String> {
+    let code = strip_comment(text);
+    let mut rest = code.trim_start();
+    let mut label = None;
+
+    let first = next_word(rest);
+    if let Some(after) = rest[first.len()..].strip_prefix(':') {
+        label = Some(first);
+        rest = after.trim_start();
+    } else if !first.is_empty()
+        && !code.starts_with(char::is_whitespace)
+        && !is_operation(first)
+    {
+        label = Some(first);
+        rest = rest[first.len()..].trim_start();
+    }
+
+    let mut operation = Some(next_word(rest)).filter(|word| !word.is_empty());
+    rest = rest[operation.map_or(0, str::len)..].trim_start();
+
+    // "name EQU value" and "name MACRO" with the name indented and no ':'
+    if label.is_none() {
+        let second = next_word(rest);
+        if ["EQU", "SET", "MACRO"].iter().any(|word| second.eq_ignore_ascii_case(word)) {
+            label = operation;
+            operation = Some(second);
+            rest = rest[second.len()..].trim_start();
+        }
+    }
+
+    Ok(Line {
+        label,
+        operation,
+        operands: split_operands(rest)?,
+    })
+}
+
+fn next_word(text: &str) -> &str {
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == ':')
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+fn is_name_character(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_?@.$".contains(c)
+}
+
+// Replaces whole words outside quotes and comments, ignoring case. A '&' next to a replaced
+// word is removed to join it to the text around it.
+fn substitute(text: &str, replacements: &[(String, String)]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::new();
+    let mut quote = None;
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => {
+                result.extend(&chars[index..]);
+                break;
+            }
+            None if is_name_character(c) => {
+                let start = index;
+                while index < chars.len() && is_name_character(chars[index]) {
+                    index += 1;
+                }
+                let word: String = chars[start..index].iter().collect();
+                match replacements.iter().find(|(name, _)| name.eq_ignore_ascii_case(&word)) {
+                    Some((_, value)) => {
+                        if result.ends_with('&') {
+                            result.pop();
+                        }
+                        result.push_str(value);
+                        if chars.get(index) == Some(&'&') {
+                            index += 1;
+                        }
+                    }
+                    None => result.push_str(&word),
+                }
+                continue;
+            }
+            None => {}
+        }
+        result.push(c);
+        index += 1;
+    }
+    result
+}
+
+// Everything before a ';' that isn't inside quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => return &text[..index],
+            None => {}
+        }
+    }
+    text
+}
+
+// Splits on commas outside quotes
+fn split_operands(text: &str) -> Result<Vec<String>, String> {
+    let mut operands = Vec::new();
+    if text.trim().is_empty() {
+        return Ok(operands);
+    }
+    let mut current = String::new();
+    let mut quote = None;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ',' => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            None => {}
+        }
+        current.push(c);
+    }
+    if quote.is_some() {
+        return Err("Unterminated string".to_string());
+    }
+    operands.push(current.trim().to_string());
+    if operands.iter().any(String::is_empty) {
+        return Err("Empty operand".to_string());
+    }
+    Ok(operands)
+}
+
+// The text of a quoted string with more than one character. Single characters are values.
+fn string_literal(operand: &str) -> Option<String> {
+    let quote = operand.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let inner = operand.strip_prefix(quote)?.strip_suffix(quote)?;
+    let doubled = format!("{}{}", quote, quote);
+    let text = inner.replace(&doubled, &quote.to_string());
+    if text.chars().count() > 1 {
+        Some(text)
+    } else {
+        None
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || "_?@.".contains(c))
+        && text.chars().all(is_name_character)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+const OPERATORS: [&str; 20] = [
+    "<<", ">>", "==", "!=", "<=", ">=", "<", ">", "=", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+    "(", ")",
+];
+
+// Intel operator names and the operators they stand for
+const OPERATOR_NAMES: [(&str, &str); 15] = [
+    ("EQ", "=="),
+    ("NE", "!="),
+    ("LT", "<"),
+    ("GT", ">"),
+    ("LE", "<="),
+    ("GE", ">="),
+    ("MOD", "%"),
+    ("AND", "&"),
+    ("OR", "|"),
+    ("XOR", "^"),
+    ("NOT", "~"),
+    ("SHL", "<<"),
+    ("SHR", ">>"),
+    ("HIGH", "HIGH"),
+    ("LOW", "LOW"),
+];
+
+// Binary operators from the lowest precedence to the highest. Comparisons give -1 for true, so
+// NOT of a comparison is its opposite.
+const PRECEDENCE: [&[&str]; 7] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "=", "!=", "<", ">", "<=", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+fn evaluate(text: &str, here: i64, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    let tokens = tokenize(text, here)?;
+    let mut parser = ExpressionParser {
+        tokens,
+        position: 0,
+        lookup,
+    };
+    let value = parser.binary(0)?;
+    match parser.tokens.get(parser.position) {
+        None => Ok(value),
+        Some(_) => Err(format!("Invalid expression '{}'", text)),
+    }
+}
+
+fn tokenize(text: &str, here: i64) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        if c.is_whitespace() {
+            index += 1;
+        } else if c == '\'' || c == '"' {
+            // Character constant, two characters make a word
+            let mut value: i64 = 0;
+            let mut count = 0;
+            index += 1;
+            loop {
+                match chars.get(index) {
+                    None => return Err("Unterminated character constant".to_string()),
+                    Some(q) if *q == c && chars.get(index + 1) == Some(&c) => index += 1,
+                    Some(q) if *q == c => break,
+                    Some(_) => {}
+                }
+                value = value << 8 | (chars[index] as i64 & 0xff);
+                count += 1;
+                index += 1;
+            }
+            if count == 0 || count > 2 {
+                return Err(format!("Invalid character constant in '{}'", text));
+            }
+            tokens.push(Token::Number(value));
+            index += 1;
+        } else if is_name_character(c) {
+            let start = index;
+            while index < chars.len() && is_name_character(chars[index]) {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+            let upper = word.to_ascii_uppercase();
+            if word == "$" {
+                tokens.push(Token::Number(here));
+            } else if c.is_ascii_digit() || c == '$' {
+                tokens.push(Token::Number(
+                    parse_number(&word).ok_or_else(|| format!("Invalid number '{}'", word))?,
+                ));
+            } else if let Some((_, operator)) = OPERATOR_NAMES.iter().find(|(name, _)| *name == upper) {
+                tokens.push(Token::Operator(operator));
+            } else {
+                tokens.push(Token::Name(word));
+            }
+        } else {
+            let rest: String = chars[index..].iter().collect();
+            match OPERATORS.iter().find(|operator| rest.starts_with(**operator)) {
+                Some(operator) => {
+                    tokens.push(Token::Operator(operator));
+                    index += operator.len();
+                }
+                None => return Err(format!("Unexpected '{}'", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_suffix('h') {
+        (digits, 16)
+    } else if let Some(digits) = lower.strip_suffix('o').or_else(|| lower.strip_suffix('q')) {
+        (digits, 8)
+    } else if let Some(digits) = lower.strip_suffix('b').filter(|digits| digits.chars().all(|c| c == '0' || c == '1')) {
+        (digits, 2)
+    } else if let Some(digits) = lower.strip_suffix('d') {
+        (digits, 10)
+    } else {
+        (lower.as_str(), 10)
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+struct ExpressionParser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a dyn Fn(&str) -> Option<i64>,
+}
+
+impl ExpressionParser<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut value = self.binary(level + 1)?;
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position) {
+            let operator = *operator;
+            if !PRECEDENCE[level].contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            let right = self.binary(level + 1)?;
+            value = match operator {
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "<<" => value.checked_shl(right as u32).unwrap_or(0),
+                ">>" => value.checked_shr(right as u32).unwrap_or(0),
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                "==" | "=" => -((value == right) as i64),
+                "!=" => -((value != right) as i64),
+                "<" => -((value < right) as i64),
+                ">" => -((value > right) as i64),
+                "<=" => -((value <= right) as i64),
+                ">=" => -((value >= right) as i64),
+                _ if right == 0 => return Err("Division by zero".to_string()),
+                "/" => value / right,
+                _ => value % right,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Name(name)) => {
+                (self.lookup)(&name).ok_or_else(|| format!("Undefined symbol '{}'", name))
+            }
+            Some(Token::Operator("(")) => {
+                let value = self.binary(0)?;
+                match self.next() {
+                    Some(Token::Operator(")")) => Ok(value),
+                    _ => Err("Missing ')'".to_string()),
+                }
+            }
+            Some(Token::Operator("-")) => Ok(-self.unary()?),
+            Some(Token::Operator("+")) => self.unary(),
+            Some(Token::Operator("~")) => Ok(!self.unary()?),
+            Some(Token::Operator("HIGH")) => Ok(self.unary()? >> 8 & 0xff),
+            Some(Token::Operator("LOW")) => Ok(self.unary()? & 0xff),
+            Some(Token::Operator(operator)) => Err(format!("Unexpected '{}'", operator)),
+            None => Err("Missing value".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod assembler_tests {
+    use super::*;
+    use crate::emulator::disassembler::disassemble;
+
+    fn image(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().image
+    }
+
+    fn error(source: &str) -> AssemblyError {
+        assemble(source).err().unwrap()
+    }
+
+    #[test]
+    fn every_documented_opcode() {
+        // Disassembling and reassembling each opcode gives back the same bytes
+        for opcode in 0..=0xffu8 {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = disassemble(&bytes, 0);
+            if instruction.mnemonic.starts_with('*') {
+                continue;
+            }
+            let source = format!("    {}", instruction);
+            assert_eq!(
+                image(&source),
+                bytes[..instruction.length as usize].to_vec(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn labels_and_directives() {
+        let assembly = assemble(
+            "; test program\n\
+             SCREEN  EQU 2400h\n\
+             COUNT   equ SCREEN - 23FEh\n\
+                     ORG 10h\n\
+             Start:  lxi h,SCREEN\n\
+                     mvi b,COUNT\n\
+             Loop    dcr b        ; no colon in the first column\n\
+                     jnz Loop\n\
+                     jmp Done\n\
+             Table:  db 1, 'AB', \"it's\", -1, LOW Table, HIGH Table\n\
+                     dw Start, $\n\
+                     ds 2\n\
+             Done:   hlt\n\
+                     end\n\
+                     this isn't assembled\n",
+        )
+        .unwrap();
+        let mut expected = vec![0; 0x10];
+        expected.extend([
+            0x21, 0x00, 0x24, // 10 LXI H,2400h
+            0x06, 0x02, // 13 MVI B,02h
+            0x05, // 15 DCR B
+            0xc2, 0x15, 0x00, // 16 JNZ 0015h
+            0xc3, 0x2c, 0x00, // 19 JMP 002Ch
+            0x01, b'A', b'B', b'i', b't', b'\'', b's', 0xff, 0x1c, 0x00, // 1C DB
+            0x10, 0x00, 0x26, 0x00, // 26 DW
+            0x00, 0x00, // 2A DS
+            0x76, // 2C HLT
+        ]);
+        assert_eq!(assembly.image, expected);
+        assert_eq!(assembly.symbols.get("Loop"), Some(&0x0015));
+        assert_eq!(assembly.symbols.get("SCREEN"), Some(&0x2400));
+    }
+
+    #[test]
+    fn expressions() {
+        let lookup = |name: &str| if name == "X" { Some(0x1234) } else { None };
+        assert_eq!(evaluate("1 + 2 * 3", 0, &lookup), Ok(7));
+        assert_eq!(evaluate("(1 + 2) * 3", 0, &lookup), Ok(9));
+        assert_eq!(evaluate("0FFh & 1010b | 17o", 0, &lookup), Ok(0x0f));
+        assert_eq!(evaluate("HIGH X + LOW X", 0, &lookup), Ok(0x12 + 0x34));
+        assert_eq!(evaluate("X SHR 4 AND 0FFh", 0, &lookup), Ok(0x23));
+        assert_eq!(evaluate("$ + 3", 0x100, &lookup), Ok(0x103));
+        assert_eq!(evaluate("'A' + 0x10 - $10", 0, &lookup), Ok(0x41));
+        assert_eq!(evaluate("10 MOD 3", 0, &lookup), Ok(1));
+        assert_eq!(evaluate("1 + 1 == 2 & 3 < 2", 0, &lookup), Ok(0));
+        assert_eq!(evaluate("NOT (X EQ 1234h)", 0, &lookup), Ok(0));
+        assert_eq!(evaluate("X = 1234h AND 'A' GE 41h", 0, &lookup), Ok(-1));
+        assert_eq!(evaluate("Y", 0, &lookup), Err("Undefined symbol 'Y'".to_string()));
+        assert_eq!(evaluate("1 / 0", 0, &lookup), Err("Division by zero".to_string()));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("  nop\n  jmp Nowhere"), AssemblyError { file: None, line: 2, message: "Undefined symbol 'Nowhere'".to_string() });
+        assert_eq!(error("A: nop").message, "'A' is reserved");
+        assert_eq!(error("X: nop\nX: nop").message, "'X' is already defined");
+        assert_eq!(error("  mov m,m").message, "MOV M,M is not an instruction");
+        assert_eq!(error("  mvi b,300").message, "Value 300 doesn't fit in a byte");
+        assert_eq!(error("  ldax h").message, "LDAX only takes B or D");
+        assert_eq!(error("  push sp").message, "Expected a register pair, found 'sp'");
+        assert_eq!(error("  frob a").message, "Unknown instruction 'FROB'");
+        assert_eq!(error("  org Later\nLater: nop").message, "Undefined symbol 'Later'");
+        assert_eq!(error("  org 0FFFFh\n  jmp 0").message, "Code runs past FFFFh");
+    }
+
+    #[test]
+    fn macros() {
+        let assembly = assemble(
+            "Fill    MACRO value, count\n\
+                     LOCAL again\n\
+                     mvi a,value\n\
+                     mvi b,count\n\
+             again:  stax d\n\
+                     dcr b\n\
+                     jnz again\n\
+                     ENDM\n\
+             Load    macro r, value\n\
+                     mvi r,value  ; value isn't replaced in comments\n\
+                     IF value EQ 0\n\
+                     exitm\n\
+                     endif\n\
+                     inr r\n\
+                     endm\n\
+             Entry   macro n\n\
+             Item&n: db n\n\
+                     endm\n\
+             Start:  fill 0FFh, 2\n\
+                     Fill 0, 3\n\
+                     load a, 0\n\
+                     load c, 1\n\
+                     entry 1\n\
+                     entry 2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            assembly.image,
+            [
+                0x3e, 0xff, 0x06, 0x02, 0x12, 0x05, 0xc2, 0x04, 0x00, // first Fill
+                0x3e, 0x00, 0x06, 0x03, 0x12, 0x05, 0xc2, 0x0d, 0x00, // second Fill
+                0x3e, 0x00, // Load A stops at EXITM
+                0x0e, 0x01, 0x0c, // Load C
+                0x01, 0x02, // Entry
+            ]
+        );
+        assert_eq!(assembly.symbols.get("??0001"), Some(&0x0004));
+        assert_eq!(assembly.symbols.get("??0002"), Some(&0x000d));
+        assert_eq!(assembly.symbols.get("Item2"), Some(&0x0018));
+        assert!(assembly.listing.contains("   19+ 0004  12           ??0001:  stax d\n"));
+        assert!(assembly.listing.contains("   21+ 0012  3E 00        mvi a,0  ; value isn't replaced in comments\n"));
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "DEBUG   EQU 1\n\
+                      IF DEBUG\n\
+                        IFDEF Missing\n\
+                          db 1\n\
+                        ELSE\n\
+                          db 2\n\
+                        ENDIF\n\
+                      ELSE\n\
+                        db 3\n\
+                        IF 1\n\
+                          db 4\n\
+                        ELSE\n\
+                          db 5\n\
+                        ENDIF\n\
+                      ENDIF\n\
+                      IFNDEF DEBUG\n\
+                        this isn't assembled\n\
+                      ENDIF\n";
+        assert_eq!(image(source), [2]);
+        assert_eq!(error("  IF 1\n  db 1").message, "IF without ENDIF");
+        assert_eq!(error("  ELSE").message, "ELSE without IF");
+        assert_eq!(error("  ENDIF").message, "ENDIF without IF");
+        assert_eq!(error("  IF Later\n  ENDIF\nLater: nop").message, "Undefined symbol 'Later'");
+    }
+
+    #[test]
+    fn local_labels() {
+        let assembly = assemble(
+            "First:  dcr b\n\
+             .loop:  jnz .loop\n\
+             Second: dcr c\n\
+             .loop   jnz .loop\n\
+                     jmp First.loop\n",
+        )
+        .unwrap();
+        assert_eq!(assembly.image[1..4], [0xc2, 0x01, 0x00]);
+        assert_eq!(assembly.image[5..8], [0xc2, 0x05, 0x00]);
+        assert_eq!(assembly.image[8..11], [0xc3, 0x01, 0x00]);
+        assert_eq!(assembly.symbols.get("Second.loop"), Some(&0x0005));
+    }
+
+    #[test]
+    fn includes() {
+        let directory = std::env::temp_dir().join(format!("assembler_includes_{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::write(directory.join("main.asm"), "  include 'lib/defs.asm'\n  mvi a,VALUE\n  Twice\n").unwrap();
+        std::fs::write(directory.join("lib/defs.asm"), "VALUE EQU 42\n  INCLUDE macros.asm\n").unwrap();
+        std::fs::write(directory.join("lib/macros.asm"), "Twice MACRO\n  inr a\n  inr a\n  ENDM\n").unwrap();
+        std::fs::write(directory.join("bad.asm"), "  nop\n  include lib/broken.asm\n").unwrap();
+        std::fs::write(directory.join("lib/broken.asm"), "\n  jmp Nowhere\n").unwrap();
+        std::fs::write(directory.join("loop.asm"), "  include loop.asm\n").unwrap();
+
+        let assembly = assemble_file(&directory.join("main.asm")).unwrap();
+        assert_eq!(assembly.image, [0x3e, 42, 0x3c, 0x3c]);
+        let error = assemble_file(&directory.join("bad.asm")).err().unwrap();
+        assert_eq!(error.file, Some(directory.join("lib/broken.asm")));
+        assert_eq!(error.line, 2);
+        let error = assemble_file(&directory.join("loop.asm")).err().unwrap();
+        assert_eq!(error.message, "Includes nested too deeply");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn macro_errors() {
+        assert_eq!(error("M MACRO\n  nop").message, "MACRO M without ENDM");
+        assert_eq!(error("  ENDM").message, "ENDM without MACRO");
+        assert_eq!(error("  EXITM").message, "EXITM outside a macro");
+        assert_eq!(error("M MACRO a\n  ENDM\n  M 1,2").message, "Macro M takes 1 arguments, found 2");
+        assert_eq!(error("M MACRO\n  M\n  ENDM\n  M").message, "Macros nested too deeply (in macro M)");
+        let error = error("M MACRO\n  mvi a,Nowhere\n  ENDM\n  nop\n  M");
+        assert_eq!((error.line, error.message.as_str()), (5, "Undefined symbol 'Nowhere' (in macro M)"));
+    }
+
+    #[test]
+    fn listing_and_symbols() {
+        let assembly = assemble("Start:  mvi a,1 ; one\n        db 1,2,3,4,5\n").unwrap();
+        assert_eq!(
+            assembly.listing,
+            "    1  0000  3E 01        Start:  mvi a,1 ; one\n\
+             \x20   2  0002  01 02 03 04          db 1,2,3,4,5\n\
+             \x20      0006  05\n\
+             \n\
+             Symbols:\n\
+             Start            0000h\n"
+        );
+        assert_eq!(assembly.symbol_file(), "Start EQU 0000h\n");
+    }
+}
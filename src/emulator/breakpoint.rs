@@ -0,0 +1,57 @@
+use super::cpu::{Cpu, Register};
+use super::expression::{Expression, ParseError};
+use super::symbols::SymbolTable;
+
+pub struct Breakpoint {
+    // Address the breakpoint is placed on, or None to check the condition after every instruction
+    pub address: Option<u16>,
+    pub condition: Option<Expression>,
+    pub hits: u32,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address: Some(address),
+            condition: None,
+            hits: 0,
+        }
+    }
+
+    // A breakpoint that stops whenever the condition is true, e.g. "pc == 0x0A93 && a > 0x10"
+    pub fn condition(condition: &str, symbols: &SymbolTable) -> Result<Breakpoint, ParseError> {
+        Ok(Breakpoint {
+            address: None,
+            condition: Some(Expression::parse_with_symbols(condition, symbols)?),
+            hits: 0,
+        })
+    }
+
+    // A breakpoint on an address that only stops when the condition is true, e.g. "hits == 100"
+    pub fn conditional(
+        address: u16,
+        condition: &str,
+        symbols: &SymbolTable,
+    ) -> Result<Breakpoint, ParseError> {
+        Ok(Breakpoint {
+            address: Some(address),
+            condition: Some(Expression::parse_with_symbols(condition, symbols)?),
+            hits: 0,
+        })
+    }
+
+    // Called with the cpu about to execute its next instruction.
+    // Returns true if execution should stop.
+    pub fn check(&mut self, cpu: &Cpu) -> bool {
+        if let Some(address) = self.address {
+            if cpu.register(Register::Pc) != address {
+                return false;
+            }
+        }
+        self.hits += 1;
+        match &self.condition {
+            Some(condition) => condition.is_true(cpu, self.hits),
+            None => true,
+        }
+    }
+}
@@ -1,15 +1,604 @@
-use core::panic;
-use std::u8;
+use std::fmt;
 
-#[derive(Debug)]
+use super::error::EmulatorError;
+use super::io::Io;
+use super::watchpoint::{WatchHit, Watchpoint};
+
+mod z80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Pc,
+    Psw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Z,
+    S,
+    P,
+    Cy,
+    Ac,
+}
+
+impl Flag {
+    // The flag's bit in the 8080's flag byte
+    pub fn mask(self) -> u8 {
+        match self {
+            Flag::Z => FLAG_Z,
+            Flag::S => FLAG_S,
+            Flag::P => FLAG_P,
+            Flag::Cy => FLAG_CY,
+            Flag::Ac => FLAG_AC,
+        }
+    }
+}
+
+// The processor being emulated. The 8085 runs the 8080's instructions with its own timings,
+// adds RIM and SIM for its interrupt masks and serial lines, and decodes the rest of the 8080's
+// undefined opcodes as instructions Intel never documented. The Z80 has its own decoder, see
+// z80.rs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    I8080,
+    I8085,
+    Z80,
+}
+
+impl Model {
+    // The flag byte as this processor packs it, with its fixed bits at their fixed values:
+    // S Z 0 AC 0 P 1 CY on the 8080, S Z K AC 0 P V CY on the 8085 and S Z 0 H 0 P/V N C on
+    // the Z80
+    pub fn pack_flags(self, flags: u8) -> u8 {
+        match self {
+            Model::I8080 => flags & 0xd5 | 0x02,
+            Model::I8085 => flags & 0xf7,
+            Model::Z80 => flags & 0xd7,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Model> {
+        match name {
+            "8080" => Some(Model::I8080),
+            "8085" => Some(Model::I8085),
+            "z80" | "Z80" => Some(Model::Z80),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Model::I8080 => write!(f, "8080"),
+            Model::I8085 => write!(f, "8085"),
+            Model::Z80 => write!(f, "Z80"),
+        }
+    }
+}
+
+// The 8085's interrupt inputs besides INTR, highest priority first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptLine {
+    Trap,
+    Rst75,
+    Rst65,
+    Rst55,
+}
+
+// Clock states taken by each opcode. Conditional calls and returns take 6 more when taken.
+pub(super) const CYCLES: [u8; 256] = [
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, // 00
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4, // 10
+    4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4, // 20
+    4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4, // 30
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 40
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 50
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5, // 60
+    7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5, // 70
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 80
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 90
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // A0
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // B0
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11, // C0
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11, // D0
+    5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11, // E0
+    5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11, // F0
+];
+
+// The registers at one point in time, in the trace log's format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub flags: u8,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub pc: u16,
+    pub cycles: u64,
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC: {:04X}, AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}, CYC: {}",
+            self.pc,
+            (self.a as u16) << 8 | self.flags as u16,
+            self.bc,
+            self.de,
+            self.hl,
+            self.sp,
+            self.cycles
+        )
+    }
+}
+
+// A copy of the registers for tools to inspect, change and put back with Cpu::set_registers.
+// The flags are packed as Cpu::flags packs them for `model`, and setting PSW keeps the fixed bits
+// the cpu would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub a: u8,
+    pub flags: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub model: Model,
+}
+
+impl Registers {
+    pub fn get(&self, register: Register) -> u16 {
+        match register {
+            Register::A => self.a as u16,
+            Register::B => self.b as u16,
+            Register::C => self.c as u16,
+            Register::D => self.d as u16,
+            Register::E => self.e as u16,
+            Register::H => self.h as u16,
+            Register::L => self.l as u16,
+            Register::Bc => (self.b as u16) << 8 | self.c as u16,
+            Register::De => (self.d as u16) << 8 | self.e as u16,
+            Register::Hl => (self.h as u16) << 8 | self.l as u16,
+            Register::Sp => self.sp,
+            Register::Pc => self.pc,
+            Register::Psw => (self.a as u16) << 8 | self.flags as u16,
+        }
+    }
+
+    // 8-bit registers take the low byte of the value
+    pub fn set(&mut self, register: Register, value: u16) {
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        match register {
+            Register::A => self.a = low,
+            Register::B => self.b = low,
+            Register::C => self.c = low,
+            Register::D => self.d = low,
+            Register::E => self.e = low,
+            Register::H => self.h = low,
+            Register::L => self.l = low,
+            Register::Bc => (self.b, self.c) = (high, low),
+            Register::De => (self.d, self.e) = (high, low),
+            Register::Hl => (self.h, self.l) = (high, low),
+            Register::Sp => self.sp = value,
+            Register::Pc => self.pc = value,
+            Register::Psw => (self.a, self.flags) = (high, self.model.pack_flags(low)),
+        }
+    }
+
+    pub fn bc(&self) -> u16 {
+        self.get(Register::Bc)
+    }
+
+    pub fn set_bc(&mut self, value: u16) {
+        self.set(Register::Bc, value);
+    }
+
+    pub fn de(&self) -> u16 {
+        self.get(Register::De)
+    }
+
+    pub fn set_de(&mut self, value: u16) {
+        self.set(Register::De, value);
+    }
+
+    pub fn hl(&self) -> u16 {
+        self.get(Register::Hl)
+    }
+
+    pub fn set_hl(&mut self, value: u16) {
+        self.set(Register::Hl, value);
+    }
+
+    pub fn psw(&self) -> u16 {
+        self.get(Register::Psw)
+    }
+
+    pub fn set_psw(&mut self, value: u16) {
+        self.set(Register::Psw, value);
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        self.flags & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, set: bool) {
+        if set {
+            self.flags |= flag.mask();
+        } else {
+            self.flags &= !flag.mask();
+        }
+    }
+}
+
+// The registers with the flags that are set named, e.g. "A: 3E, BC: 0102, ... (Z P CY)"
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: Vec<&str> = [(Flag::S, "S"), (Flag::Z, "Z"), (Flag::Ac, "AC"), (Flag::P, "P"), (Flag::Cy, "CY")]
+            .into_iter()
+            .filter(|(flag, _)| self.flag(*flag))
+            .map(|(_, name)| name)
+            .collect();
+        write!(
+            f,
+            "A: {:02X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}, PC: {:04X}, F: {:02X} ({})",
+            self.a,
+            self.bc(),
+            self.de(),
+            self.hl(),
+            self.sp,
+            self.pc,
+            self.flags,
+            flags.join(" ")
+        )
+    }
+}
+
+// Flag bits as the 8080 stores them: S Z 0 AC 0 P 1 CY
+const FLAG_S: u8 = 0x80;
+const FLAG_Z: u8 = 0x40;
+const FLAG_AC: u8 = 0x10;
+const FLAG_P: u8 = 0x04;
+const FLAG_CY: u8 = 0x01;
+
+// Zero, sign and parity flags for every result
+const ZSP: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut value = 0;
+    while value < 256 {
+        let mut flags = 0;
+        if value == 0 {
+            flags |= FLAG_Z;
+        }
+        if value & 0x80 != 0 {
+            flags |= FLAG_S;
+        }
+        if (value as u8).count_ones().is_multiple_of(2) {
+            flags |= FLAG_P;
+        }
+        table[value] = flags;
+        value += 1;
+    }
+    table
+};
+
+/*
+Opcodes are decoded from their bit fields, 76 543 210:
+
+    00 ... ...  loads, increments and rotates, with a register in 543 or a pair in 54
+    01 ddd sss  MOV ddd,sss, except 76 which is HLT
+    10 ooo sss  ALU operation ooo on register sss
+    11 ... ...  jumps, calls, returns, stack and immediate ALU operations, with a condition in 543
+
+Registers are numbered B C D E H L M A, pairs BC DE HL SP and conditions NZ Z NC C PO PE P M.
+*/
+
+// Memory at HL in the register field
+pub(super) const M: u8 = 6;
+// Pair 3 is SP, except for PUSH and POP where it's A and the flags
+pub(super) const SP_OR_PSW: u8 = 3;
+// The condition of the unconditional jumps, calls and returns
+const ALWAYS: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Alu {
+    Add,
+    Adc,
+    Sub,
+    Sbb,
+    Ana,
+    Xra,
+    Ora,
+    Cmp,
+}
+
+const ALU: [Alu; 8] = [Alu::Add, Alu::Adc, Alu::Sub, Alu::Sbb, Alu::Ana, Alu::Xra, Alu::Ora, Alu::Cmp];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Op {
+    Nop,
+    Lxi(u8),
+    Stax(u8),
+    Ldax(u8),
+    Inx(u8),
+    Dcx(u8),
+    Dad(u8),
+    Inr(u8),
+    Dcr(u8),
+    Mvi(u8),
+    Mov(u8, u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    Shld,
+    Lhld,
+    Sta,
+    Lda,
+    Hlt,
+    Alu(Alu, u8),
+    AluImmediate(Alu),
+    Jmp(u8),
+    Call(u8),
+    Ret(u8),
+    Rst(u8),
+    Push(u8),
+    Pop(u8),
+    Out,
+    In,
+    Xthl,
+    Xchg,
+    Pchl,
+    Sphl,
+    Di,
+    Ei,
+    // 8085 only
+    Rim,
+    Sim,
+    Dsub,
+    Arhl,
+    Rdel,
+    Ldhi,
+    Ldsi,
+    Rstv,
+    Shlx,
+    Lhlx,
+    // JK when true, JNK when false
+    Jk(bool),
+}
+
+const fn decode(opcode: u8) -> Op {
+    let y = (opcode >> 3) & 7;
+    let z = opcode & 7;
+    let pair = y >> 1;
+    match opcode >> 6 {
+        0 => match z {
+            0 => Op::Nop,
+            1 if y & 1 == 0 => Op::Lxi(pair),
+            1 => Op::Dad(pair),
+            2 => match y {
+                0 | 2 => Op::Stax(pair),
+                1 | 3 => Op::Ldax(pair),
+                4 => Op::Shld,
+                5 => Op::Lhld,
+                6 => Op::Sta,
+                _ => Op::Lda,
+            },
+            3 if y & 1 == 0 => Op::Inx(pair),
+            3 => Op::Dcx(pair),
+            4 => Op::Inr(y),
+            5 => Op::Dcr(y),
+            6 => Op::Mvi(y),
+            _ => [Op::Rlc, Op::Rrc, Op::Ral, Op::Rar, Op::Daa, Op::Cma, Op::Stc, Op::Cmc][y as usize],
+        },
+        1 if opcode == 0x76 => Op::Hlt,
+        1 => Op::Mov(y, z),
+        2 => Op::Alu(ALU[y as usize], z),
+        _ => match z {
+            0 => Op::Ret(y),
+            1 if y & 1 == 0 => Op::Pop(pair),
+            1 => [Op::Ret(ALWAYS), Op::Ret(ALWAYS), Op::Pchl, Op::Sphl][pair as usize],
+            2 => Op::Jmp(y),
+            3 => [
+                Op::Jmp(ALWAYS),
+                Op::Jmp(ALWAYS),
+                Op::Out,
+                Op::In,
+                Op::Xthl,
+                Op::Xchg,
+                Op::Di,
+                Op::Ei,
+            ][y as usize],
+            4 => Op::Call(y),
+            5 if y & 1 == 0 => Op::Push(pair),
+            5 => Op::Call(ALWAYS),
+            6 => Op::AluImmediate(ALU[y as usize]),
+            _ => Op::Rst(y << 3),
+        },
+    }
+}
+
+pub(super) const DECODE: [Op; 256] = {
+    let mut table = [Op::Nop; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = decode(opcode as u8);
+        opcode += 1;
+    }
+    table
+};
+
+// Opcodes Intel left undefined, which the 8080 decodes as NOP, JMP (CB), RET (D9) and CALL (DD,
+// ED, FD)
+pub(super) const UNDOCUMENTED: [u8; 12] = [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+
+/*
+The 8085 gives 20 and 30 to RIM and SIM, and the other undefined opcodes to instructions that
+were left out of its data sheet:
+
+    08 DSUB     HL = HL - BC
+    10 ARHL     arithmetic shift right of HL, bit 0 into CY
+    18 RDEL     rotate DE left through CY
+    28 LDHI d8  DE = HL + d8
+    38 LDSI d8  DE = SP + d8
+    CB RSTV     RST 8 (call 0040h) if V is set
+    D9 SHLX     (DE) = HL
+    DD JNK a16  jump if K is clear
+    ED LHLX     HL = (DE)
+    FD JK a16   jump if K is set
+
+They use two more flags, V in bit 1 of the flag byte for signed overflow, and K in bit 5, which
+is S xor V after arithmetic (a signed less-than after a compare) and is set when INX or DCX
+wraps around.
+*/
+const fn decode_8085(opcode: u8) -> Op {
+    match opcode {
+        0x08 => Op::Dsub,
+        0x10 => Op::Arhl,
+        0x18 => Op::Rdel,
+        0x20 => Op::Rim,
+        0x28 => Op::Ldhi,
+        0x30 => Op::Sim,
+        0x38 => Op::Ldsi,
+        0xcb => Op::Rstv,
+        0xd9 => Op::Shlx,
+        0xdd => Op::Jk(false),
+        0xed => Op::Lhlx,
+        0xfd => Op::Jk(true),
+        _ => decode(opcode),
+    }
+}
+
+const DECODE_8085: [Op; 256] = {
+    let mut table = [Op::Nop; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = decode_8085(opcode as u8);
+        opcode += 1;
+    }
+    table
+};
+
+const UNDOCUMENTED_8085: [u8; 10] = [0x08, 0x10, 0x18, 0x28, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+
+// Clock states on the 8085 where they differ from the 8080. Conditional jumps take 3 more when
+// taken, conditional calls 9 more and conditional returns and RSTV 6 more.
+const CYCLES_8085: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        table[opcode] = match decode_8085(opcode as u8) {
+            Op::Mov(destination, source) if destination != M && source != M => 4,
+            Op::Inr(register) | Op::Dcr(register) if register != M => 4,
+            Op::Inx(_) | Op::Dcx(_) | Op::Pchl | Op::Sphl => 6,
+            Op::Hlt => 5,
+            Op::Jmp(condition) if condition != ALWAYS => 7,
+            Op::Call(ALWAYS) => 18,
+            Op::Call(_) => 9,
+            Op::Ret(condition) if condition != ALWAYS => 6,
+            Op::Rst(_) | Op::Push(_) => 12,
+            Op::Xthl => 16,
+            Op::Rim | Op::Sim => 4,
+            Op::Arhl => 7,
+            Op::Dsub | Op::Rdel | Op::Ldhi | Op::Ldsi | Op::Shlx | Op::Lhlx => 10,
+            Op::Jk(_) => 7,
+            Op::Rstv => 6,
+            _ => CYCLES[opcode],
+        };
+        opcode += 1;
+    }
+    table
+};
+
+// The instruction set and timings of a model
+#[derive(PartialEq)]
+struct InstructionSet {
+    decode: &'static [Op; 256],
+    cycles: &'static [u8; 256],
+    undocumented: &'static [u8],
+    // Clock states added when a conditional jump or call is taken
+    jump_taken: u64,
+    call_taken: u64,
+}
+
+const I8080: InstructionSet = InstructionSet {
+    decode: &DECODE,
+    cycles: &CYCLES,
+    undocumented: &UNDOCUMENTED,
+    jump_taken: 0,
+    call_taken: 6,
+};
+
+const I8085: InstructionSet = InstructionSet {
+    decode: &DECODE_8085,
+    cycles: &CYCLES_8085,
+    undocumented: &UNDOCUMENTED_8085,
+    jump_taken: 3,
+    call_taken: 9,
+};
+
+// Only the cycles are used, for RST; the Z80 decodes its prefixed opcodes itself
+const Z80: InstructionSet = InstructionSet {
+    decode: &DECODE,
+    cycles: &z80::CYCLES,
+    undocumented: &[],
+    jump_taken: 0,
+    call_taken: 7,
+};
+
+// The 8085's interrupt inputs and serial lines
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Pins8085 {
+    // Indexed by InterruptLine
+    lines: [bool; 4],
+    // TRAP and RST 7.5 are latched on a rising edge
+    trap: bool,
+    rst75: bool,
+    // M7.5 M6.5 M5.5 in bits 2-0, set by SIM
+    masks: u8,
+    // INTE as it was before a TRAP, which RIM reports until it's read
+    enabled_before_trap: Option<bool>,
+    sid: bool,
+    sod: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct ConditionCodes {
     z: bool,
     s: bool,
     p: bool,
     cy: bool,
     ac: bool,
+    // The 8085's overflow and K flags, see decode_8085
+    v: bool,
+    k: bool,
+    // The Z80's subtract flag, for DAA. Its P/V flag is p and its half carry ac.
+    n: bool,
 }
 
+#[derive(Clone, PartialEq)]
 pub struct Cpu {
     a: u8,
     b: u8,
@@ -20,14 +609,66 @@ pub struct Cpu {
     l: u8,
     sp: u16,
     pub pc: u16,
-    pub memory: [u8; 0xffff],
+    pub memory: [u8; 0x10000],
     condition_codes: ConditionCodes,
     pub enable: u8,
+    cycles: u64,
+    instruction_pc: u16,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Option<WatchHit>,
+    strict: bool,
+    // INTE, set by EI and cleared by DI and by taking an interrupt
+    interrupts_enabled: bool,
+    model: Model,
+    instructions: &'static InstructionSet,
+    pins: Pins8085,
+    z80: z80::Registers,
+    io: Io,
+    // Pages of memory holding translated code: 0 none, CODE translated, CODE_WRITTEN written since
+    #[cfg(feature = "dynarec")]
+    pub(super) code_pages: [u8; 256],
+    // Set by any write to a page of translated code, so the dynarec can stop and invalidate it
+    #[cfg(feature = "dynarec")]
+    pub(super) code_written: bool,
+}
+
+// Memory is left out, it's too big to print
+impl fmt::Debug for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cpu")
+            .field("registers", &self.registers())
+            .field("cycles", &self.cycles)
+            .field("model", &self.model)
+            .field("interrupts_enabled", &self.interrupts_enabled)
+            .field("enable", &self.enable)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "dynarec")]
+pub(super) const CODE: u8 = 1;
+#[cfg(feature = "dynarec")]
+pub(super) const CODE_WRITTEN: u8 = 2;
+
+// Byte offsets into Cpu of the fields translated code uses directly
+#[cfg(feature = "dynarec")]
+pub(super) struct Layout {
+    // B C D E H L - A, indexed by the register field of an opcode
+    pub registers: [usize; 8],
+    pub sp: usize,
+    pub pc: usize,
+    pub cycles: usize,
+    pub sign: usize,
+    pub zero: usize,
+    pub aux_carry: usize,
+    pub parity: usize,
+    pub carry: usize,
+    pub code_written: usize,
 }
 
 impl Cpu {
     pub fn new(program: Vec<u8>) -> Cpu {
-        let mut memory: [u8; 0xffff] = [0; 0xffff];
+        let mut memory: [u8; 0x10000] = [0; 0x10000];
         for (index, opcode) in program.into_iter().enumerate() {
             memory[index] = opcode;
         }
@@ -48,1531 +689,735 @@ impl Cpu {
                 p: false,
                 cy: false,
                 ac: false,
+                v: false,
+                k: false,
+                n: false,
             },
             enable: 0,
+            cycles: 0,
+            instruction_pc: 0,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            strict: false,
+            interrupts_enabled: false,
+            model: Model::I8080,
+            instructions: &I8080,
+            pins: Pins8085::default(),
+            z80: z80::Registers::default(),
+            io: Io::new(),
+            #[cfg(feature = "dynarec")]
+            code_pages: [0; 256],
+            #[cfg(feature = "dynarec")]
+            code_written: false,
         }
     }
 
-    fn unimplimented(&self) {
-        panic!("Unimplimented instruction: {:#04X}", self.memory[self.pc as usize]);
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
     }
 
-    pub fn cycle(&mut self) {
-        // println!("{:04X}: {:02X}", self.pc, self.memory[self.pc as usize]);
-        match self.memory[self.pc as usize] {
-            0x00 => {
-                // NOP
-                self.pc += 1; // instruction
-            }
-            0x01 => {
-                // LXI B,D16
-                self.pc += 1; // instruction
-                self.c = self.memory[self.pc as usize];
-                self.b = self.memory[(self.pc+1) as usize];
-                self.pc += 2;
-            }
-            0x02 => {
-                // STAX B
-                self.pc += 1; // instruction
-                self.memory[self.get_bc() as usize] = self.a;
-            }
-            0x03 => {
-                // INX B
-                self.pc += 1; // instruction
-                let answer: u16 = self.get_bc() + 1;
-                self.set_bc(answer);
-            }
-            0x04 => {
-                // INR B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.b + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.b = answer as u8;
-            }
-            0x05 => {
-                // DCR B
-                self.pc += 1; // instruction
-                let answer: u16 = self.b.overflowing_sub(1).0 as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.b = answer as u8;
-            }
-            0x06 => {
-                // MVI B, D8
-                self.pc += 1; // instruction
-                self.b = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x07 => {
-                // RLC
-                self.pc += 1; // instruction
-                let x = self.a;
-                self.a = x << 1 | x >> 7;
-                self.condition_codes.cy = (x & 1) == 1;
-            }
-            0x08 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x09 => {
-                // DAD B
-                self.pc += 1; // instruction
-                let answer = self.get_hl() + self.get_bc();
-                self.set_hl(answer);
-            }
-            0x0a => {
-                // LDAX B
-                self.pc += 1; // instruction
-                self.a = self.memory[self.get_bc() as usize];
-            }
-            0x0b => {
-                // DCX B
-                self.pc += 1; // instruction
-                let answer = self.get_bc() - 1;
-                self.set_bc(answer);
-            }
-            0x0c => {
-                // INR C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.c + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.c = answer as u8;
-            }
-            0x0d => {
-                // DCR C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.c - 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.c = answer as u8;
-            }
-            0x0e => {
-                // MVI C, D8
-                self.pc += 1; // instruction
-                self.c = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x0f => {
-                // RRC
-                self.pc += 1; // instruction
-                let x = self.a;
-                self.a = x << 7 | x >> 1;
-                self.condition_codes.cy = (x & 1) == 1;
-            }
-            0x10 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x11 => {
-                // LXI D,D16
-                self.pc += 1; // instruction
-                self.e = self.memory[self.pc as usize];
-                self.d = self.memory[(self.pc+1) as usize];
-                self.pc += 2;
-            }
-            0x12 => {
-                // STAX D
-                self.pc += 1; // instruction
-                self.memory[self.get_de() as usize] = self.a;
-            }
-            0x13 => {
-                // INX D
-                self.pc += 1; // instruction
-                let answer: u16 = self.get_de() + 1;
-                self.set_de(answer);
-            }
-            0x14 => {
-                // INR D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.d + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.d = answer as u8;
-            }
-            0x15 => {
-                // DCR D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.d - 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.d = answer as u8;
-            }
-            0x16 => {
-                // MVI D, D8
-                self.pc += 1; // instruction
-                self.d = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x17 => {
-                // RAL
-                self.pc += 1; // instruction
-                let x = self.a;
-                self.a = x << 1 | self.condition_codes.cy as u8;
-                self.condition_codes.cy = (x & 1) == 1;
-            }
-            0x18 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x19 => {
-                // DAD D
-                self.pc += 1; // instruction
-                let answer = self.get_hl() + self.get_de();
-                self.set_hl(answer);
-            }
-            0x1a => {
-                // LDAX D
-                self.pc += 1; // instruction
-                self.a = self.memory[self.get_de() as usize];
-            }
-            0x1b => {
-                // DCX D
-                self.pc += 1; // instruction
-                let answer = self.get_de() - 1;
-                self.set_de(answer);
-            }
-            0x1c => {
-                // INR E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.e + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.e = answer as u8;
-            }
-            0x1d => {
-                // DCR E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.e - 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.e = answer as u8;
-            }
-            0x1e => {
-                // MVI E, D8
-                self.pc += 1; // instruction
-                self.e = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x1f => {
-                // RAR
-                self.pc += 1; // instruction
-                let x = self.a;
-                self.a = (self.condition_codes.cy as u8) << 7 | x >> 1;
-                self.condition_codes.cy = (x & 1) == 1;
-            }
-            0x20 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x21 => {
-                // LXI H,D,D16
-                self.pc += 1; // instruction
-                self.l = self.memory[self.pc as usize];
-                self.h = self.memory[(self.pc+1) as usize];
-                self.pc += 2;
-            }
-            0x22 => {
-                // SHLD adr
-                self.pc += 1; // instruction
-                let addr: u16 = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                self.memory[addr as usize] = self.h;
-                self.memory[(addr + 1) as usize] = self.l;
-            }
-            0x23 => {
-                // INX H
-                self.pc += 1; // instruction
-                let answer: u16 = self.get_hl() + 1;
-                self.set_hl(answer);
-            }
-            0x24 => {
-                // INR H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.h + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.h = answer as u8;
-            }
-            0x25 => {
-                // DCR H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.h - 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.h = answer as u8;
-            }
-            0x26 => {
-                // MVI H, D8
-                self.pc += 1; // instruction
-                self.h = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x27 => {
-                // DAA
-                self.pc += 1; // instruction
-            }
-            0x28 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x29 => {
-                // DAD H
-                self.pc += 1; // instruction
-                let answer = self.get_hl() + self.get_hl();
-                self.update_condition_codes(answer, false, false, false, true, false);
-                self.set_hl(answer);
-            }
-            0x2a => {
-                // JHJD adr
-                self.pc += 1; // instruction
-                let addr: u16 = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                self.memory[addr as usize] = self.h;
-                self.memory[(addr + 1) as usize] = self.l;
-            }
-            0x2b => {
-                // DCX H
-                self.pc += 1; // instruction
-                let answer: u16 = self.get_hl() - 1;
-                self.set_hl(answer);
-            }
-            0x2c => {
-                // INR L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.l + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.l = answer as u8;
-            }
-            0x2d => {
-                // DCR L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.l - 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.l = answer as u8;
-            }
-            0x2e => {
-                // MVI L, D8
-                self.pc += 1; // instruction
-                self.l = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x2f => {
-                // CMA
-                self.pc += 1; // instruction
-                self.a = !self.a;
-            }
-            0x30 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x31 => {
-                // LXI SP,D16
-                self.pc += 1; // instruction
-                self.sp = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-            }
-            0x32 => {
-                // STA adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                self.memory[adr as usize] = self.a;
-            }
-            0x33 => {
-                //INX SP
-                self.pc += 1; // instruction
-                self.sp += 1;
-            }
-            0x34 => {
-                // INR M
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.memory[self.get_hl() as usize] + 1;
-            }
-            0x35 => {
-                // DCR M
-                self.pc += 1; // instruction
-                let answer: u16 = self.get_hl() - 1;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.set_hl(answer);
-            }
-            0x36 => {
-                // 	MVI M,D8
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x37 => {
-                // STC
-                self.pc += 1; // instruction
-                self.condition_codes.cy = true;
-            }
-            0x38 => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0x39 => {
-                // DAD SP
-                let answer = self.get_hl() + self.sp;
-                self.set_hl(answer);
-                // TODO: set CY
-            }
-            0x3a => {
-                // LDA adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                self.a = self.memory[adr as usize];
-            }
-            0x3b => {
-                // DCX SP
-                self.pc += 1; // instruction
-                self.sp += 1;
-            }
-            0x3c => {
-                // INR A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.a = answer as u8;
-            }
-            0x3d => {
-                // DCR A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - 1) as u16;
-                self.update_condition_codes(answer, true, true, true, false, true);
-                self.a = answer as u8;
-            }
-            0x3e => {
-                // MVI A, D8
-                self.pc += 1; // instruction
-                self.a = self.memory[self.pc as usize];
-                self.pc += 1;
-            }
-            0x3f => {
-                // CMC
-                self.pc += 1; // instruction
-                self.condition_codes.cy = !self.condition_codes.cy;
-            }
-            0x40 => {
-                // MOV B,B
-                self.pc += 1; // instruction
-                self.b = self.b;
-            }
-            0x41 => {
-                // MOV B,C
-                self.pc += 1; // instruction
-                self.b = self.c;
-            }
-            0x42 => {
-                // MOV B,D
-                self.pc += 1; // instruction
-                self.b = self.d; 
-            }
-            0x43 => {
-                // MOV B,E
-                self.pc += 1; // instruction
-                self.b = self.e;
-            }
-            0x44 => {
-                // MOV B,H
-                self.pc += 1; // instruction
-                self.b = self.h;
-            }
-            0x45 => {
-                // MOV B,L
-                self.pc += 1; // instruction
-                self.b = self.l;
-            }
-            0x46 => {
-                // MOV B,M
-                self.pc += 1; // instruction
-                self.b = self.memory[self.get_hl() as usize];
-            }
-            0x47 => {
-                // MOV B,A
-                self.pc += 1; // instruction
-                self.b = self.a;
-            }
-            0x48 => {
-                // MOV C,B
-                self.pc += 1; // instruction
-                self.c = self.b;
-            }
-            0x49 => {
-                // MOV C,C
-                self.pc += 1; // instruction
-                self.c = self.c;
-            }
-            0x4a => {
-                // MOV C,D
-                self.pc += 1; // instruction
-                self.c = self.d;
-            }
-            0x4b => {
-                // MOV C,E
-                self.pc += 1; // instruction
-                self.c = self.e;
-            }
-            0x4c => {
-                // MOV C,H
-                self.pc += 1; // instruction
-                self.c = self.h;
-            }
-            0x4d => {
-                // MOV C,L
-                self.pc += 1; // instruction
-                self.c = self.l;
-            }
-            0x4e => {
-                // MOV C,M
-                self.pc += 1; // instruction
-                self.c = self.memory[self.get_hl() as usize];
-            }
-            0x4f => {
-                // MOV C,A
-                self.pc += 1; // instruction
-                self.c = self.a;
-            }
-            0x50 => {
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) {
+        self.watchpoints.retain(|existing| existing != watchpoint);
+    }
 
-                self.pc += 1; // instruction
-                self.d = self.b; // MOV D,B
-            }
-            0x51 => {
-                // MOV D,C
-                self.pc += 1; // instruction
-                self.d = self.c;
-            }
-            0x52 => {
-                // MOV D,D
-                self.pc += 1; // instruction
-                self.d = self.d;
-            }
-            0x53 => {
-                // MOV D,E
-                self.pc += 1; // instruction
-                self.d = self.e;
-            }
-            0x54 => {
-                // MOV D,H
-                self.pc += 1; // instruction
-                self.d = self.h;
-            }
-            0x55 => {
-                // MOV D,L
-                self.pc += 1; // instruction
-                self.d = self.l;
-            }
-            0x56 => {
-                // MOV D,M
-                self.pc += 1; // instruction
-                self.d = self.memory[self.get_hl() as usize];
-            }
-            0x57 => {
-                // MOV D,A
-                self.pc += 1; // instruction
-                self.d = self.a;
-            }
-            0x58 => {
-                // MOV E,B
-                self.pc += 1; // instruction
-                self.e = self.b;
-            }
-            0x59 => {
-                // MOV E,C
-                self.pc += 1; // instruction
-                self.e = self.c;
-            }
-            0x5a => {
-                // MOV E,D
-                self.pc += 1; // instruction
-                self.e = self.d;
-            }
-            0x5b => {
-                // MOV E,E
-                self.pc += 1; // instruction
-                self.e = self.e;
-            }
-            0x5c => {
-                // MOV E,H
-                self.pc += 1; // instruction
-                self.e = self.h;
-            }
-            0x5d => {
-                // MOV E,L
-                self.pc += 1; // instruction
-                self.e = self.l;
-            }
-            0x5e => {
-                // MOV E,M
-                self.pc += 1; // instruction
-                self.e = self.memory[self.get_hl() as usize];
-            }
-            0x5f => {
-                // MOV E,A
-                self.pc += 1; // instruction
-                self.e = self.a;
-            }
-            0x60 => {
-                // MOV H,B
-                self.pc += 1; // instruction
-                self.h = self.b;
-            }
-            0x61 => {
-                // MOV H,C
-                self.pc += 1; // instruction
-                self.h = self.c;
-            }
-            0x62 => {
-                // MOV H,D
-                self.pc += 1; // instruction
-                self.h = self.d;
-            }
-            0x63 => {
-                // MOV H,E
-                self.pc += 1; // instruction
-                self.h = self.e;
-            }
-            0x64 => {
-                // MOV H,H
-                self.pc += 1; // instruction
-                self.h = self.h;
-            }
-            0x65 => {
-                // MOV H,L
-                self.pc += 1; // instruction
-                self.h = self.l;
-            }
-            0x66 => {
-                // MOV H,M
-                self.pc += 1; // instruction
-                self.h = self.memory[self.get_hl() as usize];
-            }
-            0x67 => {
-                // MOV H,A
-                self.pc += 1; // instruction
-                self.h = self.a;
-            }
-            0x68 => {
-                // MOV L,B
-                self.pc += 1; // instruction
-                self.l = self.b;
-            }
-            0x69 => {
-                // MOV L,C
-                self.pc += 1; // instruction
-                self.l = self.c;
-            }
-            0x6a => {
-                // MOV L,D
-                self.pc += 1; // instruction
-                self.l = self.d;
-            }
-            0x6b => {
-                // MOV L,E
-                self.pc += 1; // instruction
-                self.l = self.e;
-            }
-            0x6c => {
-                // MOV L,H
-                self.pc += 1; // instruction
-                self.l = self.h;
-            }
-            0x6d => {
-                // MOV L,L
-                self.pc += 1; // instruction
-                self.l = self.l;
-            }
-            0x6e => {
-                // MOV L,M
-                self.pc += 1; // instruction
-                self.l = self.memory[self.get_hl() as usize];
-            }
-            0x6f => {
-                // MOV L,A
-                self.pc += 1; // instruction
-                self.l = self.a;
-            }
-            0x70 => {
-                // MOV M,B
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.b;
-            }
-            0x71 => {
-                // MOV M,C
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.c;
-            }
-            0x72 => {
-                // MOV M,D
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.d;
-            }
-            0x73 => {
-                // MOV M,E
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.e;
-            }
-            0x74 => {
-                // MOV M,H
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.h;
-            }
-            0x75 => {
-                // MOV M,L
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.l;
-            }
-            0x76 => {
-                // HLT
-                self.pc += 1; // instruction
-                println!("Halting");
-                self.enable = 0;
-            }
-            0x77 => { 
-                // MOV M,A
-                self.pc += 1; // instruction
-                self.memory[self.get_hl() as usize] = self.a;
-            }
-            0x78 => { 
-                // MOV A,B
-                self.pc += 1; // instruction
-                self.a = self.b;
-            }                       
-            0x79 => { 
-                // MOV A,C
-                self.pc += 1; // instruction
-                self.a = self.c;
-            }        
-            0x7a => { 
-                // MOV A,D
-                self.pc += 1; // instruction
-                self.a = self.d;
-            }                          
-            0x7b => { 
-                // MOV A,E
-                self.pc += 1; // instruction
-                self.a = self.e;
-            }                          
-            0x7c => { 
-                // MOV A,H
-                self.pc += 1; // instruction
-                self.a = self.h;
-            }                          
-            0x7d => { 
-                // MOV A,L
-                self.pc += 1; // instruction
-                self.a = self.l;
-            }                          
-            0x7e => { 
-                // MOV A,M
-                self.pc += 1; // instruction
-                self.a = self.memory[self.get_hl() as usize];
-            }
-            0x7f => { 
-                // MOV A,A
-                self.pc += 1; // instruction
-                self.a = self.a;
-            }                  
-            0x80 => {
-                // ADD B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.b) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x81 => {
-                // ADD C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.c) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x82 => {
-                // ADD D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.d) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x83 => {
-                // ADD E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.e) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x84 => {
-                // ADD H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.h) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x85 => {
-                // ADD L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.l) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x86 => {
-                // ADD M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.memory[self.get_hl() as usize]) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x87 => {
-                // ADD A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.a) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x88 => {
-                // ADC B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.b + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x89 => {
-                // ADC C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.c + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x8a => {
-                // ADC D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.d + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x8b => {
-                // ADC E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.e + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x8c => {
-                // ADC H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.h + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x8d => {
-                // ADC L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.l + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x8e => {
-                // ADC M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a
-                    + self.memory[self.get_hl() as usize]
-                    + self.condition_codes.cy as u8)
-                    as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x8f => {
-                // ADC A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.a + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x90 => {
-                // SUB B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.b) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x91 => {
-                // SUB C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.c) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x92 => {
-                // SUB D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.d) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x93 => {
-                // SUB E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.e) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x94 => {
-                // SUB H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.h) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x95 => {
-                // SUB L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.l) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x96 => {
-                // SUB M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.memory[self.get_hl() as usize]) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x97 => {
-                // SUB A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.a) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x98 => {
-                // SBB B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.b - self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x99 => {
-                // SBB C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.c - self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x9a => {
-                // SBB D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.d - self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x9b => {
-                // SBB E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a - self.e - self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x9c => {
-                self.pc += 1; // instruction
-                // SBB H
-                let answer: u16 = (self.a - self.h - self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x9d => {
-                self.pc += 1; // instruction
-                // SBB L
-                let answer: u16 = (self.a - self.l - self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x9e => {
-                // SUB M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a
-                    - self.memory[self.get_hl() as usize]
-                    - self.condition_codes.cy as u8)
-                    as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0x9f => {
-                // SBB A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a + self.a + self.condition_codes.cy as u8) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa0 => {
-                // ANA B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.b) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa1 => {
-                // ANA C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.c) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa2 => {
-                // ANA D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.d) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa3 => {
-                // ANA E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.e) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa4 => {
-                // ANA H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.h) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa5 => {
-                // ANA L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.l) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa6 => {
-                // ANA M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.memory[self.get_hl() as usize]) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa7 => {
-                // ANA A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a & self.a) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa8 => {
-                // XRA B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.b) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xa9 => {
-                // XRA C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.c) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xaa => {
-                // XRA D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.d) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xab => {
-                // XRA E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.e) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xac => {
-                // XRA H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.h) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xad => {
-                // XRA L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.l) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xae => {
-                // XRA M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.memory[self.get_hl() as usize]) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xaf => {
-                // ORA A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.a) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb0 => {
-                // ORA B
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.b) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb1 => {
-                // ORA C
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.c) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb2 => {
-                // ORA D
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.d) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb3 => {
-                // ORA E
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.e) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb4 => {
-                // ORA H
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.h) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb5 => {
-                // ORA L
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.l) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb6 => {
-                // ORA M
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a | self.memory[self.get_hl() as usize]) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xb7 => {
-                // ORA A
-                self.pc += 1; // instruction
-                let answer: u16 = (self.a ^ self.a) as u16;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
 
-            0xc0 => {
-                // RNZ
-                self.pc += 1; // instruction
-                if !self.condition_codes.z {
-                    self.ret();
-                }
-            }
-            0xc1 => {
-                // POP B
-                self.pc += 1; // instruction
-                self.c = self.memory[self.sp as usize];
-                self.b = self.memory[(self.sp + 1) as usize];
-                self.sp += 2;
-            }
-            0xc2 => {
-                // JNZ adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if !self.condition_codes.z {
-                    self.pc = adr;
-                }
-            }
-            0xc3 => {
-                // JMP adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                self.pc = adr;
-            }
-            0xc4 => {
-                // CNZ adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if !self.condition_codes.z {
-                    self.call(adr);
-                }
-            }
-            0xc5 => {
-                // PUSH B
-                self.pc += 1; // instruction
-                self.memory[(self.sp - 1) as usize] = self.b;
-                self.memory[(self.sp - 2) as usize] = self.c;
-                self.sp -= 2;
-            }
-            0xc6 => {
-                // ADI D8
-                self.pc += 1; // instruction
-                let answer: u16 = self.a as u16 + self.memory[self.pc as usize] as u16;
-                self.pc += 1;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xc7 => {
-                // RST 0
-                // CALL $0
-                self.pc += 1; // instruction
-            }
-            0xc8 => {
-                // RZ
-                self.pc += 1; // instruction
-                if self.condition_codes.z {
-                    self.ret();
-                }
-            }
-            0xc9 => {
-                self.ret();
-            }
-            0xca => {
-                // JZ adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if self.condition_codes.z {
-                    self.pc = adr;
-                }
-            }
-            0xcb => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0xcc => {
-                // CZ adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if self.condition_codes.z {
-                    self.call(adr);
-                }
-            }
-            0xcd => {
-                // CALL adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                self.call(adr);
-            }
-            0xce => {
-                // ACI D8
-                self.pc += 1; // instruction
-                let answer: u16 =
-                    self.a as u16 + self.memory[self.pc as usize] as u16 + self.condition_codes.cy as u16;
-                self.pc += 1;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xcf => {
-                // RST 1
-                // CALL $8
-                self.pc += 1; // instruction
-            }
-            0xd0 => {
-                self.pc += 1; // instruction
-                if !self.condition_codes.cy {
-                    self.ret();
-                }
-            }
-            0xd1 => {
-                // POP D
-                self.pc += 1; // instruction
-                self.e = self.memory[self.sp as usize];
-                self.d = self.memory[(self.sp + 1) as usize];
-                self.sp += 2;
-            }
-            0xd2 => {
-                // JNC adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if !self.condition_codes.cy {
-                    self.pc = adr;
-                }
-            }
-            0xd3 => {
-                // OUT D8
-                self.pc += 1; // instruction
-                self.pc += 1;
-            }
-            0xd4 => {
-                // CNC adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if !self.condition_codes.cy {
-                    self.call(adr);
-                }
-            }
-            0xd5 => {
-                // PUSH D
-                self.pc += 1; // instruction
-                self.memory[(self.sp - 1) as usize] = self.d;
-                self.memory[(self.sp - 2) as usize] = self.e;
-                self.sp -= 2;
-            }
-            0xd6 => {
-                // SUI D8
-                self.pc += 1; // instruction
-                let answer: u16 = self.a as u16 - self.memory[self.pc as usize] as u16;
-                self.pc += 1;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xd7 => {
-                // RST 2
-                // CALL $10
-                self.pc += 1; // instruction
-            }
-            0xd8 => {
-                // RC
-                self.pc += 1; // instruction
-                if self.condition_codes.cy {
-                    self.ret();
+    // Stops on undocumented opcodes with an IllegalOpcode error instead of running them like the
+    // hardware does
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+        self.instructions = match model {
+            Model::I8080 => &I8080,
+            Model::I8085 => &I8085,
+            Model::Z80 => &Z80,
+        };
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // The devices IN and OUT talk to, nothing by default
+    pub fn set_io(&mut self, io: Io) {
+        self.io = io;
+    }
+
+    pub fn io(&self) -> &Io {
+        &self.io
+    }
+
+    // Drives one of the 8085's interrupt inputs. TRAP and RST 7.5 are latched when they go
+    // high, RST 6.5 and 5.5 are taken for as long as they're held high.
+    pub fn set_interrupt_line(&mut self, line: InterruptLine, high: bool) {
+        let rising = high && !self.pins.lines[line as usize];
+        self.pins.lines[line as usize] = high;
+        match line {
+            InterruptLine::Trap => self.pins.trap |= rising,
+            InterruptLine::Rst75 => self.pins.rst75 |= rising,
+            _ => {}
+        }
+    }
+
+    // The 8085's serial input, read by RIM
+    pub fn set_sid(&mut self, high: bool) {
+        self.pins.sid = high;
+    }
+
+    // The 8085's serial output, set by SIM
+    pub fn sod(&self) -> bool {
+        self.pins.sod
+    }
+
+    // Returns the watchpoint that stopped execution, if any, and clears it.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    // The watchpoint that stopped execution, if any, leaving it for take_watch_hit
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit
+    }
+
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.a,
+            flags: self.flags(),
+            bc: self.get_bc(),
+            de: self.get_de(),
+            hl: self.get_hl(),
+            sp: self.sp,
+            pc: self.pc,
+            cycles: self.cycles,
+        }
+    }
+
+    // Runs RST n, as an interrupting device would put on the bus, if interrupts are enabled.
+    // Returns whether the interrupt was taken.
+    pub fn interrupt(&mut self, rst: u8) -> bool {
+        self.interrupt_data(0xc7 | (rst & 7) << 3)
+    }
+
+    // Takes an interrupt with data on the bus, if interrupts are enabled: an RST opcode, or on
+    // the Z80 in interrupt mode 2 the low byte of the vector's address. Interrupt mode 1 ignores
+    // it and runs RST 7.
+    pub fn interrupt_data(&mut self, data: u8) -> bool {
+        if !self.interrupts_enabled {
+            return false;
+        }
+        self.interrupts_enabled = false;
+        if self.model == Model::Z80 {
+            self.interrupt_z80(data);
+        } else {
+            self.call(u16::from(data & 0x38));
+            self.cycles += self.instructions.cycles[0xc7 | data as usize & 0x38] as u64;
+        }
+        true
+    }
+
+    // Takes the highest priority 8085 interrupt that is pending and not masked, if any
+    fn interrupt_8085(&mut self) -> bool {
+        let pins = &mut self.pins;
+        let vector = if pins.trap {
+            pins.trap = false;
+            pins.enabled_before_trap = Some(self.interrupts_enabled);
+            0x24
+        } else if !self.interrupts_enabled {
+            return false;
+        } else if pins.rst75 && pins.masks & 0x04 == 0 {
+            pins.rst75 = false;
+            0x3c
+        } else if pins.lines[InterruptLine::Rst65 as usize] && pins.masks & 0x02 == 0 {
+            0x34
+        } else if pins.lines[InterruptLine::Rst55 as usize] && pins.masks & 0x01 == 0 {
+            0x2c
+        } else {
+            return false;
+        };
+        self.interrupts_enabled = false;
+        self.call(vector);
+        self.cycles += 12;
+        true
+    }
+
+    // Clock states executed since reset
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Executes the instruction at pc, or leaves everything as it was if strict mode rejects it.
+    // On the 8085 a pending interrupt is taken instead. Strict mode doesn't apply to the Z80,
+    // which has no undefined opcodes.
+    pub fn cycle(&mut self) -> Result<(), EmulatorError> {
+        match self.model {
+            Model::I8080 => {}
+            Model::I8085 => {
+                if self.interrupt_8085() {
+                    return Ok(());
                 }
             }
-            0xd9 => {
-                // -
-                self.pc += 1; // instruction
+            Model::Z80 => {
+                self.instruction_pc = self.pc;
+                self.execute_z80();
+                return Ok(());
             }
-            0xda => {
-                // JC adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if self.condition_codes.cy {
-                    self.pc = adr;
+        }
+        let opcode = self.memory[self.pc as usize];
+        if self.strict && self.instructions.undocumented.contains(&opcode) {
+            return Err(EmulatorError::IllegalOpcode {
+                opcode,
+                state: self.state(),
+            });
+        }
+        self.execute_opcode(opcode);
+        Ok(())
+    }
+
+    // Executes the opcode at pc once it has been fetched and checked
+    pub(super) fn execute_opcode(&mut self, opcode: u8) {
+        self.instruction_pc = self.pc;
+        self.pc = self.pc.wrapping_add(1);
+        self.cycles += self.instructions.cycles[opcode as usize] as u64;
+        self.execute(self.instructions.decode[opcode as usize]);
+    }
+
+    // Whether the dynarec has to leave everything to the interpreter, which checks every data
+    // access against the watchpoints and runs the 8085
+    #[cfg(feature = "dynarec")]
+    pub(super) fn needs_interpreter(&self) -> bool {
+        !self.watchpoints.is_empty() || self.watch_hit.is_some() || self.model != Model::I8080
+    }
+
+    #[cfg(feature = "dynarec")]
+    pub(super) fn layout() -> Layout {
+        use std::mem::offset_of;
+        Layout {
+            registers: [
+                offset_of!(Cpu, b),
+                offset_of!(Cpu, c),
+                offset_of!(Cpu, d),
+                offset_of!(Cpu, e),
+                offset_of!(Cpu, h),
+                offset_of!(Cpu, l),
+                usize::MAX,
+                offset_of!(Cpu, a),
+            ],
+            sp: offset_of!(Cpu, sp),
+            pc: offset_of!(Cpu, pc),
+            cycles: offset_of!(Cpu, cycles),
+            sign: offset_of!(Cpu, condition_codes.s),
+            zero: offset_of!(Cpu, condition_codes.z),
+            aux_carry: offset_of!(Cpu, condition_codes.ac),
+            parity: offset_of!(Cpu, condition_codes.p),
+            carry: offset_of!(Cpu, condition_codes.cy),
+            code_written: offset_of!(Cpu, code_written),
+        }
+    }
+
+    fn execute(&mut self, op: Op) {
+        match op {
+            Op::Nop => {}
+            Op::Di => self.interrupts_enabled = false,
+            Op::Ei => self.interrupts_enabled = true,
+            Op::Lxi(pair) => {
+                let value = self.fetch_word();
+                self.set_pair(pair, value);
+            }
+            Op::Stax(pair) => self.write_byte(self.pair(pair), self.a),
+            Op::Ldax(pair) => self.a = self.read_byte(self.pair(pair)),
+            Op::Inx(pair) => {
+                let value = self.pair(pair).wrapping_add(1);
+                self.set_pair(pair, value);
+                self.condition_codes.k = value == 0x0000;
+            }
+            Op::Dcx(pair) => {
+                let value = self.pair(pair).wrapping_sub(1);
+                self.set_pair(pair, value);
+                self.condition_codes.k = value == 0xffff;
+            }
+            Op::Dad(pair) => {
+                let (sum, carry) = self.get_hl().overflowing_add(self.pair(pair));
+                self.set_hl(sum);
+                self.condition_codes.cy = carry;
+            }
+            Op::Inr(register) => {
+                let value = self.reg(register).wrapping_add(1);
+                self.set_zsp(value);
+                self.condition_codes.ac = value & 0x0f == 0;
+                self.set_overflow(value == 0x80);
+                self.set_reg(register, value);
+            }
+            Op::Dcr(register) => {
+                let value = self.reg(register).wrapping_sub(1);
+                self.set_zsp(value);
+                self.condition_codes.ac = value & 0x0f != 0x0f;
+                self.set_overflow(value == 0x7f);
+                self.set_reg(register, value);
+            }
+            Op::Mvi(register) => {
+                let value = self.fetch_byte();
+                self.set_reg(register, value);
+            }
+            Op::Mov(destination, source) => {
+                let value = self.reg(source);
+                self.set_reg(destination, value);
+            }
+            Op::Rlc => {
+                self.condition_codes.cy = self.a & 0x80 != 0;
+                self.a = self.a.rotate_left(1);
+            }
+            Op::Rrc => {
+                self.condition_codes.cy = self.a & 0x01 != 0;
+                self.a = self.a.rotate_right(1);
+            }
+            Op::Ral => {
+                let carry = self.condition_codes.cy as u8;
+                self.condition_codes.cy = self.a & 0x80 != 0;
+                self.a = self.a << 1 | carry;
+            }
+            Op::Rar => {
+                let carry = self.condition_codes.cy as u8;
+                self.condition_codes.cy = self.a & 0x01 != 0;
+                self.a = self.a >> 1 | carry << 7;
+            }
+            Op::Daa => self.daa(),
+            Op::Cma => self.a = !self.a,
+            Op::Stc => self.condition_codes.cy = true,
+            Op::Cmc => self.condition_codes.cy = !self.condition_codes.cy,
+            Op::Shld => {
+                let address = self.fetch_word();
+                self.write_byte(address, self.l);
+                self.write_byte(address.wrapping_add(1), self.h);
+            }
+            Op::Lhld => {
+                let address = self.fetch_word();
+                self.l = self.read_byte(address);
+                self.h = self.read_byte(address.wrapping_add(1));
+            }
+            Op::Sta => {
+                let address = self.fetch_word();
+                self.write_byte(address, self.a);
+            }
+            Op::Lda => {
+                let address = self.fetch_word();
+                self.a = self.read_byte(address);
+            }
+            Op::Hlt => self.enable = 0,
+            Op::Alu(operation, register) => {
+                let value = self.reg(register);
+                self.alu(operation, value);
+            }
+            Op::AluImmediate(operation) => {
+                let value = self.fetch_byte();
+                self.alu(operation, value);
+            }
+            Op::Jmp(condition) => {
+                let address = self.fetch_word();
+                if self.condition(condition) {
+                    if condition != ALWAYS {
+                        self.cycles += self.instructions.jump_taken;
+                    }
+                    self.pc = address;
                 }
             }
-            0xdb => {
-                // IN D8
-                self.pc += 1; // instruction
-                self.pc += 1;
-            }
-            0xdc => {
-                // CC adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if self.condition_codes.cy {
-                    self.call(adr);
+            Op::Call(condition) => {
+                let address = self.fetch_word();
+                if self.condition(condition) {
+                    if condition != ALWAYS {
+                        self.cycles += self.instructions.call_taken;
+                    }
+                    self.call(address);
                 }
             }
-            0xdd => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0xde => {
-                // SBI D8
-                self.pc += 1; // instruction
-                let answer: u16 =
-                    self.a as u16 - self.memory[self.pc as usize] as u16 - self.condition_codes.cy as u16;
-                self.pc += 1;
-                self.update_condition_codes(answer, true, true, true, true, true);
-                self.a = answer as u8;
-            }
-            0xdf => {
-                // RST 3
-                // CALL $18
-                self.pc += 1; // instruction
-            }
-            0xe0 => {
-                // RPO
-                self.pc += 1; // instruction
-                if !self.condition_codes.p {
+            Op::Ret(condition) => {
+                if self.condition(condition) {
+                    if condition != ALWAYS {
+                        self.cycles += 6;
+                    }
                     self.ret();
                 }
             }
-            0xe1 => {
-                // POP H
-                self.pc += 1; // instruction
-                self.l = self.memory[self.sp as usize];
-                self.h = self.memory[(self.sp + 1) as usize];
-                self.sp += 2;
-            }
-            0xe2 => {
-                // JPO adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if !self.condition_codes.p {
-                    self.pc = adr;
-                }
-            }
-            0xe3 => {
-                // XTHL
-                self.pc += 1; // instruction
-                let temp = self.l;
-                self.l = self.memory[self.sp as usize];
-                self.memory[self.sp as usize] = temp;
-                let temp = self.h;
-                self.h = self.memory[(self.sp+1) as usize];
-                self.memory[(self.sp+1) as usize] = temp;
-            }
-            0xe4 => {
-                // CPO adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if !self.condition_codes.p {
-                    self.call(adr);
+            Op::Rst(address) => self.call(address as u16),
+            Op::Push(pair) => {
+                let value = if pair == SP_OR_PSW {
+                    self.psw()
+                } else {
+                    self.pair(pair)
+                };
+                self.push(value);
+            }
+            Op::Pop(pair) => {
+                let value = self.pop();
+                if pair == SP_OR_PSW {
+                    self.set_psw(value);
+                } else {
+                    self.set_pair(pair, value);
                 }
             }
-            0xe5 => {
-                // PUSH H
-                self.pc += 1; // instruction
-                self.memory[(self.sp - 1) as usize] = self.h;
-                self.memory[(self.sp - 2) as usize] = self.l;
-                self.sp -= 2;
-            }
-            0xe6 => {
-                // ANI D8
-                self.pc += 1; // instruction
-                let answer: u8 = self.a & self.memory[self.pc as usize];
-                self.pc += 1;
-                self.update_condition_codes(answer as u16, true, true, true, true, true);
-                self.a = answer;
-            }
-            0xe7 => {
-                // RST 4
-                // CALL $20
-                self.pc += 1; // instruction
-            }
-            0xe8 => {
-                // RPE
-                self.pc += 1; // instruction
-                if self.condition_codes.p {
-                    self.ret();
+            Op::Out => {
+                let port = self.fetch_byte();
+                self.io.output(port, self.a);
+            }
+            Op::In => {
+                let port = self.fetch_byte();
+                self.a = self.io.input(port);
+            }
+            Op::Xthl => {
+                let low = self.read_byte(self.sp);
+                let high = self.read_byte(self.sp.wrapping_add(1));
+                self.write_byte(self.sp, self.l);
+                self.write_byte(self.sp.wrapping_add(1), self.h);
+                self.l = low;
+                self.h = high;
+            }
+            Op::Xchg => {
+                std::mem::swap(&mut self.d, &mut self.h);
+                std::mem::swap(&mut self.e, &mut self.l);
+            }
+            Op::Pchl => self.pc = self.get_hl(),
+            Op::Sphl => self.sp = self.get_hl(),
+            Op::Rim => self.a = self.rim(),
+            Op::Sim => self.sim(),
+            Op::Dsub => self.dsub(),
+            Op::Arhl => {
+                self.condition_codes.cy = self.l & 0x01 != 0;
+                self.set_hl((self.get_hl() as i16 >> 1) as u16);
+            }
+            Op::Rdel => {
+                let de = self.get_de();
+                let rotated = de << 1 | self.condition_codes.cy as u16;
+                self.condition_codes.cy = de & 0x8000 != 0;
+                self.condition_codes.v = (de ^ rotated) & 0x8000 != 0;
+                self.set_de(rotated);
+            }
+            Op::Ldhi => {
+                let offset = self.fetch_byte() as u16;
+                self.set_de(self.get_hl().wrapping_add(offset));
+            }
+            Op::Ldsi => {
+                let offset = self.fetch_byte() as u16;
+                self.set_de(self.sp.wrapping_add(offset));
+            }
+            Op::Rstv => {
+                if self.condition_codes.v {
+                    self.cycles += 6;
+                    self.call(0x0040);
                 }
             }
-            0xe9 => {
-                // PCHL
-                self.pc += 1; // instruction
-                self.pc = ((self.h as u16) << 8) | self.l as u16;
-            }
-            0xea => {
-                // JPE adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if self.condition_codes.p {
-                    self.pc = adr;
+            Op::Shlx => {
+                let address = self.get_de();
+                self.write_byte(address, self.l);
+                self.write_byte(address.wrapping_add(1), self.h);
+            }
+            Op::Lhlx => {
+                let address = self.get_de();
+                self.l = self.read_byte(address);
+                self.h = self.read_byte(address.wrapping_add(1));
+            }
+            Op::Jk(set) => {
+                let address = self.fetch_word();
+                if self.condition_codes.k == set {
+                    self.cycles += self.instructions.jump_taken;
+                    self.pc = address;
                 }
             }
-            0xeb => {
-                // XCHG
-                self.pc += 1; // instruction
-                let temp = self.d;
-                self.d = self.h;
-                self.h = temp;
-                let temp = self.e;
-                self.e = self.l;
-                self.l = temp;
-            }
-            0xec => {
-                // CPE adr
-                self.pc += 1; // instruction
-                let adr = (self.memory[(self.pc+1) as usize] as u16) << 8 | self.memory[self.pc as usize] as u16;
-                self.pc += 2;
-                if self.condition_codes.p {
-                    self.call(adr);
+        }
+    }
+
+    // SID, the pending RST 7.5, 6.5 and 5.5 interrupts, INTE and the masks: SID I7.5 I6.5 I5.5
+    // IE M7.5 M6.5 M5.5
+    fn rim(&mut self) -> u8 {
+        let pins = &mut self.pins;
+        let enabled = pins.enabled_before_trap.take().unwrap_or(self.interrupts_enabled);
+        (pins.sid as u8) << 7
+            | (pins.rst75 as u8) << 6
+            | (pins.lines[InterruptLine::Rst65 as usize] as u8) << 5
+            | (pins.lines[InterruptLine::Rst55 as usize] as u8) << 4
+            | (enabled as u8) << 3
+            | pins.masks
+    }
+
+    // A holds SOD SOE - R7.5 MSE M7.5 M6.5 M5.5. The masks are only set with MSE and SOD only
+    // with SOE, and R7.5 clears a pending RST 7.5.
+    fn sim(&mut self) {
+        let pins = &mut self.pins;
+        if self.a & 0x08 != 0 {
+            pins.masks = self.a & 0x07;
+        }
+        if self.a & 0x10 != 0 {
+            pins.rst75 = false;
+        }
+        if self.a & 0x40 != 0 {
+            pins.sod = self.a & 0x80 != 0;
+        }
+    }
+
+    // HL - BC, with the flags of a 16-bit subtraction: P and AC come from the high byte
+    fn dsub(&mut self) {
+        let (hl, bc) = (self.get_hl(), self.get_bc());
+        let (difference, borrow) = hl.overflowing_sub(bc);
+        let borrow_low = (hl & 0xff) < (bc & 0xff);
+        let high = (difference >> 8) as u8;
+        self.set_zsp(high);
+        self.condition_codes.z = difference == 0;
+        self.condition_codes.ac =
+            (self.h & 0x0f) + (!self.b & 0x0f) + (1 - borrow_low as u8) > 0x0f;
+        self.condition_codes.cy = borrow;
+        self.set_overflow((hl ^ bc) & (hl ^ difference) & 0x8000 != 0);
+        self.set_hl(difference);
+    }
+
+    // V, and K as S xor V, which only the 8085 keeps
+    fn set_overflow(&mut self, overflow: bool) {
+        if self.model == Model::I8080 {
+            return;
+        }
+        self.condition_codes.v = overflow;
+        self.condition_codes.k = self.condition_codes.s ^ overflow;
+    }
+
+    // Shared by the register and immediate forms of the arithmetic and logic instructions
+    fn alu(&mut self, operation: Alu, value: u8) {
+        let a = self.a;
+        let carry = self.condition_codes.cy as u8;
+        let result = match operation {
+            Alu::Add | Alu::Adc => {
+                let carry = if operation == Alu::Adc { carry } else { 0 };
+                let sum = a as u16 + value as u16 + carry as u16;
+                self.condition_codes.ac = (a & 0x0f) + (value & 0x0f) + carry > 0x0f;
+                self.condition_codes.cy = sum > 0xff;
+                sum as u8
+            }
+            Alu::Sub | Alu::Sbb | Alu::Cmp => {
+                let borrow = if operation == Alu::Sbb { carry } else { 0 };
+                let difference = (a as u16).wrapping_sub(value as u16 + borrow as u16);
+                // The 8080 subtracts by adding the complement, and AC is the carry out of bit 3
+                // of that addition
+                self.condition_codes.ac = (a & 0x0f) + (!value & 0x0f) + (1 - borrow) > 0x0f;
+                self.condition_codes.cy = difference > 0xff;
+                difference as u8
+            }
+            Alu::Ana => {
+                // The 8080 sets AC from bit 3 of the operands, the 8085 always sets it
+                self.condition_codes.ac = self.model == Model::I8085 || (a | value) & 0x08 != 0;
+                self.condition_codes.cy = false;
+                a & value
+            }
+            Alu::Xra | Alu::Ora => {
+                self.condition_codes.ac = false;
+                self.condition_codes.cy = false;
+                if operation == Alu::Xra {
+                    a ^ value
+                } else {
+                    a | value
                 }
             }
-            0xed => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0xee => {
-                // XRI D8
-                self.pc += 1; // instruction
-                let answer: u8 = self.a ^ self.memory[self.pc as usize];
-                self.pc += 1;
-                self.update_condition_codes(answer as u16, true, true, true, true, true);
-                self.a = answer;
-            }
+        };
+        self.set_zsp(result);
+        self.set_overflow(match operation {
+            Alu::Add | Alu::Adc => (a ^ result) & (value ^ result) & 0x80 != 0,
+            Alu::Sub | Alu::Sbb | Alu::Cmp => (a ^ value) & (a ^ result) & 0x80 != 0,
+            _ => false,
+        });
+        if operation != Alu::Cmp {
+            self.a = result;
+        }
+    }
 
-            0xf1 => {
-                // POP PSW
-                self.pc += 1; // instruction
-                self.a = self.memory[(self.sp+1) as usize];  
-                let psw: u8 = self.memory[self.sp as usize];    
-                self.condition_codes.z  = 0x01 == (psw & 0x01);  
-                self.condition_codes.s  = 0x02 == (psw & 0x02); 
-                self.condition_codes.p  = 0x04 == (psw & 0x04);
-                self.condition_codes.cy = 0x05 == (psw & 0x08);
-                self.condition_codes.ac = 0x10 == (psw & 0x10);
-                self.sp += 2;
-            }
+    fn daa(&mut self) {
+        let mut correction = 0;
+        let mut carry = self.condition_codes.cy;
+        if self.condition_codes.ac || self.a & 0x0f > 9 {
+            correction |= 0x06;
+        }
+        if carry || self.a > 0x99 {
+            correction |= 0x60;
+            carry = true;
+        }
+        self.alu(Alu::Add, correction);
+        self.condition_codes.cy = carry;
+    }
 
-            0xf3 => {
-                // DI
-                self.pc += 1; // instruction
-            }
+    pub fn register(&self, register: Register) -> u16 {
+        match register {
+            Register::A => self.a as u16,
+            Register::B => self.b as u16,
+            Register::C => self.c as u16,
+            Register::D => self.d as u16,
+            Register::E => self.e as u16,
+            Register::H => self.h as u16,
+            Register::L => self.l as u16,
+            Register::Bc => self.get_bc(),
+            Register::De => self.get_de(),
+            Register::Hl => self.get_hl(),
+            Register::Sp => self.sp,
+            Register::Pc => self.pc,
+            Register::Psw => self.psw(),
+        }
+    }
 
-            0xf5 => {
-                // PUSH PSW
-                self.pc += 1; // instruction
-                let psw = 
-                    self.condition_codes.z as u8 |    
-                    (self.condition_codes.s as u8) << 1 |    
-                    (self.condition_codes.p as u8) << 2 |    
-                    (self.condition_codes.cy as u8) << 3 |    
-                    (self.condition_codes.ac as u8) << 4; 
-                self.memory[(self.sp-2) as usize] = psw;
-                self.memory[(self.sp-1) as usize] = self.a;
-                self.sp -= 2;
-            }
-            0xf6 => {
-                // ORI D8
-                self.pc += 1; // instruction
-                let answer: u8 = self.a | self.memory[self.pc as usize];
-                self.pc += 1;
-                self.update_condition_codes(answer as u16, true, true, true, true, true);
-                self.a = answer;
-            }
-            0xf7 => {
-                // RES 6
-                // CALL $30
-                self.pc += 1; // instruction
-            }
+    pub fn flag(&self, flag: Flag) -> bool {
+        match flag {
+            Flag::Z => self.condition_codes.z,
+            Flag::S => self.condition_codes.s,
+            Flag::P => self.condition_codes.p,
+            Flag::Cy => self.condition_codes.cy,
+            Flag::Ac => self.condition_codes.ac,
+        }
+    }
 
-            0xf9 => {
-                // SPHL
-                self.pc += 1; // instruction
-                self.sp = ((self.h as u16) << 8) | self.l as u16;
-            }
+    pub fn set_flag(&mut self, flag: Flag, set: bool) {
+        match flag {
+            Flag::Z => self.condition_codes.z = set,
+            Flag::S => self.condition_codes.s = set,
+            Flag::P => self.condition_codes.p = set,
+            Flag::Cy => self.condition_codes.cy = set,
+            Flag::Ac => self.condition_codes.ac = set,
+        }
+    }
 
-            0xfb => {
-                // EL
-                self.pc += 1; // instruction
-            }
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            flags: self.flags(),
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+            model: self.model,
+        }
+    }
 
-            0xfd => {
-                // -
-                self.pc += 1; // instruction
-            }
-            0xfe => {
-                // CPI D8
-                self.pc += 1; // instruction
-                let answer: u8 = self.a.overflowing_sub(self.memory[self.pc as usize]).0;
-                self.pc += 1;
-                self.update_condition_codes(answer as u16, true, true, true, true, true);
-            }
-            0xff => {
-                // RST 7
-                // CALL $38
-                self.pc += 1; // instruction
-            }
-            _ => self.unimplimented(),
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.set_flags(registers.flags);
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.sp = registers.sp;
+        self.pc = registers.pc;
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::A => self.a = value as u8,
+            Register::B => self.b = value as u8,
+            Register::C => self.c = value as u8,
+            Register::D => self.d = value as u8,
+            Register::E => self.e = value as u8,
+            Register::H => self.h = value as u8,
+            Register::L => self.l = value as u8,
+            Register::Bc => self.set_bc(value),
+            Register::De => self.set_de(value),
+            Register::Hl => self.set_hl(value),
+            Register::Sp => self.sp = value,
+            Register::Pc => self.pc = value,
+            Register::Psw => self.set_psw(value),
+        }
+    }
+
+    // The flags packed as the 8080 stores them: S Z 0 AC 0 P 1 CY. The 8085 has K in bit 5 and
+    // V in bit 1, the Z80 N in bit 1.
+    pub fn flags(&self) -> u8 {
+        let fixed = match self.model {
+            Model::I8080 => 0x02,
+            Model::I8085 => (self.condition_codes.k as u8) << 5 | (self.condition_codes.v as u8) << 1,
+            Model::Z80 => (self.condition_codes.n as u8) << 1,
+        };
+        (self.condition_codes.s as u8) << 7
+            | (self.condition_codes.z as u8) << 6
+            | (self.condition_codes.ac as u8) << 4
+            | (self.condition_codes.p as u8) << 2
+            | fixed
+            | self.condition_codes.cy as u8
+    }
+
+    pub fn set_flags(&mut self, flags: u8) {
+        self.condition_codes.s = flags & 0x80 != 0;
+        self.condition_codes.z = flags & 0x40 != 0;
+        self.condition_codes.ac = flags & 0x10 != 0;
+        self.condition_codes.p = flags & 0x04 != 0;
+        self.condition_codes.cy = flags & 0x01 != 0;
+        match self.model {
+            Model::I8080 => {}
+            Model::I8085 => {
+                self.condition_codes.k = flags & 0x20 != 0;
+                self.condition_codes.v = flags & 0x02 != 0;
+            }
+            Model::Z80 => self.condition_codes.n = flags & 0x02 != 0,
+        }
+    }
+
+    // A in the high byte and the flags in the low, as PUSH PSW stores them
+    pub fn psw(&self) -> u16 {
+        (self.a as u16) << 8 | self.flags() as u16
+    }
+
+    // As POP PSW loads it. The fixed bits of the flag byte are ignored.
+    pub fn set_psw(&mut self, psw: u16) {
+        self.a = (psw >> 8) as u8;
+        self.set_flags(psw as u8);
+    }
+
+    // Reads memory for debugging without triggering watchpoints
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    // All data accesses made by instructions go through read_byte and write_byte so
+    // they can be checked against the watchpoints. Opcode and operand fetches don't.
+    fn read_byte(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, false, value, value);
+        }
+        value
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            let old = self.memory[address as usize];
+            self.check_watchpoints(address, true, old, value);
+        }
+        #[cfg(feature = "dynarec")]
+        if self.code_pages[(address >> 8) as usize] != 0 {
+            self.code_pages[(address >> 8) as usize] = CODE_WRITTEN;
+            self.code_written = true;
+        }
+        self.memory[address as usize] = value;
+    }
+
+    fn check_watchpoints(&mut self, address: u16, write: bool, old: u8, new: u8) {
+        if self.watch_hit.is_some() {
+            // Keep the first hit until the frontend has reported it
+            return;
+        }
+        if let Some(watchpoint) = self
+            .watchpoints
+            .iter()
+            .find(|watchpoint| watchpoint.triggers(address, write, old, new))
+        {
+            self.watch_hit = Some(WatchHit {
+                kind: watchpoint.kind,
+                write,
+                address,
+                pc: self.instruction_pc,
+                old,
+                new,
+            });
+            self.enable = 0;
         }
     }
 
@@ -1603,93 +1448,117 @@ impl Cpu {
         self.l = answer as u8;
     }
 
-    fn call(&mut self, adr: u16) {
-        self.memory[(self.sp - 1) as usize] = (self.pc >> 8) as u8;
-        self.memory[(self.sp - 2) as usize] = self.pc as u8;
-        self.sp -= 2;
-        self.pc = adr;
+    fn fetch_byte(&mut self) -> u8 {
+        let value = self.memory[self.pc as usize];
+        self.pc = self.pc.wrapping_add(1);
+        value
     }
 
-    fn ret(&mut self) {
-        self.pc =
-            (self.memory[(self.sp+1) as usize] as u16) << 8 | self.memory[self.sp as usize] as u16;
-        self.sp += 2;
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch_byte() as u16;
+        low | (self.fetch_byte() as u16) << 8
     }
 
-    fn update_condition_codes(
-        &mut self,
-        value: u16,
-        z: bool,
-        s: bool,
-        p: bool,
-        cy: bool,
-        ac: bool,
-    ) {
-        let codes: &mut ConditionCodes = &mut self.condition_codes;
-        if z {
-            codes.z = (value & 0xff) == 0;
+    // B C D E H L M A, as encoded in bits 0-2 and 3-5 of an opcode
+    fn reg(&mut self, register: u8) -> u8 {
+        match register {
+            0 => self.b,
+            1 => self.c,
+            2 => self.d,
+            3 => self.e,
+            4 => self.h,
+            5 => self.l,
+            M => self.read_byte(self.get_hl()),
+            _ => self.a,
         }
-        if s {
-            codes.s = (value & 0x80) != 0;
+    }
+
+    fn set_reg(&mut self, register: u8, value: u8) {
+        match register {
+            0 => self.b = value,
+            1 => self.c = value,
+            2 => self.d = value,
+            3 => self.e = value,
+            4 => self.h = value,
+            5 => self.l = value,
+            M => self.write_byte(self.get_hl(), value),
+            _ => self.a = value,
         }
-        if p {
-            /*
-            Parity IS determined by counting the number of one bits set in the result in the accumulator.
-            Instructions that affect the parity flag set the flag to one for even parity and reset the
-            flag to zero to indicate odd parity.
-            */
-            codes.p = value.count_ones() % 2 == 0;
+    }
+
+    // BC DE HL SP, as encoded in bits 4-5 of an opcode
+    fn pair(&self, pair: u8) -> u16 {
+        match pair {
+            0 => self.get_bc(),
+            1 => self.get_de(),
+            2 => self.get_hl(),
+            _ => self.sp,
         }
-        if cy {
-            codes.cy = value > 0xff;
+    }
+
+    fn set_pair(&mut self, pair: u8, value: u16) {
+        match pair {
+            0 => self.set_bc(value),
+            1 => self.set_de(value),
+            2 => self.set_hl(value),
+            _ => self.sp = value,
         }
-        if ac {
-            codes.ac = false;
+    }
+
+    // NZ Z NC C PO PE P M, as encoded in bits 3-5 of an opcode, or ALWAYS
+    fn condition(&self, condition: u8) -> bool {
+        let codes = &self.condition_codes;
+        match condition {
+            0 => !codes.z,
+            1 => codes.z,
+            2 => !codes.cy,
+            3 => codes.cy,
+            4 => !codes.p,
+            5 => codes.p,
+            6 => !codes.s,
+            7 => codes.s,
+            _ => true,
         }
     }
 
-    #[allow(dead_code)]
+    fn set_zsp(&mut self, value: u8) {
+        let flags = ZSP[value as usize];
+        self.condition_codes.z = flags & FLAG_Z != 0;
+        self.condition_codes.s = flags & FLAG_S != 0;
+        self.condition_codes.p = flags & FLAG_P != 0;
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_byte(self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_byte(self.sp, value as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let low = self.read_byte(self.sp) as u16;
+        let high = self.read_byte(self.sp.wrapping_add(1)) as u16;
+        self.sp = self.sp.wrapping_add(2);
+        high << 8 | low
+    }
+
+    fn call(&mut self, address: u16) {
+        self.push(self.pc);
+        self.pc = address;
+    }
+
+    fn ret(&mut self) {
+        self.pc = self.pop();
+    }
+
     pub fn print_registers(&self) {
-        println!(
-            "a={:02X} ({})\nb={:02X} ({})\nc={:02X} ({})\nd={:02X} ({})\ne={:02X} ({})\nh={:02X} ({})\nl={:02X} ({})\nsp={:04X}\npc={:04X}\n{:?}",
-            self.a,
-            self.a,
-            self.b,
-            self.b,
-            self.c,
-            self.c,
-            self.d,
-            self.d,
-            self.e,
-            self.e,
-            self.h,
-            self.h,
-            self.l,
-            self.l,
-            self.sp,
-            self.pc,
-            self.condition_codes
-        );
-        println!("(BC)= {}", self.memory[self.get_bc() as usize]);
-        println!("(DE)= {}", self.memory[self.get_de() as usize]);
-        println!("(HL)= {}", self.memory[self.get_hl() as usize]);
-        if self.sp < 0x2400 {
-            print!("Stack: ");
-            let mut temp = self.sp as usize;
-            while temp < 0x2400 {
-                print!("{:02X} ", self.memory[temp]);
-                temp += 1;
-            }
-            println!();
-        }
+        println!("{}", self.registers());
     }
 
-    #[allow(dead_code)]
     pub fn print_memory(&self) {
         self.print_memory_width(32);
     }
 
-    #[allow(dead_code)]
     pub fn print_memory_width(&self, width: usize) {
         print!("0000");
         for (index, code) in (&self.memory).into_iter().enumerate() {
@@ -1702,3 +1571,687 @@ impl Cpu {
         }
     }
 }
+
+#[cfg(test)]
+mod cycle_tests {
+    use super::*;
+    use crate::emulator::disassembler;
+
+    // Runs the program with A and the flags set, until pc leaves it
+    fn run(program: &[u8], a: u8, flags: u8) -> Cpu {
+        let mut cpu = Cpu::new(program.to_vec());
+        cpu.a = a;
+        cpu.set_flags(flags);
+        while (cpu.pc as usize) < program.len() {
+            cpu.cycle().unwrap();
+        }
+        cpu
+    }
+
+    fn mnemonic(op: Op) -> String {
+        const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+        let conditional = |always: &str, prefix: &str, condition: u8| match condition {
+            ALWAYS => always.to_string(),
+            _ => format!("{}{}", prefix, CONDITIONS[condition as usize]),
+        };
+        match op {
+            Op::Jmp(condition) => conditional("JMP", "J", condition),
+            Op::Call(condition) => conditional("CALL", "C", condition),
+            Op::Ret(condition) => conditional("RET", "R", condition),
+            Op::Alu(alu, _) => ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"][alu as usize].to_string(),
+            Op::AluImmediate(alu) => ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"][alu as usize].to_string(),
+            _ => {
+                let name = format!("{:?}", op);
+                name.split('(').next().unwrap().to_uppercase()
+            }
+        }
+    }
+
+    #[test]
+    fn push_and_pop_psw() {
+        // PUSH PSW stores S Z 0 AC 0 P 1 CY under A
+        let cpu = run(&[0xf5], 0x12, 0xff);
+        assert_eq!(cpu.memory[cpu.sp as usize..cpu.sp as usize + 2], [0xd7, 0x12]);
+        assert_eq!(cpu.psw(), 0x12d7);
+
+        // POP PSW restores every flag, carry included, and ignores the fixed bits
+        let mut cpu = Cpu::new(vec![0xf1]);
+        cpu.sp = 0x1000;
+        cpu.memory[0x1000] = 0x29;
+        cpu.memory[0x1001] = 0x34;
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.a, 0x34);
+        assert!(cpu.flag(Flag::Cy));
+        assert!(!cpu.flag(Flag::Z));
+        assert_eq!(cpu.psw(), 0x3403);
+
+        cpu.set_psw(0xff44);
+        assert_eq!((cpu.a, cpu.flags()), (0xff, 0x46));
+    }
+
+    #[test]
+    fn decode_matches_disassembler() {
+        for opcode in 0..=255u8 {
+            let instruction = disassembler::disassemble(&[opcode, 0, 0], 0);
+            assert_eq!(
+                mnemonic(DECODE[opcode as usize]),
+                instruction.mnemonic.trim_start_matches('*'),
+                "opcode {:02X}",
+                opcode
+            );
+        }
+        assert_eq!(DECODE[0x7e], Op::Mov(7, M));
+        assert_eq!(DECODE[0xf5], Op::Push(SP_OR_PSW));
+        assert_eq!(DECODE[0xef], Op::Rst(0x28));
+    }
+
+    #[test]
+    fn alu_flags() {
+        // ADD B, carrying out of both nibbles
+        let cpu = run(&[0x06, 0xc6, 0x80], 0x3a, 0x00);
+        assert_eq!((cpu.a, cpu.flags()), (0x00, 0x57));
+        // SUB A
+        let cpu = run(&[0x97], 0x3e, 0x01);
+        assert_eq!((cpu.a, cpu.flags()), (0x00, 0x56));
+        // CPI 10h leaves A alone
+        let cpu = run(&[0xfe, 0x10], 0x05, 0x00);
+        assert_eq!((cpu.a, cpu.flags()), (0x05, 0x97));
+        // ANI 0Fh sets AC from bit 3 of the operands
+        let cpu = run(&[0xe6, 0x0f], 0xfc, 0x01);
+        assert_eq!((cpu.a, cpu.flags()), (0x0c, 0x16));
+        // DAA
+        let cpu = run(&[0x27], 0x9b, 0x00);
+        assert_eq!((cpu.a, cpu.flags()), (0x01, 0x13));
+        // SBB with a borrow in, and INR wrapping
+        let cpu = run(&[0xde, 0x01], 0x00, 0x01);
+        assert_eq!((cpu.a, cpu.flags()), (0xfe, 0x83));
+        let cpu = run(&[0x3c], 0xff, 0x01);
+        assert_eq!((cpu.a, cpu.flags()), (0x00, 0x57));
+    }
+
+    #[test]
+    fn interrupts() {
+        // LXI SP,2000h / EI / DI / EI
+        let mut cpu = Cpu::new(vec![0x31, 0x00, 0x20, 0xfb, 0xf3, 0xfb]);
+        cpu.cycle().unwrap();
+        assert!(!cpu.interrupt(2));
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert!(!cpu.interrupt(2));
+        cpu.cycle().unwrap();
+        assert!(cpu.interrupt(2));
+        assert_eq!((cpu.pc, cpu.sp, cpu.cycles()), (0x0010, 0x1ffe, 10 + 4 + 4 + 4 + 11));
+        assert_eq!((cpu.peek(0x1ffe), cpu.peek(0x1fff)), (0x06, 0x00));
+        // Taking it disables further interrupts until the next EI
+        assert!(!cpu.interrupt(1));
+    }
+
+    #[test]
+    fn ports() {
+        // OUT 04h / IN 03h / IN 07h
+        let program = [0xd3, 0x04, 0xdb, 0x03, 0xdb, 0x07];
+        let cpu = run(&program[..4], 0x5a, 0x02);
+        assert_eq!(cpu.a, 0xff);
+
+        // The shift register of the Space Invaders board hands back what was shifted in
+        let mut cpu = Cpu::new(program.to_vec());
+        cpu.set_io(Io::board(crate::emulator::rom::Machine::find("invaders").unwrap().config));
+        cpu.a = 0x5a;
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert_eq!((cpu.a, cpu.cycles()), (0x5a, 20));
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.a, 0xff);
+    }
+
+    #[test]
+    fn control_flow_and_cycles() {
+        // LXI SP,2000h / CALL 0008h / HLT / ... / RNZ at 0008h
+        let mut program = vec![0x31, 0x00, 0x20, 0xcd, 0x08, 0x00, 0x76, 0x00, 0xc0];
+        let mut cpu = Cpu::new(program.clone());
+        cpu.set_flags(0x40);
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+        // RNZ isn't taken with Z set
+        assert_eq!((cpu.pc, cpu.sp, cpu.cycles()), (0x0009, 0x1ffe, 10 + 17 + 5));
+
+        // With Z clear RNZ returns to an RST 1, which calls it again
+        program[6] = 0xcf;
+        let mut cpu = Cpu::new(program);
+        cpu.set_flags(0x00);
+        for _ in 0..4 {
+            cpu.cycle().unwrap();
+        }
+        assert_eq!((cpu.pc, cpu.sp, cpu.cycles()), (0x0008, 0x1ffe, 10 + 17 + 11 + 11));
+        assert_eq!((cpu.peek(0x1ffe), cpu.peek(0x1fff)), (0x07, 0x00));
+    }
+
+    #[test]
+    fn illegal_opcode_is_recoverable() {
+        // MVI A,07h / *RET / HLT
+        let mut cpu = Cpu::new(vec![0x3e, 0x07, 0xd9, 0x76]);
+        cpu.set_strict(true);
+        cpu.cycle().unwrap();
+        let error = cpu.cycle().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Illegal opcode D9 at 0002\nPC: 0002, AF: 0742, BC: 0000, DE: 0000, HL: 0000, SP: FFFE, CYC: 7"
+        );
+        assert_eq!(cpu.pc, 0x0002);
+
+        cpu.pc = 0x0003;
+        assert!(cpu.cycle().is_ok());
+    }
+
+    #[test]
+    fn undocumented_aliases() {
+        // *CALL 0008h / *NOP / *JMP 000Ch / HLT / *RET
+        let program = vec![0xfd, 0x08, 0x00, 0x76, 0x76, 0x76, 0x76, 0x76, 0x08, 0xcb, 0x0c, 0x00, 0xd9];
+        let mut cpu = Cpu::new(program.clone());
+        let mut pcs = Vec::new();
+        for _ in 0..4 {
+            cpu.cycle().unwrap();
+            pcs.push(cpu.pc);
+        }
+        assert_eq!(pcs, [0x0008, 0x0009, 0x000c, 0x0003]);
+        assert_eq!(cpu.sp, 0xfffe);
+        assert_eq!(cpu.cycles(), 17 + 4 + 10 + 10);
+
+        let mut cpu = Cpu::new(program);
+        cpu.set_strict(true);
+        let error = cpu.cycle().err().unwrap();
+        assert!(matches!(error, EmulatorError::IllegalOpcode { opcode: 0xfd, .. }));
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.cycles(), 0);
+    }
+}
+
+#[cfg(test)]
+mod register_tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut cpu = Cpu::new(vec![0x3c]);
+        let mut registers = cpu.registers();
+        assert_eq!(registers.flags, 0x42, "Z and the fixed bit after reset");
+        registers.set_bc(0x1234);
+        registers.set(Register::De, 0x5678);
+        registers.h = 0x9a;
+        registers.set_psw(0x7f00);
+        registers.set_flag(Flag::Cy, true);
+        registers.set_flag(Flag::Z, true);
+        assert!(registers.flag(Flag::Cy));
+        assert_eq!(registers.psw(), 0x7f43);
+        assert_eq!(registers.get(Register::Hl), 0x9a00);
+
+        cpu.set_registers(registers);
+        assert_eq!(cpu.register(Register::Bc), 0x1234);
+        assert_eq!(cpu.register(Register::Psw), 0x7f43);
+        assert!(cpu.flag(Flag::Cy));
+        assert_eq!(
+            cpu.registers().to_string(),
+            "A: 7F, BC: 1234, DE: 5678, HL: 9A00, SP: FFFE, PC: 0000, F: 43 (Z CY)"
+        );
+
+        // INR A from 7Fh sets S and AC and leaves CY alone
+        let before = cpu.clone();
+        assert_eq!(before, cpu);
+        cpu.cycle().unwrap();
+        assert_ne!(before, cpu);
+        assert_eq!(cpu.registers().psw(), 0x8093);
+        cpu.set_flag(Flag::S, false);
+        assert_eq!(cpu.flags(), 0x13);
+        assert!(format!("{:?}", cpu).starts_with("Cpu { registers: Registers { a: 128,"));
+    }
+
+    #[test]
+    fn packed_flags() {
+        for model in [Model::I8080, Model::I8085, Model::Z80] {
+            let mut cpu = Cpu::new(vec![]);
+            cpu.set_model(model);
+            let mut registers = cpu.registers();
+            for psw in [0x7f41, 0x00ff, 0x1228] {
+                registers.set_psw(psw);
+                cpu.set_psw(psw);
+                assert_eq!(registers.psw(), cpu.psw(), "{} {:04X}", model, psw);
+            }
+            registers.set_flag(Flag::S, true);
+            cpu.set_flag(Flag::S, true);
+            assert_eq!(registers.flags, cpu.flags(), "{}", model);
+        }
+    }
+}
+
+#[cfg(test)]
+mod watchpoint_tests {
+    use super::*;
+    use crate::emulator::watchpoint::WatchKind;
+
+    // MVI A,05h / STA 20F8h / STA 20F8h / LDA 20F8h
+    const PROGRAM: [u8; 11] = [0x3e, 0x05, 0x32, 0xf8, 0x20, 0x32, 0xf8, 0x20, 0x3a, 0xf8, 0x20];
+
+    fn run(watchpoint: Watchpoint) -> Vec<WatchHit> {
+        let mut cpu = Cpu::new(PROGRAM.to_vec());
+        cpu.add_watchpoint(watchpoint);
+        let mut hits = Vec::new();
+        while (cpu.pc as usize) < PROGRAM.len() {
+            cpu.cycle().unwrap();
+            if let Some(hit) = cpu.take_watch_hit() {
+                assert_eq!(cpu.enable, 0);
+                hits.push(hit);
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn write_reports_pc_and_values() {
+        let hits = run(Watchpoint::write(0x20f8));
+        assert_eq!(hits.len(), 2);
+        assert_eq!(
+            hits[0],
+            WatchHit {
+                kind: WatchKind::Write,
+                write: true,
+                address: 0x20f8,
+                pc: 0x0002,
+                old: 0x00,
+                new: 0x05
+            }
+        );
+        assert_eq!(hits[1].pc, 0x0005);
+    }
+
+    #[test]
+    fn change_ignores_same_value() {
+        let hits = run(Watchpoint::change(0x20f8));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].pc, 0x0002);
+    }
+
+    #[test]
+    fn read_in_range() {
+        let hits = run(Watchpoint::new(WatchKind::Read, 0x20f0, 0x20ff));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].pc, 0x0008);
+        assert_eq!(hits[0].old, 0x05);
+    }
+}
+
+#[cfg(test)]
+mod i8085_tests {
+    use super::*;
+
+    fn cpu(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new(program.to_vec());
+        cpu.set_model(Model::I8085);
+        cpu
+    }
+
+    fn run(cpu: &mut Cpu, steps: usize) {
+        for _ in 0..steps {
+            cpu.cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn timings() {
+        // MOV B,C / INX H / LXI SP,2000h / JNZ 000Bh / JZ 000Bh / CALL 000Fh / NOP / RET
+        let program = [
+            0x41, 0x23, 0x31, 0x00, 0x20, 0xc2, 0x0b, 0x00, 0xca, 0x0b, 0x00, 0xcd, 0x0f, 0x00, 0x00, 0xc9,
+        ];
+        let mut i8085 = cpu(&program);
+        run(&mut i8085, 7);
+        assert_eq!((i8085.pc, i8085.cycles()), (0x000e, 4 + 6 + 10 + 7 + 10 + 18 + 10));
+        let mut i8080 = Cpu::new(program.to_vec());
+        run(&mut i8080, 7);
+        assert_eq!((i8080.pc, i8080.cycles()), (0x000e, 5 + 5 + 10 + 10 + 10 + 17 + 10));
+    }
+
+    #[test]
+    fn and_sets_auxiliary_carry() {
+        // MVI A,F0h / ANI 01h / ANA A
+        let program = [0x3e, 0xf0, 0xe6, 0x01, 0xa7];
+        let mut i8085 = cpu(&program);
+        run(&mut i8085, 2);
+        assert!(i8085.condition_codes.ac);
+        run(&mut i8085, 1);
+        assert!(i8085.condition_codes.ac);
+        let mut i8080 = Cpu::new(program.to_vec());
+        run(&mut i8080, 2);
+        assert!(!i8080.condition_codes.ac);
+        run(&mut i8080, 1);
+        assert!(!i8080.condition_codes.ac);
+    }
+
+    #[test]
+    fn rim_and_sim() {
+        // MVI A,DDh / SIM / EI / RIM: SOD high, clear RST 7.5, mask 7.5 and 5.5
+        let mut cpu = cpu(&[0x3e, 0xdd, 0x30, 0xfb, 0x20]);
+        cpu.set_sid(true);
+        cpu.set_interrupt_line(InterruptLine::Rst75, true);
+        cpu.set_interrupt_line(InterruptLine::Rst55, true);
+        run(&mut cpu, 4);
+        assert!(cpu.sod());
+        // SID, I5.5 pending, IE, M7.5 and M5.5
+        assert_eq!(cpu.register(Register::A), 0x9d);
+        assert_eq!(cpu.pc, 0x0005);
+    }
+
+    #[test]
+    fn interrupts() {
+        let mut program = vec![0; 0x40];
+        // LXI SP,2000h / EI
+        program[..4].copy_from_slice(&[0x31, 0x00, 0x20, 0xfb]);
+        program[0x24] = 0x20;
+        program[0x34] = 0xfb;
+        let mut cpu = cpu(&program);
+        run(&mut cpu, 2);
+
+        // RST 6.5 is taken while it's high and interrupts are enabled
+        cpu.set_interrupt_line(InterruptLine::Rst65, true);
+        run(&mut cpu, 1);
+        assert_eq!((cpu.pc, cpu.sp, cpu.cycles()), (0x0034, 0x1ffe, 10 + 4 + 12));
+        assert_eq!(cpu.peek(0x1ffe), 0x04);
+        cpu.set_interrupt_line(InterruptLine::Rst65, false);
+        run(&mut cpu, 1);
+
+        // RST 7.5 comes before 5.5
+        cpu.set_interrupt_line(InterruptLine::Rst55, true);
+        cpu.set_interrupt_line(InterruptLine::Rst75, true);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x003c);
+
+        // TRAP is taken with interrupts disabled, and RIM reports INTE from before it
+        run(&mut cpu, 1);
+        cpu.set_interrupt_line(InterruptLine::Trap, true);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0024);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.register(Register::A), 0x10);
+        // It stays high but was only latched on the rising edge
+        run(&mut cpu, 1);
+        assert_eq!(cpu.pc, 0x0026);
+    }
+
+    #[test]
+    fn undocumented_instructions() {
+        // DSUB
+        let mut cpu = self::cpu(&[0x08]);
+        cpu.set_register(Register::Hl, 0x1234);
+        cpu.set_register(Register::Bc, 0x2345);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.register(Register::Hl), 0xeeef);
+        // S, K and CY, V and Z clear
+        assert_eq!(cpu.flags() & 0xe3, 0xa1);
+        assert_eq!(cpu.cycles(), 10);
+
+        // ARHL
+        let mut cpu = self::cpu(&[0x10]);
+        cpu.set_register(Register::Hl, 0x8003);
+        run(&mut cpu, 1);
+        assert_eq!((cpu.register(Register::Hl), cpu.flag(Flag::Cy)), (0xc001, true));
+
+        // RDEL, with V set as bit 15 changes
+        let mut cpu = self::cpu(&[0x18]);
+        cpu.set_register(Register::De, 0x8001);
+        cpu.set_flags(0x00);
+        run(&mut cpu, 1);
+        assert_eq!((cpu.register(Register::De), cpu.flags() & 0x03), (0x0002, 0x03));
+
+        // LDHI 10h / LDSI 02h
+        let mut cpu = self::cpu(&[0x28, 0x10, 0x38, 0x02]);
+        cpu.set_register(Register::Hl, 0x1000);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.register(Register::De), 0x1010);
+        cpu.set_register(Register::Sp, 0x2000);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.register(Register::De), 0x2002);
+
+        // SHLX / LHLX
+        let mut cpu = self::cpu(&[0xd9, 0xed]);
+        cpu.set_register(Register::De, 0x3000);
+        cpu.set_register(Register::Hl, 0xbeef);
+        run(&mut cpu, 1);
+        assert_eq!((cpu.peek(0x3000), cpu.peek(0x3001)), (0xef, 0xbe));
+        cpu.set_register(Register::Hl, 0);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.register(Register::Hl), 0xbeef);
+    }
+
+    #[test]
+    fn k_and_v_jumps() {
+        // DCX B wraps and sets K / JNK 1000h / JK 0008h / RSTV / MVI A,7Fh / INR A sets V / RSTV
+        let mut cpu = cpu(&[0x0b, 0xdd, 0x00, 0x10, 0xfd, 0x08, 0x00, 0x00, 0xcb, 0x3e, 0x7f, 0x3c, 0xcb]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.pc, 0x0008);
+        run(&mut cpu, 4);
+        assert_eq!((cpu.pc, cpu.sp), (0x0040, 0xfffc));
+        assert_eq!(cpu.peek(0xfffc), 0x0d);
+        assert_eq!(cpu.cycles(), 6 + 7 + 10 + 6 + 7 + 4 + 12);
+    }
+
+    #[test]
+    fn strict_mode() {
+        // RIM is documented, DSUB isn't
+        let mut cpu = cpu(&[0x20, 0x08]);
+        cpu.set_strict(true);
+        run(&mut cpu, 1);
+        assert!(matches!(
+            cpu.cycle(),
+            Err(EmulatorError::IllegalOpcode { opcode: 0x08, .. })
+        ));
+    }
+}
+
+/*
+Checks the 8080's arithmetic and logic flags against a reference model written from the
+manual, which adds a bit at a time and so shares none of the wide arithmetic in alu(). Random
+operands and flags run through every register and memory form, and the immediate forms are
+run for every operand and carry.
+*/
+#[cfg(test)]
+mod alu_property_tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const REGISTERS: [Register; 6] = [Register::B, Register::C, Register::D, Register::E, Register::H, Register::L];
+
+    // RLC RRC RAL RAR CMA STC CMC and DAD, which only change CY, if anything
+    const CARRY_ONLY: [u8; 11] = [0x07, 0x0f, 0x17, 0x1f, 0x2f, 0x37, 0x3f, 0x09, 0x19, 0x29, 0x39];
+
+    // S Z 0 AC 0 P 1 CY
+    fn pack(result: u8, ac: bool, cy: bool) -> u8 {
+        let mut flags = 0x02 | (ac as u8) << 4 | cy as u8;
+        if result & 0x80 != 0 {
+            flags |= 0x80;
+        }
+        if result == 0 {
+            flags |= 0x40;
+        }
+        if result.count_ones().is_multiple_of(2) {
+            flags |= 0x04;
+        }
+        flags
+    }
+
+    // Returns the sum and the carries out of bits 3 and 7
+    fn adder(a: u8, b: u8, carry_in: bool) -> (u8, bool, bool) {
+        let mut sum = 0;
+        let mut carry = carry_in;
+        let mut half_carry = false;
+        for bit in 0..8 {
+            let (x, y) = (a >> bit & 1 != 0, b >> bit & 1 != 0);
+            if x ^ y ^ carry {
+                sum |= 1 << bit;
+            }
+            carry = x && y || carry && (x ^ y);
+            if bit == 3 {
+                half_carry = carry;
+            }
+        }
+        (sum, half_carry, carry)
+    }
+
+    // A and the flags after ADD ADC SUB SBB ANA XRA ORA CMP. Subtraction adds the complement,
+    // and CY is set on a borrow, the inverse of the adder's carry.
+    fn reference_alu(operation: u8, a: u8, value: u8, carry: bool) -> (u8, u8) {
+        let (result, ac, cy) = match operation {
+            0 => adder(a, value, false),
+            1 => adder(a, value, carry),
+            2 | 7 => {
+                let (result, ac, carry) = adder(a, !value, true);
+                (result, ac, !carry)
+            }
+            3 => {
+                let (result, ac, carry) = adder(a, !value, !carry);
+                (result, ac, !carry)
+            }
+            4 => (a & value, (a | value) & 0x08 != 0, false),
+            5 => (a ^ value, false, false),
+            _ => (a | value, false, false),
+        };
+        (if operation == 7 { a } else { result }, pack(result, ac, cy))
+    }
+
+    // DAA as the manual describes it, in two steps that each see the result of the one before
+    fn reference_daa(a: u8, flags: u8) -> (u8, u8) {
+        let (mut result, mut ac, mut cy) = (a, flags & 0x10 != 0, flags & 0x01 != 0);
+        if result & 0x0f > 9 || ac {
+            let (sum, half_carry, carry) = adder(result, 0x06, false);
+            result = sum;
+            ac = half_carry;
+            cy |= carry;
+        } else {
+            ac = false;
+        }
+        if result >> 4 > 9 || cy {
+            let (sum, _, carry) = adder(result, 0x60, false);
+            result = sum;
+            cy |= carry;
+        }
+        (result, pack(result, ac, cy))
+    }
+
+    // Runs the program's first instruction with A, the flags, B to L and memory at 0100h set
+    fn execute(program: &[u8], a: u8, flags: u8, registers: [u8; 6], memory: u8) -> Box<Cpu> {
+        let mut cpu = Box::new(Cpu::new(program.to_vec()));
+        cpu.set_register(Register::A, a as u16);
+        cpu.set_flags(flags);
+        for (register, value) in REGISTERS.into_iter().zip(registers) {
+            cpu.set_register(register, value as u16);
+        }
+        cpu.memory[0x0100] = memory;
+        cpu.cycle().unwrap();
+        cpu
+    }
+
+    // The register forms take their operand from B to L, M at HL = 0100h, or A
+    fn registers_for(source: u8, value: u8) -> [u8; 6] {
+        let mut registers = [0, 0, 0, 0, 0x01, 0x00];
+        if (source as usize) < registers.len() {
+            registers[source as usize] = value;
+        }
+        registers
+    }
+
+    proptest! {
+        #[test]
+        fn alu_registers(operation in 0..8u8, source in 0..8u8, a: u8, value: u8, flags: u8) {
+            let registers = registers_for(source, value);
+            let operand = match source {
+                0..=5 => registers[source as usize],
+                M => value,
+                _ => a,
+            };
+            let cpu = execute(&[0x80 | operation << 3 | source], a, flags, registers, value);
+            let (expected_a, expected_flags) = reference_alu(operation, a, operand, flags & 0x01 != 0);
+            prop_assert_eq!(cpu.a, expected_a);
+            prop_assert_eq!(cpu.flags(), expected_flags);
+        }
+
+        #[test]
+        fn increment_and_decrement(register in 0..8u8, decrement: bool, value: u8, flags: u8) {
+            let registers = registers_for(register, value);
+            let value = match register {
+                0..=5 => registers[register as usize],
+                _ => value,
+            };
+            let opcode = 0x04 | register << 3 | decrement as u8;
+            let cpu = execute(&[opcode], value, flags, registers, value);
+            let (result, ac, _) = adder(value, if decrement { 0xff } else { 0x01 }, false);
+            let actual = match register {
+                0..=5 => cpu.register(REGISTERS[register as usize]) as u8,
+                M => cpu.memory[0x0100],
+                _ => cpu.a,
+            };
+            prop_assert_eq!(actual, result);
+            prop_assert_eq!(cpu.flags(), pack(result, ac, flags & 0x01 != 0), "CY is left alone");
+        }
+
+        #[test]
+        fn decimal_adjust(a: u8, flags: u8) {
+            let cpu = execute(&[0x27], a, flags, [0; 6], 0);
+            let (expected_a, expected_flags) = reference_daa(a, flags);
+            prop_assert_eq!(cpu.a, expected_a);
+            prop_assert_eq!(cpu.flags(), expected_flags);
+        }
+
+        #[test]
+        fn carry_only(opcode in prop::sample::select(&CARRY_ONLY[..]), a: u8, flags: u8, registers: [u8; 6]) {
+            let cpu = execute(&[opcode], a, flags, registers, 0);
+            let carry = flags & 0x01 != 0;
+            let word = |high: usize| (registers[high] as u16) << 8 | registers[high + 1] as u16;
+            let hl = word(4);
+            let pair = [word(0), word(2), hl, cpu.sp];
+            let cy = match opcode {
+                0x07 | 0x17 => a & 0x80 != 0,
+                0x0f | 0x1f => a & 0x01 != 0,
+                0x2f => carry,
+                0x37 => true,
+                0x3f => !carry,
+                _ => hl as u32 + pair[(opcode >> 4) as usize] as u32 > 0xffff,
+            };
+            let expected_a = match opcode {
+                0x07 => a.rotate_left(1),
+                0x0f => a.rotate_right(1),
+                0x17 => a << 1 | carry as u8,
+                0x1f => a >> 1 | (carry as u8) << 7,
+                0x2f => !a,
+                _ => a,
+            };
+            let expected_hl = match opcode {
+                0x09 | 0x19 | 0x29 | 0x39 => (hl as u32 + pair[(opcode >> 4) as usize] as u32) as u16,
+                _ => hl,
+            };
+            prop_assert_eq!(cpu.a, expected_a);
+            prop_assert_eq!(cpu.register(Register::Hl), expected_hl);
+            prop_assert_eq!(cpu.flags(), flags & 0xd4 | 0x02 | cy as u8);
+        }
+    }
+
+    #[test]
+    fn alu_immediates() {
+        let mut cpu = Box::new(Cpu::new(Vec::new()));
+        for operation in 0..8 {
+            cpu.memory[0] = 0xc6 | operation << 3;
+            for a in 0..=255 {
+                for value in 0..=255 {
+                    cpu.memory[1] = value;
+                    for carry in [false, true] {
+                        cpu.pc = 0;
+                        cpu.a = a;
+                        cpu.set_flags(carry as u8);
+                        cpu.cycle().unwrap();
+                        let expected = reference_alu(operation, a, value, carry);
+                        let (opcode, flags) = (cpu.memory[0], cpu.flags());
+                        assert_eq!((cpu.a, flags), expected, "{:02X} {:02X} with A {:02X}, carry {}", opcode, value, a, carry);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1091 @@
+/*
+The Z80 runs the 8080's instructions on the same registers and memory, and fills the 8080's
+undefined opcodes with relative jumps, DJNZ, EX AF,AF' and EXX and the CB, DD, ED and FD
+prefixes. CB has the bit instructions, ED the block moves, 16-bit ADC and SBC and the I and R
+registers, DD and FD run the next opcode with IX or IY in place of HL.
+
+Some of the 8080's instructions set the flags differently: arithmetic puts overflow in P/V, the
+8080's P, and N records whether the last operation was a subtraction so that DAA can correct it.
+Bits 3 and 5 of the flags, which the Z80 fills with copies of result bits, always read 0.
+
+Opcodes are decoded from their fields, see http://www.z80.info/decoding.htm: x in bits 6-7,
+y in bits 3-5, z in bits 0-2, and y split into p in bits 4-5 and q in bit 3.
+*/
+
+use super::{Cpu, M};
+
+// T-states of the unprefixed opcodes. JR, DJNZ and conditional calls take 5, 5 and 7 more when
+// taken and conditional returns 6 more. CB and ED instructions are timed as a whole when they
+// run, DD and FD add 4 to the instruction they prefix.
+pub(super) const CYCLES: [u8; 256] = [
+    4, 10, 7, 6, 4, 4, 7, 4, 4, 11, 7, 6, 4, 4, 7, 4, // 00
+    8, 10, 7, 6, 4, 4, 7, 4, 12, 11, 7, 6, 4, 4, 7, 4, // 10
+    7, 10, 16, 6, 4, 4, 7, 4, 7, 11, 16, 6, 4, 4, 7, 4, // 20
+    7, 10, 13, 6, 11, 11, 10, 4, 7, 11, 13, 6, 4, 4, 7, 4, // 30
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 40
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 50
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 60
+    7, 7, 7, 7, 7, 7, 4, 7, 4, 4, 4, 4, 4, 4, 7, 4, // 70
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 80
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // 90
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // A0
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4, // B0
+    5, 10, 10, 10, 10, 11, 7, 11, 5, 10, 10, 0, 10, 17, 7, 11, // C0
+    5, 10, 10, 11, 10, 11, 7, 11, 5, 4, 10, 11, 10, 0, 7, 11, // D0
+    5, 10, 10, 19, 10, 11, 7, 11, 5, 4, 10, 4, 10, 0, 7, 11, // E0
+    5, 10, 10, 4, 10, 11, 7, 11, 5, 6, 10, 4, 10, 0, 7, 11, // F0
+];
+
+// The registers the Z80 adds to the 8080's
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct Registers {
+    // AF' BC' DE' HL', swapped in by EX AF,AF' and EXX
+    alternate_af: u16,
+    alternate_bc: u16,
+    alternate_de: u16,
+    alternate_hl: u16,
+    ix: u16,
+    iy: u16,
+    // The interrupt vector's high byte for mode 2, and the refresh counter
+    i: u8,
+    r: u8,
+    // 0, 1 or 2, set by IM
+    interrupt_mode: u8,
+    // IFF2, where IFF1 is the 8080's INTE. It keeps IFF1 over an NMI for RETN to restore.
+    iff2: bool,
+}
+
+// The register a DD or FD prefix puts in place of HL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Index {
+    Hl,
+    Ix,
+    Iy,
+}
+
+impl Cpu {
+    // Takes a maskable interrupt once interrupts_enabled has been checked and cleared
+    pub(super) fn interrupt_z80(&mut self, data: u8) {
+        self.z80.iff2 = false;
+        self.refresh();
+        match self.z80.interrupt_mode {
+            2 => {
+                let pointer = (self.z80.i as u16) << 8 | data as u16;
+                let low = self.read_byte(pointer) as u16;
+                let high = self.read_byte(pointer.wrapping_add(1)) as u16;
+                self.call(high << 8 | low);
+                self.cycles += 19;
+            }
+            1 => {
+                self.call(0x38);
+                self.cycles += 13;
+            }
+            _ => {
+                self.call(u16::from(data & 0x38));
+                self.cycles += 13;
+            }
+        }
+    }
+
+    // The Z80's non-maskable interrupt, which calls 0066h whether or not interrupts are enabled.
+    // RETN returns from it with interrupts enabled again if they were before.
+    pub fn nmi(&mut self) {
+        self.z80.iff2 = self.interrupts_enabled;
+        self.interrupts_enabled = false;
+        self.refresh();
+        self.call(0x66);
+        self.cycles += 11;
+    }
+
+    pub(super) fn execute_z80(&mut self) {
+        let opcode = self.fetch_opcode();
+        self.execute_main(opcode, Index::Hl);
+    }
+
+    // Fetches an opcode or a prefix, which counts up the low 7 bits of R
+    fn fetch_opcode(&mut self) -> u8 {
+        self.refresh();
+        self.fetch_byte()
+    }
+
+    fn refresh(&mut self) {
+        let r = self.z80.r;
+        self.z80.r = r & 0x80 | r.wrapping_add(1) & 0x7f;
+    }
+
+    fn execute_main(&mut self, opcode: u8, index: Index) {
+        let (x, y, z) = (opcode >> 6, opcode >> 3 & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        self.cycles += CYCLES[opcode as usize] as u64;
+        if index != Index::Hl {
+            self.cycles += 4;
+        }
+        match x {
+            0 => match z {
+                0 => match y {
+                    0 => {}
+                    1 => self.exchange_af(),
+                    2 => {
+                        let offset = self.fetch_byte();
+                        self.b = self.b.wrapping_sub(1);
+                        if self.b != 0 {
+                            self.jump_relative(offset);
+                        }
+                    }
+                    3 => {
+                        let offset = self.fetch_byte();
+                        self.pc = self.pc.wrapping_add_signed(offset as i8 as i16);
+                    }
+                    _ => {
+                        let offset = self.fetch_byte();
+                        if self.condition(y - 4) {
+                            self.jump_relative(offset);
+                        }
+                    }
+                },
+                1 if q == 0 => {
+                    let value = self.fetch_word();
+                    self.set_index_pair(p, index, value);
+                }
+                1 => {
+                    let base = self.index(index);
+                    let value = self.index_pair(p, index);
+                    let sum = self.add_words(base, value);
+                    self.set_index(index, sum);
+                }
+                2 => {
+                    let address = match p {
+                        0 => self.get_bc(),
+                        1 => self.get_de(),
+                        _ => self.fetch_word(),
+                    };
+                    match (p, q) {
+                        (2, 0) => self.write_word(address, self.index(index)),
+                        (2, _) => {
+                            let value = self.read_word(address);
+                            self.set_index(index, value);
+                        }
+                        (_, 0) => self.write_byte(address, self.a),
+                        _ => self.a = self.read_byte(address),
+                    }
+                }
+                3 => {
+                    let value = self.index_pair(p, index);
+                    let value = if q == 0 { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                    self.set_index_pair(p, index, value);
+                }
+                4 | 5 => {
+                    let address = self.operand_address(y, index);
+                    let value = self.read_operand(y, index, address);
+                    let result = if z == 4 { self.increment(value) } else { self.decrement(value) };
+                    self.write_operand(y, index, address, result);
+                }
+                6 => {
+                    let address = self.operand_address(y, index);
+                    if address.is_some() && index != Index::Hl {
+                        // The displacement and the immediate overlap
+                        self.cycles -= 3;
+                    }
+                    let value = self.fetch_byte();
+                    self.write_operand(y, index, address, value);
+                }
+                _ => self.accumulator_operation(y),
+            },
+            1 if y == M && z == M => self.enable = 0,
+            // With (IX+d) the other operand is H or L, not IXH or IXL
+            1 if z == M => {
+                let address = self.memory_operand(index);
+                let value = self.read_byte(address);
+                self.set_reg(y, value);
+            }
+            1 if y == M => {
+                let address = self.memory_operand(index);
+                let value = self.reg(z);
+                self.write_byte(address, value);
+            }
+            1 => {
+                let value = self.index_reg(z, index);
+                self.set_index_reg(y, index, value);
+            }
+            2 => {
+                let address = self.operand_address(z, index);
+                let value = self.read_operand(z, index, address);
+                self.alu_z80(y, value);
+            }
+            _ => match z {
+                0 => {
+                    if self.condition(y) {
+                        self.ret();
+                        self.cycles += 6;
+                    }
+                }
+                1 if q == 0 => {
+                    let value = self.pop();
+                    if p == 3 {
+                        self.set_psw(value);
+                    } else {
+                        self.set_index_pair(p, index, value);
+                    }
+                }
+                1 => match p {
+                    0 => self.ret(),
+                    1 => self.exchange_registers(),
+                    2 => self.pc = self.index(index),
+                    _ => self.sp = self.index(index),
+                },
+                2 => {
+                    let address = self.fetch_word();
+                    if self.condition(y) {
+                        self.pc = address;
+                    }
+                }
+                3 => match y {
+                    0 => self.pc = self.fetch_word(),
+                    1 if index == Index::Hl => {
+                        let opcode = self.fetch_opcode();
+                        self.execute_bit(opcode);
+                    }
+                    1 => self.execute_indexed_bit(index),
+                    2 => {
+                        let port = self.fetch_byte();
+                        self.io.output(port, self.a);
+                    }
+                    3 => {
+                        let port = self.fetch_byte();
+                        self.a = self.io.input(port);
+                    }
+                    4 => {
+                        let value = self.read_word(self.sp);
+                        self.write_word(self.sp, self.index(index));
+                        self.set_index(index, value);
+                    }
+                    5 => {
+                        std::mem::swap(&mut self.d, &mut self.h);
+                        std::mem::swap(&mut self.e, &mut self.l);
+                    }
+                    6 => {
+                        self.interrupts_enabled = false;
+                        self.z80.iff2 = false;
+                    }
+                    _ => {
+                        self.interrupts_enabled = true;
+                        self.z80.iff2 = true;
+                    }
+                },
+                4 => {
+                    let address = self.fetch_word();
+                    if self.condition(y) {
+                        self.call(address);
+                        self.cycles += 7;
+                    }
+                }
+                5 if q == 0 => {
+                    let value = if p == 3 {
+                        self.psw()
+                    } else {
+                        self.index_pair(p, index)
+                    };
+                    self.push(value);
+                }
+                5 => match p {
+                    0 => {
+                        let address = self.fetch_word();
+                        self.call(address);
+                    }
+                    // A prefix after a prefix replaces it
+                    1 => {
+                        let opcode = self.fetch_opcode();
+                        self.execute_main(opcode, Index::Ix);
+                    }
+                    2 => self.execute_extended(),
+                    _ => {
+                        let opcode = self.fetch_opcode();
+                        self.execute_main(opcode, Index::Iy);
+                    }
+                },
+                6 => {
+                    let value = self.fetch_byte();
+                    self.alu_z80(y, value);
+                }
+                _ => self.call(u16::from(y) * 8),
+            },
+        }
+    }
+
+    // The CB instructions: rotates and shifts, BIT, RES and SET
+    fn execute_bit(&mut self, opcode: u8) {
+        let (x, y, z) = (opcode >> 6, opcode >> 3 & 7, opcode & 7);
+        self.cycles += match (x, z) {
+            (1, M) => 12,
+            (_, M) => 15,
+            _ => 8,
+        };
+        let value = self.reg(z);
+        if let Some(result) = self.bit_operation(x, y, value) {
+            self.set_reg(z, result);
+        }
+    }
+
+    // DD CB and FD CB, which take the displacement before the opcode and always work on
+    // (IX+d). The undocumented forms with a register other than M also copy the result to it.
+    fn execute_indexed_bit(&mut self, index: Index) {
+        let displacement = self.fetch_byte();
+        let opcode = self.fetch_byte();
+        let (x, y, z) = (opcode >> 6, opcode >> 3 & 7, opcode & 7);
+        self.cycles += if x == 1 { 16 } else { 19 };
+        let address = self.index(index).wrapping_add_signed(displacement as i8 as i16);
+        let value = self.read_byte(address);
+        if let Some(result) = self.bit_operation(x, y, value) {
+            self.write_byte(address, result);
+            if z != M {
+                self.set_reg(z, result);
+            }
+        }
+    }
+
+    // Returns the result to store, or None for BIT, which only sets the flags
+    fn bit_operation(&mut self, x: u8, y: u8, value: u8) -> Option<u8> {
+        match x {
+            0 => Some(self.shift(y, value)),
+            1 => {
+                let clear = value & 1 << y == 0;
+                let codes = &mut self.condition_codes;
+                codes.z = clear;
+                codes.p = clear;
+                codes.s = y == 7 && !clear;
+                codes.ac = true;
+                codes.n = false;
+                None
+            }
+            2 => Some(value & !(1 << y)),
+            _ => Some(value | 1 << y),
+        }
+    }
+
+    // RLC RRC RL RR SLA SRA SLL SRL, SLL being the undocumented shift left that sets bit 0
+    fn shift(&mut self, operation: u8, value: u8) -> u8 {
+        let carry = self.condition_codes.cy as u8;
+        let (result, carry) = match operation {
+            0 => (value.rotate_left(1), value & 0x80 != 0),
+            1 => (value.rotate_right(1), value & 0x01 != 0),
+            2 => (value << 1 | carry, value & 0x80 != 0),
+            3 => (value >> 1 | carry << 7, value & 0x01 != 0),
+            4 => (value << 1, value & 0x80 != 0),
+            5 => (value >> 1 | value & 0x80, value & 0x01 != 0),
+            6 => (value << 1 | 1, value & 0x80 != 0),
+            _ => (value >> 1, value & 0x01 != 0),
+        };
+        self.set_logic_flags(result, false);
+        self.condition_codes.cy = carry;
+        result
+    }
+
+    // The ED instructions. The opcodes ED leaves undefined run as 8 T-state NOPs.
+    fn execute_extended(&mut self) {
+        let opcode = self.fetch_opcode();
+        let (x, y, z) = (opcode >> 6, opcode >> 3 & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        self.cycles += 8;
+        match (x, z) {
+            // IN r,(C), where r is M only sets the flags, and OUT (C),r, where r is M outputs 0.
+            // Ports are 8 bits like IN A,(n)'s, so B on the upper address lines is ignored.
+            (1, 0) => {
+                let value = self.io.input(self.c);
+                self.set_logic_flags(value, false);
+                if y != M {
+                    self.set_reg(y, value);
+                }
+                self.cycles += 4;
+            }
+            (1, 1) => {
+                let value = if y == M { 0 } else { self.reg(y) };
+                self.io.output(self.c, value);
+                self.cycles += 4;
+            }
+            (1, 2) => {
+                let value = self.pair(p);
+                if q == 0 {
+                    self.subtract_hl(value);
+                } else {
+                    self.add_hl(value);
+                }
+                self.cycles += 7;
+            }
+            (1, 3) => {
+                let address = self.fetch_word();
+                if q == 0 {
+                    self.write_word(address, self.pair(p));
+                } else {
+                    let value = self.read_word(address);
+                    self.set_pair(p, value);
+                }
+                self.cycles += 12;
+            }
+            (1, 4) => self.a = self.subtract_bytes(0, self.a, false),
+            // RETI and RETN. They differ only in how a Z80 peripheral sees them on the bus.
+            (1, 5) => {
+                self.ret();
+                self.interrupts_enabled = self.z80.iff2;
+                self.cycles += 6;
+            }
+            (1, 6) => self.z80.interrupt_mode = [0, 0, 1, 2][y as usize & 3],
+            (1, _) => self.execute_extended_misc(y),
+            (2, 0..=3) if y >= 4 => self.execute_block(y, z),
+            _ => {}
+        }
+    }
+
+    // LD I,A, LD R,A, LD A,I, LD A,R, RRD and RLD
+    fn execute_extended_misc(&mut self, y: u8) {
+        match y {
+            0 => self.z80.i = self.a,
+            1 => self.z80.r = self.a,
+            2 | 3 => {
+                self.a = if y == 2 { self.z80.i } else { self.z80.r };
+                let codes = &mut self.condition_codes;
+                codes.s = self.a & 0x80 != 0;
+                codes.z = self.a == 0;
+                codes.ac = false;
+                codes.n = false;
+                codes.p = self.z80.iff2;
+            }
+            4 | 5 => {
+                let address = self.get_hl();
+                let value = self.read_byte(address);
+                let (value, digit) = if y == 4 {
+                    (self.a << 4 | value >> 4, value & 0x0f)
+                } else {
+                    (value << 4 | self.a & 0x0f, value >> 4)
+                };
+                self.write_byte(address, value);
+                self.a = self.a & 0xf0 | digit;
+                self.set_logic_flags(self.a, false);
+                self.cycles += 10;
+                return;
+            }
+            _ => return,
+        }
+        self.cycles += 1;
+    }
+
+    // LDI CPI INI OUTI, LDD CPD IND OUTD and their repeating forms, which run again from the
+    // same pc until BC or B counts down to 0, or CPIR and CPDR find A
+    fn execute_block(&mut self, y: u8, z: u8) {
+        let step: u16 = if y & 1 == 0 { 1 } else { 0xffff };
+        let hl = self.get_hl();
+        self.set_hl(hl.wrapping_add(step));
+        self.cycles += 8;
+        let more = match z {
+            0 => {
+                let value = self.read_byte(hl);
+                let de = self.get_de();
+                self.write_byte(de, value);
+                self.set_de(de.wrapping_add(step));
+                let bc = self.get_bc().wrapping_sub(1);
+                self.set_bc(bc);
+                let codes = &mut self.condition_codes;
+                codes.ac = false;
+                codes.n = false;
+                codes.p = bc != 0;
+                bc != 0
+            }
+            1 => {
+                let value = self.read_byte(hl);
+                let result = self.a.wrapping_sub(value);
+                let bc = self.get_bc().wrapping_sub(1);
+                self.set_bc(bc);
+                let codes = &mut self.condition_codes;
+                codes.s = result & 0x80 != 0;
+                codes.z = result == 0;
+                codes.ac = self.a & 0x0f < value & 0x0f;
+                codes.n = true;
+                codes.p = bc != 0;
+                bc != 0 && result != 0
+            }
+            _ => {
+                if z == 2 {
+                    let value = self.io.input(self.c);
+                    self.write_byte(hl, value);
+                } else {
+                    let value = self.read_byte(hl);
+                    self.io.output(self.c, value);
+                }
+                self.b = self.b.wrapping_sub(1);
+                self.condition_codes.z = self.b == 0;
+                self.condition_codes.n = true;
+                self.b != 0
+            }
+        };
+        if y >= 6 && more {
+            self.pc = self.pc.wrapping_sub(2);
+            self.cycles += 5;
+        }
+    }
+
+    // RLCA RRCA RLA RRA DAA CPL SCF CCF
+    fn accumulator_operation(&mut self, operation: u8) {
+        let a = self.a;
+        let carry = self.condition_codes.cy;
+        match operation {
+            0 => {
+                self.a = a.rotate_left(1);
+                self.condition_codes.cy = a & 0x80 != 0;
+            }
+            1 => {
+                self.a = a.rotate_right(1);
+                self.condition_codes.cy = a & 0x01 != 0;
+            }
+            2 => {
+                self.a = a << 1 | carry as u8;
+                self.condition_codes.cy = a & 0x80 != 0;
+            }
+            3 => {
+                self.a = a >> 1 | (carry as u8) << 7;
+                self.condition_codes.cy = a & 0x01 != 0;
+            }
+            4 => {
+                self.decimal_adjust();
+                return;
+            }
+            5 => {
+                self.a = !a;
+                self.condition_codes.ac = true;
+                self.condition_codes.n = true;
+                return;
+            }
+            6 => self.condition_codes.cy = true,
+            _ => {
+                self.condition_codes.cy = !carry;
+                self.condition_codes.ac = carry;
+                self.condition_codes.n = false;
+                return;
+            }
+        }
+        self.condition_codes.ac = false;
+        self.condition_codes.n = false;
+    }
+
+    // DAA corrects after a subtraction as well as after an addition, going by N
+    fn decimal_adjust(&mut self) {
+        let a = self.a;
+        let codes = &self.condition_codes;
+        let mut correction = 0;
+        let mut carry = codes.cy;
+        if codes.ac || a & 0x0f > 9 {
+            correction |= 0x06;
+        }
+        if codes.cy || a > 0x99 {
+            correction |= 0x60;
+            carry = true;
+        }
+        let half_carry = if codes.n {
+            codes.ac && a & 0x0f < 6
+        } else {
+            a & 0x0f > 9
+        };
+        self.a = if codes.n { a.wrapping_sub(correction) } else { a.wrapping_add(correction) };
+        self.set_zsp(self.a);
+        self.condition_codes.ac = half_carry;
+        self.condition_codes.cy = carry;
+    }
+
+    // ADD ADC SUB SBC AND XOR OR CP
+    fn alu_z80(&mut self, operation: u8, value: u8) {
+        let a = self.a;
+        let carry = self.condition_codes.cy;
+        match operation {
+            0 => self.a = self.add_bytes(a, value, false),
+            1 => self.a = self.add_bytes(a, value, carry),
+            2 => self.a = self.subtract_bytes(a, value, false),
+            3 => self.a = self.subtract_bytes(a, value, carry),
+            4 => {
+                self.a = a & value;
+                self.set_logic_flags(self.a, true);
+            }
+            5 => {
+                self.a = a ^ value;
+                self.set_logic_flags(self.a, false);
+            }
+            6 => {
+                self.a = a | value;
+                self.set_logic_flags(self.a, false);
+            }
+            _ => {
+                self.subtract_bytes(a, value, false);
+            }
+        }
+        if (4..7).contains(&operation) {
+            self.condition_codes.cy = false;
+        }
+    }
+
+    fn add_bytes(&mut self, a: u8, value: u8, carry: bool) -> u8 {
+        let sum = a as u16 + value as u16 + carry as u16;
+        let result = sum as u8;
+        self.set_zsp(result);
+        let codes = &mut self.condition_codes;
+        codes.ac = (a & 0x0f) + (value & 0x0f) + carry as u8 > 0x0f;
+        codes.p = (a ^ result) & (value ^ result) & 0x80 != 0;
+        codes.n = false;
+        codes.cy = sum > 0xff;
+        result
+    }
+
+    fn subtract_bytes(&mut self, a: u8, value: u8, carry: bool) -> u8 {
+        let difference = (a as u16).wrapping_sub(value as u16 + carry as u16);
+        let result = difference as u8;
+        self.set_zsp(result);
+        let codes = &mut self.condition_codes;
+        codes.ac = a & 0x0f < (value & 0x0f) + carry as u8;
+        codes.p = (a ^ value) & (a ^ result) & 0x80 != 0;
+        codes.n = true;
+        codes.cy = difference > 0xff;
+        result
+    }
+
+    fn increment(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.set_zsp(result);
+        let codes = &mut self.condition_codes;
+        codes.ac = result & 0x0f == 0;
+        codes.p = result == 0x80;
+        codes.n = false;
+        result
+    }
+
+    fn decrement(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.set_zsp(result);
+        let codes = &mut self.condition_codes;
+        codes.ac = result & 0x0f == 0x0f;
+        codes.p = result == 0x7f;
+        codes.n = true;
+        result
+    }
+
+    // S Z and parity for the logical operations, which leave the carry to the caller
+    fn set_logic_flags(&mut self, result: u8, half_carry: bool) {
+        self.set_zsp(result);
+        self.condition_codes.ac = half_carry;
+        self.condition_codes.n = false;
+    }
+
+    // ADD HL,rr, ADD IX,rr and ADD IY,rr, which leave S Z and P/V alone
+    fn add_words(&mut self, a: u16, value: u16) -> u16 {
+        let (sum, carry) = a.overflowing_add(value);
+        let codes = &mut self.condition_codes;
+        codes.ac = (a & 0x0fff) + (value & 0x0fff) > 0x0fff;
+        codes.n = false;
+        codes.cy = carry;
+        sum
+    }
+
+    // ADC HL,rr
+    fn add_hl(&mut self, value: u16) {
+        let hl = self.get_hl();
+        let carry = self.condition_codes.cy as u32;
+        let sum = hl as u32 + value as u32 + carry;
+        let result = sum as u16;
+        self.set_hl(result);
+        let codes = &mut self.condition_codes;
+        codes.s = result & 0x8000 != 0;
+        codes.z = result == 0;
+        codes.ac = (hl & 0x0fff) as u32 + (value & 0x0fff) as u32 + carry > 0x0fff;
+        codes.p = (hl ^ result) & (value ^ result) & 0x8000 != 0;
+        codes.n = false;
+        codes.cy = sum > 0xffff;
+    }
+
+    // SBC HL,rr
+    fn subtract_hl(&mut self, value: u16) {
+        let hl = self.get_hl();
+        let carry = self.condition_codes.cy as u32;
+        let difference = (hl as u32).wrapping_sub(value as u32 + carry);
+        let result = difference as u16;
+        self.set_hl(result);
+        let codes = &mut self.condition_codes;
+        codes.s = result & 0x8000 != 0;
+        codes.z = result == 0;
+        codes.ac = ((hl & 0x0fff) as u32) < (value & 0x0fff) as u32 + carry;
+        codes.p = (hl ^ value) & (hl ^ result) & 0x8000 != 0;
+        codes.n = true;
+        codes.cy = difference > 0xffff;
+    }
+
+    fn jump_relative(&mut self, offset: u8) {
+        self.pc = self.pc.wrapping_add_signed(offset as i8 as i16);
+        self.cycles += 5;
+    }
+
+    fn exchange_af(&mut self) {
+        let af = self.psw();
+        let alternate = std::mem::replace(&mut self.z80.alternate_af, af);
+        self.set_psw(alternate);
+    }
+
+    fn exchange_registers(&mut self) {
+        let (bc, de, hl) = (self.get_bc(), self.get_de(), self.get_hl());
+        let z80 = &mut self.z80;
+        let bc = std::mem::replace(&mut z80.alternate_bc, bc);
+        let de = std::mem::replace(&mut z80.alternate_de, de);
+        let hl = std::mem::replace(&mut z80.alternate_hl, hl);
+        self.set_bc(bc);
+        self.set_de(de);
+        self.set_hl(hl);
+    }
+
+    fn read_word(&mut self, address: u16) -> u16 {
+        let low = self.read_byte(address) as u16;
+        low | (self.read_byte(address.wrapping_add(1)) as u16) << 8
+    }
+
+    fn write_word(&mut self, address: u16, value: u16) {
+        self.write_byte(address, value as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    // HL, IX or IY
+    fn index(&self, index: Index) -> u16 {
+        match index {
+            Index::Hl => self.get_hl(),
+            Index::Ix => self.z80.ix,
+            Index::Iy => self.z80.iy,
+        }
+    }
+
+    fn set_index(&mut self, index: Index, value: u16) {
+        match index {
+            Index::Hl => self.set_hl(value),
+            Index::Ix => self.z80.ix = value,
+            Index::Iy => self.z80.iy = value,
+        }
+    }
+
+    // BC DE HL SP, as encoded in bits 4-5 of an opcode, with HL replaced by the index
+    fn index_pair(&self, pair: u8, index: Index) -> u16 {
+        if pair == 2 {
+            self.index(index)
+        } else {
+            self.pair(pair)
+        }
+    }
+
+    fn set_index_pair(&mut self, pair: u8, index: Index, value: u16) {
+        if pair == 2 {
+            self.set_index(index, value);
+        } else {
+            self.set_pair(pair, value);
+        }
+    }
+
+    // B C D E H L - A with H and L replaced by the halves of the index, undocumented for IX
+    // and IY. Never called for M.
+    fn index_reg(&mut self, register: u8, index: Index) -> u8 {
+        match (register, index) {
+            (4 | 5, Index::Ix | Index::Iy) => {
+                let value = self.index(index);
+                if register == 4 {
+                    (value >> 8) as u8
+                } else {
+                    value as u8
+                }
+            }
+            _ => self.reg(register),
+        }
+    }
+
+    fn set_index_reg(&mut self, register: u8, index: Index, value: u8) {
+        match (register, index) {
+            (4 | 5, Index::Ix | Index::Iy) => {
+                let old = self.index(index);
+                let new = if register == 4 {
+                    (value as u16) << 8 | old & 0x00ff
+                } else {
+                    old & 0xff00 | value as u16
+                };
+                self.set_index(index, new);
+            }
+            _ => self.set_reg(register, value),
+        }
+    }
+
+    // The address of the memory operand, HL or IX or IY plus a displacement fetched now
+    fn memory_operand(&mut self, index: Index) -> u16 {
+        if index == Index::Hl {
+            return self.get_hl();
+        }
+        let displacement = self.fetch_byte();
+        self.cycles += 8;
+        self.index(index).wrapping_add_signed(displacement as i8 as i16)
+    }
+
+    // Instructions that read and then write an operand find its address once
+    fn operand_address(&mut self, register: u8, index: Index) -> Option<u16> {
+        (register == M).then(|| self.memory_operand(index))
+    }
+
+    fn read_operand(&mut self, register: u8, index: Index, address: Option<u16>) -> u8 {
+        match address {
+            Some(address) => self.read_byte(address),
+            None => self.index_reg(register, index),
+        }
+    }
+
+    fn write_operand(&mut self, register: u8, index: Index, address: Option<u16>, value: u8) {
+        match address {
+            Some(address) => self.write_byte(address, value),
+            None => self.set_index_reg(register, index, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod z80_tests {
+    use super::super::Model;
+    use crate::emulator::io::Io;
+    use super::*;
+
+    fn z80(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new(program.to_vec());
+        cpu.set_model(Model::Z80);
+        cpu
+    }
+
+    fn run(cpu: &mut Cpu, instructions: usize) {
+        for _ in 0..instructions {
+            cpu.cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn arithmetic_flags() {
+        // LD A,7Fh; ADD A,1 overflows into the sign bit
+        let mut cpu = z80(&[0x3e, 0x7f, 0xc6, 0x01, 0xd6, 0x02, 0x3e, 0x15, 0xd6, 0x06, 0x27]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.a, 0x80);
+        assert_eq!(cpu.flags(), 0x94, "S, H and V set, N clear");
+        // SUB 2 borrows from bit 4 and overflows back
+        run(&mut cpu, 1);
+        assert_eq!(cpu.a, 0x7e);
+        assert_eq!(cpu.flags(), 0x16, "H, V and N set");
+        // 15h - 06h is 0Fh, which DAA corrects to 09h going by N
+        run(&mut cpu, 3);
+        assert_eq!(cpu.a, 0x09);
+        assert!(cpu.condition_codes.n);
+        assert!(!cpu.condition_codes.cy);
+    }
+
+    #[test]
+    fn relative_jumps_and_alternate_registers() {
+        let program = [
+            0x06, 0x05, // LD B,5
+            0x3c, // loop: INC A
+            0x10, 0xfd, // DJNZ loop
+            0x08, // EX AF,AF'
+            0x21, 0x34, 0x12, // LD HL,1234h
+            0xd9, // EXX
+            0x18, 0x01, // JR +1
+            0x76, // HALT, skipped
+            0x28, 0xfe, // JR Z,-2 falls through with the alternate flags
+            0x76,
+        ];
+        let mut cpu = z80(&program);
+        run(&mut cpu, 1 + 10);
+        assert_eq!(cpu.a, 5);
+        assert_eq!(cpu.b, 0);
+        assert_eq!(cpu.cycles, 7 + 5 * 4 + 4 * 13 + 8);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.a, 0);
+        assert_eq!(cpu.get_hl(), 0);
+        assert_eq!(cpu.z80.alternate_hl, 0x1234);
+        assert_eq!(cpu.pc, 0x000f);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.enable, 0);
+    }
+
+    #[test]
+    fn index_registers() {
+        let program = [
+            0xdd, 0x21, 0x00, 0x20, // LD IX,2000h
+            0xdd, 0x36, 0xff, 0x41, // LD (IX-1),41h
+            0xdd, 0x34, 0xff, // INC (IX-1)
+            0xfd, 0x21, 0xfe, 0x1f, // LD IY,1FFEh
+            0xfd, 0x7e, 0x01, // LD A,(IY+1)
+            0xdd, 0x26, 0x30, // LD IXH,30h
+            0xdd, 0xcb, 0xff, 0xc6, // SET 0,(IX-1) with IX now 3000h
+            0xdd, 0xe5, // PUSH IX
+            0xfd, 0xe1, // POP IY
+        ];
+        let mut cpu = z80(&program);
+        run(&mut cpu, 5);
+        assert_eq!(cpu.memory[0x1fff], 0x42);
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.cycles, 14 + 19 + 23 + 14 + 19);
+        run(&mut cpu, 4);
+        assert_eq!(cpu.z80.ix, 0x3000);
+        assert_eq!(cpu.memory[0x2fff], 0x01);
+        assert_eq!(cpu.z80.iy, 0x3000);
+        assert_eq!(cpu.get_hl(), 0);
+    }
+
+    #[test]
+    fn bit_instructions() {
+        let program = [
+            0x21, 0x00, 0x20, // LD HL,2000h
+            0x36, 0x81, // LD (HL),81h
+            0xcb, 0x06, // RLC (HL)
+            0xcb, 0x7e, // BIT 7,(HL)
+            0xcb, 0x46, // BIT 0,(HL)
+            0xcb, 0xbe, // RES 7,(HL)
+            0xcb, 0x3f, // SRL A
+        ];
+        let mut cpu = z80(&program);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.memory[0x2000], 0x03);
+        assert!(cpu.condition_codes.cy);
+        run(&mut cpu, 1);
+        assert!(cpu.condition_codes.z);
+        run(&mut cpu, 1);
+        assert!(!cpu.condition_codes.z);
+        assert!(cpu.condition_codes.ac);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.cycles, 10 + 10 + 15 + 12 + 12 + 15 + 8);
+    }
+
+    #[test]
+    fn block_instructions() {
+        let mut program = vec![
+            0x21, 0x00, 0x10, // LD HL,1000h
+            0x11, 0x00, 0x20, // LD DE,2000h
+            0x01, 0x04, 0x00, // LD BC,4
+            0xed, 0xb0, // LDIR
+            0x21, 0x00, 0x20, // LD HL,2000h
+            0x01, 0x04, 0x00, // LD BC,4
+            0x3e, 0x33, // LD A,33h
+            0xed, 0xb1, // CPIR
+        ];
+        program.resize(0x1000, 0);
+        program.extend([0x11, 0x22, 0x33, 0x44]);
+        let mut cpu = z80(&program);
+        run(&mut cpu, 3 + 4);
+        assert_eq!(cpu.memory[0x2000..0x2004], [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(cpu.get_bc(), 0);
+        assert!(!cpu.condition_codes.p);
+        assert_eq!(cpu.cycles, 30 + 3 * 21 + 16);
+        run(&mut cpu, 3 + 3);
+        assert!(cpu.condition_codes.z);
+        assert_eq!(cpu.get_hl(), 0x2003);
+        assert_eq!(cpu.get_bc(), 1);
+        assert_eq!(cpu.pc, 0x0015);
+    }
+
+    #[test]
+    fn extended_instructions() {
+        let program = [
+            0x21, 0x00, 0x80, // LD HL,8000h
+            0x01, 0x01, 0x00, // LD BC,1
+            0xed, 0x42, // SBC HL,BC
+            0xed, 0x4a, // ADC HL,BC
+            0xed, 0x53, 0x00, 0x30, // LD (3000h),DE
+            0xed, 0x4b, 0x00, 0x30, // LD BC,(3000h)
+            0x3e, 0x01, // LD A,1
+            0xed, 0x44, // NEG
+            0x36, 0x12, // LD (HL),12h
+            0xed, 0x6f, // RLD
+        ];
+        let mut cpu = z80(&program);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.get_hl(), 0x7fff);
+        assert!(cpu.condition_codes.p, "overflow");
+        assert!(cpu.condition_codes.n);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.get_hl(), 0x8000);
+        assert!(cpu.condition_codes.s);
+        run(&mut cpu, 4);
+        assert_eq!(cpu.get_bc(), 0);
+        assert_eq!(cpu.a, 0xff);
+        assert!(cpu.condition_codes.cy);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.memory[0x8000], 0x2f);
+        assert_eq!(cpu.a, 0xf1);
+    }
+
+    #[test]
+    fn ports() {
+        let program = [
+            0x3e, 0x5a, // LD A,5Ah
+            0x0e, 0x04, // LD C,04h
+            0xed, 0x79, // OUT (C),A
+            0x0e, 0x03, // LD C,03h
+            0xed, 0x50, // IN D,(C)
+            0xdb, 0x03, // IN A,(03h)
+            0x0e, 0x07, // LD C,07h
+            0xed, 0x58, // IN E,(C)
+            0xdb, 0x07, // IN A,(07h)
+        ];
+        let mut cpu = z80(&program);
+        cpu.set_io(Io::board(crate::emulator::rom::Machine::find("invaders").unwrap().config));
+        run(&mut cpu, 6);
+        assert_eq!((cpu.d, cpu.a), (0x5a, 0x5a));
+        run(&mut cpu, 3);
+        assert_eq!((cpu.e, cpu.a), (0xff, 0xff));
+
+        // INI reads the port into (HL), OUTI writes (HL) to it
+        let program = [
+            0x21, 0x00, 0x40, // LD HL,4000h
+            0x01, 0x04, 0x01, // LD BC,0104h
+            0xed, 0xa3, // OUTI
+            0x0e, 0x03, // LD C,03h
+            0xed, 0xa2, // INI
+        ];
+        let mut cpu = z80(&program);
+        cpu.set_io(Io::board(crate::emulator::rom::Machine::find("invaders").unwrap().config));
+        cpu.memory[0x4000] = 0x77;
+        run(&mut cpu, 5);
+        assert_eq!(cpu.memory[0x4001], 0x77);
+        assert_eq!(cpu.get_hl(), 0x4002);
+    }
+
+    #[test]
+    fn interrupt_modes() {
+        let program = [
+            0xed, 0x5e, // IM 2
+            0x3e, 0x40, // LD A,40h
+            0xed, 0x47, // LD I,A
+            0xfb, // EI
+            0x00, 0x00,
+        ];
+        let mut cpu = z80(&program);
+        cpu.memory[0x4010] = 0x00;
+        cpu.memory[0x4011] = 0x50;
+        run(&mut cpu, 4);
+        assert!(cpu.interrupt_data(0x10));
+        assert_eq!(cpu.pc, 0x5000);
+        assert!(!cpu.z80.iff2);
+
+        // Mode 1 always calls 0038h
+        cpu.memory[0x5000..0x5003].copy_from_slice(&[0xed, 0x56, 0xfb]);
+        run(&mut cpu, 2);
+        assert!(cpu.interrupt(2));
+        assert_eq!(cpu.pc, 0x0038);
+
+        // An NMI keeps IFF1 in IFF2 and RETN puts it back
+        cpu.memory[0x0038] = 0xfb;
+        cpu.memory[0x0066..0x0068].copy_from_slice(&[0xed, 0x45]);
+        run(&mut cpu, 1);
+        cpu.nmi();
+        assert_eq!(cpu.pc, 0x0066);
+        assert!(!cpu.interrupts_enabled);
+        run(&mut cpu, 1);
+        assert!(cpu.interrupts_enabled);
+        assert_eq!(cpu.pc, 0x0039);
+    }
+
+    #[test]
+    fn refresh_register() {
+        // Every opcode and prefix fetched counts, LD A,R's own included
+        let mut cpu = z80(&[0x00, 0xdd, 0x21, 0x00, 0x00, 0xed, 0x5f]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.a, 5);
+    }
+}
@@ -0,0 +1,1119 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::Path,
+    sync::mpsc::{self, TryRecvError},
+    thread,
+};
+
+use serde_json::{json, Value};
+
+use super::breakpoint::Breakpoint;
+use super::cpu::{Cpu, Flag, Model, Register};
+use super::disassembler::{self, format_byte, format_word};
+use super::expression::Expression;
+use super::symbols::SymbolTable;
+use super::Runner;
+
+/*
+A Debug Adapter Protocol server, spoken over stdio or a localhost TCP connection. Every message
+is a JSON object preceded by a "Content-Length: n" header and a blank line.
+
+The launch request takes the ROM to debug:
+
+    "program": "invaders.bin"     path to the ROM
+    "format": "binary" | "text" | "hex" | "zip"
+                                  how the ROM is stored, by default from the extension:
+                                  text for .txt, Intel HEX for .hex and .ihx, a machine's
+                                  ROM set for .zip or a directory like roms/invaders
+    "symbols": "invaders.sym"     optional symbol file
+    "stopOnEntry": true           stop before the first instruction
+    "strict": true                stop on undocumented opcodes instead of running them
+    "cpu": "8080" | "8085" | "z80"
+                                  the processor to emulate, by default the identified
+                                  machine's or the 8080
+
+An identified machine runs on its board, with its I/O ports and the video interrupts between
+instructions, as in the frontend.
+
+There is no source code to place breakpoints in, so breakpoints are set on addresses with
+setInstructionBreakpoints or on labels and addresses with setFunctionBreakpoints. Both take
+conditions in the --break-if syntax. Memory and instruction references are "0x"-prefixed hex
+addresses or labels.
+*/
+
+// Instructions run between checks for new requests while the cpu is running
+const RUN_SLICE: u32 = 10000;
+
+const THREAD_ID: i64 = 1;
+
+// variablesReference of the registers scope and the flags nested in it
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+
+const REGISTERS: [(&str, Register); 12] = [
+    ("A", Register::A),
+    ("B", Register::B),
+    ("C", Register::C),
+    ("D", Register::D),
+    ("E", Register::E),
+    ("H", Register::H),
+    ("L", Register::L),
+    ("BC", Register::Bc),
+    ("DE", Register::De),
+    ("HL", Register::Hl),
+    ("SP", Register::Sp),
+    ("PC", Register::Pc),
+];
+
+const FLAGS: [(&str, Flag); 5] = [
+    ("S", Flag::S),
+    ("Z", Flag::Z),
+    ("AC", Flag::Ac),
+    ("P", Flag::P),
+    ("CY", Flag::Cy),
+];
+
+pub fn serve_stdio() -> io::Result<()> {
+    serve(io::stdin(), io::stdout())
+}
+
+pub fn serve_tcp(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for a DAP client on 127.0.0.1:{}", port);
+    let (stream, address) = listener.accept()?;
+    println!("DAP client connected from {}", address);
+    serve(stream.try_clone()?, stream)?;
+    println!("DAP client disconnected");
+    Ok(())
+}
+
+fn serve<R: Read + Send + 'static, W: Write>(input: R, mut output: W) -> io::Result<()> {
+    // Requests are read on their own thread so they can arrive while the cpu is running
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut server = DapServer::new();
+    while !server.finished {
+        let message = if server.running() {
+            match receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        if let Some(message) = message {
+            server.handle(&message);
+        }
+        server.run(RUN_SLICE);
+        for message in server.take_messages() {
+            write_message(&mut output, &message)?;
+        }
+    }
+    Ok(())
+}
+
+// Returns None once the client has closed the stream
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+// What the cpu is doing between requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Run {
+    Stopped,
+    Continue,
+    // Step over a call: run until it returns to `address` with the stack back at `sp`
+    Over { address: u16, sp: u16 },
+    // Run until a return pops the stack above `sp`
+    Out { sp: u16 },
+}
+
+pub struct DapServer {
+    cpu: Option<Cpu>,
+    // Runs the cpu with the video interrupts of the identified machine's board
+    runner: Option<Runner>,
+    symbols: SymbolTable,
+    // Each set request replaces its own list, so the two kinds are kept apart
+    instruction_breakpoints: Vec<(i64, Breakpoint)>,
+    function_breakpoints: Vec<(i64, Breakpoint)>,
+    next_breakpoint_id: i64,
+    run: Run,
+    stop_on_entry: bool,
+    configured: bool,
+    seq: i64,
+    messages: Vec<Value>,
+    finished: bool,
+}
+
+impl Default for DapServer {
+    fn default() -> DapServer {
+        DapServer::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer {
+            cpu: None,
+            runner: None,
+            symbols: SymbolTable::new(),
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            next_breakpoint_id: 1,
+            run: Run::Stopped,
+            stop_on_entry: false,
+            configured: false,
+            seq: 1,
+            messages: Vec::new(),
+            finished: false,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.run != Run::Stopped
+    }
+
+    // Responses and events produced since the last call
+    pub fn take_messages(&mut self) -> Vec<Value> {
+        let mut messages = std::mem::take(&mut self.messages);
+        for message in &mut messages {
+            message["seq"] = json!(self.seq);
+            self.seq += 1;
+        }
+        messages
+    }
+
+    pub fn handle(&mut self, message: &Value) {
+        if message["type"] != "request" {
+            return;
+        }
+        // Events raised while handling the request follow its response
+        let events = self.messages.len();
+        let arguments = &message["arguments"];
+        let result = match message["command"].as_str().unwrap_or("") {
+            "initialize" => Ok(self.initialize()),
+            "launch" => self.launch(arguments),
+            "configurationDone" => Ok(self.configuration_done()),
+            "setBreakpoints" => Ok(set_source_breakpoints(arguments)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(arguments)),
+            "setFunctionBreakpoints" => Ok(self.set_function_breakpoints(arguments)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "8080" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(arguments),
+            "setVariable" => self.set_variable(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "continue" => self.resume(Run::Continue),
+            "next" => self.next(),
+            "stepIn" => self.step_in(),
+            "stepOut" => self.step_out(),
+            "pause" => Ok(self.pause()),
+            "readMemory" => self.read_memory(arguments),
+            "writeMemory" => self.write_memory(arguments),
+            "disassemble" => self.disassemble(arguments),
+            "disconnect" | "terminate" => {
+                self.finished = true;
+                Ok(json!({}))
+            }
+            command => Err(format!("Unsupported request '{}'", command)),
+        };
+        self.respond(message, result, events);
+        if message["command"] == "initialize" {
+            self.event("initialized", json!({}));
+        }
+    }
+
+    // Runs up to `count` instructions if the cpu is running, stopping early on a breakpoint
+    pub fn run(&mut self, count: u32) {
+        for _ in 0..count {
+            if self.run == Run::Stopped {
+                return;
+            }
+            self.execute();
+        }
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>, position: usize) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.messages.insert(position, response);
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.messages.push(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>, breakpoint: Option<i64>) {
+        self.run = Run::Stopped;
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+        }
+        if let Some(id) = breakpoint {
+            body["hitBreakpointIds"] = json!([id]);
+        }
+        self.event("stopped", body);
+    }
+
+    fn cpu(&self) -> Result<&Cpu, String> {
+        self.cpu.as_ref().ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn cpu_mut(&mut self) -> Result<&mut Cpu, String> {
+        self.cpu.as_mut().ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn initialize(&self) -> Value {
+        json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsFunctionBreakpoints": true,
+            "supportsConditionalBreakpoints": true,
+            "supportsHitConditionalBreakpoints": true,
+            "supportsInstructionBreakpoints": true,
+            "supportsSetVariable": true,
+            "supportsEvaluateForHovers": true,
+            "supportsReadMemoryRequest": true,
+            "supportsWriteMemoryRequest": true,
+            "supportsDisassembleRequest": true,
+            "supportsSteppingGranularity": true,
+            "supportsTerminateRequest": true,
+        })
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["program"]
+            .as_str()
+            .ok_or_else(|| "Missing 'program' in launch arguments".to_string())?;
+        let path = Path::new(path);
+        let flag = match arguments["format"].as_str() {
+            Some("binary") => "-b",
+            Some("text") => "-t",
+            Some("hex") => "-i",
+            Some("zip") => "-m",
+            Some(format) => return Err(format!("Invalid format '{}'", format)),
+            None if path.is_dir() => "-m",
+            None => match path.extension().and_then(|extension| extension.to_str()) {
+                Some("txt") => "-t",
+                Some("hex") | Some("ihx") => "-i",
+                Some("zip") => "-m",
+                _ => "-b",
+            },
+        };
+        // Machines are also loaded from a directory of ROMs
+        let found = if flag == "-m" { path.exists() } else { path.is_file() };
+        if !found {
+            return Err(format!("ROM '{}' not found", path.display()));
+        }
+        if let Some(symbols) = arguments["symbols"].as_str() {
+            self.symbols = SymbolTable::load(Path::new(symbols))
+                .map_err(|error| format!("Failed to read symbol file '{}': {}", symbols, error))?;
+        }
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        let program = super::read_program(flag, path).map_err(|error| error.to_string())?;
+        let model = match arguments["cpu"].as_str() {
+            Some(name) => Some(Model::parse(name).ok_or_else(|| format!("Invalid cpu '{}'", name))?),
+            None => None,
+        };
+        let (mut cpu, runner) = program.machine_runner(model).map_err(|error| error.to_string())?;
+        cpu.set_strict(arguments["strict"].as_bool().unwrap_or(false));
+        self.cpu = Some(cpu);
+        self.runner = Some(runner);
+        if self.configured {
+            self.start();
+        }
+        Ok(json!({}))
+    }
+
+    fn configuration_done(&mut self) -> Value {
+        self.configured = true;
+        if self.cpu.is_some() {
+            self.start();
+        }
+        json!({})
+    }
+
+    fn start(&mut self) {
+        if self.stop_on_entry {
+            self.stopped("entry", None, None);
+        } else {
+            self.run = Run::Continue;
+        }
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Value {
+        let mut breakpoints = Vec::new();
+        let mut results = Vec::new();
+        for requested in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let address = requested["instructionReference"]
+                .as_str()
+                .and_then(|reference| self.reference(reference))
+                .map(|address| {
+                    address.wrapping_add(requested["offset"].as_i64().unwrap_or(0) as u16)
+                });
+            results.push(self.add_breakpoint(&mut breakpoints, address, requested));
+        }
+        self.instruction_breakpoints = breakpoints;
+        json!({ "breakpoints": results })
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Value) -> Value {
+        let mut breakpoints = Vec::new();
+        let mut results = Vec::new();
+        for requested in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let address = requested["name"]
+                .as_str()
+                .and_then(|name| self.reference(name));
+            results.push(self.add_breakpoint(&mut breakpoints, address, requested));
+        }
+        self.function_breakpoints = breakpoints;
+        json!({ "breakpoints": results })
+    }
+
+    // Builds the breakpoint for one entry of a set request and returns its description
+    fn add_breakpoint(
+        &mut self,
+        breakpoints: &mut Vec<(i64, Breakpoint)>,
+        address: Option<u16>,
+        requested: &Value,
+    ) -> Value {
+        let address = match address {
+            Some(address) => address,
+            None => return json!({ "verified": false, "message": "Unknown label or address" }),
+        };
+        let breakpoint = match breakpoint_condition(requested) {
+            Some(condition) => match Breakpoint::conditional(address, &condition, &self.symbols) {
+                Ok(breakpoint) => breakpoint,
+                Err(error) => {
+                    return json!({
+                        "verified": false,
+                        "message": format!("Invalid condition '{}': {}", condition, error),
+                    })
+                }
+            },
+            None => Breakpoint::new(address),
+        };
+        let id = self.next_breakpoint_id;
+        self.next_breakpoint_id += 1;
+        breakpoints.push((id, breakpoint));
+        json!({
+            "id": id,
+            "verified": true,
+            "instructionReference": reference(address),
+        })
+    }
+
+    fn stack_trace(&self) -> Result<Value, String> {
+        let cpu = self.cpu()?;
+        let pc = cpu.register(Register::Pc);
+        let instruction = disassembler::disassemble_model(&cpu.memory, pc, cpu.model());
+        let name = match self.symbols.name(pc) {
+            Some(label) => format!("{} ({})  {}", reference(pc), label, instruction.symbolic(&self.symbols)),
+            None => format!("{}  {}", reference(pc), instruction.symbolic(&self.symbols)),
+        };
+        Ok(json!({
+            "stackFrames": [{
+                "id": 0,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": reference(pc),
+            }],
+            "totalFrames": 1,
+        }))
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let cpu = self.cpu()?;
+        let variables: Vec<Value> = match arguments["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => REGISTERS
+                .iter()
+                .map(|(name, register)| register_variable(cpu, name, *register))
+                .chain(std::iter::once(json!({
+                    "name": "Flags",
+                    "value": format_byte(cpu.flags()),
+                    "variablesReference": FLAGS_REFERENCE,
+                })))
+                .collect(),
+            Some(FLAGS_REFERENCE) => FLAGS
+                .iter()
+                .map(|(name, flag)| {
+                    json!({
+                        "name": name,
+                        "value": (cpu.flag(*flag) as u8).to_string(),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    // Values are expressions, so "3Fh", "0x3F", "[hl]" and labels all work
+    fn set_variable(&mut self, arguments: &Value) -> Result<Value, String> {
+        let name = arguments["name"].as_str().unwrap_or("");
+        let value = self.evaluate_expression(arguments["value"].as_str().unwrap_or(""))?;
+        let cpu = self.cpu_mut()?;
+        if let Some((_, register)) = REGISTERS.iter().find(|(register, _)| *register == name) {
+            cpu.set_register(*register, value as u16);
+            return Ok(register_variable(cpu, name, *register));
+        }
+        if name == "Flags" {
+            cpu.set_flags(value as u8);
+            return Ok(json!({ "value": format_byte(cpu.flags()) }));
+        }
+        if let Some((_, flag)) = FLAGS.iter().find(|(flag, _)| *flag == name) {
+            cpu.set_flag(*flag, value != 0);
+            return Ok(json!({ "value": (value != 0) as u8 }));
+        }
+        Err(format!("Unknown variable '{}'", name))
+    }
+
+    fn evaluate(&self, arguments: &Value) -> Result<Value, String> {
+        let value = self.evaluate_expression(arguments["expression"].as_str().unwrap_or(""))?;
+        Ok(json!({
+            "result": format!("{} ({})", value, format_word(value as u16)),
+            "variablesReference": 0,
+            "memoryReference": reference(value as u16),
+        }))
+    }
+
+    fn evaluate_expression(&self, text: &str) -> Result<i64, String> {
+        let cpu = self.cpu()?;
+        let expression = Expression::parse_with_symbols(text, &self.symbols)
+            .map_err(|error| format!("Invalid expression '{}': {}", text, error))?;
+        Ok(expression.evaluate(cpu, 0))
+    }
+
+    fn resume(&mut self, run: Run) -> Result<Value, String> {
+        self.cpu()?;
+        self.run = run;
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    // Steps over calls and restarts, single steps everything else
+    fn next(&mut self) -> Result<Value, String> {
+        let cpu = self.cpu()?;
+        let pc = cpu.register(Register::Pc);
+        let instruction = disassembler::disassemble_model(&cpu.memory, pc, cpu.model());
+        if instruction.is_call() {
+            self.resume(Run::Over {
+                address: pc.wrapping_add(instruction.length),
+                sp: cpu.register(Register::Sp),
+            })
+        } else {
+            self.step_in()
+        }
+    }
+
+    fn step_in(&mut self) -> Result<Value, String> {
+        self.resume(Run::Continue)?;
+        self.execute();
+        if self.run != Run::Stopped {
+            self.stopped("step", None, None);
+        }
+        Ok(json!({}))
+    }
+
+    fn step_out(&mut self) -> Result<Value, String> {
+        let sp = self.cpu()?.register(Register::Sp);
+        self.resume(Run::Out { sp })
+    }
+
+    fn pause(&mut self) -> Value {
+        if self.running() {
+            self.stopped("pause", None, None);
+        }
+        json!({})
+    }
+
+    // Executes one instruction and stops if anything asks for it
+    fn execute(&mut self) {
+        let cpu = match &mut self.cpu {
+            Some(cpu) => cpu,
+            None => return,
+        };
+        let instruction = disassembler::disassemble_model(&cpu.memory, cpu.register(Register::Pc), cpu.model());
+        cpu.enable = 1;
+        let result = match &mut self.runner {
+            Some(runner) => runner.run(cpu, true).map(|_| ()),
+            None => cpu.cycle(),
+        };
+        let halted = cpu.enable == 0;
+        cpu.enable = 0;
+        if let Err(error) = result {
+            self.stopped("exception", Some(error.to_string()), None);
+            return;
+        }
+        if let Some(hit) = cpu.take_watch_hit() {
+            self.stopped("data breakpoint", Some(hit.to_string()), None);
+            return;
+        }
+        if halted {
+            self.stopped("pause", Some("Halted".to_string()), None);
+            return;
+        }
+
+        let pc = cpu.register(Register::Pc);
+        let sp = cpu.register(Register::Sp);
+        let done = match self.run {
+            Run::Over { address, sp: start } => pc == address && sp >= start,
+            Run::Out { sp: start } => instruction.is_return() && sp > start,
+            _ => false,
+        };
+        if done {
+            self.stopped("step", None, None);
+            return;
+        }
+
+        let hit = match find_breakpoint(&mut self.instruction_breakpoints, cpu) {
+            Some(id) => Some(("instruction breakpoint", id)),
+            None => find_breakpoint(&mut self.function_breakpoints, cpu)
+                .map(|id| ("function breakpoint", id)),
+        };
+        if let Some((reason, id)) = hit {
+            self.stopped(reason, None, Some(id));
+        }
+    }
+
+    fn read_memory(&self, arguments: &Value) -> Result<Value, String> {
+        let cpu = self.cpu()?;
+        let address = self.memory_address(arguments)?;
+        let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+        let start = (address as usize).min(cpu.memory.len());
+        let end = start.saturating_add(count).min(cpu.memory.len());
+        Ok(json!({
+            "address": reference(address),
+            "data": base64_encode(&cpu.memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn write_memory(&mut self, arguments: &Value) -> Result<Value, String> {
+        let address = self.memory_address(arguments)? as usize;
+        let data = arguments["data"]
+            .as_str()
+            .and_then(base64_decode)
+            .ok_or_else(|| "Invalid base64 data".to_string())?;
+        let cpu = self.cpu_mut()?;
+        let end = (address + data.len()).min(cpu.memory.len());
+        cpu.memory[address..end].copy_from_slice(&data[..end - address]);
+        Ok(json!({ "bytesWritten": end - address }))
+    }
+
+    fn disassemble(&self, arguments: &Value) -> Result<Value, String> {
+        let cpu = self.cpu()?;
+        let address = self.memory_address(arguments)?;
+        let count = arguments["instructionCount"].as_u64().unwrap_or(0);
+        let mut address = seek_instruction(
+            &cpu.memory,
+            address,
+            arguments["instructionOffset"].as_i64().unwrap_or(0),
+            cpu.model(),
+        );
+        let symbols = arguments["resolveSymbols"].as_bool().unwrap_or(true);
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let instruction = disassembler::disassemble_model(&cpu.memory, address, cpu.model());
+            let mut disassembled = json!({
+                "address": reference(address),
+                "instructionBytes": instruction.hex_bytes(),
+                "instruction": if symbols {
+                    instruction.symbolic(&self.symbols)
+                } else {
+                    instruction.to_string()
+                },
+            });
+            if let Some(label) = self.symbols.name(address) {
+                disassembled["symbol"] = json!(label);
+            }
+            instructions.push(disassembled);
+            address = address.wrapping_add(instruction.length);
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    // memoryReference plus the optional byte offset
+    fn memory_address(&self, arguments: &Value) -> Result<u16, String> {
+        let text = arguments["memoryReference"].as_str().unwrap_or("");
+        let address = self
+            .reference(text)
+            .ok_or_else(|| format!("Invalid memory reference '{}'", text))?;
+        Ok(address.wrapping_add(arguments["offset"].as_i64().unwrap_or(0) as u16))
+    }
+
+    // "0x18DF" or a label or address in the symbol file syntax
+    fn reference(&self, text: &str) -> Option<u16> {
+        match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => self.symbols.resolve(text),
+        }
+    }
+}
+
+// There is no source to map lines to, so line breakpoints can't be verified
+fn set_source_breakpoints(arguments: &Value) -> Value {
+    let breakpoints: Vec<Value> = arguments["breakpoints"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|_| {
+            json!({
+                "verified": false,
+                "message": "Use instruction or function breakpoints, source breakpoints aren't supported",
+            })
+        })
+        .collect();
+    json!({ "breakpoints": breakpoints })
+}
+
+// The condition and hit condition of a requested breakpoint combined into one expression.
+// A hit condition like "5" stops from the fifth hit on, ">5", "==5" or "%5" compare hits.
+fn breakpoint_condition(requested: &Value) -> Option<String> {
+    let condition = requested["condition"].as_str().filter(|text| !text.trim().is_empty());
+    let hits = requested["hitCondition"]
+        .as_str()
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(|text| match text.strip_prefix('%') {
+            Some(modulo) => format!("hits % ({}) == 0", modulo),
+            None if text.starts_with(|c: char| "<>=!".contains(c)) => format!("hits {}", text),
+            None => format!("hits >= {}", text),
+        });
+    match (condition, hits) {
+        (Some(condition), Some(hits)) => Some(format!("({}) && {}", condition, hits)),
+        (Some(condition), None) => Some(condition.to_string()),
+        (None, hits) => hits,
+    }
+}
+
+fn scopes() -> Value {
+    json!({
+        "scopes": [{
+            "name": "Registers",
+            "presentationHint": "registers",
+            "variablesReference": REGISTERS_REFERENCE,
+            "expensive": false,
+        }]
+    })
+}
+
+fn register_variable(cpu: &Cpu, name: &str, register: Register) -> Value {
+    let value = cpu.register(register);
+    if name.len() == 1 {
+        json!({ "name": name, "value": format_byte(value as u8), "variablesReference": 0 })
+    } else {
+        json!({
+            "name": name,
+            "value": format_word(value),
+            "variablesReference": 0,
+            "memoryReference": reference(value),
+        })
+    }
+}
+
+// The id of the first breakpoint that stops at the cpu's next instruction
+fn find_breakpoint(breakpoints: &mut [(i64, Breakpoint)], cpu: &Cpu) -> Option<i64> {
+    breakpoints
+        .iter_mut()
+        .find_map(|(id, breakpoint)| breakpoint.check(cpu).then_some(*id))
+}
+
+fn reference(address: u16) -> String {
+    format!("0x{:04X}", address)
+}
+
+// The address `offset` instructions away from `address`. Instructions have different lengths,
+// so going backwards decodes forward from a few bytes earlier until it lines up with `address`.
+fn seek_instruction(memory: &[u8], address: u16, offset: i64, model: Model) -> u16 {
+    if offset >= 0 {
+        return (0..offset).fold(address, |address, _| {
+            address.wrapping_add(disassembler::disassemble_model(memory, address, model).length)
+        });
+    }
+    let count = offset.unsigned_abs() as usize;
+    let longest = if model == Model::Z80 { 4 } else { 3 };
+    let earliest = address.saturating_sub((count * longest).min(0xffff) as u16);
+    for start in earliest..address {
+        let mut addresses = Vec::new();
+        let mut current = start as u32;
+        while current < address as u32 {
+            addresses.push(current as u16);
+            current += disassembler::disassemble_model(memory, current as u16, model).length as u32;
+        }
+        if current == address as u32 {
+            return addresses[addresses.len().saturating_sub(count)];
+        }
+    }
+    earliest
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (index, byte)| bits | (*byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for character in text.bytes().filter(|character| *character != b'=') {
+        let value = BASE64.iter().position(|c| *c == character)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod dap_tests {
+    use super::*;
+
+    fn request(server: &mut DapServer, command: &str, arguments: Value) -> Vec<Value> {
+        server.handle(&json!({
+            "seq": 1,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        }));
+        server.take_messages()
+    }
+
+    fn launched(program: Vec<u8>) -> DapServer {
+        let mut server = DapServer::new();
+        server.cpu = Some(Cpu::new(program));
+        server
+    }
+
+    // A cpu on the Space Invaders board, run with its video interrupts
+    fn launched_board(program: Vec<u8>) -> DapServer {
+        let mut server = launched(program);
+        let cpu = server.cpu.as_mut().unwrap();
+        cpu.set_io(crate::emulator::io::Io::board(crate::emulator::rom::Machine::find("invaders").unwrap().config));
+        server.runner = Some(Runner::new(cpu, true).unwrap());
+        server
+    }
+
+    fn stopped_reason(messages: &[Value]) -> Option<&str> {
+        messages
+            .iter()
+            .find(|message| message["event"] == "stopped")
+            .and_then(|message| message["body"]["reason"].as_str())
+    }
+
+    #[test]
+    fn framing() {
+        let mut output = Vec::new();
+        write_message(&mut output, &json!({ "seq": 1 })).unwrap();
+        assert_eq!(output, b"Content-Length: 9\r\n\r\n{\"seq\":1}");
+        let mut reader = BufReader::new(&output[..]);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn initialize_and_errors() {
+        let mut server = DapServer::new();
+        let messages = request(&mut server, "initialize", json!({}));
+        assert_eq!(messages[0]["success"], true);
+        assert_eq!(messages[0]["body"]["supportsDisassembleRequest"], true);
+        assert_eq!(messages[1]["event"], "initialized");
+
+        let messages = request(&mut server, "stackTrace", json!({}));
+        assert_eq!(messages[0]["success"], false);
+        let messages = request(&mut server, "launch", json!({ "program": "missing.bin" }));
+        assert_eq!(messages[0]["message"], "ROM 'missing.bin' not found");
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        // 0000 CALL 0006 / 0003 JMP 0003 / 0006 MVI A,05h / 0008 RET
+        let mut server = launched(vec![0xcd, 0x06, 0x00, 0xc3, 0x03, 0x00, 0x3e, 0x05, 0xc9]);
+        server.stop_on_entry = true;
+        let messages = request(&mut server, "configurationDone", json!({}));
+        assert_eq!(messages[0]["command"], "configurationDone");
+        assert_eq!(stopped_reason(&messages), Some("entry"));
+        assert_eq!(messages[1]["seq"], 2);
+
+        // Step over the call
+        request(&mut server, "next", json!({}));
+        server.run(100);
+        assert_eq!(stopped_reason(&server.take_messages()), Some("step"));
+        assert_eq!(server.cpu.as_ref().unwrap().register(Register::Pc), 0x0003);
+        assert_eq!(server.cpu.as_ref().unwrap().register(Register::A), 0x05);
+
+        // Step into it and back out
+        server.cpu.as_mut().unwrap().set_register(Register::Pc, 0x0000);
+        let messages = request(&mut server, "stepIn", json!({}));
+        assert_eq!(stopped_reason(&messages), Some("step"));
+        assert_eq!(server.cpu.as_ref().unwrap().register(Register::Pc), 0x0006);
+        request(&mut server, "stepOut", json!({}));
+        server.run(100);
+        assert_eq!(stopped_reason(&server.take_messages()), Some("step"));
+        assert_eq!(server.cpu.as_ref().unwrap().register(Register::Pc), 0x0003);
+
+        // Stop on the third time round the loop
+        let messages = request(
+            &mut server,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": "0x0003", "hitCondition": "3" }] }),
+        );
+        let id = messages[0]["body"]["breakpoints"][0]["id"].clone();
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["verified"], true);
+        request(&mut server, "continue", json!({}));
+        server.run(100);
+        let messages = server.take_messages();
+        assert_eq!(stopped_reason(&messages), Some("instruction breakpoint"));
+        assert_eq!(messages[0]["body"]["hitBreakpointIds"][0], id);
+        assert_eq!(server.instruction_breakpoints[0].1.hits, 3);
+
+        let messages = request(
+            &mut server,
+            "setFunctionBreakpoints",
+            json!({ "breakpoints": [{ "name": "Nowhere" }, { "name": "0x0003", "condition": "a ==" }] }),
+        );
+        assert_eq!(messages[0]["body"]["breakpoints"][0]["verified"], false);
+        assert_eq!(messages[0]["body"]["breakpoints"][1]["verified"], false);
+    }
+
+    #[test]
+    fn pause() {
+        let mut server = launched(vec![0xc3, 0x00, 0x00]);
+        request(&mut server, "configurationDone", json!({}));
+        server.run(100);
+        assert!(server.running());
+        let messages = request(&mut server, "pause", json!({}));
+        assert_eq!(stopped_reason(&messages), Some("pause"));
+        assert!(!server.running());
+    }
+
+    #[test]
+    fn registers() {
+        let mut server = launched(vec![0x00]);
+        server.cpu.as_mut().unwrap().set_register(Register::Hl, 0x2400);
+        let messages = request(&mut server, "scopes", json!({ "frameId": 0 }));
+        assert_eq!(messages[0]["body"]["scopes"][0]["name"], "Registers");
+
+        let messages = request(&mut server, "variables", json!({ "variablesReference": REGISTERS_REFERENCE }));
+        let variables = &messages[0]["body"]["variables"];
+        assert_eq!(variables[9]["name"], "HL");
+        assert_eq!(variables[9]["value"], "2400h");
+        assert_eq!(variables[9]["memoryReference"], "0x2400");
+        assert_eq!(variables[12]["value"], "42h");
+
+        request(&mut server, "setVariable", json!({ "variablesReference": 1, "name": "A", "value": "0FFh" }));
+        request(&mut server, "setVariable", json!({ "variablesReference": 2, "name": "CY", "value": "1" }));
+        let cpu = server.cpu.as_ref().unwrap();
+        assert_eq!(cpu.register(Register::A), 0xff);
+        assert!(cpu.flag(Flag::Cy));
+
+        let messages = request(&mut server, "evaluate", json!({ "expression": "hl + 1" }));
+        assert_eq!(messages[0]["body"]["result"], "9217 (2401h)");
+    }
+
+    #[test]
+    fn memory() {
+        let mut server = launched(vec![0x01, 0x02, 0x03, 0x04]);
+        let messages = request(
+            &mut server,
+            "writeMemory",
+            json!({ "memoryReference": "0x0001", "data": base64_encode(&[0xaa, 0xbb]) }),
+        );
+        assert_eq!(messages[0]["body"]["bytesWritten"], 2);
+        let messages = request(
+            &mut server,
+            "readMemory",
+            json!({ "memoryReference": "0x0000", "offset": 0, "count": 4 }),
+        );
+        assert_eq!(base64_decode(messages[0]["body"]["data"].as_str().unwrap()), Some(vec![0x01, 0xaa, 0xbb, 0x04]));
+        let messages = request(
+            &mut server,
+            "readMemory",
+            json!({ "memoryReference": "0xFFFE", "count": 4 }),
+        );
+        assert_eq!(messages[0]["body"]["unreadableBytes"], 2);
+        let messages = request(
+            &mut server,
+            "readMemory",
+            json!({ "memoryReference": "0xFFFE", "count": u64::MAX }),
+        );
+        assert_eq!(messages[0]["success"], true);
+        assert_eq!(messages[0]["body"]["unreadableBytes"], u64::MAX - 2);
+    }
+
+    #[test]
+    fn launch_machine_directory() {
+        let directory = std::env::temp_dir().join(format!("dap_machine_{}", std::process::id())).join("invaders");
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in ["invaders.h", "invaders.g", "invaders.f", "invaders.e"] {
+            std::fs::write(directory.join(name), [0; 0x800]).unwrap();
+        }
+
+        let mut server = DapServer::new();
+        let messages = request(&mut server, "launch", json!({ "program": directory.to_str().unwrap() }));
+        assert_eq!(messages[0]["success"], true);
+        assert_eq!(server.cpu.as_ref().unwrap().memory[0x1fff], 0);
+        std::fs::remove_dir_all(directory.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn disassembly() {
+        // 0000 MVI B,03h / 0002 DCR B / 0003 JMP 18D4h / 0006 NOP
+        let mut server = launched(vec![0x06, 0x03, 0x05, 0xc3, 0xd4, 0x18, 0x00]);
+        server.symbols.insert("Loop", 0x0002);
+        let messages = request(
+            &mut server,
+            "disassemble",
+            json!({ "memoryReference": "0x0003", "instructionOffset": -2, "instructionCount": 3 }),
+        );
+        let instructions = &messages[0]["body"]["instructions"];
+        assert_eq!(instructions[0]["address"], "0x0000");
+        assert_eq!(instructions[0]["instruction"], "MVI B,03h");
+        assert_eq!(instructions[1]["symbol"], "Loop");
+        assert_eq!(instructions[2]["instructionBytes"], "C3 D4 18");
+        assert_eq!(instructions[2]["instruction"], "JMP 18D4h");
+    }
+
+    #[test]
+    fn z80_disassembly_and_stepping() {
+        // 0000 LD (IX+01h),02h / 0004 CALL 000Ah / 0007 DJNZ 0007h / 0009 NOP / 000A RET
+        let program = vec![0xdd, 0x36, 0x01, 0x02, 0xcd, 0x0a, 0x00, 0x10, 0xfe, 0x00, 0xc9];
+        let mut server = launched(program);
+        server.cpu.as_mut().unwrap().set_model(Model::Z80);
+        let messages = request(
+            &mut server,
+            "disassemble",
+            json!({ "memoryReference": "0x0007", "instructionOffset": -2, "instructionCount": 3 }),
+        );
+        let instructions = &messages[0]["body"]["instructions"];
+        assert_eq!(instructions[0]["instructionBytes"], "DD 36 01 02");
+        assert_eq!(instructions[0]["instruction"], "LD (IX+01h),02h");
+        assert_eq!(instructions[1]["instruction"], "CALL 000Ah");
+        assert_eq!(instructions[2]["instruction"], "DJNZ 0007h");
+
+        server.cpu.as_mut().unwrap().set_register(Register::Pc, 0x0004);
+        server.stop_on_entry = true;
+        request(&mut server, "configurationDone", json!({}));
+        request(&mut server, "next", json!({}));
+        server.run(100);
+        assert_eq!(stopped_reason(&server.take_messages()), Some("step"));
+        assert_eq!(server.cpu.as_ref().unwrap().register(Register::Pc), 0x0007);
+    }
+
+    #[test]
+    fn video_interrupts() {
+        // Waits for the RST 1 handler to set 2000h, as games wait for the screen
+        let program = "
+        jmp Start
+        org 8
+        mvi a,1
+        sta 2000h
+        ei
+        ret
+Start:  lxi sp,2400h
+        ei
+Wait:   lda 2000h
+        ora a
+        jz Wait
+Done:   jmp Done
+";
+        let image = crate::emulator::assembler::assemble(program).unwrap().image;
+        let done = image.len() as u16 - 3;
+        let mut server = launched_board(image);
+        request(
+            &mut server,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [{ "instructionReference": reference(done) }] }),
+        );
+        request(&mut server, "configurationDone", json!({}));
+        server.run(10_000);
+        assert_eq!(stopped_reason(&server.take_messages()), Some("instruction breakpoint"));
+        let cpu = server.cpu.as_ref().unwrap();
+        assert_eq!(cpu.register(Register::Pc), done);
+        assert!(cpu.cycles() >= super::super::CYCLES_PER_FRAME / 2);
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b"8080"), "ODA4MA==");
+        assert_eq!(base64_encode(b"i8080"), "aTgwODA=");
+        assert_eq!(base64_decode("aTgwODA="), Some(b"i8080".to_vec()));
+        assert_eq!(base64_decode("a?"), None);
+    }
+}
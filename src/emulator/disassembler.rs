@@ -0,0 +1,563 @@
+use std::fmt;
+
+use super::cpu::Model;
+use super::symbols::SymbolTable;
+
+mod z80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operands {
+    None,
+    // Registers or a restart number, e.g. "B,C"
+    Fixed(&'static str),
+    // Immediate byte after an optional prefix, e.g. "B," for MVI B,D8
+    Byte(&'static str),
+    // Immediate word after an optional prefix, e.g. "SP," for LXI SP,D16
+    Word(&'static str),
+    // Jump, call or memory address
+    Address,
+}
+
+// Mnemonics prefixed with '*' are the undocumented aliases of unused opcodes
+const OPCODES: [(&str, Operands); 256] = [
+    ("NOP", Operands::None), // 00
+    ("LXI", Operands::Word("B,")), // 01
+    ("STAX", Operands::Fixed("B")), // 02
+    ("INX", Operands::Fixed("B")), // 03
+    ("INR", Operands::Fixed("B")), // 04
+    ("DCR", Operands::Fixed("B")), // 05
+    ("MVI", Operands::Byte("B,")), // 06
+    ("RLC", Operands::None), // 07
+    ("*NOP", Operands::None), // 08
+    ("DAD", Operands::Fixed("B")), // 09
+    ("LDAX", Operands::Fixed("B")), // 0A
+    ("DCX", Operands::Fixed("B")), // 0B
+    ("INR", Operands::Fixed("C")), // 0C
+    ("DCR", Operands::Fixed("C")), // 0D
+    ("MVI", Operands::Byte("C,")), // 0E
+    ("RRC", Operands::None), // 0F
+    ("*NOP", Operands::None), // 10
+    ("LXI", Operands::Word("D,")), // 11
+    ("STAX", Operands::Fixed("D")), // 12
+    ("INX", Operands::Fixed("D")), // 13
+    ("INR", Operands::Fixed("D")), // 14
+    ("DCR", Operands::Fixed("D")), // 15
+    ("MVI", Operands::Byte("D,")), // 16
+    ("RAL", Operands::None), // 17
+    ("*NOP", Operands::None), // 18
+    ("DAD", Operands::Fixed("D")), // 19
+    ("LDAX", Operands::Fixed("D")), // 1A
+    ("DCX", Operands::Fixed("D")), // 1B
+    ("INR", Operands::Fixed("E")), // 1C
+    ("DCR", Operands::Fixed("E")), // 1D
+    ("MVI", Operands::Byte("E,")), // 1E
+    ("RAR", Operands::None), // 1F
+    ("*NOP", Operands::None), // 20
+    ("LXI", Operands::Word("H,")), // 21
+    ("SHLD", Operands::Address), // 22
+    ("INX", Operands::Fixed("H")), // 23
+    ("INR", Operands::Fixed("H")), // 24
+    ("DCR", Operands::Fixed("H")), // 25
+    ("MVI", Operands::Byte("H,")), // 26
+    ("DAA", Operands::None), // 27
+    ("*NOP", Operands::None), // 28
+    ("DAD", Operands::Fixed("H")), // 29
+    ("LHLD", Operands::Address), // 2A
+    ("DCX", Operands::Fixed("H")), // 2B
+    ("INR", Operands::Fixed("L")), // 2C
+    ("DCR", Operands::Fixed("L")), // 2D
+    ("MVI", Operands::Byte("L,")), // 2E
+    ("CMA", Operands::None), // 2F
+    ("*NOP", Operands::None), // 30
+    ("LXI", Operands::Word("SP,")), // 31
+    ("STA", Operands::Address), // 32
+    ("INX", Operands::Fixed("SP")), // 33
+    ("INR", Operands::Fixed("M")), // 34
+    ("DCR", Operands::Fixed("M")), // 35
+    ("MVI", Operands::Byte("M,")), // 36
+    ("STC", Operands::None), // 37
+    ("*NOP", Operands::None), // 38
+    ("DAD", Operands::Fixed("SP")), // 39
+    ("LDA", Operands::Address), // 3A
+    ("DCX", Operands::Fixed("SP")), // 3B
+    ("INR", Operands::Fixed("A")), // 3C
+    ("DCR", Operands::Fixed("A")), // 3D
+    ("MVI", Operands::Byte("A,")), // 3E
+    ("CMC", Operands::None), // 3F
+    ("MOV", Operands::Fixed("B,B")), // 40
+    ("MOV", Operands::Fixed("B,C")), // 41
+    ("MOV", Operands::Fixed("B,D")), // 42
+    ("MOV", Operands::Fixed("B,E")), // 43
+    ("MOV", Operands::Fixed("B,H")), // 44
+    ("MOV", Operands::Fixed("B,L")), // 45
+    ("MOV", Operands::Fixed("B,M")), // 46
+    ("MOV", Operands::Fixed("B,A")), // 47
+    ("MOV", Operands::Fixed("C,B")), // 48
+    ("MOV", Operands::Fixed("C,C")), // 49
+    ("MOV", Operands::Fixed("C,D")), // 4A
+    ("MOV", Operands::Fixed("C,E")), // 4B
+    ("MOV", Operands::Fixed("C,H")), // 4C
+    ("MOV", Operands::Fixed("C,L")), // 4D
+    ("MOV", Operands::Fixed("C,M")), // 4E
+    ("MOV", Operands::Fixed("C,A")), // 4F
+    ("MOV", Operands::Fixed("D,B")), // 50
+    ("MOV", Operands::Fixed("D,C")), // 51
+    ("MOV", Operands::Fixed("D,D")), // 52
+    ("MOV", Operands::Fixed("D,E")), // 53
+    ("MOV", Operands::Fixed("D,H")), // 54
+    ("MOV", Operands::Fixed("D,L")), // 55
+    ("MOV", Operands::Fixed("D,M")), // 56
+    ("MOV", Operands::Fixed("D,A")), // 57
+    ("MOV", Operands::Fixed("E,B")), // 58
+    ("MOV", Operands::Fixed("E,C")), // 59
+    ("MOV", Operands::Fixed("E,D")), // 5A
+    ("MOV", Operands::Fixed("E,E")), // 5B
+    ("MOV", Operands::Fixed("E,H")), // 5C
+    ("MOV", Operands::Fixed("E,L")), // 5D
+    ("MOV", Operands::Fixed("E,M")), // 5E
+    ("MOV", Operands::Fixed("E,A")), // 5F
+    ("MOV", Operands::Fixed("H,B")), // 60
+    ("MOV", Operands::Fixed("H,C")), // 61
+    ("MOV", Operands::Fixed("H,D")), // 62
+    ("MOV", Operands::Fixed("H,E")), // 63
+    ("MOV", Operands::Fixed("H,H")), // 64
+    ("MOV", Operands::Fixed("H,L")), // 65
+    ("MOV", Operands::Fixed("H,M")), // 66
+    ("MOV", Operands::Fixed("H,A")), // 67
+    ("MOV", Operands::Fixed("L,B")), // 68
+    ("MOV", Operands::Fixed("L,C")), // 69
+    ("MOV", Operands::Fixed("L,D")), // 6A
+    ("MOV", Operands::Fixed("L,E")), // 6B
+    ("MOV", Operands::Fixed("L,H")), // 6C
+    ("MOV", Operands::Fixed("L,L")), // 6D
+    ("MOV", Operands::Fixed("L,M")), // 6E
+    ("MOV", Operands::Fixed("L,A")), // 6F
+    ("MOV", Operands::Fixed("M,B")), // 70
+    ("MOV", Operands::Fixed("M,C")), // 71
+    ("MOV", Operands::Fixed("M,D")), // 72
+    ("MOV", Operands::Fixed("M,E")), // 73
+    ("MOV", Operands::Fixed("M,H")), // 74
+    ("MOV", Operands::Fixed("M,L")), // 75
+    ("HLT", Operands::None), // 76
+    ("MOV", Operands::Fixed("M,A")), // 77
+    ("MOV", Operands::Fixed("A,B")), // 78
+    ("MOV", Operands::Fixed("A,C")), // 79
+    ("MOV", Operands::Fixed("A,D")), // 7A
+    ("MOV", Operands::Fixed("A,E")), // 7B
+    ("MOV", Operands::Fixed("A,H")), // 7C
+    ("MOV", Operands::Fixed("A,L")), // 7D
+    ("MOV", Operands::Fixed("A,M")), // 7E
+    ("MOV", Operands::Fixed("A,A")), // 7F
+    ("ADD", Operands::Fixed("B")), // 80
+    ("ADD", Operands::Fixed("C")), // 81
+    ("ADD", Operands::Fixed("D")), // 82
+    ("ADD", Operands::Fixed("E")), // 83
+    ("ADD", Operands::Fixed("H")), // 84
+    ("ADD", Operands::Fixed("L")), // 85
+    ("ADD", Operands::Fixed("M")), // 86
+    ("ADD", Operands::Fixed("A")), // 87
+    ("ADC", Operands::Fixed("B")), // 88
+    ("ADC", Operands::Fixed("C")), // 89
+    ("ADC", Operands::Fixed("D")), // 8A
+    ("ADC", Operands::Fixed("E")), // 8B
+    ("ADC", Operands::Fixed("H")), // 8C
+    ("ADC", Operands::Fixed("L")), // 8D
+    ("ADC", Operands::Fixed("M")), // 8E
+    ("ADC", Operands::Fixed("A")), // 8F
+    ("SUB", Operands::Fixed("B")), // 90
+    ("SUB", Operands::Fixed("C")), // 91
+    ("SUB", Operands::Fixed("D")), // 92
+    ("SUB", Operands::Fixed("E")), // 93
+    ("SUB", Operands::Fixed("H")), // 94
+    ("SUB", Operands::Fixed("L")), // 95
+    ("SUB", Operands::Fixed("M")), // 96
+    ("SUB", Operands::Fixed("A")), // 97
+    ("SBB", Operands::Fixed("B")), // 98
+    ("SBB", Operands::Fixed("C")), // 99
+    ("SBB", Operands::Fixed("D")), // 9A
+    ("SBB", Operands::Fixed("E")), // 9B
+    ("SBB", Operands::Fixed("H")), // 9C
+    ("SBB", Operands::Fixed("L")), // 9D
+    ("SBB", Operands::Fixed("M")), // 9E
+    ("SBB", Operands::Fixed("A")), // 9F
+    ("ANA", Operands::Fixed("B")), // A0
+    ("ANA", Operands::Fixed("C")), // A1
+    ("ANA", Operands::Fixed("D")), // A2
+    ("ANA", Operands::Fixed("E")), // A3
+    ("ANA", Operands::Fixed("H")), // A4
+    ("ANA", Operands::Fixed("L")), // A5
+    ("ANA", Operands::Fixed("M")), // A6
+    ("ANA", Operands::Fixed("A")), // A7
+    ("XRA", Operands::Fixed("B")), // A8
+    ("XRA", Operands::Fixed("C")), // A9
+    ("XRA", Operands::Fixed("D")), // AA
+    ("XRA", Operands::Fixed("E")), // AB
+    ("XRA", Operands::Fixed("H")), // AC
+    ("XRA", Operands::Fixed("L")), // AD
+    ("XRA", Operands::Fixed("M")), // AE
+    ("XRA", Operands::Fixed("A")), // AF
+    ("ORA", Operands::Fixed("B")), // B0
+    ("ORA", Operands::Fixed("C")), // B1
+    ("ORA", Operands::Fixed("D")), // B2
+    ("ORA", Operands::Fixed("E")), // B3
+    ("ORA", Operands::Fixed("H")), // B4
+    ("ORA", Operands::Fixed("L")), // B5
+    ("ORA", Operands::Fixed("M")), // B6
+    ("ORA", Operands::Fixed("A")), // B7
+    ("CMP", Operands::Fixed("B")), // B8
+    ("CMP", Operands::Fixed("C")), // B9
+    ("CMP", Operands::Fixed("D")), // BA
+    ("CMP", Operands::Fixed("E")), // BB
+    ("CMP", Operands::Fixed("H")), // BC
+    ("CMP", Operands::Fixed("L")), // BD
+    ("CMP", Operands::Fixed("M")), // BE
+    ("CMP", Operands::Fixed("A")), // BF
+    ("RNZ", Operands::None), // C0
+    ("POP", Operands::Fixed("B")), // C1
+    ("JNZ", Operands::Address), // C2
+    ("JMP", Operands::Address), // C3
+    ("CNZ", Operands::Address), // C4
+    ("PUSH", Operands::Fixed("B")), // C5
+    ("ADI", Operands::Byte("")), // C6
+    ("RST", Operands::Fixed("0")), // C7
+    ("RZ", Operands::None), // C8
+    ("RET", Operands::None), // C9
+    ("JZ", Operands::Address), // CA
+    ("*JMP", Operands::Address), // CB
+    ("CZ", Operands::Address), // CC
+    ("CALL", Operands::Address), // CD
+    ("ACI", Operands::Byte("")), // CE
+    ("RST", Operands::Fixed("1")), // CF
+    ("RNC", Operands::None), // D0
+    ("POP", Operands::Fixed("D")), // D1
+    ("JNC", Operands::Address), // D2
+    ("OUT", Operands::Byte("")), // D3
+    ("CNC", Operands::Address), // D4
+    ("PUSH", Operands::Fixed("D")), // D5
+    ("SUI", Operands::Byte("")), // D6
+    ("RST", Operands::Fixed("2")), // D7
+    ("RC", Operands::None), // D8
+    ("*RET", Operands::None), // D9
+    ("JC", Operands::Address), // DA
+    ("IN", Operands::Byte("")), // DB
+    ("CC", Operands::Address), // DC
+    ("*CALL", Operands::Address), // DD
+    ("SBI", Operands::Byte("")), // DE
+    ("RST", Operands::Fixed("3")), // DF
+    ("RPO", Operands::None), // E0
+    ("POP", Operands::Fixed("H")), // E1
+    ("JPO", Operands::Address), // E2
+    ("XTHL", Operands::None), // E3
+    ("CPO", Operands::Address), // E4
+    ("PUSH", Operands::Fixed("H")), // E5
+    ("ANI", Operands::Byte("")), // E6
+    ("RST", Operands::Fixed("4")), // E7
+    ("RPE", Operands::None), // E8
+    ("PCHL", Operands::None), // E9
+    ("JPE", Operands::Address), // EA
+    ("XCHG", Operands::None), // EB
+    ("CPE", Operands::Address), // EC
+    ("*CALL", Operands::Address), // ED
+    ("XRI", Operands::Byte("")), // EE
+    ("RST", Operands::Fixed("5")), // EF
+    ("RP", Operands::None), // F0
+    ("POP", Operands::Fixed("PSW")), // F1
+    ("JP", Operands::Address), // F2
+    ("DI", Operands::None), // F3
+    ("CP", Operands::Address), // F4
+    ("PUSH", Operands::Fixed("PSW")), // F5
+    ("ORI", Operands::Byte("")), // F6
+    ("RST", Operands::Fixed("6")), // F7
+    ("RM", Operands::None), // F8
+    ("SPHL", Operands::None), // F9
+    ("JM", Operands::Address), // FA
+    ("EI", Operands::None), // FB
+    ("CM", Operands::Address), // FC
+    ("*CALL", Operands::Address), // FD
+    ("CPI", Operands::Byte("")), // FE
+    ("RST", Operands::Fixed("7")), // FF
+];
+
+// The 8085's instructions in opcodes the 8080 leaves unused
+const I8085_OPCODES: [(u8, &str, Operands); 12] = [
+    (0x08, "DSUB", Operands::None),
+    (0x10, "ARHL", Operands::None),
+    (0x18, "RDEL", Operands::None),
+    (0x20, "RIM", Operands::None),
+    (0x28, "LDHI", Operands::Byte("")),
+    (0x30, "SIM", Operands::None),
+    (0x38, "LDSI", Operands::Byte("")),
+    (0xcb, "RSTV", Operands::None),
+    (0xd9, "SHLX", Operands::None),
+    (0xdd, "JNK", Operands::Address),
+    (0xed, "LHLX", Operands::None),
+    (0xfd, "JK", Operands::Address),
+];
+
+// Whether an instruction comes back to the one after it, or returns to where one was called from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    Call,
+    Return,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub length: u16,
+    pub bytes: [u8; 4],
+    pub mnemonic: &'static str,
+    pub operands: String,
+    target: Option<u16>,
+    flow: Flow,
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    // Target of a jump, call or memory access, if the instruction has one
+    pub fn address_operand(&self) -> Option<u16> {
+        self.target
+    }
+
+    // CALL, conditional calls and restarts, which return to the next instruction
+    pub fn is_call(&self) -> bool {
+        self.flow == Flow::Call
+    }
+
+    // RET and conditional returns
+    pub fn is_return(&self) -> bool {
+        self.flow == Flow::Return
+    }
+
+    // Like the Display text but with the address operand replaced by its label, e.g. "CALL DrawSprite"
+    pub fn symbolic(&self, symbols: &SymbolTable) -> String {
+        match self.address_operand().and_then(|address| Some((address, symbols.name(address)?))) {
+            Some((address, name)) => {
+                format!("{} {}", self.mnemonic, self.operands.replace(&format_word(address), name))
+            }
+            None => self.to_string(),
+        }
+    }
+
+    // Opcode and operand bytes as hex, e.g. "C3 D4 18"
+    pub fn hex_bytes(&self) -> String {
+        self.bytes[..self.length as usize]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.operands.is_empty() {
+            write!(f, "{}", self.mnemonic)
+        } else {
+            write!(f, "{} {}", self.mnemonic, self.operands)
+        }
+    }
+}
+
+// Decodes the 8080 instruction at `address`. Memory past the end of the slice reads as zero.
+pub fn disassemble(memory: &[u8], address: u16) -> Instruction {
+    disassemble_model(memory, address, Model::I8080)
+}
+
+// Decodes the instruction at `address` as the given processor runs it
+pub fn disassemble_model(memory: &[u8], address: u16, model: Model) -> Instruction {
+    let read = |offset: u16| -> u8 {
+        memory
+            .get(address.wrapping_add(offset) as usize)
+            .copied()
+            .unwrap_or(0)
+    };
+    if model == Model::Z80 {
+        return z80::disassemble(&read, address);
+    }
+    let opcode = read(0);
+    let (mnemonic, operands) = match I8085_OPCODES.iter().find(|(code, _, _)| *code == opcode) {
+        Some(&(_, mnemonic, operands)) if model == Model::I8085 => (mnemonic, operands),
+        _ => OPCODES[opcode as usize],
+    };
+    let flow = match mnemonic {
+        "CALL" | "*CALL" | "RST" | "RSTV" => Flow::Call,
+        _ if opcode & 0xc7 == 0xc4 => Flow::Call,
+        "RET" | "*RET" => Flow::Return,
+        _ if opcode & 0xc7 == 0xc0 => Flow::Return,
+        _ => Flow::Next,
+    };
+    let length = match operands {
+        Operands::None | Operands::Fixed(_) => 1,
+        Operands::Byte(_) => 2,
+        Operands::Word(_) | Operands::Address => 3,
+    };
+    let mut bytes = [opcode, 0, 0, 0];
+    for offset in 1..length {
+        bytes[offset as usize] = read(offset);
+    }
+    let word = (bytes[2] as u16) << 8 | bytes[1] as u16;
+    let text = match operands {
+        Operands::None => String::new(),
+        Operands::Fixed(text) => text.to_string(),
+        Operands::Byte(prefix) => format!("{}{}", prefix, format_byte(bytes[1])),
+        Operands::Word(prefix) => format!("{}{}", prefix, format_word(word)),
+        Operands::Address => format_word(word),
+    };
+    Instruction {
+        address,
+        length,
+        bytes,
+        mnemonic,
+        operands: text,
+        target: (operands == Operands::Address).then_some(word),
+        flow,
+    }
+}
+
+// Whether text is one of the 8080's mnemonics, in either case
+pub fn is_mnemonic(text: &str) -> bool {
+    OPCODES.iter().any(|(mnemonic, _)| mnemonic.eq_ignore_ascii_case(text))
+}
+
+// Intel style hex, with a leading zero when the number would start with a letter
+pub fn format_byte(value: u8) -> String {
+    intel_hex(format!("{:02X}", value))
+}
+
+pub fn format_word(value: u16) -> String {
+    intel_hex(format!("{:04X}", value))
+}
+
+fn intel_hex(digits: String) -> String {
+    if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{}h", digits)
+    } else {
+        format!("{}h", digits)
+    }
+}
+
+// Disassembles every instruction starting in start..=end, one per line
+pub fn disassemble_range(memory: &[u8], start: u16, end: u16, model: Model) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let instruction = disassemble_model(memory, address as u16, model);
+        address += instruction.length as u32;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod disassembler_tests {
+    use super::*;
+
+    fn text(bytes: &[u8]) -> String {
+        disassemble(bytes, 0).to_string()
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(text(&[0x00]), "NOP");
+        assert_eq!(text(&[0x06, 0x06]), "MVI B,06h");
+        assert_eq!(text(&[0xc2, 0xd4, 0x18]), "JNZ 18D4h");
+        assert_eq!(text(&[0xc3, 0x00, 0xd4]), "JMP 0D400h");
+        assert_eq!(text(&[0x31, 0x00, 0x24]), "LXI SP,2400h");
+        assert_eq!(text(&[0x7e]), "MOV A,M");
+        assert_eq!(text(&[0x76]), "HLT");
+        assert_eq!(text(&[0xbe]), "CMP M");
+        assert_eq!(text(&[0xfe, 0xff]), "CPI 0FFh");
+        assert_eq!(text(&[0xf5]), "PUSH PSW");
+        assert_eq!(text(&[0xef]), "RST 5");
+        assert_eq!(text(&[0xdd, 0x34, 0x12]), "*CALL 1234h");
+    }
+
+    #[test]
+    fn lengths() {
+        let lengths: Vec<u16> = (0..=0xff)
+            .map(|opcode| disassemble(&[opcode], 0).length)
+            .collect();
+        assert_eq!(lengths.iter().filter(|length| **length == 2).count(), 18);
+        assert_eq!(lengths.iter().filter(|length| **length == 3).count(), 30);
+    }
+
+    #[test]
+    fn range() {
+        // Space Invaders reset vector
+        let rom = [0x00, 0x00, 0x00, 0xc3, 0xd4, 0x18, 0x00, 0x00];
+        let instructions = disassemble_range(&rom, 0, 7, Model::I8080);
+        let addresses: Vec<u16> = instructions.iter().map(|i| i.address).collect();
+        assert_eq!(addresses, vec![0, 1, 2, 3, 6, 7]);
+        assert_eq!(instructions[3].hex_bytes(), "C3 D4 18");
+        assert_eq!(instructions[3].address_operand(), Some(0x18d4));
+    }
+
+    #[test]
+    fn i8085() {
+        let text = |bytes: &[u8]| disassemble_model(bytes, 0, Model::I8085).to_string();
+        assert_eq!(text(&[0x20]), "RIM");
+        assert_eq!(text(&[0x30]), "SIM");
+        assert_eq!(text(&[0x28, 0x10]), "LDHI 10h");
+        assert_eq!(text(&[0xdd, 0x34, 0x12]), "JNK 1234h");
+        assert_eq!(text(&[0xed]), "LHLX");
+        assert_eq!(disassemble_model(&[0xed], 0, Model::I8085).length, 1);
+        assert!(disassemble_model(&[0xcb], 0, Model::I8085).is_call());
+        assert!(!disassemble_model(&[0xd9], 0, Model::I8085).is_return());
+        assert!(disassemble(&[0xd9], 0).is_return());
+        assert!(disassemble(&[0xfd, 0x00, 0x00], 0).is_call());
+        assert_eq!(text(&[0x3e, 0x01]), "MVI A,01h");
+    }
+
+    #[test]
+    fn z80() {
+        let decode = |bytes: &[u8]| {
+            let mut memory = vec![0; 0x100];
+            memory.extend_from_slice(bytes);
+            disassemble_model(&memory, 0x0100, Model::Z80)
+        };
+        let text = |bytes: &[u8]| decode(bytes).to_string();
+        assert_eq!(text(&[0x3e, 0x5a]), "LD A,5Ah");
+        assert_eq!(text(&[0x20, 0xfe]), "JR NZ,0100h");
+        assert_eq!(text(&[0x10, 0x10]), "DJNZ 0112h");
+        assert_eq!(text(&[0xcb, 0x7e]), "BIT 7,(HL)");
+        assert_eq!(text(&[0xdd, 0x36, 0x05, 0x12]), "LD (IX+05h),12h");
+        assert_eq!(text(&[0xfd, 0x66, 0xfe]), "LD H,(IY-02h)");
+        assert_eq!(text(&[0xdd, 0x26, 0x01]), "LD IXH,01h");
+        assert_eq!(text(&[0xfd, 0xcb, 0x02, 0xc6]), "SET 0,(IY+02h)");
+        assert_eq!(text(&[0xed, 0x78]), "IN A,(C)");
+        assert_eq!(text(&[0xed, 0xb0]), "LDIR");
+        assert_eq!(text(&[0xdd, 0xe9]), "JP (IX)");
+        assert_eq!(text(&[0xfe, 0x10]), "CP 10h");
+
+        let store = decode(&[0xed, 0x43, 0x00, 0x30]);
+        assert_eq!((store.to_string().as_str(), store.length), ("LD (3000h),BC", 4));
+        assert_eq!(store.hex_bytes(), "ED 43 00 30");
+        let mut symbols = SymbolTable::new();
+        symbols.insert("Buffer", 0x3000);
+        symbols.insert("Loop", 0x0100);
+        assert_eq!(store.symbolic(&symbols), "LD (Buffer),BC");
+        assert_eq!(decode(&[0x20, 0xfe]).symbolic(&symbols), "JR NZ,Loop");
+
+        assert!(decode(&[0xc4, 0x00, 0x10]).is_call());
+        assert!(decode(&[0xff]).is_call());
+        assert!(!decode(&[0xfe, 0x10]).is_call());
+        assert!(!decode(&[0xdd, 0xcd, 0x00, 0x10]).is_return());
+        assert!(decode(&[0xdd, 0xcd, 0x00, 0x10]).is_call());
+        assert!(decode(&[0xed, 0x4d]).is_return());
+        assert!(decode(&[0xe0]).is_return());
+    }
+
+    #[test]
+    fn symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("DrawSprite", 0x1439);
+        assert_eq!(disassemble(&[0xcd, 0x39, 0x14], 0).symbolic(&symbols), "CALL DrawSprite");
+        assert_eq!(disassemble(&[0xcd, 0x3a, 0x14], 0).symbolic(&symbols), "CALL 143Ah");
+        assert_eq!(disassemble(&[0x21, 0x39, 0x14], 0).symbolic(&symbols), "LXI H,1439h");
+    }
+}
@@ -0,0 +1,277 @@
+/*
+Z80 mnemonics, in Zilog's syntax rather than Intel's. Opcodes are decoded from their fields like
+the Z80's own decoder in cpu/z80.rs does, and prefixes the same way: DD and FD put IX or IY in
+place of HL and H and L, with a displacement byte after the opcode for (HL), and a prefix after a
+prefix replaces it.
+*/
+
+use super::{format_byte, format_word, Flow, Instruction};
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+// The ALU operations and what comes before their operand
+const ALU: [(&str, &str); 8] = [
+    ("ADD", "A,"),
+    ("ADC", "A,"),
+    ("SUB", ""),
+    ("SBC", "A,"),
+    ("AND", ""),
+    ("XOR", ""),
+    ("OR", ""),
+    ("CP", ""),
+];
+const ACCUMULATOR: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+const SHIFTS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SLL", "SRL"];
+const BLOCK: [[&str; 4]; 4] = [
+    ["LDI", "CPI", "INI", "OUTI"],
+    ["LDD", "CPD", "IND", "OUTD"],
+    ["LDIR", "CPIR", "INIR", "OTIR"],
+    ["LDDR", "CPDR", "INDR", "OTDR"],
+];
+
+type Decoded = (&'static str, String, Flow);
+
+// `read` gives the byte `offset` bytes after `address`
+pub(super) fn disassemble(read: &dyn Fn(u16) -> u8, address: u16) -> Instruction {
+    let mut decoder = Decoder {
+        read,
+        address,
+        length: 0,
+        index: None,
+        target: None,
+    };
+    let opcode = decoder.byte();
+    let (mnemonic, operands, flow) = decoder.main(opcode);
+    let mut bytes = [0; 4];
+    for offset in 0..decoder.length {
+        bytes[offset as usize] = read(offset);
+    }
+    Instruction {
+        address,
+        length: decoder.length,
+        bytes,
+        mnemonic,
+        operands,
+        target: decoder.target,
+        flow,
+    }
+}
+
+struct Decoder<'a> {
+    read: &'a dyn Fn(u16) -> u8,
+    address: u16,
+    // Bytes decoded so far
+    length: u16,
+    // "IX" or "IY" after a DD or FD prefix
+    index: Option<&'static str>,
+    target: Option<u16>,
+}
+
+fn op(mnemonic: &'static str, operands: impl Into<String>) -> Decoded {
+    (mnemonic, operands.into(), Flow::Next)
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> u8 {
+        let value = (self.read)(self.length);
+        self.length += 1;
+        value
+    }
+
+    fn word(&mut self) -> u16 {
+        let low = self.byte() as u16;
+        (self.byte() as u16) << 8 | low
+    }
+
+    fn immediate(&mut self) -> String {
+        format_byte(self.byte())
+    }
+
+    // The address a jump or call goes to, or a load or store goes through
+    fn address(&mut self) -> String {
+        let address = self.word();
+        self.target = Some(address);
+        format_word(address)
+    }
+
+    // JR and DJNZ count from the instruction after them
+    fn relative(&mut self) -> String {
+        let offset = self.byte() as i8;
+        let address = self.address.wrapping_add(self.length).wrapping_add_signed(offset as i16);
+        self.target = Some(address);
+        format_word(address)
+    }
+
+    fn hl(&self) -> &'static str {
+        self.index.unwrap_or("HL")
+    }
+
+    fn pair(&self, p: u8) -> &'static str {
+        if p == 2 {
+            self.hl()
+        } else {
+            PAIRS[p as usize]
+        }
+    }
+
+    // (HL), or (IX+d) reading the displacement
+    fn memory(&mut self) -> String {
+        match self.index {
+            Some(index) => {
+                let displacement = self.byte() as i8;
+                let sign = if displacement < 0 { '-' } else { '+' };
+                format!("({}{}{})", index, sign, format_byte(displacement.unsigned_abs()))
+            }
+            None => "(HL)".to_string(),
+        }
+    }
+
+    // B C D E H L (HL) A, with H and L as IXH and IXL after a prefix unless the other operand
+    // is (IX+d)
+    fn register(&mut self, register: u8, memory_operand: bool) -> String {
+        match (register, self.index) {
+            (6, _) => self.memory(),
+            (4, Some(index)) if !memory_operand => format!("{}H", index),
+            (5, Some(index)) if !memory_operand => format!("{}L", index),
+            _ => REGISTERS[register as usize].to_string(),
+        }
+    }
+
+    fn main(&mut self, opcode: u8) -> Decoded {
+        let (x, y, z) = (opcode >> 6, opcode >> 3 & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => op("NOP", ""),
+                1 => op("EX", "AF,AF'"),
+                2 => op("DJNZ", self.relative()),
+                3 => op("JR", self.relative()),
+                _ => op("JR", format!("{},{}", CONDITIONS[y as usize - 4], self.relative())),
+            },
+            (0, 1) if q == 0 => op("LD", format!("{},{}", self.pair(p), format_word(self.word()))),
+            (0, 1) => op("ADD", format!("{},{}", self.hl(), self.pair(p))),
+            (0, 2) => match (p, q) {
+                (0, 0) => op("LD", "(BC),A"),
+                (0, _) => op("LD", "A,(BC)"),
+                (1, 0) => op("LD", "(DE),A"),
+                (1, _) => op("LD", "A,(DE)"),
+                (2, 0) => op("LD", format!("({}),{}", self.address(), self.hl())),
+                (2, _) => op("LD", format!("{},({})", self.hl(), self.address())),
+                (_, 0) => op("LD", format!("({}),A", self.address())),
+                _ => op("LD", format!("A,({})", self.address())),
+            },
+            (0, 3) => op(if q == 0 { "INC" } else { "DEC" }, self.pair(p)),
+            (0, 4) => op("INC", self.register(y, false)),
+            (0, 5) => op("DEC", self.register(y, false)),
+            (0, 6) => {
+                let register = self.register(y, false);
+                op("LD", format!("{},{}", register, self.immediate()))
+            }
+            (0, _) => op(ACCUMULATOR[y as usize], ""),
+            (1, _) if y == 6 && z == 6 => op("HALT", ""),
+            (1, _) => {
+                let memory_operand = y == 6 || z == 6;
+                let destination = self.register(y, memory_operand);
+                op("LD", format!("{},{}", destination, self.register(z, memory_operand)))
+            }
+            (2, _) => {
+                let (mnemonic, prefix) = ALU[y as usize];
+                op(mnemonic, format!("{}{}", prefix, self.register(z, false)))
+            }
+            (_, 0) => ("RET", CONDITIONS[y as usize].to_string(), Flow::Return),
+            (_, 1) if q == 0 => op("POP", if p == 3 { "AF" } else { self.pair(p) }),
+            (_, 1) => match p {
+                0 => ("RET", String::new(), Flow::Return),
+                1 => op("EXX", ""),
+                2 => op("JP", format!("({})", self.hl())),
+                _ => op("LD", format!("SP,{}", self.hl())),
+            },
+            (_, 2) => op("JP", format!("{},{}", CONDITIONS[y as usize], self.address())),
+            (_, 3) => match y {
+                0 => op("JP", self.address()),
+                1 if self.index.is_none() => self.bit(),
+                1 => self.indexed_bit(),
+                2 => op("OUT", format!("({}),A", self.immediate())),
+                3 => op("IN", format!("A,({})", self.immediate())),
+                4 => op("EX", format!("(SP),{}", self.hl())),
+                5 => op("EX", "DE,HL"),
+                6 => op("DI", ""),
+                _ => op("EI", ""),
+            },
+            (_, 4) => {
+                let condition = CONDITIONS[y as usize];
+                ("CALL", format!("{},{}", condition, self.address()), Flow::Call)
+            }
+            (_, 5) if q == 0 => op("PUSH", if p == 3 { "AF" } else { self.pair(p) }),
+            (_, 5) => match p {
+                0 => ("CALL", self.address(), Flow::Call),
+                2 => self.extended(),
+                _ => {
+                    self.index = Some(if p == 1 { "IX" } else { "IY" });
+                    let opcode = self.byte();
+                    self.main(opcode)
+                }
+            },
+            (_, 6) => {
+                let (mnemonic, prefix) = ALU[y as usize];
+                op(mnemonic, format!("{}{}", prefix, self.immediate()))
+            }
+            _ => ("RST", format_byte(y * 8), Flow::Call),
+        }
+    }
+
+    // CB: rotates and shifts, BIT, RES and SET
+    fn bit(&mut self) -> Decoded {
+        let opcode = self.byte();
+        self.bit_operation(opcode, REGISTERS[opcode as usize & 7].to_string())
+    }
+
+    // DD CB and FD CB, where the displacement comes before the opcode
+    fn indexed_bit(&mut self) -> Decoded {
+        let operand = self.memory();
+        let opcode = self.byte();
+        self.bit_operation(opcode, operand)
+    }
+
+    fn bit_operation(&self, opcode: u8, operand: String) -> Decoded {
+        let (x, y) = (opcode >> 6, opcode >> 3 & 7);
+        match x {
+            0 => op(SHIFTS[y as usize], operand),
+            1 => op("BIT", format!("{},{}", y, operand)),
+            2 => op("RES", format!("{},{}", y, operand)),
+            _ => op("SET", format!("{},{}", y, operand)),
+        }
+    }
+
+    // ED, which ignores a DD or FD before it. The opcodes it leaves undefined run as NOPs.
+    fn extended(&mut self) -> Decoded {
+        self.index = None;
+        let opcode = self.byte();
+        let (x, y, z) = (opcode >> 6, opcode >> 3 & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (1, 0) if y == 6 => op("IN", "(C)"),
+            (1, 0) => op("IN", format!("{},(C)", REGISTERS[y as usize])),
+            (1, 1) if y == 6 => op("OUT", "(C),0"),
+            (1, 1) => op("OUT", format!("(C),{}", REGISTERS[y as usize])),
+            (1, 2) => op(if q == 0 { "SBC" } else { "ADC" }, format!("HL,{}", PAIRS[p as usize])),
+            (1, 3) if q == 0 => op("LD", format!("({}),{}", self.address(), PAIRS[p as usize])),
+            (1, 3) => op("LD", format!("{},({})", PAIRS[p as usize], self.address())),
+            (1, 4) => op("NEG", ""),
+            (1, 5) => (if y == 1 { "RETI" } else { "RETN" }, String::new(), Flow::Return),
+            (1, 6) => op("IM", ["0", "0", "1", "2"][y as usize & 3]),
+            (1, _) => match y {
+                0 => op("LD", "I,A"),
+                1 => op("LD", "R,A"),
+                2 => op("LD", "A,I"),
+                3 => op("LD", "A,R"),
+                4 => op("RRD", ""),
+                5 => op("RLD", ""),
+                _ => op("*NOP", ""),
+            },
+            (2, 0..=3) if y >= 4 => op(BLOCK[y as usize - 4][z as usize], ""),
+            _ => op("*NOP", ""),
+        }
+    }
+}
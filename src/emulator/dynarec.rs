@@ -0,0 +1,655 @@
+use std::{collections::HashSet, io, ptr};
+
+use super::{
+    cpu::{Alu, Cpu, Layout, Op, CODE, CODE_WRITTEN, CYCLES, DECODE, M, SP_OR_PSW, UNDOCUMENTED},
+    disassembler,
+    error::EmulatorError,
+};
+
+/*
+A dynamic recompiler for x86-64 Linux, built with the dynarec feature.
+
+Each block of straight-line 8080 code is translated into one host function, called with the
+Cpu in rdi and returning the number of instructions it executed. Instructions on registers
+become x86 instructions on the Cpu's fields, with the flags copied from the x86 flags, which
+are laid out like the 8080's. Everything else, memory operands, the stack and jumps, calls back
+into the interpreter for that one opcode, which saves the fetch and dispatch and keeps the
+memory accesses and cycle counts exactly as they are.
+
+A block ends after a jump, call or return, and before IN, OUT, HLT, EI, DI and undocumented
+opcodes, which the interpreter runs one at a time, so I/O and interrupts are only ever seen
+between blocks. Blocks also end before breakpoints, so the frontend can check them in between.
+Writes to a page holding translated code, including writes by the block itself, stop the block
+after the writing instruction and throw away every block on the page.
+*/
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("The dynarec feature needs x86-64 Linux");
+
+// The longest block translated, in instructions
+const MAX_INSTRUCTIONS: u32 = 64;
+// Executable memory for translated code. When it fills up everything is translated again.
+const BUFFER_SIZE: usize = 4 << 20;
+
+// Opcode extensions in the ModRM byte of the x86 group 1 instructions
+const ADD: u8 = 0;
+const ADC: u8 = 2;
+const SBB: u8 = 3;
+const SUB: u8 = 5;
+const XOR: u8 = 6;
+const CMP: u8 = 7;
+// x86 byte registers
+const AL: u8 = 0;
+const CL: u8 = 1;
+const AH: u8 = 4;
+// The x86 condition codes of SETcc that match the 8080 flags
+const SETS: u8 = 0x98;
+const SETZ: u8 = 0x94;
+const SETP: u8 = 0x9a;
+const SETC: u8 = 0x92;
+
+// x86 opcodes of the ALU operations as OP al,r/m8 and OP al,imm8. The 8080 flags come out the
+// same, except that the 8080 sets AC on subtraction when there is no borrow from bit 4, and
+// ANA sets it from bit 3 of the operands.
+fn alu_opcodes(operation: Alu) -> (u8, u8) {
+    match operation {
+        Alu::Add => (0x02, 0x04),
+        Alu::Adc => (0x12, 0x14),
+        Alu::Sub => (0x2a, 0x2c),
+        Alu::Sbb => (0x1a, 0x1c),
+        Alu::Ana => (0x22, 0x24),
+        Alu::Xra => (0x32, 0x34),
+        Alu::Ora => (0x0a, 0x0c),
+        Alu::Cmp => (0x3a, 0x3c),
+    }
+}
+
+type BlockFn = unsafe extern "C" fn(*mut Cpu) -> u32;
+
+// The interpreter's half of an instruction in a block, with pc at the opcode
+extern "C" fn execute_opcode(cpu: *mut Cpu, opcode: u32) {
+    // The block was called with the only reference to the Cpu, see Dynarec::step
+    let cpu = unsafe { &mut *cpu };
+    cpu.execute_opcode(opcode as u8);
+}
+
+// Anonymous read, write and execute memory the blocks are copied into
+struct CodeBuffer {
+    memory: *mut u8,
+    used: usize,
+}
+
+impl CodeBuffer {
+    fn new() -> Result<CodeBuffer, EmulatorError> {
+        let memory = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                BUFFER_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err(EmulatorError::Io(io::Error::last_os_error()));
+        }
+        Ok(CodeBuffer {
+            memory: memory as *mut u8,
+            used: 0,
+        })
+    }
+
+    // Copies the code in, or returns None if it's full
+    fn add(&mut self, code: &[u8]) -> Option<BlockFn> {
+        if self.used + code.len() > BUFFER_SIZE {
+            return None;
+        }
+        unsafe {
+            let entry = self.memory.add(self.used);
+            ptr::copy_nonoverlapping(code.as_ptr(), entry, code.len());
+            self.used += code.len();
+            Some(std::mem::transmute::<*mut u8, BlockFn>(entry))
+        }
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory as *mut libc::c_void, BUFFER_SIZE);
+        }
+    }
+}
+
+// The second operand of an ALU operation
+#[derive(Clone, Copy)]
+enum Operand {
+    // A register, as the offset of its Cpu field
+    Field(usize),
+    Immediate(u8),
+}
+
+// x86-64 code for a block, which keeps the Cpu in rbx
+struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    fn new() -> Emitter {
+        // push rbx; mov rbx,rdi
+        Emitter {
+            code: vec![0x53, 0x48, 0x89, 0xfb],
+        }
+    }
+
+    // An instruction on the Cpu field at offset, with reg in the ModRM byte
+    fn field(&mut self, opcode: &[u8], reg: u8, offset: usize) {
+        self.code.extend(opcode);
+        self.code.push(0x83 | reg << 3);
+        self.code.extend((offset as u32).to_le_bytes());
+    }
+
+    fn load(&mut self, reg: u8, offset: usize) {
+        self.field(&[0x8a], reg, offset);
+    }
+
+    fn store(&mut self, reg: u8, offset: usize) {
+        self.field(&[0x88], reg, offset);
+    }
+
+    fn set_byte(&mut self, offset: usize, value: u8) {
+        self.field(&[0xc6], 0, offset);
+        self.code.push(value);
+    }
+
+    fn set_word(&mut self, offset: usize, value: u16) {
+        self.field(&[0x66, 0xc7], 0, offset);
+        self.code.extend(value.to_le_bytes());
+    }
+
+    fn byte_operation(&mut self, operation: u8, offset: usize, value: u8) {
+        self.field(&[0x80], operation, offset);
+        self.code.push(value);
+    }
+
+    fn word_operation(&mut self, operation: u8, offset: usize, value: u8) {
+        self.field(&[0x66, 0x83], operation, offset);
+        self.code.push(value);
+    }
+
+    fn add_cycles(&mut self, offset: usize, cycles: u32) {
+        self.field(&[0x48, 0x81], ADD, offset);
+        self.code.extend(cycles.to_le_bytes());
+    }
+
+    fn not(&mut self, offset: usize) {
+        self.field(&[0xf6], 2, offset);
+    }
+
+    // inc or dec byte [offset]
+    fn increment(&mut self, reg: u8, offset: usize) {
+        self.field(&[0xfe], reg, offset);
+    }
+
+    // Sets CF from the 8080 carry: mov cl,[carry]; shr cl,1
+    fn load_carry(&mut self, layout: &Layout) {
+        self.load(CL, layout.carry);
+        self.code.extend([0xd0, 0xe9]);
+    }
+
+    // The 8080 ANA rule, AC from bit 3 of A or'd with the operand: mov cl,al; or cl,operand;
+    // shr cl,3; and cl,1
+    fn ana_aux_carry(&mut self, layout: &Layout, operand: Operand) {
+        self.code.extend([0x88, 0xc1]);
+        match operand {
+            Operand::Field(offset) => self.field(&[0x0a], CL, offset),
+            Operand::Immediate(value) => self.code.extend([0x80, 0xc9, value]),
+        }
+        self.code.extend([0xc0, 0xe9, 0x03, 0x80, 0xe1, 0x01]);
+        self.store(CL, layout.aux_carry);
+    }
+
+    // Copies the x86 flags into the 8080's. AC comes from AF through lahf, inverted when it
+    // means a borrow.
+    fn store_flags(&mut self, layout: &Layout, carry: bool, aux_carry: Option<bool>) {
+        self.field(&[0x0f, SETS], 0, layout.sign);
+        self.field(&[0x0f, SETZ], 0, layout.zero);
+        self.field(&[0x0f, SETP], 0, layout.parity);
+        if carry {
+            self.field(&[0x0f, SETC], 0, layout.carry);
+        }
+        if let Some(borrow) = aux_carry {
+            // lahf; shr ah,4; and ah,1
+            self.code.extend([0x9f, 0xc0, 0xec, 0x04, 0x80, 0xe4, 0x01]);
+            if borrow {
+                // xor ah,1
+                self.code.extend([0x80, 0xf4, 0x01]);
+            }
+            self.store(AH, layout.aux_carry);
+        }
+    }
+
+    // An ALU operation on A and a register, or an immediate value
+    fn alu(&mut self, layout: &Layout, operation: Alu, operand: Operand) {
+        let a = layout.registers[7];
+        if matches!(operation, Alu::Adc | Alu::Sbb) {
+            self.load_carry(layout);
+        }
+        self.load(AL, a);
+        if operation == Alu::Ana {
+            self.ana_aux_carry(layout, operand);
+        }
+        let (register_opcode, immediate_opcode) = alu_opcodes(operation);
+        match operand {
+            Operand::Field(offset) => self.field(&[register_opcode], AL, offset),
+            Operand::Immediate(value) => self.code.extend([immediate_opcode, value]),
+        }
+        match operation {
+            Alu::Add | Alu::Adc => self.store_flags(layout, true, Some(false)),
+            Alu::Sub | Alu::Sbb | Alu::Cmp => self.store_flags(layout, true, Some(true)),
+            Alu::Ana => self.store_flags(layout, true, None),
+            Alu::Xra | Alu::Ora => {
+                self.store_flags(layout, true, None);
+                self.set_byte(layout.aux_carry, 0);
+            }
+        }
+        if operation != Alu::Cmp {
+            self.store(AL, a);
+        }
+    }
+
+    // execute_opcode(rbx, opcode)
+    fn call(&mut self, opcode: u8) {
+        let function = execute_opcode as extern "C" fn(*mut Cpu, u32) as usize as u64;
+        self.code.extend([0x48, 0x89, 0xdf]);
+        self.code.push(0xbe);
+        self.code.extend((opcode as u32).to_le_bytes());
+        self.code.extend([0x48, 0xb8]);
+        self.code.extend(function.to_le_bytes());
+        self.code.extend([0xff, 0xd0]);
+    }
+
+    // Returns count if the instruction just executed wrote to translated code
+    fn exit_if_written(&mut self, offset: usize, count: u32) {
+        self.byte_operation(CMP, offset, 0);
+        // je over the 7 bytes of exit
+        self.code.extend([0x74, 0x07]);
+        self.exit(count);
+    }
+
+    // mov eax,count; pop rbx; ret
+    fn exit(&mut self, count: u32) {
+        self.code.push(0xb8);
+        self.code.extend(count.to_le_bytes());
+        self.code.extend([0x5b, 0xc3]);
+    }
+}
+
+pub struct Dynarec {
+    buffer: CodeBuffer,
+    // The translated block starting at each address
+    blocks: Vec<Option<BlockFn>>,
+    // The start addresses of the blocks with code in each page
+    page_blocks: Vec<Vec<u16>>,
+    breakpoints: HashSet<u16>,
+    layout: Layout,
+}
+
+// One Dynarec translates the memory of one Cpu. Anything that writes to the Cpu's memory
+// directly rather than by running instructions should call invalidate_all afterwards.
+impl Dynarec {
+    pub fn new() -> Result<Dynarec, EmulatorError> {
+        Ok(Dynarec {
+            buffer: CodeBuffer::new()?,
+            blocks: vec![None; 0x10000],
+            page_blocks: vec![Vec::new(); 256],
+            breakpoints: HashSet::new(),
+            layout: Cpu::layout(),
+        })
+    }
+
+    // Addresses no block runs past, translating everything again if they've changed
+    pub fn set_breakpoints(&mut self, cpu: &mut Cpu, breakpoints: HashSet<u16>) {
+        if breakpoints != self.breakpoints {
+            self.breakpoints = breakpoints;
+            self.invalidate_all(cpu);
+        }
+    }
+
+    // Runs the block at pc, translating it first if needed, or one instruction in the
+    // interpreter if there is no block. Returns the number of instructions executed.
+    pub fn step(&mut self, cpu: &mut Cpu) -> Result<u32, EmulatorError> {
+        // Code may have been written since the last step by the interpreter or an interrupt
+        if cpu.code_written {
+            self.invalidate_written(cpu);
+        }
+        let block = match cpu.needs_interpreter() {
+            true => None,
+            false => match self.blocks[cpu.pc as usize] {
+                Some(entry) => Some(entry),
+                None => self.translate(cpu),
+            },
+        };
+        let executed = match block {
+            Some(entry) => unsafe { entry(cpu as *mut Cpu) },
+            None => {
+                cpu.cycle()?;
+                1
+            }
+        };
+        if cpu.code_written {
+            self.invalidate_written(cpu);
+        }
+        Ok(executed)
+    }
+
+    pub fn invalidate_all(&mut self, cpu: &mut Cpu) {
+        self.blocks.fill(None);
+        self.page_blocks.iter_mut().for_each(Vec::clear);
+        self.buffer.used = 0;
+        cpu.code_pages = [0; 256];
+        cpu.code_written = false;
+    }
+
+    // Removes the blocks on pages that have been written to. A block spanning two pages stays
+    // listed on the other one, which only costs an extra check of its writes.
+    fn invalidate_written(&mut self, cpu: &mut Cpu) {
+        cpu.code_written = false;
+        for (page, state) in cpu.code_pages.iter_mut().enumerate() {
+            if *state == CODE_WRITTEN {
+                *state = 0;
+                for start in self.page_blocks[page].drain(..) {
+                    self.blocks[start as usize] = None;
+                }
+            }
+        }
+    }
+
+    fn translate(&mut self, cpu: &mut Cpu) -> Option<BlockFn> {
+        let start = cpu.pc;
+        let (code, end) = self.compile(cpu, start)?;
+        let entry = match self.buffer.add(&code) {
+            Some(entry) => entry,
+            None => {
+                self.invalidate_all(cpu);
+                self.buffer.add(&code)?
+            }
+        };
+        for page in (start as usize >> 8)..=((end - 1) >> 8) {
+            cpu.code_pages[page] = CODE;
+            self.page_blocks[page].push(start);
+        }
+        self.blocks[start as usize] = Some(entry);
+        Some(entry)
+    }
+
+    // The code of the block at start and the address after its last instruction, or None if
+    // its first instruction has to be interpreted
+    fn compile(&self, cpu: &Cpu, start: u16) -> Option<(Vec<u8>, usize)> {
+        let layout = &self.layout;
+        let registers = &layout.registers;
+        let mut emitter = Emitter::new();
+        let mut address = start as usize;
+        let mut count = 0;
+        // Cycles of native instructions not yet added to the Cpu
+        let mut cycles = 0;
+        // Whether the Cpu's pc is already where the block leaves it
+        let mut pc_set = false;
+
+        while count < MAX_INSTRUCTIONS && address < 0x10000 {
+            if count > 0 && self.breakpoints.contains(&(address as u16)) {
+                break;
+            }
+            let opcode = cpu.memory[address];
+            let op = DECODE[opcode as usize];
+            if UNDOCUMENTED.contains(&opcode) || matches!(op, Op::In | Op::Out | Op::Hlt | Op::Ei | Op::Di) {
+                break;
+            }
+            let length = disassembler::disassemble(&cpu.memory, address as u16).length as usize;
+            if address + length > 0x10000 {
+                break;
+            }
+            let operand = |index: usize| cpu.memory[address + index];
+            let word = || u16::from_le_bytes([operand(1), operand(2)]);
+            count += 1;
+            pc_set = false;
+
+            let native = match op {
+                Op::Nop => true,
+                Op::Mov(destination, source) if destination != M && source != M => {
+                    emitter.load(AL, registers[source as usize]);
+                    emitter.store(AL, registers[destination as usize]);
+                    true
+                }
+                Op::Mvi(register) if register != M => {
+                    emitter.set_byte(registers[register as usize], operand(1));
+                    true
+                }
+                Op::Lxi(SP_OR_PSW) => {
+                    emitter.set_word(layout.sp, word());
+                    true
+                }
+                Op::Lxi(pair) => {
+                    emitter.set_byte(registers[pair as usize * 2], operand(2));
+                    emitter.set_byte(registers[pair as usize * 2 + 1], operand(1));
+                    true
+                }
+                Op::Inx(SP_OR_PSW) | Op::Dcx(SP_OR_PSW) => {
+                    let operation = if matches!(op, Op::Inx(_)) { ADD } else { SUB };
+                    emitter.word_operation(operation, layout.sp, 1);
+                    true
+                }
+                Op::Inx(pair) => {
+                    emitter.byte_operation(ADD, registers[pair as usize * 2 + 1], 1);
+                    emitter.byte_operation(ADC, registers[pair as usize * 2], 0);
+                    true
+                }
+                Op::Dcx(pair) => {
+                    emitter.byte_operation(SUB, registers[pair as usize * 2 + 1], 1);
+                    emitter.byte_operation(SBB, registers[pair as usize * 2], 0);
+                    true
+                }
+                Op::Xchg => {
+                    for (first, second) in [(2, 4), (3, 5)] {
+                        emitter.load(AL, registers[first]);
+                        emitter.load(CL, registers[second]);
+                        emitter.store(CL, registers[first]);
+                        emitter.store(AL, registers[second]);
+                    }
+                    true
+                }
+                Op::Inr(register) | Op::Dcr(register) if register != M => {
+                    let decrement = matches!(op, Op::Dcr(_));
+                    emitter.increment(decrement as u8, registers[register as usize]);
+                    emitter.store_flags(layout, false, Some(decrement));
+                    true
+                }
+                Op::Alu(operation, register) if register != M => {
+                    emitter.alu(layout, operation, Operand::Field(registers[register as usize]));
+                    true
+                }
+                Op::AluImmediate(operation) => {
+                    emitter.alu(layout, operation, Operand::Immediate(operand(1)));
+                    true
+                }
+                Op::Cma => {
+                    emitter.not(registers[7]);
+                    true
+                }
+                Op::Stc => {
+                    emitter.set_byte(layout.carry, 1);
+                    true
+                }
+                Op::Cmc => {
+                    emitter.byte_operation(XOR, layout.carry, 1);
+                    true
+                }
+                _ => false,
+            };
+
+            let ends_block = matches!(
+                op,
+                Op::Jmp(_) | Op::Call(_) | Op::Ret(_) | Op::Rst(_) | Op::Pchl
+            );
+            if native {
+                cycles += CYCLES[opcode as usize] as u32;
+            } else {
+                if cycles != 0 {
+                    emitter.add_cycles(layout.cycles, cycles);
+                    cycles = 0;
+                }
+                emitter.set_word(layout.pc, address as u16);
+                emitter.call(opcode);
+                pc_set = true;
+                if !ends_block {
+                    emitter.exit_if_written(layout.code_written, count);
+                }
+            }
+            address += length;
+            if ends_block {
+                break;
+            }
+        }
+
+        if count == 0 {
+            return None;
+        }
+        if cycles != 0 {
+            emitter.add_cycles(layout.cycles, cycles);
+        }
+        if !pc_set {
+            emitter.set_word(layout.pc, address as u16);
+        }
+        emitter.exit(count);
+        Some((emitter.code, address))
+    }
+}
+
+#[cfg(test)]
+mod dynarec_tests {
+    use super::*;
+    use crate::emulator::assembler;
+
+    // Runs the dynarec and the interpreter side by side, checking they agree after every block
+    fn lockstep(image: Vec<u8>, steps: usize) -> Cpu {
+        let mut dynarec = Dynarec::new().unwrap();
+        let mut translated = Cpu::new(image.clone());
+        let mut interpreted = Cpu::new(image);
+        for step in 0..steps {
+            let executed = dynarec.step(&mut translated).unwrap();
+            for _ in 0..executed {
+                interpreted.cycle().unwrap();
+            }
+            assert_eq!(translated.state(), interpreted.state(), "step {}", step);
+            assert!(translated.memory == interpreted.memory, "memory differs at step {}", step);
+        }
+        translated
+    }
+
+    fn assemble(source: &str) -> Vec<u8> {
+        assembler::assemble(source).unwrap().image
+    }
+
+    #[test]
+    fn programs_match_the_interpreter() {
+        // Arithmetic, native moves and pairs, and a subroutine
+        let image = assemble(
+            "        lxi sp,8000h\n\
+             Loop:   mvi b,3\n\
+                     lxi h,1234h\n\
+                     lxi d,00ffh\n\
+             Inner:  mov a,b\n\
+                     add l\n\
+                     daa\n\
+                     mov c,a\n\
+                     inx d\n\
+                     dcx h\n\
+                     xchg\n\
+                     cma\n\
+                     stc\n\
+                     cmc\n\
+                     call Store\n\
+                     dcr b\n\
+                     jnz Inner\n\
+                     inx sp\n\
+                     dcx sp\n\
+                     jmp Loop\n\
+             Store:  push psw\n\
+                     mov m,a\n\
+                     pop psw\n\
+                     ret\n",
+        );
+        lockstep(image, 500);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // Patches the MVI later in the same block, then the JMP of another block
+        let image = assemble(
+            "Start:  lxi h,Patch+1\n\
+                     inr m\n\
+             Patch:  mvi a,0\n\
+                     mov b,a\n\
+                     lxi h,Next+1\n\
+                     mvi m,LOW Done\n\
+                     nop\n\
+             Next:   jmp Start\n\
+             Done:   jmp Start\n",
+        );
+        let cpu = lockstep(image, 40);
+        assert_ne!(cpu.peek(0x0005), 0);
+    }
+
+    #[test]
+    fn random_programs() {
+        let mut seed: u64 = 0x2545f4914f6cdd1d;
+        for _ in 0..20 {
+            let mut image = vec![0; 0x400];
+            for byte in &mut image {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                *byte = seed as u8;
+            }
+            lockstep(image, 2000);
+        }
+    }
+
+    #[test]
+    fn blocks_stop_at_breakpoints() {
+        let mut dynarec = Dynarec::new().unwrap();
+        let mut cpu = Cpu::new(assemble("nop\nnop\nnop\nnop\njmp 0\n"));
+        assert_eq!(dynarec.step(&mut cpu).unwrap(), 5);
+        dynarec.set_breakpoints(&mut cpu, HashSet::from([0x0003]));
+        assert_eq!(dynarec.step(&mut cpu).unwrap(), 3);
+        assert_eq!(cpu.pc, 0x0003);
+        assert_eq!(dynarec.step(&mut cpu).unwrap(), 2);
+    }
+
+    #[test]
+    fn code_written_between_steps() {
+        // The interpreter, or an interrupt, patching a translated block before it runs again
+        let mut dynarec = Dynarec::new().unwrap();
+        let mut cpu = Cpu::new(assemble("Start:  mvi a,1\n        jmp Start\n        mvi m,2\n"));
+        dynarec.step(&mut cpu).unwrap();
+        cpu.set_register(crate::emulator::cpu::Register::Hl, 0x0001);
+        cpu.pc = 0x0005;
+        cpu.cycle().unwrap();
+        cpu.pc = 0x0000;
+        dynarec.step(&mut cpu).unwrap();
+        assert_eq!(cpu.state().a, 2);
+    }
+
+    #[test]
+    fn watchpoints_are_checked() {
+        use crate::emulator::watchpoint::Watchpoint;
+        let mut dynarec = Dynarec::new().unwrap();
+        let mut cpu = Cpu::new(assemble("lxi h,2000h\nmvi m,1\nnop\njmp 0\n"));
+        cpu.add_watchpoint(Watchpoint::write(0x2000));
+        dynarec.step(&mut cpu).unwrap();
+        assert!(cpu.take_watch_hit().is_none());
+        dynarec.step(&mut cpu).unwrap();
+        assert_eq!(cpu.take_watch_hit().unwrap().pc, 0x0003);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{error::Error, fmt, io, path::PathBuf};
+
+use super::{assembler::AssemblyError, cpu::CpuState, rom::RomError};
+
+#[derive(Debug)]
+pub enum EmulatorError {
+    // Missing or invalid command line arguments
+    Usage(String),
+    // A file that couldn't be read or written
+    File { path: PathBuf, error: io::Error },
+    // A file that was read but isn't valid in its format
+    Format { path: PathBuf, message: String },
+    Assembly(AssemblyError),
+    Rom(RomError),
+    // Traces and debugger connections
+    Io(io::Error),
+    Sdl(String),
+    // An undocumented opcode at state.pc in strict mode. Nothing has been executed, so the
+    // caller can report it, or change pc or memory and carry on.
+    IllegalOpcode { opcode: u8, state: CpuState },
+    // A check that ran to the end and failed, like test vectors or a trace comparison. The
+    // details have already been printed.
+    Failed(String),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::Usage(message) => write!(f, "{}", message),
+            EmulatorError::File { path, error } => write!(f, "{}: {}", path.display(), error),
+            EmulatorError::Format { path, message } => write!(f, "{}: {}", path.display(), message),
+            EmulatorError::Assembly(error) => write!(f, "{}", error),
+            EmulatorError::Rom(error) => write!(f, "{}", error),
+            EmulatorError::Io(error) => write!(f, "{}", error),
+            EmulatorError::Sdl(message) => write!(f, "SDL: {}", message),
+            EmulatorError::IllegalOpcode { opcode, state } => write!(
+                f,
+                "Illegal opcode {:02X} at {:04X}\n{}",
+                opcode, state.pc, state
+            ),
+            EmulatorError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EmulatorError::File { error, .. } | EmulatorError::Io(error) => Some(error),
+            EmulatorError::Assembly(error) => Some(error),
+            EmulatorError::Rom(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for EmulatorError {
+    fn from(error: io::Error) -> EmulatorError {
+        EmulatorError::Io(error)
+    }
+}
+
+impl From<AssemblyError> for EmulatorError {
+    fn from(error: AssemblyError) -> EmulatorError {
+        EmulatorError::Assembly(error)
+    }
+}
+
+impl From<RomError> for EmulatorError {
+    fn from(error: RomError) -> EmulatorError {
+        EmulatorError::Rom(error)
+    }
+}
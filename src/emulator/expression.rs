@@ -0,0 +1,435 @@
+use std::fmt;
+
+use super::cpu::{Cpu, Flag, Register};
+use super::symbols::SymbolTable;
+
+/*
+Expressions are parsed once into a tree and evaluated against the cpu state after every
+instruction. Values are integers, comparisons and logical operators give 1 or 0 and any
+non zero value counts as true.
+
+    pc == 0x0A93 && a > 10h && [hl] == 0
+
+Operands:
+    numbers     42, 0x2A, $2A, 2Ah
+    registers   a b c d e h l
+    pairs       bc de hl sp pc
+    flags       z s p cy ac
+    memory      [expr] reads the byte at the address
+    hits        times the breakpoint has been reached, including this time
+    labels      any name from the symbol table, e.g. pc == DrawSprite
+
+Operators, from lowest to highest precedence:
+    ||  &&  |  ^  &  == !=  < <= > >=  << >>  + -  * / %  unary ! ~ -
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression {
+    Number(i64),
+    Register(Register),
+    Flag(Flag),
+    Hits,
+    Memory(Box<Expression>),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Expression, ParseError> {
+        Expression::parse_with_symbols(input, &SymbolTable::new())
+    }
+
+    // Names that aren't registers, flags or hits are looked up in the symbol table
+    pub fn parse_with_symbols(input: &str, symbols: &SymbolTable) -> Result<Expression, ParseError> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            index: 0,
+            end: input.len(),
+            symbols,
+        };
+        let expression = parser.parse_binary(0)?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some((position, token)) => Err(ParseError {
+                position,
+                message: format!("Unexpected '{}'", token),
+            }),
+        }
+    }
+
+    pub fn evaluate(&self, cpu: &Cpu, hits: u32) -> i64 {
+        match self {
+            Expression::Number(value) => *value,
+            Expression::Register(register) => cpu.register(*register) as i64,
+            Expression::Flag(flag) => cpu.flag(*flag) as i64,
+            Expression::Hits => hits as i64,
+            Expression::Memory(address) => cpu.peek(address.evaluate(cpu, hits) as u16) as i64,
+            Expression::Unary(op, operand) => {
+                let value = operand.evaluate(cpu, hits);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                }
+            }
+            Expression::Binary(BinaryOp::Or, left, right) => {
+                (left.evaluate(cpu, hits) != 0 || right.evaluate(cpu, hits) != 0) as i64
+            }
+            Expression::Binary(BinaryOp::And, left, right) => {
+                (left.evaluate(cpu, hits) != 0 && right.evaluate(cpu, hits) != 0) as i64
+            }
+            Expression::Binary(op, left, right) => {
+                let left = left.evaluate(cpu, hits);
+                let right = right.evaluate(cpu, hits);
+                match op {
+                    BinaryOp::BitOr => left | right,
+                    BinaryOp::BitXor => left ^ right,
+                    BinaryOp::BitAnd => left & right,
+                    BinaryOp::Equal => (left == right) as i64,
+                    BinaryOp::NotEqual => (left != right) as i64,
+                    BinaryOp::Less => (left < right) as i64,
+                    BinaryOp::LessEqual => (left <= right) as i64,
+                    BinaryOp::Greater => (left > right) as i64,
+                    BinaryOp::GreaterEqual => (left >= right) as i64,
+                    BinaryOp::ShiftLeft => left.wrapping_shl(right as u32),
+                    BinaryOp::ShiftRight => left.wrapping_shr(right as u32),
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Subtract => left.wrapping_sub(right),
+                    BinaryOp::Multiply => left.wrapping_mul(right),
+                    BinaryOp::Divide => left.checked_div(right).unwrap_or(0),
+                    BinaryOp::Remainder => left.checked_rem(right).unwrap_or(0),
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, cpu: &Cpu, hits: u32) -> bool {
+        self.evaluate(cpu, hits) != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Operator(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "{}", value),
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::Operator(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+// Longer operators first so "<=" isn't read as "<" followed by "="
+const OPERATORS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while position < input.len() {
+        let rest = &input[position..];
+        let next = rest.chars().next().unwrap();
+        if next.is_whitespace() {
+            position += next.len_utf8();
+        } else if next.is_ascii_digit() || next == '$' {
+            let length = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| !c.is_ascii_alphanumeric())
+                .map_or(rest.len(), |(index, _)| index);
+            let value = parse_number(&rest[..length]).ok_or_else(|| ParseError {
+                position,
+                message: format!("Invalid number '{}'", &rest[..length]),
+            })?;
+            tokens.push((position, Token::Number(value)));
+            position += length;
+        } else if next.is_ascii_alphabetic() || next == '_' {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push((position, Token::Identifier(rest[..length].to_string())));
+            position += length;
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push((position, Token::Operator(op)));
+            position += op.len();
+        } else {
+            return Err(ParseError {
+                position,
+                message: format!("Unexpected '{}'", next),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = lower.strip_suffix('h') {
+        i64::from_str_radix(hex, 16).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn parse_identifier(name: &str) -> Option<Expression> {
+    let expression = match name.to_ascii_lowercase().as_str() {
+        "a" => Expression::Register(Register::A),
+        "b" => Expression::Register(Register::B),
+        "c" => Expression::Register(Register::C),
+        "d" => Expression::Register(Register::D),
+        "e" => Expression::Register(Register::E),
+        "h" => Expression::Register(Register::H),
+        "l" => Expression::Register(Register::L),
+        "bc" => Expression::Register(Register::Bc),
+        "de" => Expression::Register(Register::De),
+        "hl" => Expression::Register(Register::Hl),
+        "sp" => Expression::Register(Register::Sp),
+        "pc" => Expression::Register(Register::Pc),
+        "z" => Expression::Flag(Flag::Z),
+        "s" => Expression::Flag(Flag::S),
+        "p" => Expression::Flag(Flag::P),
+        "cy" => Expression::Flag(Flag::Cy),
+        "ac" => Expression::Flag(Flag::Ac),
+        "hits" => Expression::Hits,
+        _ => return None,
+    };
+    Some(expression)
+}
+
+// Binary operators grouped by precedence, lowest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Equal), ("!=", BinaryOp::NotEqual)],
+    &[
+        ("<", BinaryOp::Less),
+        ("<=", BinaryOp::LessEqual),
+        (">", BinaryOp::Greater),
+        (">=", BinaryOp::GreaterEqual),
+    ],
+    &[("<<", BinaryOp::ShiftLeft), (">>", BinaryOp::ShiftRight)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+    &[
+        ("*", BinaryOp::Multiply),
+        ("/", BinaryOp::Divide),
+        ("%", BinaryOp::Remainder),
+    ],
+];
+
+struct Parser<'a> {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    end: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<(usize, &Token)> {
+        self.tokens
+            .get(self.index)
+            .map(|(position, token)| (*position, token))
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), ParseError> {
+        let token = self.tokens.get(self.index).cloned().ok_or(ParseError {
+            position: self.end,
+            message: "Unexpected end of expression".to_string(),
+        })?;
+        self.index += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ParseError> {
+        match self.next()? {
+            (_, Token::Operator(found)) if found == op => Ok(()),
+            (position, token) => Err(ParseError {
+                position,
+                message: format!("Expected '{}' but found '{}'", op, token),
+            }),
+        }
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expression, ParseError> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut left = self.parse_binary(level + 1)?;
+        while let Some((_, Token::Operator(found))) = self.peek() {
+            let op = match PRECEDENCE[level].iter().find(|(symbol, _)| symbol == found) {
+                Some((_, op)) => *op,
+                None => break,
+            };
+            self.index += 1;
+            let right = self.parse_binary(level + 1)?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, ParseError> {
+        let op = match self.peek() {
+            Some((_, Token::Operator("!"))) => UnaryOp::Not,
+            Some((_, Token::Operator("~"))) => UnaryOp::Complement,
+            Some((_, Token::Operator("-"))) => UnaryOp::Negate,
+            _ => return self.parse_primary(),
+        };
+        self.index += 1;
+        Ok(Expression::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+        match self.next()? {
+            (_, Token::Number(value)) => Ok(Expression::Number(value)),
+            (position, Token::Identifier(name)) => parse_identifier(&name)
+                .or_else(|| {
+                    self.symbols
+                        .address(&name)
+                        .map(|address| Expression::Number(address as i64))
+                })
+                .ok_or(ParseError {
+                    position,
+                    message: format!("Unknown name '{}'", name),
+                }),
+            (_, Token::Operator("(")) => {
+                let expression = self.parse_binary(0)?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            (_, Token::Operator("[")) => {
+                let address = self.parse_binary(0)?;
+                self.expect("]")?;
+                Ok(Expression::Memory(Box::new(address)))
+            }
+            (position, token) => Err(ParseError {
+                position,
+                message: format!("Unexpected '{}'", token),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod expression_tests {
+    use super::*;
+
+    // MVI A,20h / LXI H,2000h / MVI M,07h
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new(vec![0x3e, 0x20, 0x21, 0x00, 0x20, 0x36, 0x07]);
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+        cpu
+    }
+
+    fn evaluate(input: &str) -> i64 {
+        Expression::parse(input).unwrap().evaluate(&cpu(), 3)
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(evaluate("42"), 42);
+        assert_eq!(evaluate("0x2A"), 42);
+        assert_eq!(evaluate("$2a"), 42);
+        assert_eq!(evaluate("2Ah"), 42);
+        assert_eq!(evaluate("0FFh"), 255);
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9);
+        assert_eq!(evaluate("1 | 2 == 2"), 1);
+        assert_eq!(evaluate("-2 + 5"), 3);
+        assert_eq!(evaluate("!0 && ~0 == -1"), 1);
+        assert_eq!(evaluate("1 << 4 >> 2"), 4);
+        assert_eq!(evaluate("7 / 0"), 0);
+    }
+
+    #[test]
+    fn cpu_state() {
+        assert_eq!(evaluate("a"), 0x20);
+        assert_eq!(evaluate("hl"), 0x2000);
+        assert_eq!(evaluate("pc == 7 && a > 0x10 && [hl] == 7"), 1);
+        assert_eq!(evaluate("[hl + 1]"), 0);
+        assert_eq!(evaluate("z"), 1);
+        assert_eq!(evaluate("hits >= 3"), 1);
+    }
+
+    #[test]
+    fn symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("Done", 0x0007);
+        symbols.insert("Buffer", 0x2000);
+        let expression = Expression::parse_with_symbols("pc == Done && hl == Buffer", &symbols);
+        assert_eq!(expression.unwrap().evaluate(&cpu(), 0), 1);
+        assert!(Expression::parse("pc == Done").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Expression::parse("a +").unwrap_err().position, 3);
+        assert_eq!(Expression::parse("a + q").unwrap_err().position, 4);
+        assert_eq!(Expression::parse("[hl").unwrap_err().position, 3);
+        assert_eq!(Expression::parse("1 2").unwrap_err().position, 2);
+        assert_eq!(Expression::parse("a # 1").unwrap_err().position, 2);
+        assert_eq!(Expression::parse("12xy").unwrap_err().position, 0);
+    }
+}
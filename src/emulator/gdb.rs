@@ -0,0 +1,574 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use super::cpu::{Cpu, Model, Register};
+use super::{Program, Runner};
+use super::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+/*
+A GDB remote serial protocol stub. The registers are sent in this order, 16 bit ones little
+endian as on the 8080:
+
+    0 A, 1 F, 2 BC, 3 DE, 4 HL, 5 SP, 6 PC
+
+F uses the PSW layout (S Z 0 AC 0 P 1 CY). GDB has no 8080 support, so the register layout is
+also described by target.xml for clients that read it.
+*/
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.i8080.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="f" bitsize="8" type="uint8"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// How many instructions run between checks for an interrupt from the client
+const INTERRUPT_CHECK_INTERVAL: u32 = 10000;
+
+// The largest packet the stub accepts or sends, as reported in qSupported
+const PACKET_SIZE: usize = 0x1000;
+
+// Sets up the board of the identified machine, with its processor unless a model is given, and
+// fires its video interrupts while the cpu runs
+pub fn serve(program: Program, port: u16, model: Option<Model>) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on 127.0.0.1:{}", port);
+    let (stream, address) = listener.accept()?;
+    println!("GDB connected from {}", address);
+
+    let mut connection = Connection::new(stream)?;
+    let (cpu, runner) = program.machine_runner(model).map_err(|error| io::Error::other(error.to_string()))?;
+    let mut stub = GdbStub::with_runner(cpu, runner);
+    while let Some(packet) = connection.read_packet()? {
+        match stub.handle(&packet, &mut || connection.interrupted()) {
+            Action::Reply(reply) => connection.write_packet(&reply)?,
+            Action::ReplyAndClose(reply) => {
+                connection.write_packet(&reply)?;
+                break;
+            }
+            Action::Close => break,
+        }
+    }
+    println!("GDB disconnected");
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Reply(String),
+    ReplyAndClose(String),
+    Close,
+}
+
+pub struct GdbStub {
+    cpu: Cpu,
+    runner: Option<Runner>,
+    breakpoints: HashSet<u16>,
+    last_stop: String,
+}
+
+impl GdbStub {
+    pub fn new(cpu: Cpu) -> GdbStub {
+        GdbStub {
+            cpu,
+            runner: None,
+            breakpoints: HashSet::new(),
+            last_stop: "S05".to_string(),
+        }
+    }
+
+    // Runs the cpu through the runner, which takes the board's interrupts between instructions
+    pub fn with_runner(cpu: Cpu, runner: Runner) -> GdbStub {
+        GdbStub {
+            runner: Some(runner),
+            ..GdbStub::new(cpu)
+        }
+    }
+
+    // Handles one packet. `interrupted` is polled while the cpu is running and returns true
+    // once the client has asked to stop.
+    pub fn handle(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> Action {
+        let (command, arguments) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(arguments),
+            "p" => self.read_register(arguments),
+            "P" => self.write_register(arguments),
+            "m" => self.read_memory(arguments),
+            "M" => self.write_memory(arguments),
+            "s" => self.resume(arguments, true, interrupted),
+            "c" => self.resume(arguments, false, interrupted),
+            "Z" => self.set_breakpoint(arguments, true),
+            "z" => self.set_breakpoint(arguments, false),
+            "q" => self.query(arguments),
+            "H" | "T" => "OK".to_string(),
+            "D" => return Action::ReplyAndClose("OK".to_string()),
+            "k" => return Action::Close,
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    fn registers(&self) -> [u16; 7] {
+        [
+            self.cpu.register(Register::A),
+            self.cpu.flags() as u16,
+            self.cpu.register(Register::Bc),
+            self.cpu.register(Register::De),
+            self.cpu.register(Register::Hl),
+            self.cpu.register(Register::Sp),
+            self.cpu.register(Register::Pc),
+        ]
+    }
+
+    fn set_register(&mut self, number: usize, value: u16) {
+        match number {
+            0 => self.cpu.set_register(Register::A, value),
+            1 => self.cpu.set_flags(value as u8),
+            2 => self.cpu.set_register(Register::Bc, value),
+            3 => self.cpu.set_register(Register::De, value),
+            4 => self.cpu.set_register(Register::Hl, value),
+            5 => self.cpu.set_register(Register::Sp, value),
+            _ => self.cpu.set_register(Register::Pc, value),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        self.registers()
+            .iter()
+            .enumerate()
+            .map(|(number, value)| encode_register(number, *value))
+            .collect()
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> String {
+        let mut offset = 0;
+        for number in 0..7 {
+            let width = register_width(number) * 2;
+            match arguments.get(offset..offset + width).and_then(decode_hex) {
+                Some(bytes) => self.set_register(number, from_little_endian(&bytes)),
+                None => return "E01".to_string(),
+            }
+            offset += width;
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, arguments: &str) -> String {
+        match usize::from_str_radix(arguments, 16) {
+            Ok(number) if number < 7 => encode_register(number, self.registers()[number]),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, arguments: &str) -> String {
+        let parsed = arguments.split_once('=').and_then(|(number, value)| {
+            let number = usize::from_str_radix(number, 16).ok()?;
+            Some((number, decode_hex(value)?))
+        });
+        match parsed {
+            Some((number, bytes)) if number < 7 && bytes.len() == register_width(number) => {
+                self.set_register(number, from_little_endian(&bytes));
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // At most half a packet, as each byte is sent as two hex digits
+    fn read_memory(&self, arguments: &str) -> String {
+        match parse_address_length(arguments) {
+            Some((address, length)) if length <= PACKET_SIZE / 2 => (0..length)
+                .map(|offset| format!("{:02x}", self.cpu.peek(address.wrapping_add(offset as u16))))
+                .collect(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str) -> String {
+        let parsed = arguments.split_once(':').and_then(|(range, data)| {
+            Some((parse_address_length(range)?, decode_hex(data)?))
+        });
+        match parsed {
+            Some(((address, length), bytes)) if bytes.len() == length => {
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    self.cpu.memory[address.wrapping_add(offset as u16) as usize] = byte;
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2 write, Z3 read and Z4 access watchpoints
+    fn set_breakpoint(&mut self, arguments: &str, insert: bool) -> String {
+        let mut fields = arguments.split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(|field| u16::from_str_radix(field, 16).ok());
+        let length = fields
+            .next()
+            .and_then(|field| u16::from_str_radix(field, 16).ok())
+            .unwrap_or(1)
+            .max(1);
+        let address = match address {
+            Some(address) => address,
+            None => return "E01".to_string(),
+        };
+        let watch_kind = match kind {
+            Some("0") | Some("1") => {
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let watchpoint = Watchpoint::new(watch_kind, address, address.saturating_add(length - 1));
+        if insert {
+            self.cpu.add_watchpoint(watchpoint);
+        } else {
+            self.cpu.remove_watchpoint(&watchpoint);
+        }
+        "OK".to_string()
+    }
+
+    fn resume(
+        &mut self,
+        arguments: &str,
+        step: bool,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> String {
+        if let Ok(address) = u16::from_str_radix(arguments, 16) {
+            self.cpu.set_register(Register::Pc, address);
+        }
+        self.cpu.enable = 1;
+        let mut count: u32 = 0;
+        self.last_stop = loop {
+            // SIGILL for opcodes the CPU doesn't implement, with pc left on them
+            let result = match &mut self.runner {
+                Some(runner) => runner.run(&mut self.cpu, true).map(|_| ()),
+                None => self.cpu.cycle(),
+            };
+            if result.is_err() {
+                break "S04".to_string();
+            }
+            if let Some(hit) = self.cpu.take_watch_hit() {
+                break watch_reply(&hit);
+            }
+            if step || self.cpu.enable == 0 {
+                break "S05".to_string();
+            }
+            if self.breakpoints.contains(&self.cpu.register(Register::Pc)) {
+                break "T05swbreak:;".to_string();
+            }
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && interrupted() {
+                break "S02".to_string();
+            }
+        };
+        self.cpu.enable = 0;
+        self.last_stop.clone()
+    }
+
+    fn query(&self, arguments: &str) -> String {
+        if arguments.starts_with("Supported") {
+            format!("PacketSize={:x};qXfer:features:read+;swbreak+", PACKET_SIZE)
+        } else if let Some(range) = arguments.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + length).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else if arguments == "Attached" {
+            "1".to_string()
+        } else if arguments == "C" {
+            "QC1".to_string()
+        } else if arguments == "fThreadInfo" {
+            "m1".to_string()
+        } else if arguments == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
+fn watch_reply(hit: &WatchHit) -> String {
+    let reason = match hit.kind {
+        WatchKind::Read => "rwatch",
+        WatchKind::Access => "awatch",
+        WatchKind::Write | WatchKind::Change => "watch",
+    };
+    format!("T05{}:{:04x};", reason, hit.address)
+}
+
+fn register_width(number: usize) -> usize {
+    if number < 2 {
+        1
+    } else {
+        2
+    }
+}
+
+fn encode_register(number: usize, value: u16) -> String {
+    if register_width(number) == 1 {
+        format!("{:02x}", value as u8)
+    } else {
+        format!("{:02x}{:02x}", value as u8, value >> 8)
+    }
+}
+
+fn from_little_endian(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u16)
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// "addr,length" as used by the m, M and qXfer packets
+fn parse_address_length(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Packet framing: $data#checksum, acknowledged with + or -
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        Ok(Connection {
+            writer: stream.try_clone()?,
+            reader: BufReader::new(stream),
+        })
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Returns None once the client has closed the connection
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks and interrupts sent while the cpu was already stopped
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    // Escaped byte in binary data
+                    Some(b'}') => match self.read_byte()? {
+                        None => return Ok(None),
+                        Some(byte) => data.push(byte ^ 0x20),
+                    },
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected != Some(checksum(&data)) {
+                self.writer.write_all(b"-")?;
+                continue;
+            }
+            self.writer.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+            // Resend until the client acknowledges it
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // True if the client sent an interrupt (0x03) while the cpu was running
+    fn interrupted(&mut self) -> bool {
+        if self.reader.buffer().is_empty() {
+            if self.reader.get_ref().set_nonblocking(true).is_err() {
+                return false;
+            }
+            let filled = self.reader.fill_buf().map(|buffer| !buffer.is_empty());
+            let _ = self.reader.get_ref().set_nonblocking(false);
+            if !matches!(filled, Ok(true)) {
+                return false;
+            }
+        }
+        if self.reader.buffer()[0] == 0x03 {
+            self.reader.consume(1);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod gdb_tests {
+    use super::*;
+
+    // LXI H,2000h / MVI M,07h / INR A / JMP 0005h
+    const PROGRAM: [u8; 10] = [0x21, 0x00, 0x20, 0x36, 0x07, 0x3c, 0xc3, 0x05, 0x00, 0x00];
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet, &mut || false) {
+            Action::Reply(reply) => reply,
+            action => panic!("Unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut stub = GdbStub::new(Cpu::new(PROGRAM.to_vec()));
+        assert_eq!(reply(&mut stub, "g"), "0042000000000000feff0000");
+        assert_eq!(reply(&mut stub, "P6=0300"), "OK");
+        assert_eq!(reply(&mut stub, "p6"), "0300");
+        assert_eq!(reply(&mut stub, "G11d7223344556677880000"), "E01");
+        assert_eq!(reply(&mut stub, "G11d72233445566778899aa00"), "OK");
+        assert_eq!(reply(&mut stub, "p0"), "11");
+        assert_eq!(reply(&mut stub, "p1"), "d7");
+        assert_eq!(reply(&mut stub, "p2"), "2233");
+        assert_eq!(reply(&mut stub, "p6"), "aa00");
+        assert_eq!(reply(&mut stub, "p7"), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let mut stub = GdbStub::new(Cpu::new(PROGRAM.to_vec()));
+        assert_eq!(reply(&mut stub, "m0,3"), "210020");
+        assert_eq!(reply(&mut stub, "M2000,2:abcd"), "OK");
+        assert_eq!(reply(&mut stub, "m1fff,4"), "00abcd00");
+        assert_eq!(reply(&mut stub, "M2000,2:ab"), "E01");
+        assert_eq!(reply(&mut stub, "m0,800").len(), 0x1000);
+        assert_eq!(reply(&mut stub, "m0,801"), "E01");
+        assert_eq!(reply(&mut stub, "m0,ffffffff"), "E01");
+    }
+
+    #[test]
+    fn step_and_breakpoints() {
+        let mut stub = GdbStub::new(Cpu::new(PROGRAM.to_vec()));
+        assert_eq!(reply(&mut stub, "s"), "S05");
+        assert_eq!(reply(&mut stub, "p6"), "0300");
+        assert_eq!(reply(&mut stub, "Z0,6,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(reply(&mut stub, "p6"), "0600");
+        assert_eq!(reply(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(reply(&mut stub, "p0"), "02");
+        assert_eq!(reply(&mut stub, "z0,6,1"), "OK");
+        assert_eq!(reply(&mut stub, "?"), "T05swbreak:;");
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut stub = GdbStub::new(Cpu::new(PROGRAM.to_vec()));
+        assert_eq!(reply(&mut stub, "Z2,2000,1"), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05watch:2000;");
+        assert_eq!(reply(&mut stub, "p6"), "0500");
+        assert_eq!(reply(&mut stub, "z2,2000,1"), "OK");
+    }
+
+    #[test]
+    fn video_interrupts() {
+        // Waits for the RST 1 handler to set 2000h, as games wait for the screen
+        let program = "
+        jmp Start
+        org 8
+        mvi a,1
+        sta 2000h
+        ei
+        ret
+Start:  lxi sp,2400h
+        ei
+Wait:   lda 2000h
+        ora a
+        jz Wait
+Done:   jmp Done
+";
+        let image = crate::emulator::assembler::assemble(program).unwrap().image;
+        let done = image.len() as u16 - 3;
+        let mut cpu = Cpu::new(image);
+        cpu.set_io(crate::emulator::io::Io::board(crate::emulator::rom::Machine::find("invaders").unwrap().config));
+        let runner = Runner::new(&cpu, true).unwrap();
+        let mut stub = GdbStub::with_runner(cpu, runner);
+        assert_eq!(reply(&mut stub, &format!("Z0,{:x},1", done)), "OK");
+        assert_eq!(reply(&mut stub, "c"), "T05swbreak:;");
+        assert_eq!(stub.cpu.register(Register::Pc), done);
+        assert!(stub.cpu.cycles() >= super::super::CYCLES_PER_FRAME / 2);
+    }
+
+    #[test]
+    fn interrupt() {
+        // JMP 0000h
+        let mut stub = GdbStub::new(Cpu::new(vec![0xc3, 0x00, 0x00]));
+        assert_eq!(stub.handle("c", &mut || true), Action::Reply("S02".to_string()));
+    }
+
+    #[test]
+    fn queries() {
+        let mut stub = GdbStub::new(Cpu::new(PROGRAM.to_vec()));
+        assert!(reply(&mut stub, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert!(reply(&mut stub, "qXfer:features:read:target.xml:0,20").starts_with("m<?xml"));
+        assert_eq!(reply(&mut stub, "qXfer:features:read:target.xml:1000,20"), "l");
+        assert_eq!(reply(&mut stub, "vMustReplyEmpty"), "");
+        assert_eq!(stub.handle("D", &mut || false), Action::ReplyAndClose("OK".to_string()));
+        assert_eq!(stub.handle("k", &mut || false), Action::Close);
+        assert_eq!(checksum(b"OK"), 0x9a);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{error::Error, fmt};
+
+use super::Program;
+
+/*
+Intel HEX files hold one record per line:
+
+    :LLAAAATTDD...CC
+
+LL is the number of data bytes, AAAA the address, TT the record type and CC a checksum that
+makes all the bytes of the record add up to 0. The record types used by 8080 toolchains are
+
+    00  data at AAAA
+    01  end of file
+    02  extended segment address, only 0 fits in 64K
+    03  start segment address, the start address is segment * 16 + offset
+    04  extended linear address, only 0 fits in 64K
+    05  start linear address
+*/
+
+// Data bytes per record when writing
+const RECORD_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for HexError {}
+
+// Loads the data records into an image starting at address 0, with gaps filled with zeros
+pub fn parse(text: &str) -> Result<Program, HexError> {
+    let mut image = Vec::new();
+    let mut start = None;
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: String| HexError {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_record(line).map_err(error)?;
+        let [length, address_high, address_low, kind] = [record[0], record[1], record[2], record[3]];
+        let address = u16::from_be_bytes([address_high, address_low]) as usize;
+        let data = &record[4..4 + length as usize];
+        match kind {
+            0x00 => {
+                let end = address + data.len();
+                if end > 0x10000 {
+                    return Err(error(format!("Data at {:04X} runs past FFFFh", address)));
+                }
+                if image.len() < end {
+                    image.resize(end, 0);
+                }
+                image[address..end].copy_from_slice(data);
+            }
+            0x01 => break,
+            0x02 | 0x04 => {
+                if data.iter().any(|byte| *byte != 0) {
+                    return Err(error("Addresses above FFFFh aren't supported".to_string()));
+                }
+            }
+            0x03 | 0x05 if data.len() == 4 => {
+                let value = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                let value = if kind == 0x03 {
+                    (value >> 16) * 16 + (value & 0xffff)
+                } else {
+                    value
+                };
+                if value > 0xffff {
+                    return Err(error(format!("Start address {:X}h is above FFFFh", value)));
+                }
+                start = Some(value as u16);
+            }
+            0x03 | 0x05 => return Err(error("Start address records have 4 bytes".to_string())),
+            _ => return Err(error(format!("Unknown record type {:02X}", kind))),
+        }
+    }
+    Ok(Program { image, start })
+}
+
+// The bytes of a record after the ':' with the checksum checked and removed
+fn parse_record(line: &str) -> Result<Vec<u8>, String> {
+    let digits = line
+        .strip_prefix(':')
+        .ok_or_else(|| "Records start with ':'".to_string())?;
+    if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Invalid hex digits".to_string());
+    }
+    let mut bytes: Vec<u8> = (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).unwrap_or(0))
+        .collect();
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err("Record length doesn't match its byte count".to_string());
+    }
+    if checksum(&bytes) != 0 {
+        return Err(format!(
+            "Checksum is {:02X}, expected {:02X}",
+            bytes[bytes.len() - 1],
+            checksum(&bytes[..bytes.len() - 1])
+        ));
+    }
+    bytes.pop();
+    Ok(bytes)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
+
+fn record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    bytes.push(checksum(&bytes));
+    let digits: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(":{}\n", digits)
+}
+
+// Writes memory[start..=end] as data records, then the start address if there is one. An empty
+// range gives no data records.
+pub fn write(memory: &[u8], start: u16, end: u16, entry: Option<u16>) -> String {
+    let mut text = String::new();
+    let end = (end as usize + 1).min(memory.len());
+    let mut address = start as usize;
+    while address < end {
+        let length = RECORD_LENGTH.min(end - address);
+        text.push_str(&record(0x00, address as u16, &memory[address..address + length]));
+        address += length;
+    }
+    if let Some(entry) = entry {
+        text.push_str(&record(0x03, 0, &(entry as u32).to_be_bytes()));
+    }
+    text.push_str(&record(0x01, 0, &[]));
+    text
+}
+
+#[cfg(test)]
+mod intel_hex_tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse(text).err().unwrap().message
+    }
+
+    #[test]
+    fn load() {
+        let program = parse(
+            ":03001000210024A8\n\
+             \n\
+             :010000007689\n\
+             :020000020000FC\n\
+             :0400000300100010D9\n\
+             :00000001FF\n\
+             :0100200076FF\n",
+        )
+        .unwrap();
+        let mut image = vec![0x76];
+        image.resize(0x10, 0);
+        image.extend([0x21, 0x00, 0x24]);
+        assert_eq!(program.image, image);
+        // Segment 0010h, offset 0010h
+        assert_eq!(program.start, Some(0x0110));
+        assert_eq!(parse(":04000005000018D40B\n").unwrap().start, Some(0x18d4));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error(":0100000076FF"), "Checksum is FF, expected 89");
+        assert_eq!(error("0100000076FF"), "Records start with ':'");
+        assert_eq!(error(":01000000"), "Record length doesn't match its byte count");
+        assert_eq!(error(":01000000G689"), "Invalid hex digits");
+        assert_eq!(error(":00000006FA"), "Unknown record type 06");
+        assert_eq!(error(":020000040001F9"), "Addresses above FFFFh aren't supported");
+        assert_eq!(error(":02FFFF000102FD"), "Data at FFFF runs past FFFFh");
+        let error = parse(":010000007689\n:00000001FE\n").err().unwrap();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn write_and_reload() {
+        let memory: Vec<u8> = (0..0x20).collect();
+        let text = write(&memory, 0x00, 0x10, Some(5));
+        assert_eq!(
+            text,
+            ":10000000000102030405060708090A0B0C0D0E0F78\n\
+             :0100100010DF\n\
+             :0400000300000005F4\n\
+             :00000001FF\n"
+        );
+        let program = parse(&text).unwrap();
+        assert_eq!(program.image, memory[..=0x10]);
+        assert_eq!(program.start, Some(5));
+    }
+
+    #[test]
+    fn write_empty() {
+        assert_eq!(write(&[], 0, 0, None), ":00000001FF\n");
+        assert_eq!(write(&[0x76; 4], 3, 1, None), ":00000001FF\n");
+        assert_eq!(write(&[0x76; 4], 8, 9, Some(0)), ":0400000300000000F9\n:00000001FF\n");
+    }
+}
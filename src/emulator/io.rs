@@ -0,0 +1,160 @@
+use super::rom::MachineConfig;
+
+/*
+The devices on a board's I/O ports. Space Invaders' board reads the cabinet's controls and its
+DIP switches from input ports, and draws sprites at any x with a shift register: OUT to the data
+port shifts a byte into the top of a 16 bit value, OUT to the amount port picks a shift of 0-7
+and IN from the result port reads the 8 bits that many bits below the top. Sound is driven by
+latches on other output ports. Writes to the watchdog port only keep it from resetting the
+board, which isn't emulated.
+
+Ports with nothing on them read FFh, as the data bus floats high, and writes to them are lost.
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Io {
+    // What IN reads from each port, the controls released and the DIP switches at their
+    // defaults on a board's input ports
+    inputs: [u8; 256],
+    shifter: Option<Shifter>,
+    // The last value written to each sound port, in the order the board lists them
+    sound: Vec<(u8, u8)>,
+    // Every write as (port, value) in order, once record_writes has been called
+    writes: Option<Vec<(u8, u8)>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Shifter {
+    amount_port: u8,
+    data_port: u8,
+    result_port: u8,
+    value: u16,
+    amount: u8,
+}
+
+impl Io {
+    // Nothing on any port
+    pub fn new() -> Io {
+        Io {
+            inputs: [0xff; 256],
+            shifter: None,
+            sound: Vec::new(),
+            writes: None,
+        }
+    }
+
+    // Sets what IN reads from a port with no other device on it
+    pub fn set_input(&mut self, port: u8, value: u8) {
+        self.inputs[port as usize] = value;
+    }
+
+    // Keeps every write from now on for writes to return, for checking a program's output
+    pub fn record_writes(&mut self) {
+        self.writes = Some(Vec::new());
+    }
+
+    pub fn writes(&self) -> &[(u8, u8)] {
+        self.writes.as_deref().unwrap_or_default()
+    }
+
+    // The devices of a machine's board, with its DIP switches at their factory settings
+    pub fn board(config: &MachineConfig) -> Io {
+        let ports = &config.ports;
+        let mut io = Io::new();
+        for &port in ports.inputs {
+            io.inputs[port as usize] = 0x00;
+        }
+        io.inputs[config.dip_port as usize] = config.dip_switches;
+        io.shifter = Some(Shifter {
+            amount_port: ports.shift_amount,
+            data_port: ports.shift_data,
+            result_port: ports.shift_result,
+            value: 0,
+            amount: 0,
+        });
+        io.sound = ports.sound.iter().map(|&port| (port, 0)).collect();
+        io
+    }
+
+    pub fn input(&self, port: u8) -> u8 {
+        match &self.shifter {
+            Some(shifter) if port == shifter.result_port => (shifter.value >> (8 - shifter.amount)) as u8,
+            _ => self.inputs[port as usize],
+        }
+    }
+
+    pub fn output(&mut self, port: u8, value: u8) {
+        if let Some(writes) = &mut self.writes {
+            writes.push((port, value));
+        }
+        if let Some(shifter) = &mut self.shifter {
+            if port == shifter.data_port {
+                shifter.value = shifter.value >> 8 | (value as u16) << 8;
+                return;
+            }
+            if port == shifter.amount_port {
+                shifter.amount = value & 7;
+                return;
+            }
+        }
+        if let Some(latch) = self.sound.iter_mut().find(|(sound_port, _)| *sound_port == port) {
+            latch.1 = value;
+        }
+    }
+
+    // The sound bits last written to a port, 0 if it isn't one of the board's sound ports
+    pub fn sound(&self, port: u8) -> u8 {
+        self.sound
+            .iter()
+            .find(|(sound_port, _)| *sound_port == port)
+            .map_or(0, |(_, value)| *value)
+    }
+}
+
+impl Default for Io {
+    fn default() -> Io {
+        Io::new()
+    }
+}
+
+#[cfg(test)]
+mod io_tests {
+    use super::*;
+    use crate::emulator::rom::Machine;
+
+    #[test]
+    fn nothing_attached() {
+        let mut io = Io::new();
+        io.output(4, 0x12);
+        assert_eq!(io.input(3), 0xff);
+        assert_eq!(io.sound(3), 0);
+        assert_eq!(io.writes(), []);
+
+        io.set_input(3, 0x42);
+        io.record_writes();
+        io.output(4, 0x34);
+        io.output(2, 0x56);
+        assert_eq!(io.input(3), 0x42);
+        assert_eq!(io.writes(), [(4, 0x34), (2, 0x56)]);
+    }
+
+    #[test]
+    fn invaders_board() {
+        let mut io = Io::board(Machine::find("invaders").unwrap().config);
+        assert_eq!(io.input(0), 0xff);
+        assert_eq!(io.input(1), 0x00);
+        assert_eq!(io.input(2), 0x00);
+
+        io.output(4, 0xab);
+        io.output(4, 0xcd);
+        assert_eq!(io.input(3), 0xcd);
+        io.output(2, 3);
+        assert_eq!(io.input(3), 0x6d);
+        io.output(2, 0x0f);
+        assert_eq!(io.input(3), 0xd5);
+
+        io.output(5, 0x21);
+        assert_eq!(io.sound(5), 0x21);
+        assert_eq!(io.sound(6), 0);
+    }
+}
@@ -0,0 +1,581 @@
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use zip::{result::ZipError, ZipArchive};
+
+use super::{cpu::Model, symbols::parse_address, Program};
+
+/*
+Arcade boards spread their program over several ROM chips, each mapped at its own address. A
+ROM list is given as comma separated file@address specs,
+
+    invaders.h@0000,invaders.g@0800,invaders.f@1000,invaders.e@1800
+
+and known machines list their ROMs with the sizes they must have, so a directory or ZIP archive
+named after the machine is enough to load them.
+*/
+
+// A ROM file and where it's mapped, with the size it must have if known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomSpec {
+    pub path: PathBuf,
+    pub address: u16,
+    pub size: Option<usize>,
+}
+
+impl RomSpec {
+    // file@address, the address in any format symbol files use
+    pub fn parse(text: &str) -> Result<RomSpec, RomError> {
+        let (path, address) = text
+            .rsplit_once('@')
+            .ok_or_else(|| RomError::InvalidSpec(text.to_string()))?;
+        let address = parse_address(address).ok_or_else(|| RomError::InvalidSpec(text.to_string()))?;
+        if path.is_empty() {
+            return Err(RomError::InvalidSpec(text.to_string()));
+        }
+        Ok(RomSpec {
+            path: PathBuf::from(path),
+            address,
+            size: None,
+        })
+    }
+
+    pub fn parse_list(text: &str) -> Result<Vec<RomSpec>, RomError> {
+        text.split(',')
+            .filter(|spec| !spec.trim().is_empty())
+            .map(|spec| RomSpec::parse(spec.trim()))
+            .collect()
+    }
+}
+
+pub struct MachineRom {
+    pub name: &'static str,
+    pub address: u16,
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: &'static str,
+}
+
+// A ROM set, where execution starts after reset and the board it runs on
+pub struct Machine {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub roms: &'static [MachineRom],
+    pub start: u16,
+    pub config: &'static MachineConfig,
+}
+
+// The port numbers used by a board's IN and OUT instructions
+pub struct Ports {
+    pub inputs: &'static [u8],
+    pub shift_amount: u8,
+    pub shift_data: u8,
+    pub shift_result: u8,
+    pub sound: &'static [u8],
+    pub watchdog: u8,
+}
+
+// A rectangle of the logical screen tinted by the coloured film on the cabinet's glass, right
+// and bottom excluded
+pub struct OverlayBand {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    pub color: (u8, u8, u8),
+}
+
+impl OverlayBand {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        (self.left..self.right).contains(&x) && (self.top..self.bottom).contains(&y)
+    }
+}
+
+pub struct MachineConfig {
+    // The processor the board runs, unless --cpu says otherwise
+    pub cpu: Model,
+    pub ports: Ports,
+    // The input port the DIP switches are read from and their factory settings
+    pub dip_port: u8,
+    pub dip_switches: u8,
+    pub overlay: &'static [OverlayBand],
+}
+
+const RED: (u8, u8, u8) = (0xff, 0x20, 0x20);
+const GREEN: (u8, u8, u8) = (0x20, 0xff, 0x20);
+
+// Midway's Space Invaders board. DIP switches 00 give 3 ships, an extra ship at 1500 points and
+// the coin info shown in attract mode.
+const INVADERS: MachineConfig = MachineConfig {
+    cpu: Model::I8080,
+    ports: Ports {
+        inputs: &[1, 2],
+        shift_amount: 2,
+        shift_data: 4,
+        shift_result: 3,
+        sound: &[3, 5],
+        watchdog: 6,
+    },
+    dip_port: 2,
+    dip_switches: 0x00,
+    overlay: &[
+        // Flying saucer
+        OverlayBand { left: 0, top: 32, right: 224, bottom: 64, color: RED },
+        // Player and shields
+        OverlayBand { left: 0, top: 184, right: 224, bottom: 240, color: GREEN },
+        // Ships left
+        OverlayBand { left: 16, top: 240, right: 134, bottom: 256, color: GREEN },
+    ],
+};
+
+// Known good dumps, with their hashes as MAME lists them. This holds the parent Space Invaders
+// set; a set that isn't listed is reported as unknown rather than matched to a machine.
+pub const MACHINES: &[Machine] = &[Machine {
+    name: "invaders",
+    description: "Space Invaders",
+    roms: &[
+        MachineRom {
+            name: "invaders.h",
+            address: 0x0000,
+            size: 0x0800,
+            crc32: 0x734f5ad8,
+            sha1: "ff6200af4c9110d8181249cbcef1a8a40fa40b7f",
+        },
+        MachineRom {
+            name: "invaders.g",
+            address: 0x0800,
+            size: 0x0800,
+            crc32: 0x6bfaca4a,
+            sha1: "16f48649b531bdef8c2d1446c429b5f414524350",
+        },
+        MachineRom {
+            name: "invaders.f",
+            address: 0x1000,
+            size: 0x0800,
+            crc32: 0x0ccead96,
+            sha1: "537aef03468f63c5b9e11dd61e253f7ae17d9743",
+        },
+        MachineRom {
+            name: "invaders.e",
+            address: 0x1800,
+            size: 0x0800,
+            crc32: 0x14e538b0,
+            sha1: "1d6ca0c99f9df71e2990b610deb9d7da0125e2d8",
+        },
+    ],
+    start: 0x0000,
+    config: &INVADERS,
+}];
+
+impl Machine {
+    pub fn find(name: &str) -> Option<&'static Machine> {
+        MACHINES.iter().find(|machine| machine.name.eq_ignore_ascii_case(name))
+    }
+
+    // The ROMs of the machine inside directory
+    pub fn specs(&self, directory: &Path) -> Vec<RomSpec> {
+        self.roms
+            .iter()
+            .map(|rom| RomSpec {
+                path: directory.join(rom.name),
+                address: rom.address,
+                size: Some(rom.size),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    InvalidSpec(String),
+    UnknownMachine(String),
+    Read { path: PathBuf, error: io::Error },
+    Size { path: PathBuf, expected: usize, actual: usize },
+    TooLarge { path: PathBuf, address: u16, size: usize },
+    Overlap { first: PathBuf, second: PathBuf, address: u16 },
+    Zip { path: PathBuf, error: ZipError },
+    Missing { path: PathBuf, name: &'static str },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::InvalidSpec(spec) => write!(f, "Invalid ROM '{}', expected file@address", spec),
+            RomError::UnknownMachine(name) => {
+                let known: Vec<&str> = MACHINES.iter().map(|machine| machine.name).collect();
+                write!(f, "Unknown machine '{}', known machines are {}", name, known.join(", "))
+            }
+            RomError::Read { path, error } => write!(f, "Failed to read '{}': {}", path.display(), error),
+            RomError::Size { path, expected, actual } => write!(
+                f,
+                "'{}' is {} bytes, expected {}",
+                path.display(),
+                actual,
+                expected
+            ),
+            RomError::TooLarge { path, address, size } => write!(
+                f,
+                "'{}' is {} bytes and doesn't fit at {:04X}",
+                path.display(),
+                size,
+                address
+            ),
+            RomError::Overlap { first, second, address } => write!(
+                f,
+                "'{}' and '{}' overlap at {:04X}",
+                first.display(),
+                second.display(),
+                address
+            ),
+            RomError::Zip { path, error } => write!(f, "Failed to read '{}': {}", path.display(), error),
+            RomError::Missing { path, name } => write!(f, "'{}' has no {}", path.display(), name),
+        }
+    }
+}
+
+impl Error for RomError {}
+
+pub enum Identification {
+    Known(&'static Machine),
+    // Some of the ROMs match a known set but these don't
+    BadDump {
+        machine: &'static Machine,
+        bad: Vec<&'static MachineRom>,
+    },
+    Unknown,
+}
+
+impl Identification {
+    // The machine to configure the emulator for, if the image looks like one
+    pub fn machine(&self) -> Option<&'static Machine> {
+        match self {
+            Identification::Known(machine) | Identification::BadDump { machine, .. } => Some(machine),
+            Identification::Unknown => None,
+        }
+    }
+}
+
+impl fmt::Display for Identification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Identification::Known(machine) => write!(f, "Identified {} ({})", machine.description, machine.name),
+            Identification::BadDump { machine, bad } => {
+                let names: Vec<&str> = bad.iter().map(|rom| rom.name).collect();
+                write!(
+                    f,
+                    "Warning: looks like {} ({}) but {} don't match, bad or modified dump",
+                    machine.description,
+                    machine.name,
+                    names.join(", ")
+                )
+            }
+            Identification::Unknown => write!(f, "Warning: unknown ROM, not in the ROM database"),
+        }
+    }
+}
+
+// Looks up the ROMs found at each known set's addresses in the database
+pub fn identify(image: &[u8]) -> Identification {
+    identify_in(MACHINES, image)
+}
+
+fn identify_in(machines: &'static [Machine], image: &[u8]) -> Identification {
+    let mut best: Option<(usize, Identification)> = None;
+    for machine in machines {
+        let bad: Vec<&MachineRom> = machine.roms.iter().filter(|rom| !matches(rom, image)).collect();
+        let good = machine.roms.len() - bad.len();
+        if bad.is_empty() {
+            return Identification::Known(machine);
+        }
+        if good > 0 && best.as_ref().is_none_or(|(count, _)| good > *count) {
+            best = Some((good, Identification::BadDump { machine, bad }));
+        }
+    }
+    best.map_or(Identification::Unknown, |(_, identification)| identification)
+}
+
+// Whether image holds a good dump of rom at its address
+pub fn matches(rom: &MachineRom, image: &[u8]) -> bool {
+    let start = rom.address as usize;
+    match image.get(start..start + rom.size) {
+        Some(data) => {
+            crc32fast::hash(data) == rom.crc32 && sha1_smol::Sha1::from(data).digest().to_string() == rom.sha1
+        }
+        None => false,
+    }
+}
+
+// Reads every ROM and maps them into one image, starting at start
+pub fn load(specs: &[RomSpec], start: u16) -> Result<Program, RomError> {
+    let roms = specs
+        .iter()
+        .map(|spec| {
+            fs::read(&spec.path)
+                .map(|data| (spec.clone(), data))
+                .map_err(|error| RomError::Read { path: spec.path.clone(), error })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Program {
+        image: map(&roms)?,
+        start: Some(start),
+    })
+}
+
+// Loads the ROM set of the machine named after path, a directory like roms/invaders or a ZIP
+// archive like invaders.zip
+pub fn load_machine(path: &Path) -> Result<Program, RomError> {
+    let is_zip = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+    let name = if is_zip { path.file_stem() } else { path.file_name() };
+    let name = name.map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let machine = Machine::find(&name).ok_or(RomError::UnknownMachine(name))?;
+    if !is_zip {
+        return load(&machine.specs(path), machine.start);
+    }
+
+    let file = fs::File::open(path).map_err(|error| RomError::Read { path: path.to_path_buf(), error })?;
+    let roms = read_zip(file, path, machine)?;
+    Ok(Program {
+        image: map(&roms)?,
+        start: Some(machine.start),
+    })
+}
+
+// Reads the machine's ROMs out of an archive
+fn read_zip<R: Read + Seek>(
+    reader: R,
+    path: &Path,
+    machine: &Machine,
+) -> Result<Vec<(RomSpec, Vec<u8>)>, RomError> {
+    let zip_error = |error| RomError::Zip { path: path.to_path_buf(), error };
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let mut roms = Vec::new();
+    for rom in machine.roms {
+        let index = find_member(&mut archive, rom)
+            .map_err(zip_error)?
+            .ok_or(RomError::Missing { path: path.to_path_buf(), name: rom.name })?;
+        let mut member = archive.by_index(index).map_err(zip_error)?;
+        let member_path = path.join(member.name());
+        let mut data = Vec::new();
+        member
+            .read_to_end(&mut data)
+            .map_err(|error| RomError::Read { path: member_path.clone(), error })?;
+        let spec = RomSpec {
+            path: member_path,
+            address: rom.address,
+            size: Some(rom.size),
+        };
+        roms.push((spec, data));
+    }
+    Ok(roms)
+}
+
+// The member with the ROM's file name, ignoring folders and case, or failing that the one with
+// its CRC32 and size, as renamed sets are common
+fn find_member<R: Read + Seek>(archive: &mut ZipArchive<R>, rom: &MachineRom) -> Result<Option<usize>, ZipError> {
+    let mut by_crc = None;
+    for index in 0..archive.len() {
+        let member = archive.by_index_raw(index)?;
+        let file_name = member.name().rsplit('/').next().unwrap_or_default();
+        if file_name.eq_ignore_ascii_case(rom.name) {
+            return Ok(Some(index));
+        }
+        if by_crc.is_none() && member.crc32() == rom.crc32 && member.size() == rom.size as u64 {
+            by_crc = Some(index);
+        }
+    }
+    Ok(by_crc)
+}
+
+// Places each ROM at its address, with gaps filled with zeros
+pub fn map(roms: &[(RomSpec, Vec<u8>)]) -> Result<Vec<u8>, RomError> {
+    let mut image = Vec::new();
+    for (index, (spec, data)) in roms.iter().enumerate() {
+        if let Some(expected) = spec.size {
+            if data.len() != expected {
+                return Err(RomError::Size {
+                    path: spec.path.clone(),
+                    expected,
+                    actual: data.len(),
+                });
+            }
+        }
+        let start = spec.address as usize;
+        let end = start + data.len();
+        if end > 0x10000 {
+            return Err(RomError::TooLarge {
+                path: spec.path.clone(),
+                address: spec.address,
+                size: data.len(),
+            });
+        }
+        for (other, other_data) in &roms[..index] {
+            let other_start = other.address as usize;
+            let other_end = other_start + other_data.len();
+            if start < other_end && other_start < end {
+                return Err(RomError::Overlap {
+                    first: other.path.clone(),
+                    second: spec.path.clone(),
+                    address: start.max(other_start) as u16,
+                });
+            }
+        }
+        if image.len() < end {
+            image.resize(end, 0);
+        }
+        image[start..end].copy_from_slice(data);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod rom_tests {
+    use super::*;
+
+    fn rom(path: &str, address: u16, data: Vec<u8>) -> (RomSpec, Vec<u8>) {
+        (RomSpec { path: PathBuf::from(path), address, size: None }, data)
+    }
+
+    #[test]
+    fn parse_specs() {
+        let specs = RomSpec::parse_list("roms/invaders.h@0000, invaders.g@0x800,a@b@1000h").unwrap();
+        let addresses: Vec<(PathBuf, u16)> = specs.into_iter().map(|spec| (spec.path, spec.address)).collect();
+        assert_eq!(
+            addresses,
+            [
+                (PathBuf::from("roms/invaders.h"), 0x0000),
+                (PathBuf::from("invaders.g"), 0x0800),
+                (PathBuf::from("a@b"), 0x1000),
+            ]
+        );
+        assert!(RomSpec::parse("invaders.h").is_err());
+        assert!(RomSpec::parse("invaders.h@zz").is_err());
+        assert!(RomSpec::parse("@0800").is_err());
+    }
+
+    #[test]
+    fn map_roms() {
+        let image = map(&[rom("b", 0x0004, vec![3, 4]), rom("a", 0x0000, vec![1, 2])]).unwrap();
+        assert_eq!(image, [1, 2, 0, 0, 3, 4]);
+
+        let error = map(&[rom("a", 0x0000, vec![0; 4]), rom("b", 0x0002, vec![0; 4])]).err().unwrap();
+        assert_eq!(error.to_string(), "'a' and 'b' overlap at 0002");
+        let error = map(&[rom("a", 0xfffe, vec![0; 4])]).err().unwrap();
+        assert_eq!(error.to_string(), "'a' is 4 bytes and doesn't fit at FFFE");
+        assert!(map(&[rom("a", 0xfffc, vec![0; 4])]).is_ok());
+
+        let mut sized = rom("a", 0x0000, vec![0; 4]);
+        sized.0.size = Some(0x800);
+        assert_eq!(map(&[sized]).err().unwrap().to_string(), "'a' is 4 bytes, expected 2048");
+    }
+
+    const TEST_ROMS: &[MachineRom] = &[
+        MachineRom {
+            name: "zeros",
+            address: 0x0000,
+            size: 4,
+            crc32: 0x2144df1c,
+            sha1: "9069ca78e7450a285173431b3e52c5c25299e473",
+        },
+        MachineRom {
+            name: "counting",
+            address: 0x0004,
+            size: 4,
+            crc32: 0xb63cfbcd,
+            sha1: "12dada1fff4d4787ade3333147202c3b443e376f",
+        },
+    ];
+
+    const TEST_MACHINES: &[Machine] = &[
+        Machine {
+            name: "test",
+            description: "Test",
+            roms: TEST_ROMS,
+            start: 0,
+            config: &INVADERS,
+        },
+        // Shares its first ROM with test
+        Machine {
+            name: "revision",
+            description: "Revision",
+            roms: &[MachineRom {
+                name: "halts",
+                address: 0x0004,
+                size: 4,
+                crc32: 0xff6f3ec7,
+                sha1: "54a3ed0aa931b8a2c6666be8f3460ce0c9cde050",
+            }],
+            start: 0,
+            config: &INVADERS,
+        },
+    ];
+
+    #[test]
+    fn identify_sets() {
+        let name = |image: &[u8]| {
+            let identification = identify_in(TEST_MACHINES, image);
+            identification.machine().map(|machine| machine.name)
+        };
+        assert_eq!(name(&[0, 0, 0, 0, 1, 2, 3, 4]), Some("test"));
+        assert_eq!(name(&[9, 9, 9, 9, 0x76, 0x76, 0x76, 0x76, 0]), Some("revision"));
+        assert_eq!(name(&[9, 9, 9, 9, 1, 2, 3]), None);
+
+        let identification = identify_in(TEST_MACHINES, &[0, 0, 0, 0, 1, 2, 3, 5]);
+        assert_eq!(
+            identification.to_string(),
+            "Warning: looks like Test (test) but counting don't match, bad or modified dump"
+        );
+        assert!(!matches(&MACHINES[0].roms[0], &[0; 0x800]));
+    }
+
+    #[test]
+    fn zip_members() {
+        use std::io::{Cursor, Write};
+        use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+        let archive = |members: &[(&str, &[u8])]| {
+            let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+            for (name, data) in members {
+                let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+                writer.start_file(*name, options).unwrap();
+                writer.write_all(data).unwrap();
+            }
+            writer.finish().unwrap()
+        };
+        let path = Path::new("test.zip");
+
+        // By name in a folder, and a renamed ROM by CRC
+        let zip = archive(&[("readme.txt", b"hi"), ("other.rom", &[1, 2, 3, 4]), ("test/ZEROS", &[0; 4])]);
+        let roms = read_zip(zip, path, &TEST_MACHINES[0]).unwrap();
+        assert_eq!(roms[0].0.path, path.join("test/ZEROS"));
+        assert_eq!(map(&roms).unwrap(), [0, 0, 0, 0, 1, 2, 3, 4]);
+
+        let zip = archive(&[("zeros", &[0; 4])]);
+        let error = read_zip(zip, path, &TEST_MACHINES[0]).err().unwrap();
+        assert_eq!(error.to_string(), "'test.zip' has no counting");
+        let error = read_zip(Cursor::new(vec![0; 16]), path, &TEST_MACHINES[0]).err().unwrap();
+        assert!(matches!(error, RomError::Zip { .. }));
+    }
+
+    #[test]
+    fn machines() {
+        let machine = Machine::find("Invaders").unwrap();
+        let specs = machine.specs(Path::new("roms"));
+        assert_eq!(specs[3].path, Path::new("roms").join("invaders.e"));
+        assert_eq!(specs[3].address, 0x1800);
+        assert_eq!(specs[3].size, Some(0x800));
+        assert!(matches!(
+            load_machine(Path::new("roms/galaxian")),
+            Err(RomError::UnknownMachine(_))
+        ));
+        assert!(matches!(
+            load_machine(Path::new("roms/galaxian.zip")),
+            Err(RomError::UnknownMachine(name)) if name == "galaxian"
+        ));
+    }
+}
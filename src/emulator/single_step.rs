@@ -0,0 +1,231 @@
+/*
+Runs single-step test vectors, the community format with one JSON file per opcode, each an
+array of cases that run one instruction from a known state:
+
+    {
+        "name": "3c 0000",
+        "initial": {"pc": 0, "sp": 0, "a": 1, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                    "h": 0, "l": 0, "ram": [[0, 60]]},
+        "final": {"pc": 1, "sp": 0, "a": 2, ..., "ram": [[0, 60]]},
+        "cycles": [[0, 60, "r--m"], ...]
+    }
+
+There is one entry in cycles per clock state. Cases for IN and OUT have a list of their port
+accesses, e.g. "ports": [[16, 1, "r"]]. Reads are answered with the values given and writes are
+compared like RAM.
+*/
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use super::cpu::{Cpu, Register};
+use super::error::EmulatorError;
+use super::io::Io;
+
+// The registers besides F, which is set and compared as packed flags
+const REGISTERS: [(&str, Register); 9] = [
+    ("pc", Register::Pc),
+    ("sp", Register::Sp),
+    ("a", Register::A),
+    ("b", Register::B),
+    ("c", Register::C),
+    ("d", Register::D),
+    ("e", Register::E),
+    ("h", Register::H),
+    ("l", Register::L),
+];
+
+// A field whose final value didn't match: a register, "ram[addr]", "port[port]", "port writes"
+// or "cycles"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub case: String,
+    pub field: String,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub passed: usize,
+    pub failed: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+// Runs every case in a file of test vectors
+pub fn run_file(path: &Path) -> Result<Report, EmulatorError> {
+    let text = fs::read_to_string(path).map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })?;
+    run(&text).map_err(|message| EmulatorError::Format { path: path.to_path_buf(), message })
+}
+
+// Runs every case in the JSON text of a file of test vectors
+pub fn run(text: &str) -> Result<Report, String> {
+    let cases: Value = serde_json::from_str(text).map_err(|error| error.to_string())?;
+    let cases = cases.as_array().ok_or("Expected an array of test cases")?;
+    let mut cpu = Box::new(Cpu::new(Vec::new()));
+    let mut report = Report::default();
+    for (index, case) in cases.iter().enumerate() {
+        let name = case["name"].as_str().map_or_else(|| index.to_string(), str::to_string);
+        let mismatches = run_case(&mut cpu, &name, case).map_err(|message| format!("Case '{}': {}", name, message))?;
+        if mismatches.is_empty() {
+            report.passed += 1;
+        } else {
+            report.failed += 1;
+            report.mismatches.extend(mismatches);
+        }
+    }
+    Ok(report)
+}
+
+fn run_case(cpu: &mut Cpu, name: &str, case: &Value) -> Result<Vec<Mismatch>, String> {
+    let initial = &case["initial"];
+    let expected = &case["final"];
+    let ram = |state: &Value| -> Result<Vec<(u16, u8)>, String> {
+        state["ram"]
+            .as_array()
+            .ok_or("Missing ram")?
+            .iter()
+            .map(|entry| Ok((number(&entry[0], "ram address")? as u16, number(&entry[1], "ram value")? as u8)))
+            .collect()
+    };
+    let initial_ram = ram(initial)?;
+    let final_ram = ram(expected)?;
+    let ports = |direction: &str| -> Result<Vec<(u8, u8)>, String> {
+        let Some(ports) = case.get("ports") else {
+            return Ok(Vec::new());
+        };
+        ports
+            .as_array()
+            .ok_or("Invalid ports")?
+            .iter()
+            .filter(|entry| entry[2] == direction)
+            .map(|entry| Ok((number(&entry[0], "port")? as u8, number(&entry[1], "port value")? as u8)))
+            .collect()
+    };
+    let mut io = Io::new();
+    for (port, value) in ports("r")? {
+        io.set_input(port, value);
+    }
+    io.record_writes();
+    cpu.set_io(io);
+    let cycles = case["cycles"].as_array().ok_or("Missing cycles")?.len() as u64;
+
+    for (field, register) in REGISTERS {
+        cpu.set_register(register, number(&initial[field], field)? as u16);
+    }
+    cpu.set_flags(number(&initial["f"], "f")? as u8);
+    for &(address, value) in &initial_ram {
+        cpu.memory[address as usize] = value;
+    }
+    let start = cpu.cycles();
+    cpu.cycle().map_err(|error| error.to_string())?;
+
+    let mut mismatches = Vec::new();
+    let mut check = |field: String, expected: u64, actual: u64| {
+        if expected != actual {
+            mismatches.push(Mismatch { case: name.to_string(), field, expected, actual });
+        }
+    };
+    for (field, register) in REGISTERS {
+        check(field.to_string(), number(&expected[field], field)?, cpu.register(register) as u64);
+    }
+    check("f".to_string(), number(&expected["f"], "f")?, cpu.flags() as u64);
+    for &(address, value) in &final_ram {
+        check(format!("ram[{:04X}]", address), value as u64, cpu.memory[address as usize] as u64);
+    }
+    let writes = ports("w")?;
+    let written = cpu.io().writes();
+    check("port writes".to_string(), writes.len() as u64, written.len() as u64);
+    for (&(port, value), &(written_port, written_value)) in writes.iter().zip(written) {
+        check("port".to_string(), port as u64, written_port as u64);
+        check(format!("port[{:02X}]", port), value as u64, written_value as u64);
+    }
+    check("cycles".to_string(), cycles, cpu.cycles() - start);
+
+    // Leave memory clear for the next case
+    for (address, _) in initial_ram.into_iter().chain(final_ram) {
+        cpu.memory[address as usize] = 0;
+    }
+    Ok(mismatches)
+}
+
+fn number(value: &Value, field: &str) -> Result<u64, String> {
+    value.as_u64().ok_or_else(|| format!("Missing or invalid {}", field))
+}
+
+#[cfg(test)]
+mod single_step_tests {
+    use super::*;
+
+    #[test]
+    fn cases() {
+        let text = r#"[
+            {"name": "3c 0000",
+             "initial": {"pc": 256, "sp": 0, "a": 15, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                         "h": 0, "l": 0, "ram": [[256, 60]]},
+             "final": {"pc": 257, "sp": 0, "a": 16, "b": 0, "c": 0, "d": 0, "e": 0, "f": 18,
+                       "h": 0, "l": 0, "ram": [[256, 60]]},
+             "cycles": [[256, 60, "r--m"], [256, 60, "r--m"], [256, 60, "r--m"],
+                        [256, 60, "r--m"], [256, 60, "r--m"]]},
+            {"name": "77 0001",
+             "initial": {"pc": 0, "sp": 0, "a": 170, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                         "h": 32, "l": 0, "ram": [[0, 119]]},
+             "final": {"pc": 1, "sp": 0, "a": 170, "b": 0, "c": 0, "d": 0, "e": 0, "f": 3,
+                       "h": 32, "l": 0, "ram": [[0, 119], [8192, 171]]},
+             "cycles": [[0, 119, "r--m"], [0, 119, "r--m"], [0, 119, "r--m"],
+                        [0, 119, "r--m"], [8192, 170, "-w-m"], [8192, 170, "-w-m"],
+                        [8192, 170, "-w-m"]]},
+            {"name": "db 0002",
+             "initial": {"pc": 0, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                         "h": 0, "l": 0, "ram": [[0, 219], [1, 16]]},
+             "final": {"pc": 2, "sp": 0, "a": 1, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                       "h": 0, "l": 0, "ram": [[0, 219], [1, 16]]},
+             "cycles": [[0, 219, "r--m"], [0, 219, "r--m"], [0, 219, "r--m"], [0, 219, "r--m"],
+                        [1, 16, "r--m"], [1, 16, "r--m"], [1, 16, "r--m"],
+                        [4112, 1, "--r-i"], [4112, 1, "--r-i"], [4112, 1, "--r-i"]],
+             "ports": [[16, 1, "r"]]},
+            {"name": "d3 0003",
+             "initial": {"pc": 0, "sp": 0, "a": 85, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                         "h": 0, "l": 0, "ram": [[0, 211], [1, 32]]},
+             "final": {"pc": 2, "sp": 0, "a": 85, "b": 0, "c": 0, "d": 0, "e": 0, "f": 2,
+                       "h": 0, "l": 0, "ram": [[0, 211], [1, 32]]},
+             "cycles": [[0, 211, "r--m"], [0, 211, "r--m"], [0, 211, "r--m"], [0, 211, "r--m"],
+                        [1, 32, "r--m"], [1, 32, "r--m"], [1, 32, "r--m"],
+                        [8224, 85, "---wi"], [8224, 85, "---wi"], [8224, 85, "---wi"]],
+             "ports": [[32, 86, "w"]]}
+        ]"#;
+        let report = run(text).unwrap();
+        assert_eq!((report.passed, report.failed), (2, 2));
+        let fields: Vec<(&str, u64, u64)> = report
+            .mismatches
+            .iter()
+            .map(|mismatch| (mismatch.field.as_str(), mismatch.expected, mismatch.actual))
+            .collect();
+        assert_eq!(fields, [("f", 3, 2), ("ram[2000]", 171, 170), ("port[20]", 86, 85)]);
+        assert_eq!(report.mismatches[2].case, "d3 0003");
+
+        assert!(run(r#"[{"name": "00", "initial": {"pc": 0}}]"#).unwrap_err().starts_with("Case '00'"));
+    }
+
+    // A directory of the 8080's test vectors, e.g. SINGLE_STEP_TESTS=tests/8080/v1
+    #[test]
+    fn vectors() {
+        let Ok(directory) = std::env::var("SINGLE_STEP_TESTS") else {
+            eprintln!("Skipping: set SINGLE_STEP_TESTS to run the single-step test vectors");
+            return;
+        };
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let report = run_file(&path).unwrap();
+            assert!(report.is_ok(), "{}: {:?}", path.display(), &report.mismatches[..report.mismatches.len().min(5)]);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::read_to_string,
+    io,
+    path::Path,
+};
+
+use super::disassembler::is_mnemonic;
+
+/*
+Symbol files map label names to addresses. Each line holds one or more definitions in any of
+these forms, and anything after a ';' is a comment:
+
+    DrawSprite = 1439h          simple map files
+    DrawSprite EQU 1439H        assembler .sym output, also with a ':' after the label
+    1439 DrawSprite             address first, as in many .sym files
+    DRAWSP 1439H  LOOP 0A93H    symbol table sections of listings
+
+Addresses are hex and start with a digit or '$', written as 1439, 0A93h, 0x1439 or $1439.
+Lines that don't match are skipped so a whole listing file can be loaded. A listing's code lines
+also come in pairs, e.g. "0005 C3 00 F0  JMP 0F000h", so 8080 mnemonics and two hex digits,
+which are opcode bytes there, aren't taken as labels, and a line with any pair that isn't a
+definition defines nothing.
+*/
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    addresses: HashMap<String, u16>,
+    names: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn load(path: &Path) -> io::Result<SymbolTable> {
+        Ok(SymbolTable::parse(&read_to_string(path)?))
+    }
+
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut symbols = SymbolTable::new();
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+            let tokens: Vec<&str> = line
+                .split(|c: char| c.is_whitespace() || c == '=' || c == ':' || c == ',')
+                .filter(|token| {
+                    !token.is_empty()
+                        && !token.eq_ignore_ascii_case("equ")
+                        && !token.eq_ignore_ascii_case("set")
+                })
+                .collect();
+            if tokens.is_empty() || !tokens.len().is_multiple_of(2) {
+                continue;
+            }
+            let definitions: Option<Vec<(&str, u16)>> =
+                tokens.chunks(2).map(|pair| parse_pair(pair[0], pair[1])).collect();
+            for (name, address) in definitions.unwrap_or_default() {
+                symbols.insert(name, address);
+            }
+        }
+        symbols
+    }
+
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.addresses.insert(name.to_string(), address);
+        // Keep the first label defined for an address as its name
+        self.names.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    // A label or an address, e.g. "DrawSprite" or "1439h"
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        self.address(text).or_else(|| parse_address(text))
+    }
+}
+
+fn parse_pair<'a>(first: &'a str, second: &'a str) -> Option<(&'a str, u16)> {
+    // Labels can't start with a digit and addresses must, so the order doesn't matter
+    if starts_like_number(first) && is_label(second) {
+        Some((second, parse_address(first)?))
+    } else if is_label(first) && starts_like_number(second) {
+        Some((first, parse_address(second)?))
+    } else {
+        None
+    }
+}
+
+fn starts_like_number(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_digit() || c == '$')
+}
+
+fn is_label(text: &str) -> bool {
+    let byte = text.len() == 2 && text.chars().all(|c| c.is_ascii_hexdigit());
+    !starts_like_number(text)
+        && !byte
+        && !is_mnemonic(text)
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.?@$".contains(c))
+}
+
+pub fn parse_address(text: &str) -> Option<u16> {
+    let lower = text.to_ascii_lowercase();
+    let digits = lower
+        .strip_prefix("0x")
+        .or_else(|| lower.strip_prefix('$'))
+        .or_else(|| lower.strip_suffix('h'))
+        .unwrap_or(&lower);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let symbols = SymbolTable::parse(
+            "; Space Invaders\n\
+             DrawSprite = 1439h\n\
+             ClearPlayField: EQU 0x09D6 ; comment\n\
+             0A93 WaitOnDraw\n\
+             RSTV 0010H  SCORE $20F8\n\
+             not a symbol line\n",
+        );
+        assert_eq!(symbols.address("DrawSprite"), Some(0x1439));
+        assert_eq!(symbols.address("ClearPlayField"), Some(0x09d6));
+        assert_eq!(symbols.address("WaitOnDraw"), Some(0x0a93));
+        assert_eq!(symbols.address("RSTV"), Some(0x0010));
+        assert_eq!(symbols.address("SCORE"), Some(0x20f8));
+        assert_eq!(symbols.address("not"), None);
+        assert_eq!(symbols.name(0x1439), Some("DrawSprite"));
+    }
+
+    #[test]
+    fn listing_lines() {
+        let symbols = SymbolTable::parse(
+            "0005 C3 00 F0  JMP 0F000h\n\
+             0008 CD 39 14  CALL DrawSprite\n\
+             000B 00        NOP\n\
+             0A93 WaitOnDraw:\n",
+        );
+        assert_eq!(symbols.address("C3"), None);
+        assert_eq!(symbols.address("F0"), None);
+        assert_eq!(symbols.address("JMP"), None);
+        assert_eq!(symbols.address("CD"), None);
+        assert_eq!(symbols.name(0x0005), None);
+        assert_eq!(symbols.name(0x0000), None);
+        assert_eq!(symbols.address("WaitOnDraw"), Some(0x0a93));
+    }
+
+    #[test]
+    fn resolve() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("Start", 0x0100);
+        symbols.insert("Reset", 0x0100);
+        assert_eq!(symbols.resolve("Reset"), Some(0x0100));
+        assert_eq!(symbols.resolve("18DF"), Some(0x18df));
+        assert_eq!(symbols.resolve("Missing"), None);
+        assert_eq!(symbols.name(0x0100), Some("Start"));
+    }
+}
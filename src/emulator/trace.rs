@@ -0,0 +1,118 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use super::cpu::{Cpu, Register};
+use super::disassembler;
+use super::symbols::SymbolTable;
+
+/*
+One line per instruction, logged before it executes:
+
+PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0	(C3 D4 18 00)	JMP 18D4h
+
+Everything up to the opcode bytes follows the layout used by several popular 8080 emulators so
+their logs can be diffed against ours directly, ignoring the trailing disassembly. AF holds the
+flags in the 8080 PSW layout (S Z 0 AC 0 P 1 CY), CYC is the number of clock states executed
+before the instruction and the opcode bytes are the four bytes starting at PC.
+
+With a symbol table, instructions whose address operand has a label get it as a comment:
+
+PC: 0003, ... CYC: 12	(C3 D4 18 00)	JMP 18D4h ; Init
+*/
+
+pub struct Tracer {
+    writer: BufWriter<File>,
+    start: u16,
+    end: u16, // inclusive
+    symbols: SymbolTable,
+}
+
+impl Tracer {
+    pub fn new(path: &Path) -> io::Result<Tracer> {
+        Ok(Tracer {
+            writer: BufWriter::new(File::create(path)?),
+            start: 0x0000,
+            end: 0xffff,
+            symbols: SymbolTable::new(),
+        })
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    // Only instructions with a PC in start..=end are logged
+    pub fn set_range(&mut self, start: u16, end: u16) {
+        self.start = start;
+        self.end = end;
+    }
+
+    pub fn trace(&mut self, cpu: &Cpu) -> io::Result<()> {
+        let pc = cpu.register(Register::Pc);
+        if pc < self.start || pc > self.end {
+            return Ok(());
+        }
+        let line = format_line(cpu);
+        let label = disassembler::disassemble_model(&cpu.memory, pc, cpu.model())
+            .address_operand()
+            .and_then(|address| self.symbols.name(address));
+        match label {
+            Some(label) => writeln!(self.writer, "{} ; {}", line, label),
+            None => writeln!(self.writer, "{}", line),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub fn format_line(cpu: &Cpu) -> String {
+    let pc = cpu.register(Register::Pc);
+    let instruction = disassembler::disassemble_model(&cpu.memory, pc, cpu.model());
+    format!(
+        "PC: {:04X}, AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}, CYC: {}\t({:02X} {:02X} {:02X} {:02X})\t{}",
+        pc,
+        cpu.psw(),
+        cpu.register(Register::Bc),
+        cpu.register(Register::De),
+        cpu.register(Register::Hl),
+        cpu.register(Register::Sp),
+        cpu.cycles(),
+        cpu.peek(pc),
+        cpu.peek(pc.wrapping_add(1)),
+        cpu.peek(pc.wrapping_add(2)),
+        cpu.peek(pc.wrapping_add(3)),
+        instruction
+    )
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+    use crate::emulator::cpu::Model;
+
+    #[test]
+    fn line_format() {
+        // MVI B,03h / DCR B / JMP 18D4h
+        let mut cpu = Cpu::new(vec![0x06, 0x03, 0x05, 0xc3, 0xd4, 0x18]);
+        assert_eq!(
+            format_line(&cpu),
+            "PC: 0000, AF: 0042, BC: 0000, DE: 0000, HL: 0000, SP: FFFE, CYC: 0\t(06 03 05 C3)\tMVI B,03h"
+        );
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert_eq!(
+            format_line(&cpu),
+            "PC: 0003, AF: 0012, BC: 0200, DE: 0000, HL: 0000, SP: FFFE, CYC: 12\t(C3 D4 18 00)\tJMP 18D4h"
+        );
+
+        // RIM on the 8085, an undocumented NOP on the 8080
+        let mut cpu = Cpu::new(vec![0x20]);
+        cpu.set_model(Model::I8085);
+        assert!(format_line(&cpu).ends_with("\tRIM"));
+    }
+}
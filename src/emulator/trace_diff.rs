@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+
+use super::cpu::{Cpu, Model, Register, Registers};
+use super::error::EmulatorError;
+use super::trace;
+use super::{Program, Runner};
+
+// Machine state read from one line of a trace log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+}
+
+impl TraceState {
+    // Reads the "PC: 0000, AF: 0002, ..." fields of a trace line. Anything else on the line
+    // (cycle counts, opcode bytes, disassembly) is ignored.
+    pub fn parse(line: &str) -> Option<TraceState> {
+        let field = |name: &str| -> Option<u16> {
+            let start = line.find(&format!("{}: ", name))? + name.len() + 2;
+            let digits = line.get(start..start + 4)?;
+            u16::from_str_radix(digits, 16).ok()
+        };
+        Some(TraceState {
+            pc: field("PC")?,
+            af: field("AF")?,
+            bc: field("BC")?,
+            de: field("DE")?,
+            hl: field("HL")?,
+            sp: field("SP")?,
+        })
+    }
+
+    fn from_line(line: &str) -> TraceState {
+        TraceState::parse(line).expect("Trace line is missing fields")
+    }
+
+    // Names and values of the fields that differ, as (name, expected, actual)
+    pub fn differences(&self, actual: &TraceState) -> Vec<(&'static str, u16, u16)> {
+        [
+            ("PC", self.pc, actual.pc),
+            ("AF", self.af, actual.af),
+            ("BC", self.bc, actual.bc),
+            ("DE", self.de, actual.de),
+            ("HL", self.hl, actual.hl),
+            ("SP", self.sp, actual.sp),
+        ]
+        .into_iter()
+        .filter(|(_, expected, actual)| expected != actual)
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Number of instructions that matched before the divergence
+    pub instruction: usize,
+    // 1 based line number in the reference log
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    pub differences: Vec<(&'static str, u16, u16)>,
+    // Up to `context` of our lines before the divergence, and of the reference's and ours after
+    // it. Ours end early with the error if the cpu stopped on one.
+    pub before: Vec<String>,
+    pub reference_after: Vec<String>,
+    pub emulator_after: Vec<String>,
+    // Our registers at the divergence
+    pub registers: Registers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    // Every line matched, after this many instructions with the cpu stopped at pc
+    Matched { instructions: usize, pc: u16 },
+    Diverged(Box<Divergence>),
+}
+
+/*
+Runs the program alongside the reference log and stops at the first line where the state before
+an instruction doesn't match ours. Lines without trace fields are skipped. The cpu is set up like
+the identified machine's board, unless model says otherwise, and runs as the frontend runs it
+with --trace, one instruction at a time with the board's video interrupts in between, so logs
+recorded by either compare line for line.
+*/
+pub fn run(
+    program: Program,
+    model: Option<Model>,
+    reference: &str,
+    context: usize,
+) -> Result<Comparison, EmulatorError> {
+    let (mut cpu, mut runner) = program.machine_runner(model)?;
+    compare(&mut cpu, &mut runner, reference, context)
+}
+
+fn compare(cpu: &mut Cpu, runner: &mut Runner, reference: &str, context: usize) -> Result<Comparison, EmulatorError> {
+    let mut before: VecDeque<String> = VecDeque::with_capacity(context + 1);
+    let mut lines = reference
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| TraceState::parse(line).is_some());
+    let mut instruction = 0;
+
+    while let Some((index, line)) = lines.next() {
+        let actual = trace::format_line(cpu);
+        let differences = TraceState::from_line(line).differences(&TraceState::from_line(&actual));
+        if !differences.is_empty() {
+            let registers = cpu.registers();
+            let mut emulator_after = Vec::new();
+            for _ in 0..context {
+                match runner.run(cpu, true) {
+                    Ok(_) => emulator_after.push(trace::format_line(cpu)),
+                    Err(error) => {
+                        emulator_after.push(error.to_string());
+                        break;
+                    }
+                }
+            }
+            return Ok(Comparison::Diverged(Box::new(Divergence {
+                instruction,
+                line: index + 1,
+                expected: line.to_string(),
+                actual,
+                differences,
+                before: before.into(),
+                reference_after: lines.take(context).map(|(_, line)| line.to_string()).collect(),
+                emulator_after,
+                registers,
+            })));
+        }
+
+        if context > 0 {
+            if before.len() == context {
+                before.pop_front();
+            }
+            before.push_back(actual);
+        }
+        runner.run(cpu, true)?;
+        instruction += 1;
+    }
+
+    Ok(Comparison::Matched {
+        instructions: instruction,
+        pc: cpu.register(Register::Pc),
+    })
+}
+
+#[cfg(test)]
+mod trace_diff_tests {
+    use super::*;
+    use crate::emulator::io::Io;
+    use crate::emulator::rom::Machine;
+    use crate::emulator::CYCLES_PER_FRAME;
+
+    // MVI B,03h / DCR B / DCR B / DCR B / HLT
+    const PROGRAM: [u8; 6] = [0x06, 0x03, 0x05, 0x05, 0x05, 0x76];
+
+    fn reference() -> Vec<String> {
+        let mut cpu = Cpu::new(PROGRAM.to_vec());
+        let mut lines = Vec::new();
+        for _ in 0..5 {
+            lines.push(trace::format_line(&cpu));
+            cpu.cycle().unwrap();
+        }
+        lines
+    }
+
+    #[test]
+    fn parse() {
+        let state = TraceState::parse(
+            "PC: 18D4, AF: 0046, BC: 0102, DE: 0304, HL: 0506, SP: 2400, CYC: 7\t(31 00 24 06)",
+        );
+        assert_eq!(
+            state,
+            Some(TraceState { pc: 0x18d4, af: 0x0046, bc: 0x0102, de: 0x0304, hl: 0x0506, sp: 0x2400 })
+        );
+        assert_eq!(TraceState::parse("PC: 18D4, AF: 0046"), None);
+    }
+
+    #[test]
+    fn matching_log() {
+        let reference = reference().join("\n");
+        assert_eq!(
+            run(Program::new(PROGRAM.to_vec()), None, &reference, 2).unwrap(),
+            Comparison::Matched { instructions: 5, pc: 0x0006 }
+        );
+    }
+
+    #[test]
+    fn first_divergence() {
+        let mut reference = reference();
+        reference.insert(0, "Some header".to_string());
+        reference[3] = reference[3].replace("BC: 0200", "BC: 0201");
+        reference[4] = reference[4].replace("AF: 0046", "AF: 0002");
+
+        let divergence = match run(Program::new(PROGRAM.to_vec()), None, &reference.join("\n"), 1).unwrap() {
+            Comparison::Diverged(divergence) => divergence,
+            matched => panic!("{:?}", matched),
+        };
+        assert_eq!(divergence.instruction, 2);
+        assert_eq!(divergence.line, 4);
+        assert_eq!(divergence.differences, vec![("BC", 0x0201, 0x0200)]);
+        assert_eq!(divergence.before, vec![reference[2].clone()]);
+        assert_eq!(divergence.reference_after, vec![reference[4].clone()]);
+        assert_eq!(divergence.emulator_after.len(), 1);
+        assert!(divergence.emulator_after[0].starts_with("PC: 0004"));
+        assert_eq!(divergence.registers.pc, 0x0003);
+    }
+
+    // A log recorded with the board's interrupts and I/O, as the frontend's --trace records a
+    // game, only matches when they're emulated
+    #[test]
+    fn board_log() {
+        let program = "
+        jmp Start
+        org 8
+        inr d
+        ei
+        ret
+        org 10h
+        inr e
+        ei
+        ret
+Start:  lxi sp,2400h
+        ei
+Loop:   mov a,b
+        out 4
+        in 3
+        inr b
+        jmp Loop
+";
+        let board_cpu = || {
+            let mut cpu = Cpu::new(crate::emulator::assembler::assemble(program).unwrap().image);
+            cpu.set_io(Io::board(Machine::find("invaders").unwrap().config));
+            cpu
+        };
+        let mut cpu = board_cpu();
+        let mut runner = Runner::new(&cpu, true).unwrap();
+        let mut lines = Vec::new();
+        while cpu.cycles() < CYCLES_PER_FRAME {
+            lines.push(trace::format_line(&cpu));
+            runner.run(&mut cpu, true).unwrap();
+        }
+        let reference = lines.join("\n");
+
+        let mut cpu = board_cpu();
+        let mut runner = Runner::new(&cpu, true).unwrap();
+        let comparison = compare(&mut cpu, &mut runner, &reference, 0).unwrap();
+        assert!(matches!(comparison, Comparison::Matched { instructions, .. } if instructions == lines.len()));
+        // RST 1 was taken and RST 2 has just been
+        assert_eq!((cpu.register(Register::De), cpu.register(Register::Pc)), (0x0100, 0x0010));
+
+        let mut cpu = Cpu::new(board_cpu().memory.to_vec());
+        let mut runner = Runner::new(&cpu, false).unwrap();
+        let comparison = compare(&mut cpu, &mut runner, &reference, 0).unwrap();
+        assert!(matches!(comparison, Comparison::Diverged(_)));
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    // Any read of the address
+    Read,
+    // Any write to the address
+    Write,
+    // A write that stores a different value than the one already there
+    Change,
+    // Any read or write
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u16,
+    pub end: u16, // inclusive
+}
+
+impl Watchpoint {
+    pub fn new(kind: WatchKind, start: u16, end: u16) -> Watchpoint {
+        Watchpoint {
+            kind,
+            start: start.min(end),
+            end: start.max(end),
+        }
+    }
+
+    pub fn read(address: u16) -> Watchpoint {
+        Watchpoint::new(WatchKind::Read, address, address)
+    }
+
+    pub fn write(address: u16) -> Watchpoint {
+        Watchpoint::new(WatchKind::Write, address, address)
+    }
+
+    pub fn change(address: u16) -> Watchpoint {
+        Watchpoint::new(WatchKind::Change, address, address)
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+
+    // Checks a single memory access against this watchpoint.
+    // For reads `old` and `new` are both the value that was read.
+    pub fn triggers(&self, address: u16, write: bool, old: u8, new: u8) -> bool {
+        if !self.contains(address) {
+            return false;
+        }
+        match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Change => write && old != new,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+// A watchpoint that fired, recorded by the cpu for the frontend to report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub kind: WatchKind,
+    pub write: bool,
+    pub address: u16,
+    pub pc: u16, // address of the instruction that made the access
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.write {
+            write!(
+                f,
+                "WATCH write {:04X} at {:04X}: {:02X} -> {:02X}",
+                self.address, self.pc, self.old, self.new
+            )
+        } else {
+            write!(
+                f,
+                "WATCH read {:04X} at {:04X}: {:02X}",
+                self.address, self.pc, self.old
+            )
+        }
+    }
+}
@@ -1,13 +1,422 @@
-use std::{env, path::Path};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-mod emulator;
+use rust_8080_emulator::emulator::{
+    self,
+    breakpoint::Breakpoint,
+    cpu::Model,
+    error::EmulatorError,
+    symbols::SymbolTable,
+    trace_diff::{Comparison, Divergence},
+    watchpoint::{WatchKind, Watchpoint},
+};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if let Err(error) = run(&args) {
+        eprintln!("Error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), EmulatorError> {
+    if args.get(1).map(String::as_str) == Some("dap") {
+        return dap(&args[2..]);
+    }
+    if args.len() < 3 {
+        return Err(usage("Missing file path."));
+    }
+
+    match args[1].as_str() {
+        "asm" => return asm(&args[2..]),
+        "hex" => return hex(&args[2..]),
+        "identify" => return identify(&args[2..]),
+        "disasm" => return disasm(&args[2..]),
+        "tracediff" => return tracediff(&args[2..]),
+        "gdb" => return gdb(&args[2..]),
+        "bench" => return bench(&args[2..]),
+        "singlestep" => return single_step(&args[2..]),
+        _ => {}
+    }
+
+    let options = &args[3..];
+    let symbols = load_symbols(options)?;
+    let mut program = emulator::read_program(&args[1], Path::new(&args[2]))?;
+    if let Some(index) = options.iter().position(|option| option == "--entry") {
+        let entry = options
+            .get(index + 1)
+            .ok_or_else(|| usage("Missing value for '--entry'"))?;
+        program.start = Some(resolve(&symbols, entry)?);
+    }
+    let mut emu: emulator::Emulator = emulator::Emulator::new(program)?;
+    let mut tracer = None;
+    let mut trace_range = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let mut value = || {
+            options
+                .next()
+                .ok_or_else(|| usage(&format!("Missing value for '{}'", option)))
+        };
+        match option.as_str() {
+            // --trace <file>
+            "--trace" => {
+                let path = Path::new(value()?);
+                let mut trace = emulator::trace::Tracer::new(path)
+                    .map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })?;
+                trace.set_symbols(symbols.clone());
+                tracer = Some(trace);
+            }
+            // --trace-range <start> <end>
+            "--trace-range" => {
+                let start = resolve(&symbols, value()?)?;
+                let end = resolve(&symbols, value()?)?;
+                trace_range = Some((start, end));
+            }
+            // --strict, stop on undocumented opcodes
+            "--strict" => {
+                emu.set_strict(true);
+            }
+            // --cpu <8080, 8085 or z80>, overriding the identified machine's
+            "--cpu" => {
+                let name = value()?;
+                emu.set_model(Model::parse(name).ok_or_else(|| usage(&format!("Invalid cpu '{}'", name)))?);
+            }
+            // --symbols <file> and --entry <label or address>, already applied
+            "--symbols" | "--entry" => {
+                value()?;
+            }
+            // --break <label or address>
+            "--break" => {
+                emu.add_breakpoint(Breakpoint::new(resolve(&symbols, value()?)?));
+            }
+            // --break-if <expression>
+            "--break-if" => {
+                let condition = value()?;
+                let breakpoint = Breakpoint::condition(condition, &symbols)
+                    .map_err(|error| usage(&format!("Invalid condition '{}': {}", condition, error)))?;
+                emu.add_breakpoint(breakpoint);
+            }
+            // --watch, --watch-read, --watch-change <label or address>[-<label or address>]
+            "--watch" | "--watch-read" | "--watch-change" => {
+                let kind = match option.as_str() {
+                    "--watch-read" => WatchKind::Read,
+                    "--watch-change" => WatchKind::Change,
+                    _ => WatchKind::Write,
+                };
+                let range = value()?;
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                emu.add_watchpoint(Watchpoint::new(kind, resolve(&symbols, start)?, resolve(&symbols, end)?));
+            }
+            _ => return Err(usage(&format!("Invalid option '{}'", option))),
+        }
+    }
+    if let Some(mut tracer) = tracer {
+        if let Some((start, end)) = trace_range {
+            tracer.set_range(start, end);
+        }
+        emu.set_tracer(tracer);
+    }
+    emu.set_symbols(symbols);
+    emu.start()
+}
+
+fn usage(message: &str) -> EmulatorError {
+    EmulatorError::Usage(message.to_string())
+}
+
+fn parse_port(arg: &str) -> Result<u16, EmulatorError> {
+    arg.parse().map_err(|_| usage(&format!("Invalid port '{}'", arg)))
+}
+
+// gdb <flag> <path> [port] [--cpu <8080, 8085 or z80>]
+fn gdb(args: &[String]) -> Result<(), EmulatorError> {
+    if args.len() < 2 {
+        return Err(usage("Missing file path."));
+    }
+    let program = emulator::read_program(&args[0], Path::new(&args[1]))?;
+    let port = match args.get(2).filter(|arg| !arg.starts_with("--")) {
+        Some(arg) => parse_port(arg)?,
+        None => 1234,
+    };
+    let model = cpu_option(args)?;
+    Ok(emulator::gdb::serve(program, port, model)?)
+}
+
+// The processor given with --cpu, if any
+fn cpu_option(options: &[String]) -> Result<Option<Model>, EmulatorError> {
+    match options.iter().position(|option| option == "--cpu") {
+        Some(index) => {
+            let name = options.get(index + 1).ok_or_else(|| usage("Missing value for '--cpu'"))?;
+            Ok(Some(Model::parse(name).ok_or_else(|| usage(&format!("Invalid cpu '{}'", name)))?))
+        }
+        None => Ok(None),
+    }
+}
+
+// dap [port], over stdio without a port
+fn dap(args: &[String]) -> Result<(), EmulatorError> {
+    match args.first() {
+        Some(port) => emulator::dap::serve_tcp(parse_port(port)?)?,
+        None => emulator::dap::serve_stdio()?,
+    }
+    Ok(())
+}
+
+// Loads the file given with --symbols, if any
+fn load_symbols(options: &[String]) -> Result<SymbolTable, EmulatorError> {
+    match options.iter().position(|option| option == "--symbols") {
+        Some(index) => {
+            let path = Path::new(options.get(index + 1).ok_or_else(|| usage("Missing symbol file path."))?);
+            SymbolTable::load(path).map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })
+        }
+        None => Ok(SymbolTable::new()),
+    }
+}
+
+fn resolve(symbols: &SymbolTable, location: &str) -> Result<u16, EmulatorError> {
+    symbols
+        .resolve(location)
+        .ok_or_else(|| usage(&format!("Unknown label or address '{}'", location)))
+}
+
+// The [start] [end] arguments before any options, defaulting to the whole image
+fn address_range(args: &[String], symbols: &SymbolTable, image: &[u8]) -> Result<(u16, u16), EmulatorError> {
+    let range = args
+        .iter()
+        .take_while(|arg| !arg.starts_with("--"))
+        .map(|arg| resolve(symbols, arg))
+        .collect::<Result<Vec<u16>, _>>()?;
+    let start = range.first().copied().unwrap_or(0);
+    let end = range
+        .get(1)
+        .copied()
+        .unwrap_or(image.len().saturating_sub(1) as u16);
+    Ok((start, end))
+}
+
+fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), EmulatorError> {
+    std::fs::write(path, contents).map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })
+}
+
+// hex <flag> <path> <output> [start] [end] [--symbols <file>]
+fn hex(args: &[String]) -> Result<(), EmulatorError> {
     if args.len() < 3 {
-        panic!("Missing file path.");
+        return Err(usage("Missing output path."));
     }
+    let program = emulator::read_program(&args[0], Path::new(&args[1]))?;
+    let symbols = load_symbols(args)?;
+    let (start, end) = address_range(&args[3..], &symbols, &program.image)?;
 
-    let mut emu: emulator::Emulator = emulator::Emulator::new(&args[1], &Path::new(&args[2]));
-    emu.start();
+    let text = emulator::intel_hex::write(&program.image, start, end, program.start);
+    write_file(Path::new(&args[2]), text)
+}
+
+// asm <source> [output], also writing a listing and symbol file next to the output. Outputs
+// ending in .hex are written as Intel HEX.
+fn asm(args: &[String]) -> Result<(), EmulatorError> {
+    let source_path = Path::new(&args[0]);
+    let output = args
+        .get(1)
+        .map_or_else(|| source_path.with_extension("bin"), PathBuf::from);
+
+    let assembly = emulator::assembler::assemble_file(source_path)?;
+    if output.extension().is_some_and(|extension| extension == "hex") {
+        let end = assembly.image.len().saturating_sub(1) as u16;
+        write_file(&output, emulator::intel_hex::write(&assembly.image, 0, end, None))?;
+    } else {
+        write_file(&output, &assembly.image)?;
+    }
+    write_file(&output.with_extension("lst"), &assembly.listing)?;
+    write_file(&output.with_extension("sym"), assembly.symbol_file())?;
+    println!("Assembled {} bytes into {}", assembly.image.len(), output.display());
+    Ok(())
+}
+
+// identify <flag> <path>
+fn identify(args: &[String]) -> Result<(), EmulatorError> {
+    if args.len() < 2 {
+        return Err(usage("Missing file path."));
+    }
+    let program = emulator::read_program(&args[0], Path::new(&args[1]))?;
+    let identification = emulator::rom::identify(&program.image);
+    println!("{}", identification);
+    let machine = match identification.machine() {
+        Some(machine) => machine,
+        None => return Ok(()),
+    };
+    for rom in machine.roms {
+        let status = if emulator::rom::matches(rom, &program.image) { "ok" } else { "BAD" };
+        println!(
+            "  {:<12} {:04X}-{:04X}  crc32 {:08x}  {}",
+            rom.name,
+            rom.address,
+            rom.address as usize + rom.size - 1,
+            rom.crc32,
+            status
+        );
+    }
+    let config = machine.config;
+    let ports = &config.ports;
+    let list = |ports: &[u8]| ports.iter().map(u8::to_string).collect::<Vec<_>>().join(", ");
+    println!(
+        "  Ports: inputs {}; shift amount {}, data {}, result {}; sound {}; watchdog {}",
+        list(ports.inputs),
+        ports.shift_amount,
+        ports.shift_data,
+        ports.shift_result,
+        list(ports.sound),
+        ports.watchdog
+    );
+    println!("  DIP switches: port {} = {:02X}", config.dip_port, config.dip_switches);
+    println!("  Overlay: {} coloured bands", config.overlay.len());
+    println!("  Entry: {:04X}", machine.start);
+    Ok(())
+}
+
+// disasm <flag> <path> [start] [end] [--symbols <file>] [--cpu <8080, 8085 or z80>]
+fn disasm(args: &[String]) -> Result<(), EmulatorError> {
+    if args.len() < 2 {
+        return Err(usage("Missing file path."));
+    }
+    let program = emulator::read_program(&args[0], Path::new(&args[1]))?;
+    let symbols = load_symbols(args)?;
+    let (start, end) = address_range(&args[2..], &symbols, &program.image)?;
+    let model = program.clone().machine_cpu(cpu_option(args)?).model();
+
+    for instruction in emulator::disassembler::disassemble_range(&program.image, start, end, model) {
+        if let Some(label) = symbols.name(instruction.address) {
+            println!("{}:", label);
+        }
+        println!(
+            "{:04X}  {:<9} {}",
+            instruction.address,
+            instruction.hex_bytes(),
+            instruction.symbolic(&symbols)
+        );
+    }
+    Ok(())
+}
+
+// bench <flag> <path> [seconds]
+fn bench(args: &[String]) -> Result<(), EmulatorError> {
+    if args.len() < 2 {
+        return Err(usage("Missing file path."));
+    }
+    let program = emulator::read_program(&args[0], Path::new(&args[1]))?;
+    let seconds: f64 = match args.get(2) {
+        Some(arg) => arg
+            .parse()
+            .ok()
+            .filter(|seconds: &f64| *seconds > 0.0)
+            .ok_or_else(|| usage(&format!("Invalid number of seconds '{}'", arg)))?,
+        None => 2.0,
+    };
+
+    let (instructions, cycles, elapsed) = emulator::benchmark(program, Duration::from_secs_f64(seconds))?;
+    let per_second = |count: u64| count as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+    println!(
+        "{} instructions, {} cycles in {:.2}s: {:.1} million instructions/s, {:.1} emulated MHz",
+        instructions,
+        cycles,
+        elapsed.as_secs_f64(),
+        per_second(instructions),
+        per_second(cycles)
+    );
+    Ok(())
+}
+
+// singlestep <file or directory of test vectors>
+fn single_step(args: &[String]) -> Result<(), EmulatorError> {
+    let path = Path::new(&args[0]);
+    let mut paths = vec![path.to_path_buf()];
+    if path.is_dir() {
+        let entries = std::fs::read_dir(path).map_err(|error| EmulatorError::File { path: path.to_path_buf(), error })?;
+        paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect();
+        paths.sort();
+    }
+
+    let mut failed = 0;
+    for path in paths {
+        let report = emulator::single_step::run_file(&path)?;
+        println!(
+            "{}: {} passed, {} failed",
+            path.display(),
+            report.passed,
+            report.failed
+        );
+        for mismatch in report.mismatches.iter().take(5) {
+            println!(
+                "    {}: {} expected {:04X}, got {:04X}",
+                mismatch.case, mismatch.field, mismatch.expected, mismatch.actual
+            );
+        }
+        failed += report.failed;
+    }
+    if failed > 0 {
+        return Err(EmulatorError::Failed(format!("{} test cases failed", failed)));
+    }
+    Ok(())
+}
+
+// tracediff <flag> <path> <reference log> [context lines] [--cpu <8080, 8085 or z80>]
+fn tracediff(args: &[String]) -> Result<(), EmulatorError> {
+    if args.len() < 3 {
+        return Err(usage("Missing reference log path."));
+    }
+    let program = emulator::read_program(&args[0], Path::new(&args[1]))?;
+    let reference_path = Path::new(&args[2]);
+    let reference = std::fs::read_to_string(reference_path)
+        .map_err(|error| EmulatorError::File { path: reference_path.to_path_buf(), error })?;
+    let context = match args.get(3).filter(|arg| !arg.starts_with("--")) {
+        Some(arg) => arg
+            .parse()
+            .map_err(|_| usage(&format!("Invalid number of context lines '{}'", arg)))?,
+        None => 5,
+    };
+
+    match emulator::trace_diff::run(program, cpu_option(args)?, &reference, context)? {
+        Comparison::Diverged(divergence) => {
+            print_divergence(&divergence);
+            Err(EmulatorError::Failed(format!(
+                "Trace diverges from the reference at line {}",
+                divergence.line
+            )))
+        }
+        Comparison::Matched { instructions, pc } => {
+            println!("No divergence in {} instructions, stopped at PC {:04X}", instructions, pc);
+            Ok(())
+        }
+    }
+}
+
+fn print_divergence(divergence: &Divergence) {
+    println!(
+        "Diverged after {} instructions at line {} of the reference log",
+        divergence.instruction, divergence.line
+    );
+    println!("Before:");
+    for line in &divergence.before {
+        println!("      {}", line);
+    }
+    println!("> ref {}", divergence.expected);
+    println!("> emu {}", divergence.actual);
+    for (name, expected, actual) in &divergence.differences {
+        println!("  {}: expected {:04X}, got {:04X}", name, expected, actual);
+    }
+    println!("After:");
+    for line in &divergence.reference_after {
+        println!("  ref {}", line);
+    }
+    for line in &divergence.emulator_after {
+        println!("  emu {}", line);
+    }
+    println!();
+    println!("{}", divergence.registers);
 }